@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Measures how long it takes to start the helper and have it handle
+//! the read-only commands that tooling runs all the time.  `eden clone`
+//! runs the helper several times per checkout, so any regression here is
+//! multiplied.
+//! The commands run in the fixture mode (see src/fixtures.rs), against
+//! the golden test cases of the same names, so that they load the policy
+//! and parse the tool output as they would for real without touching any
+//! real disks.  This needs the `fixtures` feature:
+//!
+//! cargo bench --features fixtures --bench startup
+//!
+//! When run via `cargo bench`, this fails if the median time for any of
+//! the commands exceeds `STARTUP_BUDGET` so that it can be used to gate
+//! CI.  The helper is only a stub on platforms other than macOS, so there
+//! is nothing to measure there.
+#![cfg_attr(not(all(target_os = "macos", feature = "fixtures")), allow(dead_code))]
+use criterion::{criterion_group, Criterion};
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+const HELPER: &str = env!("CARGO_BIN_EXE_eden_apfs_mount_helper");
+const STARTUP_BUDGET: Duration = Duration::from_millis(25);
+const BUDGET_SAMPLES: usize = 31;
+
+/// The golden test cases whose commands we time
+const CASES: &[&str] = &["list", "capabilities"];
+
+/// Run the command of the golden test case `case` against its fixtures
+fn run_helper(case: &str) {
+    let case_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/cases")
+        .join(case);
+    let args = std::fs::read_to_string(case_dir.join("args")).unwrap();
+    let output = Command::new(HELPER)
+        .args(args.lines().filter(|line| !line.is_empty()))
+        .env("EDEN_APFS_FIXTURES", &case_dir)
+        .env_remove("EDEN_APFS_TEST_CONTAINER")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+}
+
+fn startup(c: &mut Criterion) {
+    for case in CASES {
+        c.bench_function(&format!("startup/{}", case), |b| {
+            b.iter(|| run_helper(case))
+        });
+    }
+}
+
+fn check_startup_budget() {
+    for case in CASES {
+        let mut samples: Vec<Duration> = (0..BUDGET_SAMPLES)
+            .map(|_| {
+                let start = Instant::now();
+                run_helper(case);
+                start.elapsed()
+            })
+            .collect();
+        samples.sort();
+        let median = samples[samples.len() / 2];
+        assert!(
+            median <= STARTUP_BUDGET,
+            "median time {:?} for {} exceeds the budget of {:?}",
+            median,
+            case,
+            STARTUP_BUDGET
+        );
+        println!(
+            "median time {:?} for {} is within the budget of {:?}",
+            median, case, STARTUP_BUDGET
+        );
+    }
+}
+
+criterion_group!(benches, startup);
+
+#[cfg(all(target_os = "macos", feature = "fixtures"))]
+fn main() {
+    benches();
+    // cargo passes `--bench` when running benchmarks for real, as opposed
+    // to when it smoke tests them as part of `cargo test --benches`, where
+    // timings from an unoptimized build are meaningless.
+    if std::env::args().any(|arg| arg == "--bench") {
+        check_startup_budget();
+    }
+    Criterion::default().configure_from_args().final_summary();
+}
+
+#[cfg(not(all(target_os = "macos", feature = "fixtures")))]
+fn main() {}
@@ -0,0 +1,1370 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Querying and creating APFS volumes via `diskutil`
+
+use crate::dry_run::dry_run;
+use crate::mount_table::MountTable;
+use crate::privs::{new_cmd_unprivileged, read_in_background, RunCommand};
+use crate::timeouts::{Operation, Watchdog};
+use crate::tools::tool_paths;
+use crate::transcript::{self, Tee};
+use crate::volume_name::is_edenfs_volume_name;
+pub use crate::volume_name::{decode_volume_name, encode_mount_point_as_volume_name};
+use anyhow::*;
+use serde::*;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ApfsContainer {
+    pub container_reference: String,
+    /// The total size of the container, in bytes
+    #[serde(default)]
+    pub capacity_ceiling: u64,
+    /// The space not yet allocated to any volume, in bytes
+    #[serde(default)]
+    pub capacity_free: u64,
+    /// The partitions that the container lives on
+    #[serde(default)]
+    pub physical_stores: Vec<PhysicalStore>,
+    pub volumes: Vec<ApfsVolume>,
+}
+
+impl ApfsContainer {
+    /// Whether the container lives on the physical disk `disk`, eg:
+    /// `disk2`.  The container itself is also accepted.
+    pub fn is_on_disk(&self, disk: &str) -> bool {
+        self.container_reference == disk
+            || self
+                .physical_stores
+                .iter()
+                .any(|store| whole_disk(&store.device_identifier) == disk)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PhysicalStore {
+    /// The partition, eg: `disk0s2`
+    pub device_identifier: String,
+}
+
+/// The whole disk that holds the partition or volume `ident`, eg:
+/// `disk0` for `disk0s2`
+pub fn whole_disk(ident: &str) -> &str {
+    let number_len = ident
+        .strip_prefix("disk")
+        .map(|rest| rest.chars().take_while(char::is_ascii_digit).count())
+        .unwrap_or(0);
+    if number_len == 0 {
+        return ident;
+    }
+    &ident[..4 + number_len]
+}
+
+/// The volume roles that are used by the OS itself, as opposed to the
+/// `Data` and `User` roles that hold user data
+pub const SYSTEM_ROLES: &[&str] = &[
+    "System",
+    "Preboot",
+    "Recovery",
+    "VM",
+    "Update",
+    "xART",
+    "Hardware",
+    "Baseband",
+    "Installer",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ApfsVolume {
+    pub device_identifier: String,
+    pub name: Option<String>,
+    #[serde(rename = "APFSVolumeUUID", default)]
+    pub uuid: Option<String>,
+    /// The roles that macOS assigns to the volume, eg: `System`, `Data`
+    /// or `Preboot`.  Our volumes have none.
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// True if the volume is encrypted and hasn't been unlocked yet
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub capacity_in_use: u64,
+    /// The maximum size of the volume, or 0 if it has no quota
+    #[serde(default)]
+    pub capacity_quota: u64,
+    /// Space guaranteed to the volume, or 0 if it has no reserve
+    #[serde(default)]
+    pub capacity_reserve: u64,
+}
+
+impl ApfsVolume {
+    /// Resolve the current mount point for this volume by looking
+    /// at the mount table.  The mount table is optional; if not
+    /// provided by the caller, this function will resolve it for
+    /// itself.
+    /// If you are resolving more than mount point in a loop, then
+    /// it is preferable to pass in the mount table so that it isn't
+    /// recomputed on each call.
+    pub fn get_current_mount_point(&self, table: Option<&MountTable>) -> Option<String> {
+        let table = MountTable::parse_if_needed(table).ok()?;
+        let dev_name = format!("/dev/{}", self.device_identifier);
+        for entry in table.entries {
+            if entry.device == dev_name {
+                return Some(entry.mount_point);
+            }
+        }
+        None
+    }
+
+    /// If this volume was created by this tool, return its preferred
+    /// (rather than current) mount point.
+    pub fn preferred_mount_point(&self) -> Option<String> {
+        if self.is_edenfs_managed_volume() {
+            let name = self.name.as_ref().unwrap();
+            Some(name[7..].to_owned())
+        } else {
+            None
+        }
+    }
+
+    /// If this is an edenfs managed volume that is mounted somewhere other
+    /// than its preferred location, such as under /Volumes where macOS
+    /// mounts volumes at boot, return its current mount point.
+    pub fn misplaced_mount_point(&self, table: Option<&MountTable>) -> Option<String> {
+        let preferred = self.preferred_mount_point()?;
+        let current = self.get_current_mount_point(table)?;
+        if current == preferred {
+            None
+        } else {
+            Some(current)
+        }
+    }
+
+    /// Returns true if the volume has one of the `SYSTEM_ROLES`, which
+    /// means that it holds the OS rather than user data
+    pub fn is_system_volume(&self) -> bool {
+        self.roles
+            .iter()
+            .any(|role| SYSTEM_ROLES.contains(&role.as_str()))
+    }
+
+    /// Returns true if the volume has any of `roles`, ignoring case
+    pub fn has_any_role(&self, roles: &[String]) -> bool {
+        self.roles
+            .iter()
+            .any(|role| roles.iter().any(|want| want.eq_ignore_ascii_case(role)))
+    }
+
+    /// Returns true if the volume name matches our "special" edenfs managed
+    /// volume name pattern.
+    pub fn is_edenfs_managed_volume(&self) -> bool {
+        self.name
+            .as_ref()
+            .map(|name| is_edenfs_volume_name(name))
+            .unwrap_or(false)
+    }
+
+    /// The number of bytes by which this volume can grow, given that
+    /// `container_free` bytes are unallocated in its container.
+    /// Any unused portion of our reserve is available in addition to
+    /// the free space, and our quota (if any) caps the total.
+    pub fn available_space(&self, container_free: u64) -> u64 {
+        let unused_reserve = self.capacity_reserve.saturating_sub(self.capacity_in_use);
+        let available = container_free.saturating_add(unused_reserve);
+        if self.capacity_quota > 0 {
+            available.min(self.capacity_quota.saturating_sub(self.capacity_in_use))
+        } else {
+            available
+        }
+    }
+
+    /// Returns true if this is an edenfs managed volume and if the provided
+    /// current mount point path is the preferred location.
+    /// The intent is that current is produced by calling `get_current_mount_point`
+    /// and then passed here.
+    pub fn is_preferred_location(&self, current: &str) -> Result<bool> {
+        let preferred = self
+            .preferred_mount_point()
+            .ok_or_else(|| anyhow!("this volume is not an edenfs managed volume"))?;
+        Ok(preferred == current)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Containers {
+    containers: Vec<ApfsContainer>,
+}
+
+// A note about `native-plist` vs `json-plist`.
+// The `json-plist` feature (which is the default) uses a `plutil` executable on
+// macos to convert the plist to json and then uses serde_json to extract the data
+// of interest.  The `native-plist` feature parses the plist in-process, but
+// diskutil's output has changed shape between OS releases before, so rather
+// than failing outright when the native parser rejects a plist we fall back to
+// plutil for it.  Each fallback is counted (see `plist_fallbacks`) and reported
+// by `health` so that schema drift is noticed in the field.
+
+/// The number of plists that the native parser rejected and that were
+/// parsed via plutil instead
+static PLIST_FALLBACKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of plists that this process had to parse via
+/// plutil because the native parser rejected them.  This is always zero
+/// without the `native-plist` feature.
+pub fn plist_fallbacks() -> usize {
+    PLIST_FALLBACKS.load(Ordering::Relaxed)
+}
+
+#[cfg(feature = "native-plist")]
+/// Deserialize plist data read from `input`, trying the native parser
+/// first and falling back to plutil if it fails.
+/// The data has to be buffered up front so that it can be handed to
+/// plutil if the native parser rejects it.
+fn parse_plist_from<T, R>(mut input: R) -> Result<T>
+where
+    T: de::DeserializeOwned,
+    R: std::io::Read + Send + 'static,
+{
+    let mut data = vec![];
+    input.read_to_end(&mut data).context("reading plist data")?;
+    match plist::from_bytes(&data) {
+        Ok(value) => Ok(value),
+        Err(err) => {
+            PLIST_FALLBACKS.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "warning: the native plist parser failed ({}), falling back to plutil",
+                err
+            );
+            parse_plist_with_plutil(std::io::Cursor::new(data))
+        }
+    }
+}
+
+#[cfg(not(feature = "native-plist"))]
+/// Deserialize plist data read from `input` via plutil
+fn parse_plist_from<T, R>(input: R) -> Result<T>
+where
+    T: de::DeserializeOwned,
+    R: std::io::Read + Send + 'static,
+{
+    parse_plist_with_plutil(input)
+}
+
+/// Deserialize plist data read from `input` by running it through
+/// plutil and converting it to json.
+/// The data is streamed through plutil and the json parser rather than
+/// being buffered up front, and anything that we don't model is skipped
+/// over, which keeps memory usage bounded on systems with lots of volumes.
+fn parse_plist_with_plutil<T, R>(mut input: R) -> Result<T>
+where
+    T: de::DeserializeOwned,
+    R: std::io::Read + Send + 'static,
+{
+    // Run plutil and tell it to convert stdin (that last `-` arg)
+    // into json and output it to stdout (the `-o -`).
+    let started = Instant::now();
+    let mut cmd = new_cmd_unprivileged(&tool_paths()?.plutil);
+    let mut child = cmd
+        .args(&["-convert", "json", "-o", "-", "-"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+
+    // Feed the input from another thread so that we can consume the
+    // output concurrently.  A write error here means that plutil gave
+    // up early, which will be reported via the parse error below.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || std::io::copy(&mut input, &mut stdin).ok());
+
+    let (stdout, copy) = Tee::new(child.stdout.take().unwrap());
+    let result =
+        serde_json::from_reader(std::io::BufReader::new(stdout)).context("parsing json data");
+    writer.join().ok();
+    let status = child.wait()?;
+    record_streamed_command(&cmd, started, status, copy, vec![]);
+    result
+}
+
+/// Record a command whose output we streamed in the transcript
+fn record_streamed_command(
+    cmd: &Command,
+    started: Instant,
+    status: std::process::ExitStatus,
+    stdout: Option<Arc<Mutex<Vec<u8>>>>,
+    stderr: Vec<u8>,
+) {
+    if let Some(stdout) = stdout {
+        let output = std::process::Output {
+            status,
+            stdout: std::mem::take(&mut *stdout.lock().unwrap()),
+            stderr,
+        };
+        transcript::record_command(cmd, started.elapsed(), &Ok(output));
+    }
+}
+
+/// Execute `cmd`, which produces a plist on its stdout, and deserialize
+/// the output as it is produced.  Queries are subject to the `list`
+/// timeout.
+pub(crate) fn run_plist_command<T: de::DeserializeOwned>(cmd: &mut Command) -> Result<T> {
+    #[cfg(feature = "fixtures")]
+    {
+        if crate::fixtures::is_active() {
+            let output = transcript::recording(cmd, |cmd| {
+                crate::fixtures::fake_output(cmd).expect("the fixture mode is active")
+            })?;
+            if !output.status.success() {
+                bail!("failed to execute {:?}: {:#?}", cmd, output);
+            }
+            return serde_json::from_slice(&output.stdout).context("parsing json data");
+        }
+    }
+    let started = Instant::now();
+    let mut child = cmd
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    // stderr is drained alongside, so that a tool that fills that pipe
+    // can't block before it has written all of the plist
+    let stderr = read_in_background(child.stderr.take());
+    let watchdog = Watchdog::start(&child, Operation::List);
+    let (stdout, copy) = Tee::new(child.stdout.take().unwrap());
+    let result = parse_plist_from(stdout);
+    let status = watchdog.finish(&mut child)?;
+    let output = std::process::Output {
+        status,
+        stdout: vec![],
+        stderr: stderr.join().unwrap_or_default(),
+    };
+    record_streamed_command(cmd, started, output.status, copy, output.stderr.clone());
+    if !output.status.success() {
+        bail!("failed to execute {:?}: {:#?}", cmd, output);
+    }
+    result
+}
+
+/// The subset of the output from `diskutil info -plist` that we use
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DiskInfo {
+    pub device_identifier: String,
+    #[serde(default)]
+    pub volume_name: Option<String>,
+    #[serde(rename = "VolumeUUID", default)]
+    pub volume_uuid: Option<String>,
+    /// This is the empty string if the volume isn't mounted
+    #[serde(default)]
+    pub mount_point: String,
+}
+
+/// Query information about a single disk or volume by executing `diskutil`.
+/// `target` may be a device identifier, a volume UUID or a mount point.
+/// This is much cheaper than `apfs_list` on systems with lots of volumes,
+/// so prefer it when we already know which volume we are interested in.
+pub fn disk_info(target: &str) -> Result<DiskInfo> {
+    run_plist_command(
+        new_cmd_unprivileged(&tool_paths()?.diskutil).args(&["info", "-plist", target]),
+    )
+}
+
+/// If there is a volume mounted at exactly `mount_point`, return it.
+/// This uses `disk_info` rather than `apfs_list` to resolve the volume.
+pub fn find_mounted_volume(mount_point: &str, table: &MountTable) -> Option<ApfsVolume> {
+    let entry = table
+        .entries
+        .iter()
+        .find(|entry| entry.mount_point == mount_point)?;
+    let device = entry.device.strip_prefix("/dev/")?;
+    let info = disk_info(device).ok()?;
+    Some(ApfsVolume {
+        device_identifier: info.device_identifier,
+        name: info.volume_name,
+        uuid: info.volume_uuid,
+        ..Default::default()
+    })
+}
+
+/// Obtain the list of apfs containers and volumes by executing `diskutil`.
+pub fn apfs_list() -> Result<Vec<ApfsContainer>> {
+    let containers: Containers = run_plist_command(
+        new_cmd_unprivileged(&tool_paths()?.diskutil).args(&["apfs", "list", "-plist"]),
+    )?;
+    Ok(containers.containers)
+}
+
+/// Returns true if `ident` looks like the device identifier of an
+/// APFS container, eg: `disk1`.
+pub fn is_container_identifier(ident: &str) -> bool {
+    match ident.strip_prefix("disk") {
+        Some(number) => !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// Find the container that holds the volumes of the system itself, which
+/// is where we create new volumes.  This is disk1 on most Intel Macs but
+/// disk3 on Apple Silicon and on some Macs with several disks, so we look
+/// for the container of the `Data` or `System` volume.  The releases
+/// before Catalina don't assign those roles, so failing that we look for
+/// the volume mounted at `/`.
+pub fn system_container<'a>(
+    containers: &'a [ApfsContainer],
+    mounts: Option<&MountTable>,
+) -> Result<&'a ApfsContainer> {
+    for role in &["Data", "System"] {
+        if let Some(container) = containers.iter().find(|container| {
+            container
+                .volumes
+                .iter()
+                .any(|vol| vol.roles.iter().any(|r| r == role))
+        }) {
+            return Ok(container);
+        }
+    }
+    let mounts = MountTable::parse_if_needed(mounts)?;
+    containers
+        .iter()
+        .find(|container| {
+            container
+                .volumes
+                .iter()
+                .any(|vol| vol.get_current_mount_point(Some(&mounts)).as_deref() == Some("/"))
+        })
+        .ok_or_else(|| anyhow!("unable to find the APFS container that holds the system volume"))
+}
+
+pub fn find_existing_volume<'a>(
+    containers: &'a [ApfsContainer],
+    name: &str,
+) -> Option<&'a ApfsVolume> {
+    for container in containers {
+        for volume in &container.volumes {
+            if volume.name.as_ref().map(String::as_ref) == Some(name) {
+                return Some(volume);
+            }
+        }
+    }
+    None
+}
+
+/// Extract the device identifier of a newly created volume from the
+/// output of `diskutil apfs addVolume`, which looks like this:
+///
+/// ```text
+/// Will export new APFS Volume "edenfs:/tmp/foo" from APFS Container Reference disk1
+/// Started APFS operation on disk1
+/// Preparing to add APFS Volume to APFS Container disk1
+/// Creating APFS Volume
+/// Created new APFS Volume disk1s9
+/// Disk from APFS operation: disk1s9
+/// Finished APFS operation on disk1
+/// ```
+fn parse_add_volume_output(text: &str) -> Option<String> {
+    fn is_device_identifier(ident: &str) -> bool {
+        let numbers = match ident.strip_prefix("disk") {
+            Some(numbers) => numbers,
+            None => return false,
+        };
+        let mut iter = numbers.splitn(2, 's');
+        match (iter.next(), iter.next()) {
+            (Some(disk), Some(slice)) => {
+                !disk.is_empty()
+                    && disk.chars().all(|c| c.is_ascii_digit())
+                    && !slice.is_empty()
+                    && slice.chars().all(|c| c.is_ascii_digit())
+            }
+            _ => false,
+        }
+    }
+
+    for prefix in &["Disk from APFS operation: ", "Created new APFS Volume "] {
+        for line in text.lines() {
+            if let Some(ident) = line.trim().strip_prefix(prefix) {
+                let ident = ident.trim();
+                if is_device_identifier(ident) {
+                    return Some(ident.to_owned());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Unlock an encrypted volume without mounting it.  The passphrase is
+/// passed to diskutil on stdin.
+pub fn unlock_volume(volume: &ApfsVolume, passphrase: &str) -> Result<()> {
+    let output = new_cmd_unprivileged(&tool_paths()?.diskutil)
+        .args(&[
+            "apfs",
+            "unlockVolume",
+            &volume.device_identifier,
+            "-nomount",
+            "-stdinpassphrase",
+        ])
+        .run_with_input(passphrase.as_bytes())?;
+    if !output.status.success() {
+        bail!(
+            "failed to unlock {}: {}",
+            volume.device_identifier,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// How to set up a new volume, beyond its name and container
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VolumeOptions {
+    /// The most that the volume may hold, in bytes
+    pub quota: Option<u64>,
+    /// The space in bytes that the container sets aside for the volume,
+    /// which the other volumes can't take
+    pub reserve: Option<u64>,
+    /// Whether file names that differ only in case are different files
+    pub case_sensitive: bool,
+    /// Encrypt the volume with this passphrase
+    pub passphrase: Option<String>,
+}
+
+impl VolumeOptions {
+    /// The filesystem personality to pass to `diskutil apfs addVolume`
+    fn personality(&self) -> &'static str {
+        if self.case_sensitive {
+            "APFSX"
+        } else {
+            "apfs"
+        }
+    }
+
+    /// The arguments to `diskutil apfs addVolume` that apply these options
+    fn add_volume_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(quota) = self.quota {
+            args.push("-quota".to_owned());
+            args.push(quota.to_string());
+        }
+        if let Some(reserve) = self.reserve {
+            args.push("-reserve".to_owned());
+            args.push(reserve.to_string());
+        }
+        if self.passphrase.is_some() {
+            args.push("-stdinpassphrase".to_owned());
+        }
+        args
+    }
+}
+
+/// Create a new subvolume with the specified name in `container`.
+/// Note that this does NOT require any special privilege on macOS.
+/// The passphrase of an encrypted volume is passed to diskutil on stdin.
+pub fn make_new_volume(name: &str, container: &str, options: &VolumeOptions) -> Result<ApfsVolume> {
+    let mut cmd = new_cmd_unprivileged(&tool_paths()?.diskutil);
+    cmd.args(&["apfs", "addVolume", container, options.personality(), name])
+        .args(options.add_volume_args())
+        .arg("-nomount");
+    let output = match &options.passphrase {
+        Some(passphrase) => cmd.run_timed_with_input(Operation::Create, passphrase.as_bytes())?,
+        None => cmd.run_timed(Operation::Create)?,
+    };
+    if !output.status.success() {
+        // diskutil reports some errors on stdout
+        let message = match String::from_utf8_lossy(&output.stderr).trim() {
+            "" => String::from_utf8_lossy(&output.stdout).trim().to_owned(),
+            stderr => stderr.to_owned(),
+        };
+        let mut sizes = vec![];
+        if let Some(quota) = options.quota {
+            sizes.push(format!("a quota of {} bytes", quota));
+        }
+        if let Some(reserve) = options.reserve {
+            sizes.push(format!("a reserve of {} bytes", reserve));
+        }
+        let kind = match (options.passphrase.is_some(), options.case_sensitive) {
+            (true, true) => "encrypted case-sensitive volume",
+            (true, false) => "encrypted volume",
+            (false, true) => "case-sensitive volume",
+            (false, false) => "volume",
+        };
+        if sizes.is_empty() {
+            bail!(
+                "failed to create the {} {} in {}: {}",
+                kind,
+                name,
+                container,
+                message
+            );
+        }
+        bail!(
+            "failed to create the {} {} in {} with {}: {}",
+            kind,
+            name,
+            container,
+            sizes.join(" and "),
+            message
+        );
+    }
+    if dry_run() {
+        // There is no volume to look for; the caller only reports what
+        // it would do with it
+        return Ok(ApfsVolume {
+            device_identifier: format!("{}s<new>", container),
+            name: Some(name.to_owned()),
+            ..Default::default()
+        });
+    }
+    if let Some(device_identifier) =
+        parse_add_volume_output(&String::from_utf8_lossy(&output.stdout))
+    {
+        return Ok(ApfsVolume {
+            device_identifier,
+            name: Some(name.to_owned()),
+            ..Default::default()
+        });
+    }
+    // We didn't recognize the output; fall back to looking for the volume
+    let containers = apfs_list()?;
+    containers
+        .iter()
+        .find(|c| c.container_reference == container)
+        .and_then(|c| find_existing_volume(std::slice::from_ref(c), name))
+        .ok_or_else(|| anyhow!("failed to create volume `{}`: {:#?}", name, output))
+        .map(ApfsVolume::clone)
+}
+
+/// Delete `volume`, which must be the volume that we created for
+/// `mount_point`.  This will implicitly unmount it.
+/// As a final interlock, the name of the volume is re-read from diskutil
+/// immediately before deleting it, and must decode to `mount_point`.
+/// That protects against a bug in the way that the caller selected the
+/// volume leading us to delete something that we don't own.
+pub fn delete_volume(volume: &ApfsVolume, mount_point: &str) -> Result<()> {
+    let info = disk_info(&volume.device_identifier)?;
+    check_interlock(volume, &info, mount_point, "delete")?;
+
+    let output = new_cmd_unprivileged(&tool_paths()?.diskutil)
+        .args(&["apfs", "deleteVolume", &volume.device_identifier])
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "failed to execute diskutil deleteVolume {}: {:?}",
+            volume.device_identifier,
+            output
+        );
+    }
+    Ok(())
+}
+
+/// Erase the contents of `volume`, which must be the volume that we
+/// created for `mount_point`, keeping the volume itself and its name.
+/// This is subject to the same interlock as `delete_volume`.
+pub fn erase_volume(volume: &ApfsVolume, mount_point: &str) -> Result<()> {
+    let info = disk_info(&volume.device_identifier)?;
+    check_interlock(volume, &info, mount_point, "erase")?;
+    let name = info.volume_name.unwrap_or_default();
+
+    let output = new_cmd_unprivileged(&tool_paths()?.diskutil)
+        .args(&[
+            "apfs",
+            "eraseVolume",
+            &volume.device_identifier,
+            "-name",
+            &name,
+        ])
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "failed to execute diskutil apfs eraseVolume {}: {:?}",
+            volume.device_identifier,
+            output
+        );
+    }
+    Ok(())
+}
+
+/// Set the quota of `volume`, which must be one of ours, to `quota`
+/// bytes, or remove it if `quota` is 0.  The volume keeps its data.
+pub fn set_volume_quota(volume: &ApfsVolume, quota: u64) -> Result<()> {
+    if !volume.is_edenfs_managed_volume() {
+        bail!(
+            "refusing to set the quota of {} ({:?}) because it isn't one of ours",
+            volume.device_identifier,
+            volume.name
+        );
+    }
+    let output = new_cmd_unprivileged(&tool_paths()?.diskutil)
+        .args(&[
+            "apfs",
+            "setQuota",
+            &volume.device_identifier,
+            &quota.to_string(),
+        ])
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "failed to execute diskutil apfs setQuota {}: {:?}",
+            volume.device_identifier,
+            output
+        );
+    }
+    Ok(())
+}
+
+/// Rename `volume`, which must be one of ours, to `name`.  This is used
+/// to move volumes to the current version of the name encoding, and to
+/// follow a checkout that has moved.
+pub fn rename_volume(volume: &ApfsVolume, name: &str) -> Result<()> {
+    if !volume.is_edenfs_managed_volume() || !is_edenfs_volume_name(name) {
+        bail!(
+            "refusing to rename {} from {:?} to {}",
+            volume.device_identifier,
+            volume.name,
+            name
+        );
+    }
+    let output = new_cmd_unprivileged(&tool_paths()?.diskutil)
+        .args(&["rename", &volume.device_identifier, name])
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "failed to execute diskutil rename {}: {:?}",
+            volume.device_identifier,
+            output
+        );
+    }
+    Ok(())
+}
+
+/// Verify that `info`, which was obtained independently of `volume`,
+/// describes the same volume and that its name decodes to `mount_point`,
+/// before we `operation` it.
+pub(crate) fn check_interlock(
+    volume: &ApfsVolume,
+    info: &DiskInfo,
+    mount_point: &str,
+    operation: &str,
+) -> Result<()> {
+    if info.device_identifier != volume.device_identifier {
+        bail!(
+            "refusing to {} {}: diskutil describes it as {}",
+            operation,
+            volume.device_identifier,
+            info.device_identifier
+        );
+    }
+    if info.volume_name != volume.name {
+        bail!(
+            "refusing to {} {}: its name changed from {:?} to {:?}",
+            operation,
+            volume.device_identifier,
+            volume.name,
+            info.volume_name
+        );
+    }
+    let name = info.volume_name.as_deref().unwrap_or("");
+    match decode_volume_name(name) {
+        Some(decoded) if decoded == mount_point => Ok(()),
+        _ => bail!(
+            "refusing to {} {}: its name {:?} does not decode to {}",
+            operation,
+            volume.device_identifier,
+            name,
+            mount_point
+        ),
+    }
+}
+
+// We only run the tests on macos as we currently default to a mode that requires
+// the plutil utility to be installed.  That limitation can be removed once some
+// build system work is completed that will unblock using a different crate vendoring
+// system at fb.
+#[cfg(all(test, any(target_os = "macos", feature = "native-plist")))]
+mod test {
+    use super::*;
+    use crate::mount_table::MountEntry;
+    use pretty_assertions::assert_eq;
+
+    fn parse_plist<T: de::DeserializeOwned>(data: &str) -> Result<T> {
+        parse_plist_from(std::io::Cursor::new(data.to_owned()))
+    }
+
+    fn parse_apfs_plist(data: &str) -> Result<Vec<ApfsContainer>> {
+        let containers: Containers = parse_plist(data)?;
+        Ok(containers.containers)
+    }
+
+    #[test]
+    fn test_container_identifier() {
+        assert!(is_container_identifier("disk1"));
+        assert!(is_container_identifier("disk12"));
+        assert!(!is_container_identifier("disk"));
+        assert!(!is_container_identifier("disk1s2"));
+        assert!(!is_container_identifier("/dev/disk1"));
+    }
+
+    #[test]
+    fn test_add_volume_args() {
+        assert_eq!(
+            VolumeOptions::default().add_volume_args(),
+            Vec::<String>::new()
+        );
+        let options = VolumeOptions {
+            quota: Some(50_000_000_000),
+            ..Default::default()
+        };
+        assert_eq!(options.add_volume_args(), vec!["-quota", "50000000000"]);
+        let options = VolumeOptions {
+            quota: Some(50_000_000_000),
+            reserve: Some(10_000_000_000),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.add_volume_args(),
+            vec!["-quota", "50000000000", "-reserve", "10000000000"]
+        );
+    }
+
+    #[test]
+    fn test_personality() {
+        assert_eq!(VolumeOptions::default().personality(), "apfs");
+        let options = VolumeOptions {
+            case_sensitive: true,
+            ..Default::default()
+        };
+        assert_eq!(options.personality(), "APFSX");
+        assert_eq!(options.add_volume_args(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_add_volume_args_encrypted() {
+        let options = VolumeOptions {
+            quota: Some(50_000_000_000),
+            passphrase: Some("secret".to_owned()),
+            ..Default::default()
+        };
+        // The passphrase itself goes to stdin
+        assert_eq!(
+            options.add_volume_args(),
+            vec!["-quota", "50000000000", "-stdinpassphrase"]
+        );
+    }
+
+    #[test]
+    fn test_system_container() {
+        let volume = |device: &str, roles: &[&str]| ApfsVolume {
+            device_identifier: device.to_owned(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            ..Default::default()
+        };
+        let container = |reference: &str, volumes| ApfsContainer {
+            container_reference: reference.to_owned(),
+            capacity_ceiling: 0,
+            capacity_free: 0,
+            physical_stores: vec![],
+            volumes,
+        };
+        // Apple Silicon keeps the boot policy in disk1 and the system in disk3
+        let containers = vec![
+            container("disk1", vec![volume("disk1s1", &["xART"])]),
+            container("disk2", vec![volume("disk2s1", &[])]),
+            container(
+                "disk3",
+                vec![volume("disk3s1", &["System"]), volume("disk3s5", &["Data"])],
+            ),
+        ];
+        let mounts = MountTable { entries: vec![] };
+        assert_eq!(
+            system_container(&containers, Some(&mounts))
+                .unwrap()
+                .container_reference,
+            "disk3"
+        );
+
+        // Before Catalina, there are no roles
+        let containers = vec![
+            container("disk1", vec![volume("disk1s1", &[])]),
+            container("disk2", vec![volume("disk2s1", &[])]),
+        ];
+        let mounts = MountTable {
+            entries: vec![
+                MountEntry::new("/dev/disk1s1", "/Volumes/Other"),
+                MountEntry::new("/dev/disk2s1", "/"),
+            ],
+        };
+        assert_eq!(
+            system_container(&containers, Some(&mounts))
+                .unwrap()
+                .container_reference,
+            "disk2"
+        );
+        assert!(system_container(&containers, Some(&MountTable { entries: vec![] })).is_err());
+    }
+
+    #[test]
+    fn test_whole_disk() {
+        assert_eq!(whole_disk("disk0s2"), "disk0");
+        assert_eq!(whole_disk("disk12s1"), "disk12");
+        assert_eq!(whole_disk("disk3"), "disk3");
+        assert_eq!(whole_disk("/dev/disk3"), "/dev/disk3");
+    }
+
+    #[test]
+    fn test_roles() {
+        let volume = |roles: &[&str]| ApfsVolume {
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(volume(&["System"]).is_system_volume());
+        assert!(volume(&["Preboot"]).is_system_volume());
+        assert!(!volume(&["Data"]).is_system_volume());
+        assert!(!volume(&[]).is_system_volume());
+
+        let wanted = vec!["data".to_owned(), "Backup".to_owned()];
+        assert!(volume(&["Data"]).has_any_role(&wanted));
+        assert!(!volume(&["System"]).has_any_role(&wanted));
+        assert!(!volume(&[]).has_any_role(&wanted));
+    }
+
+    #[test]
+    fn test_delete_interlock() {
+        let volume = ApfsVolume {
+            device_identifier: "disk1s5".to_owned(),
+            name: Some("edenfs:/a/b".to_owned()),
+            ..Default::default()
+        };
+        let info = DiskInfo {
+            device_identifier: "disk1s5".to_owned(),
+            volume_name: Some("edenfs:/a/b".to_owned()),
+            volume_uuid: None,
+            mount_point: String::new(),
+        };
+        assert!(check_interlock(&volume, &info, "/a/b", "delete").is_ok());
+
+        // Asked to delete a different mount point
+        assert!(check_interlock(&volume, &info, "/a", "delete").is_err());
+        assert!(check_interlock(&volume, &info, "/a/b/", "delete").is_err());
+
+        // diskutil reports on a different device
+        let other_device = DiskInfo {
+            device_identifier: "disk1s1".to_owned(),
+            ..info.clone()
+        };
+        assert!(check_interlock(&volume, &other_device, "/a/b", "delete").is_err());
+
+        // The volume was renamed after it was selected
+        let renamed = DiskInfo {
+            volume_name: Some("Macintosh HD".to_owned()),
+            ..info.clone()
+        };
+        assert!(check_interlock(&volume, &renamed, "/a/b", "delete").is_err());
+
+        // A selector resolved to a volume that we don't own
+        let system = ApfsVolume {
+            device_identifier: "disk1s1".to_owned(),
+            name: Some("Macintosh HD".to_owned()),
+            ..Default::default()
+        };
+        let system_info = DiskInfo {
+            device_identifier: "disk1s1".to_owned(),
+            volume_name: Some("Macintosh HD".to_owned()),
+            ..info.clone()
+        };
+        assert!(check_interlock(&system, &system_info, "/", "delete").is_err());
+        assert!(check_interlock(&system, &system_info, "Macintosh HD", "delete").is_err());
+
+        // A volume without a name
+        let unnamed = DiskInfo {
+            volume_name: None,
+            ..info.clone()
+        };
+        let unnamed_volume = ApfsVolume {
+            name: None,
+            ..volume.clone()
+        };
+        assert!(check_interlock(&unnamed_volume, &unnamed, "", "delete").is_err());
+
+        // A malformed name that happens to have the right prefix
+        let sneaky = ApfsVolume {
+            name: Some("edenfs:/a/../b".to_owned()),
+            ..volume.clone()
+        };
+        let sneaky_info = DiskInfo {
+            volume_name: Some("edenfs:/a/../b".to_owned()),
+            ..info
+        };
+        assert!(check_interlock(&sneaky, &sneaky_info, "/a/../b", "delete").is_err());
+    }
+
+    #[test]
+    fn test_add_volume_output() {
+        let data = r#"Will export new APFS Volume "edenfs:/tmp/foo" from APFS Container Reference disk1
+Started APFS operation on disk1
+Preparing to add APFS Volume to APFS Container disk1
+Creating APFS Volume
+Created new APFS Volume disk1s9
+Disk from APFS operation: disk1s9
+Finished APFS operation on disk1
+"#;
+        assert_eq!(parse_add_volume_output(data), Some("disk1s9".to_owned()));
+        assert_eq!(
+            parse_add_volume_output("Created new APFS Volume disk3s12\n"),
+            Some("disk3s12".to_owned())
+        );
+        assert_eq!(
+            parse_add_volume_output("Disk from APFS operation: something else\n"),
+            None
+        );
+        assert_eq!(parse_add_volume_output(""), None);
+    }
+
+    #[test]
+    fn test_disk_info_plist() {
+        let data = r#"
+<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>APFSContainerReference</key>
+	<string>disk1</string>
+	<key>DeviceIdentifier</key>
+	<string>disk1s5</string>
+	<key>DeviceNode</key>
+	<string>/dev/disk1s5</string>
+	<key>FilesystemType</key>
+	<string>apfs</string>
+	<key>MountPoint</key>
+	<string>/Users/wez/fbsource/buck-out</string>
+	<key>VolumeName</key>
+	<string>edenfs:/Users/wez/fbsource/buck-out</string>
+	<key>VolumeUUID</key>
+	<string>6C7EEDAD-385B-49AB-857B-AD15D98D13ED</string>
+	<key>Writable</key>
+	<true/>
+</dict>
+</plist>"#;
+        let info: DiskInfo = parse_plist(data).unwrap();
+        assert_eq!(
+            info,
+            DiskInfo {
+                device_identifier: "disk1s5".to_owned(),
+                volume_name: Some("edenfs:/Users/wez/fbsource/buck-out".to_owned()),
+                volume_uuid: Some("6C7EEDAD-385B-49AB-857B-AD15D98D13ED".to_owned()),
+                mount_point: "/Users/wez/fbsource/buck-out".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_plist() {
+        let data = r#"
+<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Containers</key>
+	<array>
+		<dict>
+			<key>APFSContainerUUID</key>
+			<string>C4AC89F6-8658-4857-972C-D485C213523A</string>
+			<key>CapacityCeiling</key>
+			<integer>499963174912</integer>
+			<key>CapacityFree</key>
+			<integer>30714478592</integer>
+			<key>ContainerReference</key>
+			<string>disk1</string>
+			<key>DesignatedPhysicalStore</key>
+			<string>disk0s2</string>
+			<key>Fusion</key>
+			<false/>
+			<key>PhysicalStores</key>
+			<array>
+				<dict>
+					<key>DeviceIdentifier</key>
+					<string>disk0s2</string>
+					<key>DiskUUID</key>
+					<string>2F978E12-5A2C-4EEB-BAE2-0E09CAEADC06</string>
+					<key>Size</key>
+					<integer>499963174912</integer>
+				</dict>
+			</array>
+			<key>Volumes</key>
+			<array>
+				<dict>
+					<key>APFSVolumeUUID</key>
+					<string>9AA7F3A4-A615-4F8D-91E3-F5C86D988D71</string>
+					<key>CapacityInUse</key>
+					<integer>461308219392</integer>
+					<key>CapacityQuota</key>
+					<integer>0</integer>
+					<key>CapacityReserve</key>
+					<integer>0</integer>
+					<key>CryptoMigrationOn</key>
+					<false/>
+					<key>DeviceIdentifier</key>
+					<string>disk1s1</string>
+					<key>Encryption</key>
+					<true/>
+					<key>FileVault</key>
+					<true/>
+					<key>Locked</key>
+					<false/>
+					<key>Name</key>
+					<string>Macintosh HD</string>
+					<key>Roles</key>
+					<array/>
+				</dict>
+				<dict>
+					<key>APFSVolumeUUID</key>
+					<string>A91FD4EA-684D-4122-9ACD-27E1465E99F6</string>
+					<key>CapacityInUse</key>
+					<integer>43061248</integer>
+					<key>CapacityQuota</key>
+					<integer>0</integer>
+					<key>CapacityReserve</key>
+					<integer>0</integer>
+					<key>CryptoMigrationOn</key>
+					<false/>
+					<key>DeviceIdentifier</key>
+					<string>disk1s2</string>
+					<key>Encryption</key>
+					<false/>
+					<key>FileVault</key>
+					<false/>
+					<key>Locked</key>
+					<false/>
+					<key>Name</key>
+					<string>Preboot</string>
+					<key>Roles</key>
+					<array>
+						<string>Preboot</string>
+					</array>
+				</dict>
+				<dict>
+					<key>APFSVolumeUUID</key>
+					<string>1C94FFC8-7649-470E-952D-16672E135C43</string>
+					<key>CapacityInUse</key>
+					<integer>510382080</integer>
+					<key>CapacityQuota</key>
+					<integer>0</integer>
+					<key>CapacityReserve</key>
+					<integer>0</integer>
+					<key>CryptoMigrationOn</key>
+					<false/>
+					<key>DeviceIdentifier</key>
+					<string>disk1s3</string>
+					<key>Encryption</key>
+					<false/>
+					<key>FileVault</key>
+					<false/>
+					<key>Locked</key>
+					<false/>
+					<key>Name</key>
+					<string>Recovery</string>
+					<key>Roles</key>
+					<array>
+						<string>Recovery</string>
+					</array>
+				</dict>
+				<dict>
+					<key>APFSVolumeUUID</key>
+					<string>6BC72964-0CA0-48AE-AAE1-7E9BFA8B2005</string>
+					<key>CapacityInUse</key>
+					<integer>6442676224</integer>
+					<key>CapacityQuota</key>
+					<integer>0</integer>
+					<key>CapacityReserve</key>
+					<integer>0</integer>
+					<key>CryptoMigrationOn</key>
+					<false/>
+					<key>DeviceIdentifier</key>
+					<string>disk1s4</string>
+					<key>Encryption</key>
+					<true/>
+					<key>FileVault</key>
+					<false/>
+					<key>Locked</key>
+					<false/>
+					<key>Name</key>
+					<string>VM</string>
+					<key>Roles</key>
+					<array>
+						<string>VM</string>
+					</array>
+				</dict>
+				<dict>
+					<key>APFSVolumeUUID</key>
+					<string>6C7EEDAD-385B-49AB-857B-AD15D98D13ED</string>
+					<key>CapacityInUse</key>
+					<integer>790528</integer>
+					<key>CapacityQuota</key>
+					<integer>0</integer>
+					<key>CapacityReserve</key>
+					<integer>0</integer>
+					<key>CryptoMigrationOn</key>
+					<false/>
+					<key>DeviceIdentifier</key>
+					<string>disk1s5</string>
+					<key>Encryption</key>
+					<true/>
+					<key>FileVault</key>
+					<false/>
+					<key>Locked</key>
+					<false/>
+					<key>Name</key>
+					<string>edenfs:/Users/wez/fbsource/buck-out</string>
+					<key>Roles</key>
+					<array/>
+				</dict>
+				<dict>
+					<key>APFSVolumeUUID</key>
+					<string>0DAB1407-0283-408E-88EE-CD41CE9E7BCA</string>
+					<key>CapacityInUse</key>
+					<integer>781156352</integer>
+					<key>CapacityQuota</key>
+					<integer>0</integer>
+					<key>CapacityReserve</key>
+					<integer>0</integer>
+					<key>CryptoMigrationOn</key>
+					<false/>
+					<key>DeviceIdentifier</key>
+					<string>disk1s6</string>
+					<key>Encryption</key>
+					<true/>
+					<key>FileVault</key>
+					<false/>
+					<key>Locked</key>
+					<false/>
+					<key>Name</key>
+					<string>edenfs:/Users/wez/fbsource/fbcode/buck-out</string>
+					<key>Roles</key>
+					<array/>
+				</dict>
+				<dict>
+					<key>APFSVolumeUUID</key>
+					<string>253A48CA-074E-496E-9A62-9F64831D7A65</string>
+					<key>CapacityInUse</key>
+					<integer>925696</integer>
+					<key>CapacityQuota</key>
+					<integer>0</integer>
+					<key>CapacityReserve</key>
+					<integer>0</integer>
+					<key>CryptoMigrationOn</key>
+					<false/>
+					<key>DeviceIdentifier</key>
+					<string>disk1s7</string>
+					<key>Encryption</key>
+					<true/>
+					<key>FileVault</key>
+					<false/>
+					<key>Locked</key>
+					<false/>
+					<key>Name</key>
+					<string>edenfs:/Users/wez/fbsource/fbobjc/buck-out</string>
+					<key>Roles</key>
+					<array/>
+				</dict>
+			</array>
+		</dict>
+	</array>
+</dict>
+</plist>"#;
+        let containers = parse_apfs_plist(data).unwrap();
+        assert_eq!(
+            containers,
+            vec![ApfsContainer {
+                container_reference: "disk1".to_owned(),
+                capacity_ceiling: 499963174912,
+                capacity_free: 30714478592,
+                physical_stores: vec![PhysicalStore {
+                    device_identifier: "disk0s2".to_owned(),
+                }],
+                volumes: vec![
+                    ApfsVolume {
+                        device_identifier: "disk1s1".to_owned(),
+                        name: Some("Macintosh HD".to_owned()),
+                        uuid: Some("9AA7F3A4-A615-4F8D-91E3-F5C86D988D71".to_owned()),
+                        capacity_in_use: 461308219392,
+                        ..Default::default()
+                    },
+                    ApfsVolume {
+                        device_identifier: "disk1s2".to_owned(),
+                        name: Some("Preboot".to_owned()),
+                        uuid: Some("A91FD4EA-684D-4122-9ACD-27E1465E99F6".to_owned()),
+                        roles: vec!["Preboot".to_owned()],
+                        capacity_in_use: 43061248,
+                        ..Default::default()
+                    },
+                    ApfsVolume {
+                        device_identifier: "disk1s3".to_owned(),
+                        name: Some("Recovery".to_owned()),
+                        uuid: Some("1C94FFC8-7649-470E-952D-16672E135C43".to_owned()),
+                        roles: vec!["Recovery".to_owned()],
+                        capacity_in_use: 510382080,
+                        ..Default::default()
+                    },
+                    ApfsVolume {
+                        device_identifier: "disk1s4".to_owned(),
+                        name: Some("VM".to_owned()),
+                        uuid: Some("6BC72964-0CA0-48AE-AAE1-7E9BFA8B2005".to_owned()),
+                        roles: vec!["VM".to_owned()],
+                        capacity_in_use: 6442676224,
+                        ..Default::default()
+                    },
+                    ApfsVolume {
+                        device_identifier: "disk1s5".to_owned(),
+                        name: Some("edenfs:/Users/wez/fbsource/buck-out".to_owned()),
+                        uuid: Some("6C7EEDAD-385B-49AB-857B-AD15D98D13ED".to_owned()),
+                        capacity_in_use: 790528,
+                        ..Default::default()
+                    },
+                    ApfsVolume {
+                        device_identifier: "disk1s6".to_owned(),
+                        name: Some("edenfs:/Users/wez/fbsource/fbcode/buck-out".to_owned()),
+                        uuid: Some("0DAB1407-0283-408E-88EE-CD41CE9E7BCA".to_owned()),
+                        capacity_in_use: 781156352,
+                        ..Default::default()
+                    },
+                    ApfsVolume {
+                        device_identifier: "disk1s7".to_owned(),
+                        name: Some("edenfs:/Users/wez/fbsource/fbobjc/buck-out".to_owned()),
+                        uuid: Some("253A48CA-074E-496E-9A62-9F64831D7A65".to_owned()),
+                        capacity_in_use: 925696,
+                        ..Default::default()
+                    },
+                ],
+            },]
+        );
+    }
+
+    #[cfg(feature = "native-plist")]
+    #[test]
+    fn test_native_plist_fallback() {
+        let before = plist_fallbacks();
+        let containers: Containers = parse_plist(
+            r#"<plist version="1.0"><dict><key>Containers</key><array/></dict></plist>"#,
+        )
+        .unwrap();
+        assert!(containers.containers.is_empty());
+        assert_eq!(plist_fallbacks(), before);
+
+        // Whether plutil can make sense of this isn't the point; it
+        // must have been offered the data after the native parser failed
+        parse_plist::<Containers>("this is not a plist").ok();
+        assert!(plist_fallbacks() > before);
+    }
+}
@@ -0,0 +1,326 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Capacity and placement queries.
+//! These allow the eden CLI and daemon to decide where (and whether) to
+//! place scratch space without having to parse our CLI output.
+
+#[cfg(target_os = "macos")]
+use crate::apfs::{
+    apfs_list, encode_mount_point_as_volume_name, system_container, whole_disk, ApfsContainer,
+};
+#[cfg(target_os = "macos")]
+use crate::mount_table::MountTable;
+use anyhow::*;
+use schemars::JsonSchema;
+use serde::*;
+use std::path::Path;
+
+/// Capacity information about an APFS container.  `free-space` reports
+/// a list of these.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ContainerStats {
+    pub container: String,
+    pub capacity_ceiling: u64,
+    /// The space that a newly created volume could use
+    pub capacity_free: u64,
+    /// The space held by purgeable data, such as local snapshots and
+    /// caches, which macOS frees on demand.  This is not included in
+    /// `capacity_free`; their sum is the space that would be free
+    /// after purging.
+    #[serde(default)]
+    pub purgeable: u64,
+    /// The volumes in this container that were created by this utility
+    pub volumes: Vec<ScratchVolumeStats>,
+}
+
+/// Capacity information about a volume created by this utility
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ScratchVolumeStats {
+    pub device_identifier: String,
+    pub mount_point: String,
+    pub capacity_in_use: u64,
+    /// The space available to this volume, taking its quota
+    /// and reserve into account
+    pub available: u64,
+}
+
+#[cfg(target_os = "macos")]
+/// Compute the stats for `containers`
+pub fn container_stats(containers: &[ApfsContainer]) -> Vec<ContainerStats> {
+    containers
+        .iter()
+        .map(|container| ContainerStats {
+            container: container.container_reference.clone(),
+            capacity_ceiling: container.capacity_ceiling,
+            capacity_free: container.capacity_free,
+            purgeable: 0,
+            volumes: container
+                .volumes
+                .iter()
+                .filter_map(|vol| {
+                    Some(ScratchVolumeStats {
+                        device_identifier: vol.device_identifier.clone(),
+                        mount_point: vol.preferred_mount_point()?,
+                        capacity_in_use: vol.capacity_in_use,
+                        available: vol.available_space(container.capacity_free),
+                    })
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+/// Returns the space held by purgeable data in `container`.  This can
+/// only be measured via a mounted volume, so it is zero for containers
+/// that have none.
+pub fn container_purgeable_space(container: &ApfsContainer, mounts: &MountTable) -> Result<u64> {
+    match container
+        .volumes
+        .iter()
+        .find_map(|vol| vol.get_current_mount_point(Some(mounts)))
+    {
+        Some(mount_point) => crate::purgeable::purgeable_space(Path::new(&mount_point)),
+        None => Ok(0),
+    }
+}
+
+#[cfg(target_os = "macos")]
+/// Obtain capacity information about all of the APFS containers
+pub fn get_container_stats() -> Result<Vec<ContainerStats>> {
+    let containers = apfs_list()?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let mut stats = container_stats(&containers);
+    for (stats, container) in stats.iter_mut().zip(&containers) {
+        stats.purgeable = container_purgeable_space(container, &mounts)?;
+    }
+    Ok(stats)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn get_container_stats() -> Result<Vec<ContainerStats>> {
+    Err(crate::unsupported_platform())
+}
+
+#[cfg(target_os = "macos")]
+/// The container that `device` belongs to: the one that holds it, if it
+/// is an APFS volume, or else one that lives on the same physical disk,
+/// for a partition with some other filesystem on it
+fn container_for_device(containers: &[ApfsContainer], device: &str) -> Option<String> {
+    containers
+        .iter()
+        .find(|container| {
+            container
+                .volumes
+                .iter()
+                .any(|vol| vol.device_identifier == device)
+        })
+        .or_else(|| {
+            containers
+                .iter()
+                .find(|container| container.is_on_disk(whole_disk(device)))
+        })
+        .map(|container| container.container_reference.clone())
+}
+
+#[cfg(target_os = "macos")]
+/// Determine which of `containers` backs the filesystem on which the
+/// canonical `path` resides, according to `mounts`
+pub fn container_for_path(
+    containers: &[ApfsContainer],
+    mounts: &MountTable,
+    path: &Path,
+) -> Option<String> {
+    // The filesystem is the one with the longest mount point that
+    // contains the path
+    let device = mounts
+        .entries
+        .iter()
+        .filter(|entry| path.starts_with(&entry.mount_point))
+        .max_by_key(|entry| entry.mount_point.len())
+        .and_then(|entry| entry.device.strip_prefix("/dev/"))?;
+    container_for_device(containers, device)
+}
+
+#[cfg(target_os = "macos")]
+/// Determine which APFS container holds the filesystem on which `path`
+/// resides.  Returns `None` if there is no APFS container on the disk
+/// that holds that filesystem.
+pub fn resolve_container_for_path<P: AsRef<Path>>(path: P) -> Result<Option<String>> {
+    let path = path.as_ref();
+    let path = std::fs::canonicalize(path)
+        .with_context(|| format!("canonicalizing path {}", path.display()))?;
+    let table = MountTable::parse_system_mount_table()?;
+    Ok(container_for_path(&apfs_list()?, &table, &path))
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn resolve_container_for_path<P: AsRef<Path>>(_path: P) -> Result<Option<String>> {
+    Err(crate::unsupported_platform())
+}
+
+#[cfg(target_os = "macos")]
+fn available_scratch_space(containers: &[ApfsContainer], name: &str) -> u64 {
+    for container in containers {
+        for vol in &container.volumes {
+            if vol.name.as_deref() == Some(name) {
+                return vol.available_space(container.capacity_free);
+            }
+        }
+    }
+    // There's no volume yet, so it would be created in the system container
+    system_container(containers, None)
+        .map(|container| container.capacity_free)
+        .unwrap_or(0)
+}
+
+#[cfg(target_os = "macos")]
+/// Estimate the space available to the scratch volume for `mount_point`,
+/// which must be a canonical path.  If there is no such volume yet, this
+/// is the space that it would have if it were created now.
+pub fn estimate_available_scratch_space<P: AsRef<Path>>(mount_point: P) -> Result<u64> {
+    let name = encode_mount_point_as_volume_name(mount_point);
+    Ok(available_scratch_space(&apfs_list()?, &name))
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn estimate_available_scratch_space<P: AsRef<Path>>(_mount_point: P) -> Result<u64> {
+    Err(crate::unsupported_platform())
+}
+
+#[cfg(all(test, target_os = "macos"))]
+mod test {
+    use super::*;
+    use crate::apfs::{ApfsVolume, PhysicalStore};
+    use crate::mount_table::MountEntry;
+    use pretty_assertions::assert_eq;
+
+    fn containers() -> Vec<ApfsContainer> {
+        vec![ApfsContainer {
+            container_reference: "disk1".to_owned(),
+            capacity_ceiling: 1000,
+            capacity_free: 100,
+            physical_stores: vec![],
+            volumes: vec![
+                ApfsVolume {
+                    device_identifier: "disk1s1".to_owned(),
+                    name: Some("Macintosh HD".to_owned()),
+                    roles: vec!["System".to_owned()],
+                    capacity_in_use: 800,
+                    ..Default::default()
+                },
+                ApfsVolume {
+                    device_identifier: "disk1s5".to_owned(),
+                    name: Some("edenfs:/tmp/plain".to_owned()),
+                    capacity_in_use: 10,
+                    ..Default::default()
+                },
+                ApfsVolume {
+                    device_identifier: "disk1s6".to_owned(),
+                    name: Some("edenfs:/tmp/quota".to_owned()),
+                    capacity_in_use: 30,
+                    capacity_quota: 50,
+                    ..Default::default()
+                },
+                ApfsVolume {
+                    device_identifier: "disk1s7".to_owned(),
+                    name: Some("edenfs:/tmp/reserve".to_owned()),
+                    capacity_in_use: 20,
+                    capacity_reserve: 60,
+                    ..Default::default()
+                },
+            ],
+        }]
+    }
+
+    #[test]
+    fn test_container_stats() {
+        assert_eq!(
+            container_stats(&containers()),
+            vec![ContainerStats {
+                container: "disk1".to_owned(),
+                capacity_ceiling: 1000,
+                capacity_free: 100,
+                purgeable: 0,
+                volumes: vec![
+                    ScratchVolumeStats {
+                        device_identifier: "disk1s5".to_owned(),
+                        mount_point: "/tmp/plain".to_owned(),
+                        capacity_in_use: 10,
+                        available: 100,
+                    },
+                    ScratchVolumeStats {
+                        device_identifier: "disk1s6".to_owned(),
+                        mount_point: "/tmp/quota".to_owned(),
+                        capacity_in_use: 30,
+                        available: 20,
+                    },
+                    ScratchVolumeStats {
+                        device_identifier: "disk1s7".to_owned(),
+                        mount_point: "/tmp/reserve".to_owned(),
+                        capacity_in_use: 20,
+                        available: 140,
+                    },
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_placement() {
+        let containers = containers();
+        assert_eq!(
+            container_for_device(&containers, "disk1s6"),
+            Some("disk1".to_owned())
+        );
+        assert_eq!(container_for_device(&containers, "disk2s1"), None);
+        assert_eq!(
+            available_scratch_space(&containers, "edenfs:/tmp/quota"),
+            20
+        );
+        assert_eq!(available_scratch_space(&containers, "edenfs:/tmp/new"), 100);
+    }
+
+    #[test]
+    fn test_container_for_path() {
+        let mut containers = containers();
+        containers.push(ApfsContainer {
+            container_reference: "disk5".to_owned(),
+            capacity_ceiling: 2000,
+            capacity_free: 1500,
+            physical_stores: vec![PhysicalStore {
+                device_identifier: "disk4s3".to_owned(),
+            }],
+            volumes: vec![ApfsVolume {
+                device_identifier: "disk5s1".to_owned(),
+                name: Some("External".to_owned()),
+                ..Default::default()
+            }],
+        });
+        let mounts = MountTable {
+            entries: vec![
+                MountEntry::new("/dev/disk1s1", "/"),
+                MountEntry::new("devfs", "/dev"),
+                MountEntry::new("/dev/disk5s1", "/Volumes/External"),
+                MountEntry::new("/dev/disk4s2", "/Volumes/Legacy"),
+                MountEntry::new("/dev/disk2s1", "/Volumes/Stick"),
+            ],
+        };
+        let container = |path: &str| container_for_path(&containers, &mounts, Path::new(path));
+        assert_eq!(container("/Users/me/repo"), Some("disk1".to_owned()));
+        assert_eq!(
+            container("/Volumes/External/repo/buck-out"),
+            Some("disk5".to_owned())
+        );
+        // A partition that isn't APFS is placed with the container on
+        // the same disk
+        assert_eq!(container("/Volumes/Legacy/repo"), Some("disk5".to_owned()));
+        assert_eq!(container("/Volumes/Stick/repo"), None);
+        assert_eq!(container("/dev/null"), None);
+    }
+}
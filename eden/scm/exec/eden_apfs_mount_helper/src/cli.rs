@@ -0,0 +1,6574 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The command line interface of the helper
+use anyhow::*;
+use eden_apfs_mount_helper::apfs::*;
+use eden_apfs_mount_helper::capacity::{container_for_path, container_purgeable_space};
+use eden_apfs_mount_helper::cloud_sync::cloud_sync_service;
+use eden_apfs_mount_helper::disk_image::{
+    attach_image, compact_image, delete_image_volume, has_image, image_path, list_images,
+    make_image_volume, remove_image,
+};
+use eden_apfs_mount_helper::dry_run::{dry_run, set_dry_run};
+use eden_apfs_mount_helper::environment::{
+    environment_tag, host_environment, set_environment, HostEnvironment,
+};
+use eden_apfs_mount_helper::get_container_stats;
+use eden_apfs_mount_helper::host::{
+    ensure_host_id, load_host_id, read_volume_tag, tag_volume, volume_origin,
+};
+use eden_apfs_mount_helper::keychain::{find_passphrase, generate_passphrase, store_passphrase};
+use eden_apfs_mount_helper::mount_table::MountTable;
+use eden_apfs_mount_helper::paths::{glob_match, is_nested_in, mount_order, normalize_path};
+use eden_apfs_mount_helper::policy::set_policy_loader;
+use eden_apfs_mount_helper::privs::*;
+use eden_apfs_mount_helper::ram_disk::{
+    delete_ram_disk_volume, detach_ram_disk, is_on_ram_disk, make_ram_disk_volume,
+};
+use eden_apfs_mount_helper::registry::{
+    fold_latency, push_usage, record_creator, record_history, record_latencies, record_ram_disk,
+    unix_time, LatencyHistogram, MountRecord, Registry, UsageSample, LATENCY_BUCKETS_MS,
+    LATENCY_DAYS,
+};
+use eden_apfs_mount_helper::report::{
+    to_plist_xml, Capabilities, ChangeSummary, CheckStatus, EntryUsage, FsckReport, FsckStatus,
+    HealthCheck, HealthReport, HistoryReport, LatencyReport, ListReport, ListedContainer,
+    ListedVolume, OperationLatency, OutputFormat, ResizePreflight, Severity, SpaceCheck,
+    UsageReport, VolumeOrigin, VolumeUsage,
+};
+use eden_apfs_mount_helper::timeouts::{set_timeouts, Operation, TimeoutOverride, Timeouts};
+use eden_apfs_mount_helper::tool_output::parse_lsof_commands;
+use eden_apfs_mount_helper::tools::{set_tool_paths, tool_paths, ToolPaths};
+use eden_apfs_mount_helper::transcript;
+use eden_apfs_mount_helper::volume_name::{migrated_volume_name, set_volume_prefix};
+use once_cell::sync::OnceCell;
+use serde::*;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+mod batch;
+mod exclusions;
+mod gc;
+mod policy_verify;
+mod rename;
+
+use batch::run_batch;
+use exclusions::{
+    disable_fsevents, disable_spotlight, disable_trashcan, exclude_from_backups,
+    is_excluded_from_backups, remove_exclusions, ExclusionKind,
+};
+use gc::{audit_volumes, collect_garbage, show_audit};
+use policy_verify::verify_policy;
+use rename::{migrate_from_symlink, rename_scratch};
+
+/// The location of our policy file.  This must be owned by root and
+/// not writable by anyone else, as it influences what we do while
+/// running with root privs.
+const CONFIG_PATH: &str = "/etc/eden/apfs_mount_helper.toml";
+
+/// Settings loaded from the root-owned policy file
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+struct Config {
+    /// Identifies this revision of the policy, eg: `2021-03-01`, so that
+    /// fleet tooling can confirm its rollout; see `capabilities`
+    version: Option<String>,
+    watchman: WatchmanConfig,
+    /// Overrides for the locations of the system tools that we run
+    tools: ToolPaths,
+    /// Additional exclusions to register for newly mounted volumes
+    exclusions: Vec<ExclusionKind>,
+    /// The limits that `stats --check` and `health --check` enforce
+    thresholds: Thresholds,
+    /// What to do when asked to mount inside a cloud-synced directory
+    cloud_sync: CloudSyncPolicy,
+    /// Which container new volumes are created in, when `--container`
+    /// doesn't say
+    placement: Placement,
+    /// What new volumes are made of, when `--backend` doesn't say
+    backend: Backend,
+    /// How the volumes of the `diskimage` backend are made
+    disk_image: DiskImagePolicy,
+    /// How the volumes of the `ramdisk` backend are made
+    ram_disk: RamDiskPolicy,
+    /// How long each kind of operation may run
+    timeouts: Timeouts,
+    /// What to put in each new volume
+    template: VolumeTemplate,
+    /// Which volumes `gc` must leave alone
+    gc: GcPolicy,
+    /// The kind of host that this is, eg: `virtual-machine`, for images
+    /// that we would otherwise misdetect
+    environment: Option<HostEnvironment>,
+    /// The prefix of the names of the volumes that we manage, in place
+    /// of `edenfs:`, for other tools that keep their volumes apart
+    volume_prefix: Option<String>,
+    /// Programs to run at points in the life of a volume
+    hooks: Hooks,
+    /// The options that volumes are mounted with
+    mount: MountPolicy,
+}
+
+/// The options that every volume is mounted with, which keep our volumes
+/// out of the Finder and keep devices and setuid programs on them from
+/// being honored
+const BASELINE_MOUNT_OPTIONS: &[&str] = &["nobrowse", "nodev", "nosuid"];
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct MountPolicy {
+    /// Options to add to those of every mount, eg: `["noatime"]`
+    options: Vec<String>,
+    /// The options that undo part of the baseline, eg: `suid`, which
+    /// `--mount-option` may pass.  The baseline can't be undone from the
+    /// command line otherwise.
+    allow_overrides: Vec<String>,
+    /// Whether to mount every volume noexec, as `--noexec` does
+    noexec: bool,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct Hooks {
+    /// Programs that `eject` runs as the user, with the mount point as
+    /// their argument, before unmounting each volume.  The volume is
+    /// left mounted if any of them fail.
+    pre_unmount: Vec<String>,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct GcPolicy {
+    /// Orphaned volumes that match any of these rules are kept, eg:
+    /// `keep = ["/Users/*/release/buck-out", "label:release*"]`
+    keep: Vec<KeepRule>,
+}
+
+/// A rule protecting volumes from `gc`.  `label:<glob>` matches the
+/// label of the volume, anything else is a glob matched against its
+/// mount point; see `glob_match` for the syntax.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+enum KeepRule {
+    MountPoint(String),
+    Label(String),
+}
+
+impl KeepRule {
+    fn matches(&self, mount_point: &str, label: Option<&str>) -> bool {
+        match self {
+            KeepRule::MountPoint(pattern) => glob_match(pattern, mount_point),
+            KeepRule::Label(pattern) => label.is_some_and(|label| glob_match(pattern, label)),
+        }
+    }
+}
+
+impl std::fmt::Display for KeepRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeepRule::MountPoint(pattern) => write!(f, "{}", pattern),
+            KeepRule::Label(pattern) => write!(f, "label:{}", pattern),
+        }
+    }
+}
+
+impl std::str::FromStr for KeepRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(pattern) = s.strip_prefix("label:") {
+            if pattern.is_empty() {
+                bail!("the keep rule {:?} has an empty label pattern", s);
+            }
+            return Ok(KeepRule::Label(pattern.to_owned()));
+        }
+        if !s.starts_with('/') {
+            bail!(
+                "the keep rule {:?} must be either an absolute mount point \
+                 pattern or label:<pattern>",
+                s
+            );
+        }
+        Ok(KeepRule::MountPoint(s.to_owned()))
+    }
+}
+
+impl std::convert::TryFrom<String> for KeepRule {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// The initial contents of a new volume, which are created as the user
+/// right after the volume is first mounted.  Paths are relative to the
+/// root of the volume.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct VolumeTemplate {
+    /// Directories to create, along with any missing parents
+    directories: Vec<String>,
+    /// Empty files to create, eg: marker files that tools look for
+    files: Vec<String>,
+    /// Extended attributes to set
+    xattrs: Vec<TemplateXattr>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct TemplateXattr {
+    /// The file or directory to set the attribute on; `.` is the root
+    path: String,
+    name: String,
+    value: String,
+}
+
+/// What to do when a mount point lies inside a directory that a cloud
+/// storage service keeps in sync
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum CloudSyncPolicy {
+    /// Mount anyway
+    Allow,
+    /// Mount, but warn that the service may interfere
+    #[default]
+    Warn,
+    /// Refuse to mount
+    Refuse,
+}
+
+/// How to choose the container for a new volume
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Placement {
+    /// The container that holds the system volume
+    #[default]
+    System,
+    /// Whichever container has the most free space, so that a nearly
+    /// full boot disk doesn't leave builds without room when another
+    /// container has plenty
+    MostFree,
+    /// The container on the disk that holds the mount point, so that a
+    /// checkout on an external disk doesn't have its build output sent
+    /// across to the boot disk, falling back to the system container
+    /// when that disk has no APFS container
+    SameDisk,
+}
+
+/// What a new volume is made of
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    /// A volume in an APFS container
+    #[default]
+    Apfs,
+    /// A volume in a sparse bundle disk image, for hosts whose MDM
+    /// policy forbids creating APFS volumes; see `disk_image`
+    DiskImage,
+    /// A volume on a RAM disk, whose contents are lost when it is
+    /// unmounted; see `ram_disk`
+    RamDisk,
+}
+
+impl Backend {
+    const NAMES: &'static [&'static str] = &["apfs", "diskimage", "ramdisk"];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Apfs => "apfs",
+            Self::DiskImage => "diskimage",
+            Self::RamDisk => "ramdisk",
+        }
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "apfs" => Ok(Self::Apfs),
+            "diskimage" => Ok(Self::DiskImage),
+            "ramdisk" => Ok(Self::RamDisk),
+            _ => Err(format!(
+                "unknown backend {}; expected one of {}",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct DiskImagePolicy {
+    /// The most that an image may grow to hold, in bytes, unless
+    /// `--quota` says otherwise.  The images are sparse, so this costs
+    /// nothing until it is used.
+    size: u64,
+}
+
+impl Default for DiskImagePolicy {
+    fn default() -> Self {
+        Self { size: 1 << 40 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct RamDiskPolicy {
+    /// The size of each RAM disk, in bytes, unless a smaller `--quota`
+    /// says otherwise.  This much memory is set aside while the volume is
+    /// mounted, so a larger `--quota` is refused.
+    size: u64,
+    /// Whether users other than root may ask for a RAM disk with
+    /// `--backend ramdisk`.  Each one sets memory aside, so this is off
+    /// unless the policy turns it on; a policy whose `backend` is
+    /// `ramdisk` gives everyone RAM disks regardless.
+    allow_users: bool,
+}
+
+impl Default for RamDiskPolicy {
+    fn default() -> Self {
+        Self {
+            size: 4 << 30,
+            allow_users: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+struct Thresholds {
+    /// The highest acceptable percentage of the capacity of a container
+    /// that holds our volumes to be in use
+    max_usage_percent: u64,
+    /// The number of volumes whose mount point has gone away that we
+    /// tolerate
+    max_orphans: usize,
+    /// The number of mount points whose most recent mount failed that
+    /// we tolerate
+    max_failed_mounts: usize,
+    /// The space, in bytes, that `check-space` insists is left available
+    /// on a volume after the write that it is asked about
+    min_free_bytes: u64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            max_usage_percent: 90,
+            max_orphans: 0,
+            max_failed_mounts: 0,
+            min_free_bytes: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Load the policy file, falling back to the defaults if it
+    /// doesn't exist.  A file that isn't root-owned or that is
+    /// writable by other users is rejected rather than ignored,
+    /// so that misconfigurations are noticed.
+    fn load() -> Result<Self> {
+        match read_policy()? {
+            Some((origin, text)) => {
+                toml::from_str(&text).with_context(|| format!("parsing {}", origin))
+            }
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+/// Read the text of the policy file, along with a description of where
+/// it came from, or None if it doesn't exist
+fn read_policy() -> Result<Option<(String, String)>> {
+    #[cfg(feature = "fixtures")]
+    {
+        if let Some(dir) = eden_apfs_mount_helper::fixtures::fixture_dir() {
+            // The fixture tests supply their own policy, if any
+            return Ok(std::fs::read_to_string(dir.join("policy.toml"))
+                .ok()
+                .map(|text| ("the fixture policy".to_owned(), text)));
+        }
+    }
+
+    let metadata = match std::fs::metadata(CONFIG_PATH) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context(format!("reading {}", CONFIG_PATH)),
+    };
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+        bail!(
+            "refusing to use {} because it is not owned by root \
+             or is writable by other users",
+            CONFIG_PATH
+        );
+    }
+    let text =
+        std::fs::read_to_string(CONFIG_PATH).with_context(|| format!("reading {}", CONFIG_PATH))?;
+    Ok(Some((CONFIG_PATH.to_owned(), text)))
+}
+
+/// The policy, once it has been loaded
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// The timeouts given on the command line, which override the policy
+static TIMEOUT_OVERRIDES: OnceCell<Vec<TimeoutOverride>> = OnceCell::new();
+
+/// The policy, which is loaded the first time that it is needed, either
+/// here or through `load_policy` when a setting that it configures is
+/// looked up.  Loading it installs those settings; the tools that it
+/// relocates are only verified once one of them is about to be run.
+fn config() -> Result<&'static Config> {
+    CONFIG.get_or_try_init(|| {
+        let config = Config::load()?;
+        transcript::note("policy", &format!("{:#?}", config));
+        set_tool_paths(config.tools.clone())?;
+        if let Some(prefix) = &config.volume_prefix {
+            set_volume_prefix(prefix)?;
+        }
+        if let Some(environment) = config.environment {
+            set_environment(environment);
+        }
+        // Detecting the environment costs a few system calls, which only
+        // the commands that report it should pay for
+        if transcript::is_active() {
+            transcript::note("environment", host_environment().name());
+        }
+        let mut timeouts = config.timeouts.clone();
+        if let Some(overrides) = TIMEOUT_OVERRIDES.get() {
+            timeouts.apply_overrides(overrides);
+        }
+        set_timeouts(timeouts)?;
+        Ok(config)
+    })
+}
+
+/// How we let watchman know that a volume was mounted or unmounted
+/// underneath (or on top of) one of its watches.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum WatchmanMode {
+    /// Leave watchman alone
+    Off,
+    /// Ask watchman to recrawl the affected watches
+    Recrawl,
+    /// Remove the affected watches; the next query will re-establish them
+    WatchDel,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct WatchmanConfig {
+    mode: WatchmanMode,
+    /// The absolute path to the watchman binary
+    path: String,
+}
+
+impl Default for WatchmanConfig {
+    fn default() -> Self {
+        Self {
+            mode: WatchmanMode::Recrawl,
+            path: "/usr/local/bin/watchman".to_owned(),
+        }
+    }
+}
+
+/// The environment variable that may be used in place of `--test-container`
+const TEST_CONTAINER_ENV: &str = "EDEN_APFS_TEST_CONTAINER";
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    /// Confine the creation and deletion of volumes to this container,
+    /// eg: disk3.  Volumes in other containers are ignored.  This is
+    /// intended to keep integration tests away from the boot volume.
+    #[structopt(long = "test-container", env = TEST_CONTAINER_ENV, global = true)]
+    test_container: Option<String>,
+
+    /// How to report the output: table, json or plist.  Each command
+    /// that accepts `--json` treats it as `--format json`.  The commands
+    /// that change volumes report a summary of the changes when the
+    /// format is json or plist.
+    #[structopt(
+        long = "format",
+        global = true,
+        possible_values = OutputFormat::NAMES
+    )]
+    format: Option<OutputFormat>,
+
+    /// Record everything that this run does, including the output of
+    /// each command that it runs, into this file so that it can be
+    /// attached to a bug report.  The file is replaced if it exists.
+    #[structopt(long = "transcript", global = true, parse(from_os_str))]
+    transcript: Option<PathBuf>,
+
+    /// Override the timeout for an operation from the policy file, eg:
+    /// `--timeout create=600`.  The operations are list, create, mount,
+    /// unmount and fsck; a timeout of 0 disables it.
+    #[structopt(long = "timeout", global = true, number_of_values = 1)]
+    timeouts: Vec<TimeoutOverride>,
+
+    /// Print the commands that would change volumes or mounts instead of
+    /// running them, and record nothing in the registry.  The volumes
+    /// and mounts are still inspected, so the output shows what would
+    /// be done to them.  Supported by mount, unmount, delete, gc and
+    /// batch.
+    #[structopt(long = "dry-run", global = true)]
+    dry_run: bool,
+
+    #[structopt(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(StructOpt, Debug)]
+enum SubCommand {
+    /// List APFS volumes
+    #[structopt(name = "list")]
+    List {
+        #[structopt(long = "all")]
+        all: bool,
+        /// Only list the volumes created by this utility that are mounted
+        /// somewhere other than the path encoded in their name
+        #[structopt(long = "misplaced", conflicts_with = "all")]
+        misplaced: bool,
+        /// Only list volumes that have one of these roles, eg: Data
+        #[structopt(long = "roles", use_delimiter = true)]
+        roles: Vec<String>,
+        /// Leave out the volumes that hold the OS, such as Preboot,
+        /// Recovery and VM
+        #[structopt(long = "no-system")]
+        no_system: bool,
+        /// Also show the mount point and label of each volume, and
+        /// whether each of ours was created on this host (`local`), on
+        /// another host (`foreign`) or can't be told (`unknown`), in
+        /// tab-separated columns that are empty when not applicable
+        #[structopt(long = "long")]
+        long: bool,
+        /// Instead of listing the volumes, list the mount points whose
+        /// volumes were created, deleted or otherwise changed at or after
+        /// this time, in seconds since the unix epoch.  The last line of
+        /// the output is a cursor to pass as `--since` next time.
+        #[structopt(long = "since", conflicts_with_all = &["all", "misplaced", "roles", "no-system", "long", "json"])]
+        since: Option<u64>,
+        /// Report the volumes as JSON, grouped by container
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
+    /// Move each volume created by this utility that is mounted somewhere
+    /// other than the path encoded in its name (typically under /Volumes)
+    /// to that path
+    #[structopt(name = "fix-misplaced")]
+    FixMisplaced {
+        /// Print the summary of the changes as JSON, on the last line
+        /// of the output
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
+    /// Mount each volume created by this utility at the path encoded in
+    /// its name, moving it there if macOS mounted it under /Volumes.
+    /// Volumes whose mount point doesn't exist or belongs to another user
+    /// are skipped.
+    #[structopt(name = "mount-all")]
+    MountAll,
+
+    /// Mount some space at the specified path.
+    /// You must be the owner of the path.
+    #[structopt(name = "mount")]
+    Mount {
+        mount_point: String,
+        #[structopt(flatten)]
+        new_volume: NewVolume,
+        #[structopt(flatten)]
+        mount_flags: MountFlags,
+    },
+
+    /// Mount some space at each of the specified paths, leaving alone
+    /// those that are already mounted.  You must be the owner of the
+    /// paths.
+    #[structopt(name = "ensure")]
+    Ensure {
+        #[structopt(required = true)]
+        mount_points: Vec<String>,
+        /// Mount all of them or none of them: if any can't be mounted,
+        /// unmount those that this mounted and delete the volumes that
+        /// it created, leaving things as they were
+        #[structopt(long = "atomic")]
+        atomic: bool,
+    },
+
+    /// Unlock the encrypted volume for a mount point and then mount it.
+    /// The passphrase is prompted for, or read from stdin if that isn't
+    /// a terminal.
+    #[structopt(name = "unlock")]
+    Unlock {
+        mount_point: String,
+        /// Store the passphrase in your keychain, so that the volume
+        /// can be unlocked without prompting from now on
+        #[structopt(long = "save-to-keychain")]
+        save_to_keychain: bool,
+    },
+
+    /// Manage the free-form notes attached to the volumes for mount points
+    #[structopt(name = "label")]
+    Label(LabelCommand),
+
+    /// Inspect the installed policy file
+    #[structopt(name = "policy")]
+    Policy(PolicyCommand),
+
+    /// Report the version of this utility and of the installed policy,
+    /// so that fleet tooling can confirm that a rollout has landed
+    #[structopt(name = "capabilities")]
+    Capabilities,
+
+    /// Record that the volume for a mount point belongs to the owner of
+    /// the mount point.  `mount` refuses to mount a volume that was
+    /// created by someone other than the owner of its mount point, as
+    /// happens when an account is recreated with a new uid.
+    #[structopt(name = "adopt")]
+    Adopt {
+        mount_point: String,
+        /// Take over a volume that was created by another uid, along
+        /// with the data on it.  This must be run as root.
+        #[structopt(long = "transfer-ownership")]
+        transfer_ownership: bool,
+    },
+
+    /// Move the volume for a mount point to a new mount point, as when a
+    /// checkout has moved, keeping its contents.  The volume is unmounted,
+    /// renamed to encode the new mount point and mounted there.  You must
+    /// own the new mount point, which must exist.
+    #[structopt(name = "rename")]
+    Rename {
+        /// Where the volume belongs now, which need not exist any more
+        from: String,
+        /// Where the volume should belong
+        to: String,
+    },
+
+    /// Replace a redirection that predates our volumes with a volume,
+    /// keeping its contents.  The mount point may be a symlink to the
+    /// directory holding the contents, or a plain directory.  The new
+    /// volume is mounted in its place, the contents are moved into it
+    /// and the old directory is removed.
+    #[structopt(name = "migrate-from-symlink")]
+    MigrateFromSymlink { mount_point: String },
+
+    /// Unmount the eden space from a specific path.
+    /// This will only allow unmounting volumes that were created
+    /// by this utility.  If there is no volume, the exit status says
+    /// why; see `Absence`.
+    #[structopt(name = "unmount")]
+    UnMount {
+        /// The mounted path that you wish to unmount
+        mount_point: String,
+        /// Force the unmount, even if files are open and busy
+        #[structopt(long = "force")]
+        force: bool,
+    },
+
+    /// Unmount each volume created by this utility whose mount point
+    /// belongs to you, wherever it is mounted, as is needed before
+    /// repairing the disk with Disk Utility
+    #[structopt(name = "unmount-all")]
+    UnMountAll {
+        /// Force the unmounts, even if files are open and busy
+        #[structopt(long = "force")]
+        force: bool,
+    },
+
+    /// Unmount each volume created by this utility on an external disk,
+    /// innermost first, and then eject the disk so that it is safe to
+    /// unplug.  The `hooks.pre_unmount` programs from the policy file
+    /// are run before each volume is unmounted.
+    #[structopt(name = "eject")]
+    Eject {
+        /// The physical disk, eg: `disk2`, or the container on it
+        #[structopt(long = "disk")]
+        disk: String,
+        /// Force the unmounts, even if files are open and busy
+        #[structopt(long = "force")]
+        force: bool,
+    },
+
+    /// Discard everything on the volume for a mount point, keeping the
+    /// volume itself.  The volume is unmounted, erased and mounted again
+    /// for you, which is quicker than deleting and recreating it.
+    #[structopt(name = "erase")]
+    Erase { mount_point: String },
+
+    /// Unmount and delete a volume associated with a specific path.
+    /// This will only allow deleting volumes that were created
+    /// by this utility.  If there is no volume, the exit status says
+    /// why; see `Absence`.
+    #[structopt(name = "delete")]
+    Delete {
+        /// The mounted path that you wish to unmount
+        mount_point: String,
+    },
+
+    /// Unmount and delete all APFS volumes created by this utility that
+    /// belong to you, after listing them and asking for confirmation.
+    /// When run as root this deletes every volume created by this utility.
+    #[structopt(name = "delete-all")]
+    DeleteAll {
+        /// Print the summary of the changes as JSON, on the last line
+        /// of the output
+        #[structopt(long = "json")]
+        json: bool,
+        /// Don't ask for confirmation
+        #[structopt(long = "yes", short = "y")]
+        yes: bool,
+    },
+
+    /// Delete the volumes created by this utility whose mount point no
+    /// longer exists, or is no longer owned by the user that created the
+    /// volume, as happens when a repo is moved or removed.  Volumes whose
+    /// label starts with `keep` are left alone, as are volumes that
+    /// weren't created on this host (see `list --long`) and volumes that
+    /// match a keep rule from `gc.keep` in the policy file or `--keep`.
+    #[structopt(name = "gc")]
+    Gc {
+        /// Also delete the orphans that were created on another host, or
+        /// that can't be told apart from those
+        #[structopt(long = "include-foreign")]
+        include_foreign: bool,
+        /// Also keep the volumes that match this rule: either a glob
+        /// matched against the mount point, such as
+        /// `/Users/*/release/buck-out`, or `label:<glob>` to match the
+        /// label.  May be repeated.
+        #[structopt(long = "keep", number_of_values = 1)]
+        keep: Vec<KeepRule>,
+        /// Ignore the keep rules from the policy file, so that only
+        /// those given with `--keep` apply
+        #[structopt(long = "ignore-policy-keep")]
+        ignore_policy_keep: bool,
+    },
+
+    /// Look for volumes that have gone stale: those whose mount point no
+    /// longer exists or belongs to someone other than their creator, and
+    /// those whose names don't decode to a mount point.  Each finding is
+    /// printed as a line of tab separated columns: the device, the kind
+    /// of finding, the volume name and the details.  The exit status is
+    /// non-zero if anything was found.
+    #[structopt(name = "audit")]
+    Audit,
+
+    /// Check the installation of this utility and the volumes that it
+    /// created for problems.  `health` reports the same problems with an
+    /// id and a suggested remediation for each.
+    #[structopt(name = "doctor")]
+    Doctor {
+        /// Repair the problems that can be repaired automatically
+        #[structopt(long = "fix")]
+        fix: bool,
+        /// Print the summary of the repairs as JSON, on the last line
+        /// of the output
+        #[structopt(long = "json", requires = "fix")]
+        json: bool,
+    },
+
+    /// Report the free space in each APFS container as JSON, along
+    /// with the space available to each volume created by this utility
+    #[structopt(name = "free-space")]
+    FreeSpace {
+        /// Only report on this container, eg: disk1
+        #[structopt(long = "container")]
+        container: Option<String>,
+    },
+
+    /// Show what is known about the volume for a mount point, or about
+    /// the volume picked out by one of the selectors: its identity, where
+    /// it is mounted, the space it uses and whether macOS mounted it
+    /// under /Volumes
+    #[structopt(name = "info")]
+    Info {
+        #[structopt(required_unless_one = &["device", "uuid", "name"])]
+        mount_point: Option<String>,
+        #[structopt(flatten)]
+        selector: VolumeSelector,
+    },
+
+    /// Change the quota of the volume for a mount point without
+    /// recreating it.  The data on the volume must fit within the new
+    /// quota; see `resize-preflight`.
+    #[structopt(name = "set-quota")]
+    SetQuota {
+        mount_point: String,
+        /// The new quota, in bytes; 0 removes the quota
+        quota: u64,
+    },
+
+    /// Create the volume for a mount point without mounting it, so that
+    /// it can be baked into a machine image before the user that will
+    /// mount it exists.  Their first mount finishes setting it up, and
+    /// makes them its creator.  This must be run as root.
+    #[structopt(name = "prepare")]
+    Prepare {
+        mount_point: String,
+        #[structopt(flatten)]
+        new_volume: NewVolume,
+    },
+
+    /// Check whether the data on the volume for a mount point fits within
+    /// a proposed quota, and if not, how much of it must be deleted
+    /// before the quota can be lowered that far.  The exit status is
+    /// non-zero if it doesn't fit.
+    #[structopt(name = "resize-preflight")]
+    ResizePreflight {
+        mount_point: String,
+        /// The proposed quota, in bytes; 0 means no quota
+        quota: u64,
+        /// Also list this many of the largest entries at the top of the
+        /// volume, to guide the cleanup.  The volume must be mounted.
+        #[structopt(long = "largest", default_value = "0")]
+        largest: usize,
+    },
+
+    /// Check whether the volume for a mount point has room for a write of
+    /// the given size, taking its quota, its reserve and the free space
+    /// in its container into account.  This is meant for build wrappers
+    /// to call before a large write.  The exit status is non-zero if the
+    /// write doesn't fit.
+    #[structopt(name = "check-space")]
+    CheckSpace {
+        mount_point: String,
+        /// The size of the upcoming write, in bytes
+        #[structopt(long = "need")]
+        need: u64,
+        /// The space that must be left available after the write, in
+        /// bytes, in place of `min_free_bytes` from the policy
+        #[structopt(long = "min-free")]
+        min_free: Option<u64>,
+    },
+
+    /// Check the filesystem of the volume for a mount point for damage.
+    /// This requires the volume to be unmounted, unless `--live` is used.
+    /// With `--locations`, check where each of our volumes is mounted
+    /// instead.
+    #[structopt(name = "verify")]
+    Verify {
+        #[structopt(required_unless = "locations")]
+        mount_point: Option<String>,
+        /// Verify the volume while it remains mounted.  This falls back
+        /// to a spot check of its metadata on systems that can't verify
+        /// a mounted APFS volume.
+        #[structopt(long = "live")]
+        live: bool,
+        /// Check that each of our volumes is either unmounted or mounted
+        /// at the mount point that its name encodes, as opposed to
+        /// somewhere such as /Volumes.  Each volume that isn't is printed
+        /// as a line of tab separated columns: the device, the volume
+        /// name and the problem.  The exit status is non-zero if any
+        /// volume isn't.
+        #[structopt(long = "locations", conflicts_with_all = &["mount-point", "live"])]
+        locations: bool,
+    },
+
+    /// Unmount the volume for a mount point, check its filesystem with
+    /// fsck_apfs and repair what it can, then mount it again.  Use this
+    /// when a volume won't mount after the machine lost power.
+    #[structopt(name = "fsck")]
+    Fsck { mount_point: String },
+
+    /// Run the checks that `doctor` performs without repairing anything,
+    /// and report the outcome of each of them
+    #[structopt(name = "health")]
+    Health {
+        /// Report the checks as JSON, for consumption by `eden doctor`
+        #[structopt(long = "json")]
+        json: bool,
+        /// Exit with a non-zero status if any check failed or any of the
+        /// thresholds in the policy file is exceeded; see `stats --check`
+        #[structopt(long = "check")]
+        check: bool,
+    },
+
+    /// Report the space used in the containers that hold our volumes,
+    /// the number of volumes whose mount point has gone away and the
+    /// number of mount points whose most recent mount failed
+    #[structopt(name = "stats")]
+    Stats {
+        /// Exit with a status that indicates which of the thresholds in
+        /// the policy file are exceeded: 2 for usage, 4 for orphaned
+        /// volumes and 8 for failed mounts, added together
+        #[structopt(long = "check")]
+        check: bool,
+        /// Record the space in use in each of our volumes and the
+        /// containers that hold them in the registry, for `--forecast`,
+        /// and fold the latencies noted since the last run into the
+        /// daily histograms for `--latency`.  This is meant to be run
+        /// periodically by the maintenance agent.
+        #[structopt(long = "record")]
+        record: bool,
+        /// Project the growth of the space in use in each container that
+        /// holds our volumes, and in each of our volumes that has a quota,
+        /// from the samples taken by `--record`.  The columns are the
+        /// container or mount point, what limits it (`capacity` or
+        /// `quota`), the growth in bytes per day and the number of days
+        /// until it reaches the limit at that rate, which is empty if it
+        /// isn't growing.
+        #[structopt(long = "forecast")]
+        forecast: bool,
+        /// Report how well the files on each of our mounted volumes
+        /// compress, which requires reading every directory on them.
+        /// The columns are the mount point, the size of the files, the
+        /// space that they take and the ratio of the two.
+        #[structopt(long = "compression")]
+        compression: bool,
+        /// Report how long each class of operation has taken over the
+        /// last week, from the histograms kept by `--record` and the
+        /// latencies that it has yet to fold in.  The columns are the
+        /// operation, the number of samples and the 50th, 95th and 99th
+        /// percentiles in milliseconds, which are `>300000` beyond the
+        /// last bucket.  With `--format json`, only this is reported.
+        #[structopt(long = "latency")]
+        latency: bool,
+        /// Instead of the usual report, refresh the space in use in each
+        /// of our volumes and the free space in the containers that hold
+        /// them every this many seconds, like `watch df`.  The lines are
+        /// `free`, the container and its free bytes, and `volume`, the
+        /// device, the bytes in use, the change since the previous
+        /// refresh and the mount point.
+        #[structopt(
+            long = "watch",
+            value_name = "seconds",
+            conflicts_with_all = &["check", "record", "forecast", "compression", "latency"]
+        )]
+        watch: Option<u64>,
+        /// Stop after this many refreshes of `--watch`
+        #[structopt(long = "count", requires = "watch")]
+        count: Option<u64>,
+    },
+
+    /// Report the space used by each volume created by this utility,
+    /// largest first, along with its quota.  The columns are the device,
+    /// the space in use in bytes, the quota in bytes (empty if there is
+    /// none) and the mount point.
+    #[structopt(name = "usage")]
+    Usage,
+
+    /// Compress the files on the volume for a mount point that haven't
+    /// been used for a while, using the transparent compression that
+    /// APFS provides, to make room without deleting anything.  The files
+    /// are rewritten as you, so this needs no special privileges.  Files
+    /// that are small, already compressed or have other hard links are
+    /// left alone.
+    #[structopt(name = "compress")]
+    Compress {
+        mount_point: String,
+        /// Only compress the files that haven't been read or written
+        /// for this many days
+        #[structopt(long = "older-than", default_value = "7")]
+        older_than: u64,
+    },
+
+    /// Compact the disk images of the `diskimage` backend whose volumes
+    /// aren't mounted, detaching them first if need be, to give back the
+    /// space that they grew into for files that have since been deleted.
+    /// Prints the mount point of each image and the bytes reclaimed.
+    /// This is meant to be run periodically by the maintenance agent, and
+    /// must be run as root.
+    #[structopt(name = "compact-images")]
+    CompactImages,
+
+    /// Report whether every persistent mount is up: that is, whether each
+    /// mount point whose most recent mount, unmount or delete was a
+    /// successful mount has its volume mounted there.  macOS mounts our
+    /// volumes under /Volumes at boot, and they stay there until the boot
+    /// agent runs `mount-all`; edenfs runs `ready --wait` as it starts
+    /// so that it doesn't examine its redirections before then.
+    /// Exits with a non-zero status if any persistent mount is not up.
+    #[structopt(name = "ready")]
+    Ready {
+        /// Keep checking for up to this many seconds until every
+        /// persistent mount is up
+        #[structopt(long = "wait", default_value = "0")]
+        wait: u64,
+    },
+
+    /// Show the recent operations on a mount point and their outcomes
+    #[structopt(name = "history")]
+    History {
+        mount_point: String,
+        /// Report the history as JSON
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
+    /// Mount scratch space for each of the bind redirections configured
+    /// for an eden checkout, creating any missing directories first.
+    /// The set of redirections is taken from the `.eden-redirections`
+    /// file in the repo, the checkout's own eden config and the buck-out
+    /// location from `.buckconfig`.
+    #[structopt(name = "apply")]
+    Apply {
+        /// The root of the checkout
+        checkout: String,
+        /// Print the summary of the changes as JSON, on the last line
+        /// of the output
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
+    /// Run mount, unmount and delete commands read from stdin, one JSON
+    /// object per line, eg: `{"command": "unmount", "mount_point":
+    /// "/Users/wez/repo/buck-out", "force": true}`.  The result of each
+    /// command is printed as a line of JSON as soon as it is done.
+    /// Exits with a non-zero status if any command failed.
+    #[structopt(name = "batch")]
+    Batch,
+}
+
+#[derive(StructOpt, Debug)]
+enum LabelCommand {
+    /// Attach a note to the volume for a mount point, replacing any
+    /// existing one.  A note that starts with `keep` protects the volume
+    /// from garbage collection.
+    #[structopt(name = "set")]
+    Set { mount_point: String, label: String },
+
+    /// Show the note attached to the volume for a mount point, if any
+    #[structopt(name = "get")]
+    Get { mount_point: String },
+
+    /// Remove the note attached to the volume for a mount point
+    #[structopt(name = "clear")]
+    Clear { mount_point: String },
+}
+
+#[derive(StructOpt, Debug)]
+enum PolicyCommand {
+    /// Check that the installed policy has the same settings as a
+    /// reference copy, such as the one pushed out by MDM.  The reference
+    /// must be accompanied by `<reference>.sha256`, holding its SHA-256
+    /// digest as written by `shasum -a 256`, which is checked first so
+    /// that a damaged reference isn't trusted.  Each difference is
+    /// reported as `setting\tinstalled\treference`, with `-` for a
+    /// setting that is missing, and the exit status is non-zero if
+    /// there are any.
+    #[structopt(name = "verify")]
+    Verify {
+        #[structopt(parse(from_os_str))]
+        reference: PathBuf,
+    },
+}
+
+/// How to create the volume for a mount point, for the commands that
+/// create it if it doesn't exist yet
+#[derive(StructOpt, Debug, Default)]
+struct NewVolume {
+    /// Create the volume in this APFS container, eg: disk3, instead of
+    /// the one that the policy chooses
+    #[structopt(long = "container")]
+    container: Option<String>,
+    /// The quota for the volume, in bytes, which keeps it from taking
+    /// all of the space in its container
+    #[structopt(long = "quota")]
+    quota: Option<u64>,
+    /// The space in bytes to set aside for the volume, which other
+    /// volumes in its container can't take, so that a long build isn't
+    /// starved by other apps filling the disk
+    #[structopt(long = "reserve")]
+    reserve: Option<u64>,
+    /// Create a case-sensitive volume, for the build tools that need
+    /// file names that differ only in case to be different files even
+    /// though the system volume is case-insensitive
+    #[structopt(long = "case-sensitive")]
+    case_sensitive: bool,
+    /// Encrypt the volume with a random passphrase, which is stored in
+    /// your keychain so that `mount` can unlock the volume from then on
+    #[structopt(long = "encrypted")]
+    encrypted: bool,
+    /// What to make the volume of, instead of what the policy says:
+    /// `diskimage` makes a volume in a sparse bundle disk image, for
+    /// hosts that don't allow APFS volumes to be created, and `ramdisk`
+    /// makes one on a RAM disk, which is discarded when it is unmounted
+    #[structopt(long = "backend", possible_values = Backend::NAMES)]
+    backend: Option<Backend>,
+}
+
+impl NewVolume {
+    /// The backend for the volume, checking that the other options make
+    /// sense for it
+    fn backend(&self, config: &Config, test_container: Option<&str>) -> Result<Backend> {
+        let backend = self.backend.unwrap_or(config.backend);
+        if backend == Backend::RamDisk
+            && config.backend != Backend::RamDisk
+            && !config.ram_disk.allow_users
+            && get_real_uid()? != 0
+        {
+            bail!(
+                "--backend ramdisk sets memory aside, and must be run as root \
+                 unless the policy allows users to ask for it"
+            );
+        }
+        if backend != Backend::Apfs {
+            if self.container.is_some() {
+                bail!(
+                    "--container can't be used with the {} backend",
+                    backend.name()
+                );
+            }
+            if test_container.is_some() {
+                bail!(
+                    "the {} backend can't be used with a test container",
+                    backend.name()
+                );
+            }
+        }
+        Ok(backend)
+    }
+
+    fn options(&self) -> Result<VolumeOptions> {
+        if self.quota == Some(0) {
+            bail!("--quota must be more than 0; leave it out for no quota");
+        }
+        if self.reserve == Some(0) {
+            bail!("--reserve must be more than 0; leave it out for no reserve");
+        }
+        if let (Some(quota), Some(reserve)) = (self.quota, self.reserve) {
+            if reserve > quota {
+                bail!(
+                    "--reserve {} is more than --quota {}, which the volume could never use",
+                    reserve,
+                    quota
+                );
+            }
+        }
+        Ok(VolumeOptions {
+            quota: self.quota,
+            reserve: self.reserve,
+            case_sensitive: self.case_sensitive,
+            passphrase: if self.encrypted {
+                Some(generate_passphrase()?)
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Warn that the options are ignored for `mount_point`, which already
+    /// has a volume
+    fn warn_unused(&self, mount_point: &str) {
+        if self.quota.is_some() {
+            eprintln!(
+                "warning: {} already has a volume, so --quota was ignored; `set-quota` changes its quota",
+                mount_point
+            );
+        }
+        if self.reserve.is_some() {
+            eprintln!(
+                "warning: {} already has a volume, so --reserve was ignored",
+                mount_point
+            );
+        }
+        if self.case_sensitive {
+            eprintln!(
+                "warning: {} already has a volume, so --case-sensitive was ignored; \
+                 delete the volume to recreate it as case-sensitive",
+                mount_point
+            );
+        }
+        if self.encrypted {
+            eprintln!(
+                "warning: {} already has a volume, so --encrypted was ignored; \
+                 delete the volume to recreate it encrypted",
+                mount_point
+            );
+        }
+        if self.backend.is_some() {
+            eprintln!(
+                "warning: {} already has a volume, so --backend was ignored",
+                mount_point
+            );
+        }
+    }
+}
+
+/// How to mount a volume, beyond what the policy says
+#[derive(StructOpt, Debug, Default)]
+struct MountFlags {
+    /// An option to pass to mount_apfs after those of the policy, eg:
+    /// `noatime`.  This may be repeated.  Options that undo the baseline
+    /// of `nobrowse,nodev,nosuid` are refused unless the policy allows
+    /// them.
+    #[structopt(long = "mount-option", number_of_values = 1)]
+    mount_options: Vec<String>,
+    /// Mount the volume noexec, so that programs on it can't be run.
+    /// This comes after the other options, so `--mount-option exec`
+    /// doesn't undo it.
+    #[structopt(long = "noexec")]
+    noexec: bool,
+    /// Mount the volume read-only, eg: to inspect it before deleting it.
+    /// A volume that is already mounted is re-mounted read-only, and
+    /// mounting it again without this re-mounts it read-write, and exec
+    /// unless `--noexec` is given again.
+    #[structopt(long = "read-only")]
+    read_only: bool,
+}
+
+/// The options to pass to mount_apfs: the baseline, followed by those of
+/// `policy` and then those that were requested by `flags` on the command
+/// line, which are checked against the baseline
+fn mount_options(policy: &MountPolicy, flags: &MountFlags) -> Result<String> {
+    let requested = &flags.mount_options;
+    for option in requested {
+        if option.is_empty()
+            || !option
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '='))
+        {
+            bail!("{:?} is not a valid mount option", option);
+        }
+        let undoes_baseline = BASELINE_MOUNT_OPTIONS
+            .iter()
+            .any(|baseline| baseline.strip_prefix("no") == Some(option));
+        if undoes_baseline && !policy.allow_overrides.contains(option) {
+            bail!(
+                "refusing to mount with {} because the policy doesn't allow it to undo {}",
+                option,
+                BASELINE_MOUNT_OPTIONS.join(",")
+            );
+        }
+    }
+    Ok(BASELINE_MOUNT_OPTIONS
+        .iter()
+        .map(|option| option.to_string())
+        .chain(policy.options.iter().cloned())
+        .chain(requested.iter().cloned())
+        .chain((policy.noexec || flags.noexec).then(|| "noexec".to_owned()))
+        .chain(flags.read_only.then(|| "rdonly".to_owned()))
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+/// Alternative ways to pick out a volume for the read-only commands,
+/// for volumes that are easier to identify by what Disk Utility shows
+#[derive(StructOpt, Debug, Default)]
+struct VolumeSelector {
+    /// The device identifier of the volume, eg: disk1s5
+    #[structopt(long = "device", conflicts_with_all = &["mount-point", "uuid", "name"])]
+    device: Option<String>,
+    /// The UUID of the volume
+    #[structopt(long = "uuid", conflicts_with_all = &["mount-point", "device", "name"])]
+    uuid: Option<String>,
+    /// The name of the volume, eg: edenfs:/Users/me/repo/buck-out
+    #[structopt(long = "name", conflicts_with_all = &["mount-point", "device", "uuid"])]
+    name: Option<String>,
+}
+
+impl VolumeSelector {
+    fn matches(&self, volume: &ApfsVolume) -> bool {
+        if let Some(device) = &self.device {
+            let device = device.strip_prefix("/dev/").unwrap_or(device);
+            return volume.device_identifier == device;
+        }
+        if let Some(uuid) = &self.uuid {
+            return volume
+                .uuid
+                .as_ref()
+                .map(|vol_uuid| vol_uuid.eq_ignore_ascii_case(uuid))
+                .unwrap_or(false);
+        }
+        if let Some(name) = &self.name {
+            return volume.name.as_ref() == Some(name);
+        }
+        false
+    }
+
+    /// Find the selected volume among all of the volumes on the system
+    fn find(&self) -> Result<ApfsVolume> {
+        apfs_list()?
+            .into_iter()
+            .flat_map(|container| container.volumes)
+            .find(|volume| self.matches(volume))
+            .ok_or_else(|| anyhow!("Did not find a volume matching {}", self))
+    }
+}
+
+impl std::fmt::Display for VolumeSelector {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.device, &self.uuid, &self.name) {
+            (Some(device), _, _) => write!(fmt, "--device {}", device),
+            (_, Some(uuid), _) => write!(fmt, "--uuid {}", uuid),
+            (_, _, Some(name)) => write!(fmt, "--name {}", name),
+            _ => write!(fmt, "no selector"),
+        }
+    }
+}
+
+/// List the containers that we may create or delete volumes in.
+/// When a test container is specified, all other containers are
+/// left out so that nothing outside of it can be touched.
+fn list_containers(test_container: Option<&str>) -> Result<Vec<ApfsContainer>> {
+    let containers = apfs_list()?;
+    let test_container = match test_container {
+        Some(container) => container,
+        None => return Ok(containers),
+    };
+    if !is_container_identifier(test_container) {
+        bail!(
+            "test container `{}` is not a container identifier such as disk3",
+            test_container
+        );
+    }
+    let containers: Vec<ApfsContainer> = containers
+        .into_iter()
+        .filter(|container| container.container_reference == test_container)
+        .collect();
+    if containers.is_empty() {
+        bail!("test container {} does not exist", test_container);
+    }
+    Ok(containers)
+}
+
+/// Expand a leading `~` in `path` to the home directory of the real
+/// user.  The shell normally does this for us, but not when the path
+/// is quoted or passed through by another tool.  Environment variables
+/// are not expanded, as their values belong to the caller; such paths
+/// are rejected rather than being treated as literal names.
+fn expand_user_path(path: &str, home: impl FnOnce() -> Result<String>) -> Result<String> {
+    let mut chars = path.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if c != '$' {
+            continue;
+        }
+        if let Some(&(start, next)) = chars.peek() {
+            if next == '{' || next == '_' || next.is_ascii_alphabetic() {
+                let var: String = path[start..]
+                    .chars()
+                    .take_while(|&c| c == '{' || c == '}' || c == '_' || c.is_ascii_alphanumeric())
+                    .collect();
+                bail!(
+                    "{} refers to the environment variable ${}, which is not expanded here; \
+                     let your shell expand it by leaving it unquoted",
+                    path,
+                    var
+                );
+            }
+        }
+    }
+
+    match path.strip_prefix('~') {
+        None => Ok(path.to_owned()),
+        Some("") => home(),
+        Some(rest) if rest.starts_with('/') => {
+            Ok(format!("{}{}", home()?.trim_end_matches('/'), rest))
+        }
+        Some(_) => bail!(
+            "{} refers to the home directory of another user; \
+             only ~ for your own home directory is supported",
+            path
+        ),
+    }
+}
+
+/// Canonicalize a path and return the canonical path in string form.
+/// A leading `~` is expanded first; see `expand_user_path`.
+fn canonicalize_mount_point_path(mount_point: &str) -> Result<String> {
+    let mount_point = &expand_user_path(mount_point, get_real_user_home)?;
+    let canon = std::fs::canonicalize(mount_point)
+        .with_context(|| format!("canonicalizing path {}", mount_point))?;
+    canon
+        .to_str()
+        .ok_or_else(|| anyhow!("path {} somehow isn't unicode on macOS", canon.display()))
+        .map(str::to_owned)
+}
+
+/// Normalize a mount point from the command line.  Every command that
+/// takes a mount point does this first, so that they all agree on which
+/// mount point it names; see `paths`.
+/// This is done with the privs of the real user, so that we don't
+/// resolve symlinks in directories that the user can't read and then
+/// tell them where they lead.
+fn normalize_mount_point(mount_point: &str) -> Result<String> {
+    with_real_user_privs(|| match canonicalize_mount_point_path(mount_point) {
+        Ok(canon) => Ok(canon),
+        Err(_) => {
+            let expanded = expand_user_path(mount_point, get_real_user_home)?;
+            let cwd = std::env::current_dir().context("finding the current directory")?;
+            normalize_path(&expanded, &cwd)
+        }
+    })
+}
+
+/// The key under which we record the history of `mount_point`
+fn history_key(mount_point: &str) -> String {
+    normalize_mount_point(mount_point).unwrap_or_else(|_| mount_point.to_owned())
+}
+
+/// The container in which to create a new volume for `mount_point`: the
+/// one that was asked for, or else the test container, if there is one,
+/// or else the one that `placement` chooses.  `containers` are those that
+/// we may use.
+fn new_volume_container(
+    containers: &[ApfsContainer],
+    test_container: Option<&str>,
+    requested: Option<&str>,
+    placement: Placement,
+    mount_point: &str,
+) -> Result<String> {
+    if let Some(requested) = requested {
+        match test_container {
+            Some(test_container) if test_container != requested => bail!(
+                "--container {} conflicts with the test container {}",
+                requested,
+                test_container
+            ),
+            _ => {}
+        }
+        if !containers
+            .iter()
+            .any(|container| container.container_reference == requested)
+        {
+            bail!(
+                "{} is not an APFS container; `free-space` lists the containers",
+                requested
+            );
+        }
+        return Ok(requested.to_owned());
+    }
+    match test_container {
+        Some(container) => Ok(container.to_owned()),
+        None => match placement {
+            Placement::System => Ok(system_container(containers, None)?
+                .container_reference
+                .clone()),
+            Placement::MostFree => containers
+                .iter()
+                .max_by_key(|container| container.capacity_free)
+                .map(|container| container.container_reference.clone())
+                .ok_or_else(|| anyhow!("there are no APFS containers")),
+            Placement::SameDisk => {
+                let mounts = MountTable::parse_system_mount_table()?;
+                match container_for_path(containers, &mounts, Path::new(mount_point)) {
+                    Some(container) => Ok(container),
+                    None => Ok(system_container(containers, Some(&mounts))?
+                        .container_reference
+                        .clone()),
+                }
+            }
+        },
+    }
+}
+
+/// Mount scratch space at `input_mount_point`, returning the canonical
+/// mount point.  This performs all of the privileged parts of mounting
+/// the volume; the caller must drop privileges and then call
+/// `finish_mount` to complete the job.  If the volume has to be created,
+/// it is created as `new_volume` says, and it is mounted with the options
+/// that `mount_flags` adds to those of the policy.
+fn mount_scratch_space_on(
+    config: &Config,
+    test_container: Option<&str>,
+    new_volume: &NewVolume,
+    mount_flags: &MountFlags,
+    input_mount_point: &str,
+    unlock: Option<&Unlock>,
+    summary: &mut ChangeSummary,
+) -> Result<String> {
+    let result = mount_volume_at(
+        config,
+        test_container,
+        new_volume,
+        mount_flags,
+        input_mount_point,
+        unlock,
+        summary,
+    );
+    record_history(&history_key(input_mount_point), "mount", &result);
+    match &result {
+        Ok(mount_point) => summary.mounted.push(mount_point.clone()),
+        Err(err) => summary
+            .errors
+            .push(format!("mounting {}: {:#}", input_mount_point, err)),
+    }
+    result
+}
+
+fn mount_volume_at(
+    config: &Config,
+    test_container: Option<&str>,
+    new_volume: &NewVolume,
+    mount_flags: &MountFlags,
+    input_mount_point: &str,
+    unlock: Option<&Unlock>,
+    summary: &mut ChangeSummary,
+) -> Result<String> {
+    let options = mount_options(&config.mount, mount_flags)?;
+    let mount_point = canonicalize_mount_point_path(input_mount_point)?;
+    eprintln!("want to mount at {:?}", mount_point);
+
+    // First, let's ensure that mounting at this location makes sense.
+    // Inspect the directory and ensure that it is owned by us.
+    // We hold the directory open from here on and mount on top of
+    // the directory that we inspected rather than resolving the path
+    // again, so that it can't be swapped for something else meanwhile.
+    let dir = with_real_user_privs(|| open_mount_point_dir(&mount_point))?;
+    let metadata = dir
+        .metadata()
+        .context(format!("Obtaining filesystem metadata for {}", mount_point))?;
+    let my_uid = get_real_uid()?;
+    if metadata.uid() != my_uid {
+        bail!(
+            "Refusing to set up a volume for {} because the owned uid {} doesn't match your uid {}",
+            mount_point,
+            metadata.uid(),
+            my_uid
+        );
+    }
+
+    eprintln!("my real uid is {}, effective is {}", my_uid, unsafe {
+        libc::geteuid()
+    });
+
+    if config.cloud_sync != CloudSyncPolicy::Allow {
+        if let Some(service) =
+            with_real_user_privs(|| Ok(cloud_sync_service(Path::new(&mount_point))))?
+        {
+            if config.cloud_sync == CloudSyncPolicy::Refuse {
+                bail!(
+                    "Refusing to set up a volume for {} because it is inside a directory synced by {}",
+                    mount_point,
+                    service
+                );
+            }
+            eprintln!(
+                "warning: {} is inside a directory synced by {}, which may try to sync or evict the contents of the volume",
+                mount_point, service
+            );
+        }
+    }
+
+    let mut containers = list_containers(test_container)?;
+    let name = encode_mount_point_as_volume_name(&mount_point);
+    if find_existing_volume(&containers, &name).is_none()
+        && test_container.is_none()
+        && has_image(&name)
+    {
+        // The image isn't attached, as after a reboot
+        attach_image(&image_path(&name)?)?;
+        containers = list_containers(test_container)?;
+    }
+    let mut created = find_existing_volume(&containers, &name).is_none();
+    let mut remount = false;
+    let volume = match find_existing_volume(&containers, &name) {
+        Some(existing) => {
+            new_volume.warn_unused(&mount_point);
+            let registry = Registry::load()?;
+            check_creator(&mount_point, &registry, my_uid)?;
+            if registry
+                .mounts
+                .get(&mount_point)
+                .is_some_and(MountRecord::is_prepared)
+            {
+                if mount_flags.read_only {
+                    bail!(
+                        "{} hasn't been mounted yet, so it can't be mounted read-only",
+                        mount_point
+                    );
+                }
+                // The first mount of a volume made by `prepare` finishes
+                // the setup that creating it would have done
+                record_creator(&mount_point, my_uid);
+                created = true;
+            }
+            let mount_table = MountTable::parse_system_mount_table()?;
+            if let Some(current_mount_point) = existing.get_current_mount_point(Some(&mount_table))
+            {
+                if !existing.is_preferred_location(&current_mount_point)? {
+                    // macOS will automatically mount volumes at system boot,
+                    // but mount them under /Volumes.  That will block our attempt
+                    // to mount the scratch space below, so if we see that this
+                    // volume is mounted and not where we want it, we simply unmount
+                    // it here now: this should be fine because we own these volumes
+                    // and where they get mounted.  No one else should have a legit
+                    // reason for mounting it elsewhere.
+                    // Nothing watches the location that macOS chose, so
+                    // there's no need to finish the unmount.
+                    unmount_scratch(&mount_point, true, &mount_table)?;
+                } else if current_mount_point == mount_point {
+                    // It is already mounted here, perhaps read-only, so
+                    // we update that mount with the options of this one
+                    remount = true;
+                }
+            }
+            existing.clone()
+        }
+        None => {
+            if mount_flags.read_only {
+                bail!(
+                    "there is no volume for {}, so it can't be mounted read-only",
+                    mount_point
+                );
+            }
+            check_no_image(test_container, &name, &mount_point)?;
+            if let Some(environment) = environment_tag() {
+                eprintln!(
+                    "warning: {}, so diskutil may be unable to create or mount volumes",
+                    environment.description()
+                );
+            }
+            let mut ram_disk = None;
+            let volume = new_volume.options().and_then(|options| {
+                match new_volume.backend(config, test_container)? {
+                    Backend::Apfs => {}
+                    Backend::DiskImage => {
+                        return make_image_volume(&name, &options, config.disk_image.size);
+                    }
+                    Backend::RamDisk => {
+                        let (volume, disk) =
+                            make_ram_disk_volume(&name, &options, config.ram_disk.size)?;
+                        ram_disk = Some(disk);
+                        return Ok(volume);
+                    }
+                }
+                let container = new_volume_container(
+                    &containers,
+                    test_container,
+                    new_volume.container.as_deref(),
+                    config.placement,
+                    &mount_point,
+                )?;
+                create_volume(&name, &container, &options, &mount_point)
+            });
+            record_history(&mount_point, "create", &volume);
+            if volume.is_ok() {
+                record_creator(&mount_point, my_uid);
+                // This also forgets any RAM disk that an earlier volume
+                // was on, which went away with it
+                record_ram_disk(&mount_point, ram_disk.as_deref());
+                summary.created.push(mount_point.clone());
+            }
+            volume?
+        }
+    };
+    if volume.locked {
+        unlock_for_mount(&volume, &mount_point, unlock)?;
+    }
+
+    // Mount the volume at the desired mount point.
+    // This is the only part of this utility that requires root privs.
+    // mount_apfs is run in the directory that we opened above and
+    // mounts onto ".", its working directory.  If the volume is already
+    // mounted there, the directory is the root of the volume, and mount
+    // updates the options of that mount instead.
+    let mut cmd;
+    let description;
+    if remount {
+        // An update keeps the options that it doesn't mention, so we give
+        // the full set that we want, and undo the ones that an earlier
+        // `--read-only` or `--noexec` mount may have left behind
+        let mut options = options;
+        for (option, undo) in &[("rdonly", "rw"), ("noexec", "exec")] {
+            if !options.split(',').any(|effective| effective == *option) {
+                options = format!("{},{}", options, undo);
+            }
+        }
+        cmd = new_cmd_with_root_privs("/sbin/mount");
+        cmd.args(&["-u", &format!("-o{}", options), "."]);
+        description = format!("mount -u {}", mount_point);
+    } else {
+        cmd = new_cmd_with_root_privs(&tool_paths()?.mount_apfs);
+        cmd.args(&[
+            &format!("-o{}", options),
+            "-u",
+            &format!("{}", metadata.uid()),
+            "-g",
+            &format!("{}", metadata.gid()),
+            &format!("/dev/{}", volume.device_identifier),
+            ".",
+        ]);
+        description = format!(
+            "mount_apfs /dev/{} {}",
+            volume.device_identifier, mount_point
+        );
+    }
+    let fd = dir.as_raw_fd();
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::fchdir(fd) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let output = cmd.run_timed(Operation::Mount)?;
+    if !output.status.success() {
+        anyhow::bail!("failed to execute {}: {:#?}", description, output);
+    }
+    eprintln!("output: {:?}", output);
+    if dry_run() {
+        // Nothing was mounted, so there is nothing to set up
+        return Ok(mount_point);
+    }
+    if mount_flags.read_only {
+        // The volume was set up when it was first mounted, and can't be
+        // changed now
+        return Ok(mount_point);
+    }
+
+    // Make sure that we own the mounted directory; the default is mounted
+    // with root:wheel ownership, and that isn't desirable
+    let root = open_mounted_volume_root(&mount_point, &volume)?;
+    fchown(&root, &mount_point, metadata.uid(), metadata.gid())?;
+    if created {
+        if let Err(err) = ensure_host_id().and_then(|id| tag_volume(&root, &id)) {
+            eprintln!("failed to tag {} with this host: {:#}", mount_point, err);
+        }
+        if let Err(err) = apply_template(&config.template, &mount_point) {
+            eprintln!("failed to apply the template to {}: {:#}", mount_point, err);
+        }
+    }
+
+    disable_spotlight(&mount_point).ok();
+    disable_fsevents(&mount_point).ok();
+    disable_trashcan(&mount_point).ok();
+    if let Err(err) = exclude_from_backups(&mount_point) {
+        eprintln!("failed to exclude {} from backups: {:#}", mount_point, err);
+    }
+
+    for kind in &config.exclusions {
+        if let Err(err) = kind.exclusion().apply(&mount_point) {
+            eprintln!(
+                "failed to apply {} exclusion to {}: {:#}",
+                kind.name(),
+                mount_point,
+                err
+            );
+        }
+    }
+
+    Ok(mount_point)
+}
+
+/// Refuse to create the volume `name` for `mount_point` when there is
+/// already a disk image for it that we couldn't find the volume in.
+/// The image would otherwise be taken for the home of the new volume
+/// when it is deleted.
+fn check_no_image(test_container: Option<&str>, name: &str, mount_point: &str) -> Result<()> {
+    // The test container confines us to its volumes, which are never
+    // in images
+    if test_container.is_none() && has_image(name) {
+        bail!(
+            "there is a disk image for {} at {} that doesn't hold its volume; \
+             run `eden_apfs_mount_helper delete {}` to remove it",
+            mount_point,
+            image_path(name)?.display(),
+            mount_point
+        );
+    }
+    Ok(())
+}
+
+/// Create the volume `name` in `container` for `mount_point`.  If it is
+/// encrypted, its passphrase is stored in the keychain of the user so
+/// that `mount` can unlock it from then on; nobody else knows it, so
+/// the volume is deleted again if it can't be stored.
+fn create_volume(
+    name: &str,
+    container: &str,
+    options: &VolumeOptions,
+    mount_point: &str,
+) -> Result<ApfsVolume> {
+    let mut volume = make_new_volume(name, container, options)?;
+    let passphrase = match &options.passphrase {
+        Some(passphrase) if !dry_run() => passphrase,
+        _ => return Ok(volume),
+    };
+    // The keychain item is named for the UUID of the volume, which
+    // creating it doesn't tell us
+    let stored = disk_info(&volume.device_identifier).and_then(|info| {
+        volume.uuid = info.volume_uuid;
+        store_passphrase(keychain_account(&volume), passphrase)
+    });
+    if let Err(err) = stored {
+        if let Err(delete_err) = delete_volume(&volume, mount_point) {
+            eprintln!(
+                "failed to delete {}, whose passphrase is lost: {:#}",
+                volume.device_identifier, delete_err
+            );
+        }
+        return Err(err.context(format!(
+            "storing the passphrase of the new encrypted volume for {}",
+            mount_point
+        )));
+    }
+    Ok(volume)
+}
+
+/// A passphrase supplied via the `unlock` command
+struct Unlock {
+    passphrase: String,
+    save_to_keychain: bool,
+}
+
+/// The keychain account for the passphrase of `volume`
+fn keychain_account(volume: &ApfsVolume) -> &str {
+    volume.uuid.as_deref().unwrap_or(&volume.device_identifier)
+}
+
+/// Unlock the locked `volume` so that it can be mounted, using the
+/// passphrase from `unlock` if we have one, or else the one stored in
+/// the keychain.
+fn unlock_for_mount(volume: &ApfsVolume, mount_point: &str, unlock: Option<&Unlock>) -> Result<()> {
+    let account = keychain_account(volume);
+    let passphrase = match unlock {
+        Some(unlock) => unlock.passphrase.clone(),
+        None => match find_passphrase(account)? {
+            Some(passphrase) => passphrase,
+            None => bail!(
+                "the volume for {} is locked and there is no passphrase for it in your \
+                 keychain; run `eden_apfs_mount_helper unlock {}` to unlock it",
+                mount_point,
+                mount_point
+            ),
+        },
+    };
+    unlock_volume(volume, &passphrase)?;
+
+    if let Some(Unlock {
+        save_to_keychain: true,
+        ..
+    }) = unlock
+    {
+        if let Err(err) = store_passphrase(account, &passphrase) {
+            eprintln!("{:#}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Read a passphrase from the terminal without echoing it, or from
+/// stdin if that isn't a terminal
+fn read_passphrase(prompt: &str) -> Result<String> {
+    use std::io::{BufRead, Write};
+
+    let stdin = std::io::stdin();
+    let fd = stdin.as_raw_fd();
+    let mut line = String::new();
+    if unsafe { libc::isatty(fd) } == 1 {
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("reading terminal settings");
+        }
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        eprint!("{}", prompt);
+        std::io::stderr().flush().ok();
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &quiet) };
+        let result = stdin.lock().read_line(&mut line);
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &saved) };
+        eprintln!();
+        result.context("reading the passphrase")?;
+    } else {
+        stdin
+            .lock()
+            .read_line(&mut line)
+            .context("reading the passphrase from stdin")?;
+    }
+    let passphrase = line.trim_end_matches(&['\r', '\n'][..]).to_owned();
+    if passphrase.is_empty() {
+        bail!("no passphrase was supplied");
+    }
+    Ok(passphrase)
+}
+
+/// The part of mounting a volume that happens after we have given up
+/// our privileges
+fn finish_mount(config: &Config, mount_point: &str) {
+    if dry_run() {
+        return;
+    }
+    if let Err(err) = notify_watchman(&config.watchman, mount_point) {
+        eprintln!("failed to notify watchman about {}: {:#}", mount_point, err);
+    }
+}
+
+/// Resolve `path` from a template to a location inside the volume
+/// mounted at `mount_point`.  Paths that could lead outside of the
+/// volume are rejected.
+fn template_path(mount_point: &str, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let escapes = relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || escapes {
+        bail!(
+            "template path {:?} must be relative to the root of the volume",
+            path
+        );
+    }
+    Ok(Path::new(mount_point).join(relative))
+}
+
+const XATTR: &str = "/usr/bin/xattr";
+
+/// Populate the new volume mounted at `mount_point` from `template`.
+/// Everything is created with the privs of the user, who owns the
+/// volume by now.
+fn apply_template(template: &VolumeTemplate, mount_point: &str) -> Result<()> {
+    with_real_user_privs(|| {
+        for dir in &template.directories {
+            let path = template_path(mount_point, dir)?;
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("creating {}", path.display()))?;
+        }
+        for file in &template.files {
+            let path = template_path(mount_point, file)?;
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&path)
+                .with_context(|| format!("creating {}", path.display()))?;
+        }
+        Ok(())
+    })?;
+    for xattr in &template.xattrs {
+        let path = template_path(mount_point, &xattr.path)?;
+        let output = new_cmd_unprivileged(XATTR)
+            .arg("-w")
+            .arg(&xattr.name)
+            .arg(&xattr.value)
+            .arg(&path)
+            .run()?;
+        if !output.status.success() {
+            bail!(
+                "failed to set {} on {}: {}",
+                xattr.name,
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Open the directory at `path` for use as a mount point.  The final
+/// component must not be a symlink.
+fn open_mount_point_dir(path: &str) -> Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_DIRECTORY)
+        .open(path)
+        .with_context(|| format!("opening mount point {}", path))
+}
+
+/// Open the root of `volume`, which we just mounted at `mount_point`,
+/// and verify that it is the root of that volume rather than whatever
+/// might have been put in its place since we mounted it.
+fn open_mounted_volume_root(mount_point: &str, volume: &ApfsVolume) -> Result<std::fs::File> {
+    let root = open_mount_point_dir(mount_point)?;
+    if fixtures_active() {
+        // Nothing was really mounted
+        return Ok(root);
+    }
+    let device = format!("/dev/{}", volume.device_identifier);
+    let device_metadata =
+        std::fs::metadata(&device).with_context(|| format!("inspecting {}", device))?;
+    if root.metadata()?.dev() != device_metadata.rdev() {
+        bail!(
+            "{} is not the root of {} after mounting it there",
+            mount_point,
+            device
+        );
+    }
+    Ok(root)
+}
+
+fn fchown(file: &std::fs::File, path: &str, uid: u32, gid: u32) -> Result<()> {
+    let rc = unsafe { libc::fchown(file.as_raw_fd(), uid, gid) };
+    if rc != 0 {
+        let err = std::io::Error::last_os_error();
+        Err(err).with_context(|| format!("failed to chown {} to uid={}, gid={}", path, uid, gid))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct WatchList {
+    roots: Vec<String>,
+}
+
+/// Mounting or unmounting a volume changes everything visible under
+/// `mount_point` without watchman observing the individual changes,
+/// which leaves any overlapping watch confused until it performs a full
+/// recrawl of its own accord.  Tell watchman about it according to the
+/// configured policy.
+/// This is a no-op if the user doesn't have a watchman server running;
+/// we pass `--no-spawn` so that we never start one on their behalf.
+fn notify_watchman(config: &WatchmanConfig, mount_point: &str) -> Result<()> {
+    let command = match config.mode {
+        WatchmanMode::Off => return Ok(()),
+        WatchmanMode::Recrawl => "debug-recrawl",
+        WatchmanMode::WatchDel => "watch-del",
+    };
+    if !Path::new(&config.path).is_absolute() {
+        bail!("the watchman path {} is not absolute", config.path);
+    }
+    if !Path::new(&config.path).exists() {
+        return Ok(());
+    }
+
+    let output = new_cmd_unprivileged(&config.path)
+        .args(&["--no-spawn", "--no-pretty", "watch-list"])
+        .run()?;
+    if !output.status.success() {
+        // Most likely there is no server running
+        return Ok(());
+    }
+    let list: WatchList =
+        serde_json::from_slice(&output.stdout).context("parsing watchman watch-list output")?;
+
+    let mount_point = Path::new(mount_point);
+    for root in list.roots {
+        let root_path = Path::new(&root);
+        if !root_path.starts_with(mount_point) && !mount_point.starts_with(root_path) {
+            continue;
+        }
+        let output = new_cmd_unprivileged(&config.path)
+            .args(&["--no-spawn", "--no-pretty", command, &root])
+            .run()?;
+        if !output.status.success() {
+            eprintln!(
+                "failed to execute watchman {} {}: {:#?}",
+                command, root, output
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The ways in which the registry, the APFS volumes and the mount table
+/// can disagree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiscrepancyKind {
+    /// The registry says that a volume exists, but APFS has no such volume
+    MissingVolume,
+    /// APFS has a volume that the registry doesn't know about, or that
+    /// the registry says was deleted
+    UnrecordedVolume,
+    /// Something other than our volume is mounted where one of our
+    /// volumes belongs
+    ForeignMount,
+}
+
+impl DiscrepancyKind {
+    fn name(self) -> &'static str {
+        match self {
+            DiscrepancyKind::MissingVolume => "registry-only",
+            DiscrepancyKind::UnrecordedVolume => "volume-only",
+            DiscrepancyKind::ForeignMount => "mount-only",
+        }
+    }
+
+    /// The remediation id reported by `health`
+    fn remediation(self) -> &'static str {
+        match self {
+            DiscrepancyKind::MissingVolume => "mount",
+            DiscrepancyKind::UnrecordedVolume => "mount-or-delete",
+            DiscrepancyKind::ForeignMount => "unmount-foreign",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Discrepancy {
+    mount_point: String,
+    kind: DiscrepancyKind,
+    detail: String,
+    /// What the user can do about it
+    fix: String,
+}
+
+/// Whether the registry expects the volume for `record` to exist, based
+/// on the last operation on it that succeeded.  `None` means that we
+/// don't know.
+fn registry_expects_volume(record: &MountRecord) -> Option<bool> {
+    let last = record.history.iter().rev().find(|entry| entry.ok)?;
+    match last.operation.as_str() {
+        "create" | "mount" | "unmount" => Some(true),
+        "delete" => Some(false),
+        _ => None,
+    }
+}
+
+/// Cross reference the registry, the APFS volumes and the mount table
+fn reconcile(
+    registry: &Registry,
+    containers: &[ApfsContainer],
+    mounts: &MountTable,
+) -> Vec<Discrepancy> {
+    let volumes: BTreeMap<String, &ApfsVolume> = containers
+        .iter()
+        .flat_map(|container| container.volumes.iter())
+        .filter_map(|vol| vol.preferred_mount_point().map(|path| (path, vol)))
+        .collect();
+    let mut discrepancies = vec![];
+
+    for (mount_point, record) in &registry.mounts {
+        if registry_expects_volume(record) == Some(true) && !volumes.contains_key(mount_point) {
+            discrepancies.push(Discrepancy {
+                mount_point: mount_point.clone(),
+                kind: DiscrepancyKind::MissingVolume,
+                detail: "the registry records a volume, but there is no such volume".to_owned(),
+                fix: format!(
+                    "run `eden_apfs_mount_helper mount {}` to create a new volume",
+                    mount_point
+                ),
+            });
+        }
+    }
+
+    for (mount_point, vol) in &volumes {
+        let expected = registry
+            .mounts
+            .get(mount_point)
+            .map(registry_expects_volume);
+        if let Some(Some(true)) | Some(None) = expected {
+            continue;
+        }
+        discrepancies.push(Discrepancy {
+            mount_point: mount_point.clone(),
+            kind: DiscrepancyKind::UnrecordedVolume,
+            detail: if expected.is_some() {
+                format!(
+                    "{} still exists although the registry records that it was deleted",
+                    vol.device_identifier
+                )
+            } else {
+                format!("{} is not recorded in the registry", vol.device_identifier)
+            },
+            fix: format!(
+                "run `eden_apfs_mount_helper mount {}` to use it, or \
+                 `eden_apfs_mount_helper delete {}` to remove it",
+                mount_point, mount_point
+            ),
+        });
+    }
+
+    let known: BTreeSet<&String> = registry.mounts.keys().chain(volumes.keys()).collect();
+    for entry in &mounts.entries {
+        if !known.contains(&entry.mount_point) {
+            continue;
+        }
+        let ours = volumes
+            .get(&entry.mount_point)
+            .map(|vol| entry.device == format!("/dev/{}", vol.device_identifier))
+            .unwrap_or(false);
+        if !ours {
+            discrepancies.push(Discrepancy {
+                mount_point: entry.mount_point.clone(),
+                kind: DiscrepancyKind::ForeignMount,
+                detail: format!("{} is mounted there instead of our volume", entry.device),
+                fix: format!(
+                    "unmount {} with `umount {}`",
+                    entry.device, entry.mount_point
+                ),
+            });
+        }
+    }
+    discrepancies
+}
+
+/// Check the health of the volumes managed by this tool, optionally
+/// repairing the problems that are safe to repair.
+/// Returns an error if any problem remains.
+fn run_doctor(config: &Config, fix: bool, format: OutputFormat) -> Result<()> {
+    let containers = apfs_list()?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let registry = Registry::load()?;
+    let mut problems = 0;
+    let mut summary = ChangeSummary::default();
+
+    // Problems that need a person to fix them; `health` reports these
+    // under the same ids
+    for check in installation_checks()?
+        .into_iter()
+        .chain(container_usage_checks(&containers, &config.thresholds))
+    {
+        if check.status == CheckStatus::Fail {
+            println!("{}: {}", check.id, check.message);
+            problems += 1;
+        }
+    }
+
+    for container in &containers {
+        for vol in &container.volumes {
+            if let Some(new_name) = vol.name.as_deref().and_then(migrated_volume_name) {
+                let name = vol.name.as_deref().unwrap_or("");
+                if fix {
+                    match rename_volume(vol, &new_name) {
+                        Ok(_) => {
+                            println!("{}: renamed to {}", name, new_name);
+                            summary
+                                .repaired
+                                .push(format!("{}: renamed to {}", name, new_name));
+                            continue;
+                        }
+                        Err(err) => {
+                            eprintln!("{}: {:#}", name, err);
+                            summary.errors.push(format!("{}: {:#}", name, err));
+                        }
+                    }
+                }
+                println!("{}: uses an outdated volume name encoding", name);
+                problems += 1;
+                continue;
+            }
+
+            let preferred = match vol.preferred_mount_point() {
+                Some(path) => path,
+                None => continue,
+            };
+            if let Some(service) = cloud_sync_service(Path::new(&preferred)) {
+                println!("{}: is inside a directory synced by {}", preferred, service);
+                problems += 1;
+            }
+            if let Some(problem) = mount_point_owner_problem(&preferred, &registry) {
+                println!("{}: {}", preferred, problem);
+                problems += 1;
+            }
+            // We can only inspect volumes that are mounted where they belong
+            if vol.get_current_mount_point(Some(&mounts)).as_ref() != Some(&preferred) {
+                continue;
+            }
+
+            if !is_excluded_from_backups(&preferred)? {
+                if fix {
+                    match exclude_from_backups(&preferred) {
+                        Ok(_) => {
+                            println!("{}: added the missing backup exclusion", preferred);
+                            summary
+                                .repaired
+                                .push(format!("{}: added the missing backup exclusion", preferred));
+                            continue;
+                        }
+                        Err(err) => {
+                            eprintln!("{}: {:#}", preferred, err);
+                            summary.errors.push(format!("{}: {:#}", preferred, err));
+                        }
+                    }
+                }
+                println!("{}: is not excluded from backups", preferred);
+                problems += 1;
+            }
+        }
+    }
+
+    // There is nothing to reconcile until we have started to record
+    // what we do in the registry
+    if !registry.mounts.is_empty() {
+        for discrepancy in reconcile(&registry, &containers, &mounts) {
+            println!(
+                "{}: {}: {}; {}",
+                discrepancy.mount_point,
+                discrepancy.kind.name(),
+                discrepancy.detail,
+                discrepancy.fix
+            );
+            problems += 1;
+        }
+    }
+
+    if fix {
+        print_change_summary(&summary, format)?;
+    }
+    if problems > 0 {
+        bail!(
+            "found {} problem(s){}",
+            problems,
+            if fix {
+                ""
+            } else {
+                "; run with --fix to repair"
+            }
+        );
+    }
+    println!("No problems found");
+    Ok(())
+}
+
+/// `S_ISUID`, whose type differs between platforms
+const SETUID_BIT: u32 = 0o4000;
+
+/// Describe what is wrong with the installation of an executable with
+/// these ownership and permissions, if anything
+fn setuid_problem(uid: u32, mode: u32) -> Option<String> {
+    if uid != 0 {
+        Some(format!(
+            "is owned by uid {} rather than root, so it can't mount volumes",
+            uid
+        ))
+    } else if mode & SETUID_BIT == 0 {
+        Some("doesn't have the setuid bit set, so it can't mount volumes".to_owned())
+    } else if mode & 0o022 != 0 {
+        Some("is writable by users other than root".to_owned())
+    } else {
+        None
+    }
+}
+
+/// Check that we are installed setuid root and that the tools that we
+/// run are present.  These describe the real system, so the fixture
+/// tests, which run an ordinary build with canned tool output, skip them.
+fn installation_checks() -> Result<Vec<HealthCheck>> {
+    let mut checks = vec![];
+    if fixtures_active() {
+        return Ok(checks);
+    }
+
+    let check = HealthCheck::new("setuid", "", Severity::Error);
+    checks.push(match std::env::current_exe().and_then(std::fs::metadata) {
+        Ok(metadata) => match setuid_problem(metadata.uid(), metadata.mode()) {
+            Some(problem) => check.fail(problem, Some("reinstall")),
+            None => check,
+        },
+        Err(err) => check.fail(format!("checking our executable: {}", err), None),
+    });
+
+    let paths = tool_paths()?;
+    for (tool, path) in &[
+        ("diskutil", &paths.diskutil),
+        ("mount_apfs", &paths.mount_apfs),
+        ("plutil", &paths.plutil),
+    ] {
+        let check = HealthCheck::new(&format!("tool:{}", tool), "", Severity::Error);
+        let executable = std::fs::metadata(path)
+            .map(|metadata| metadata.is_file() && metadata.mode() & 0o111 != 0)
+            .unwrap_or(false);
+        checks.push(if executable {
+            check
+        } else {
+            check.fail(
+                format!("{} is missing or isn't executable", path),
+                Some("check-tools"),
+            )
+        });
+    }
+    Ok(checks)
+}
+
+/// Check the space in use in each container that holds our volumes
+/// against `thresholds`
+fn container_usage_checks(
+    containers: &[ApfsContainer],
+    thresholds: &Thresholds,
+) -> Vec<HealthCheck> {
+    let mut checks = vec![];
+    for container in containers {
+        let ours = container
+            .volumes
+            .iter()
+            .any(|vol| vol.preferred_mount_point().is_some());
+        if !ours || container.capacity_ceiling == 0 {
+            continue;
+        }
+        let used = container
+            .capacity_ceiling
+            .saturating_sub(container.capacity_free);
+        let percent = used * 100 / container.capacity_ceiling;
+        let check = HealthCheck::new(
+            &format!("container-usage:{}", container.container_reference),
+            "",
+            Severity::Warning,
+        );
+        checks.push(if percent > thresholds.max_usage_percent {
+            check.fail(
+                format!(
+                    "{} is {}% full, which exceeds the limit of {}%",
+                    container.container_reference, percent, thresholds.max_usage_percent
+                ),
+                Some("gc"),
+            )
+        } else {
+            check
+        });
+    }
+    checks
+}
+
+/// Describe the problem if the mount point for a volume no longer
+/// belongs to the uid that created the volume; see `check_creator`
+fn mount_point_owner_problem(mount_point: &str, registry: &Registry) -> Option<String> {
+    let owner = std::fs::metadata(mount_point).ok()?.uid();
+    check_creator(mount_point, registry, owner)
+        .err()
+        .map(|err| format!("{:#}", err))
+}
+
+/// Run the non-mutating checks on the volumes managed by this tool
+fn health_checks(config: &Config) -> Result<Vec<HealthCheck>> {
+    let containers = apfs_list()?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let registry = Registry::load()?;
+    let mut checks = vec![];
+
+    for container in &containers {
+        for vol in &container.volumes {
+            let preferred = match vol.preferred_mount_point() {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let check = HealthCheck::new("last-operation", &preferred, Severity::Warning);
+            let last = registry
+                .mounts
+                .get(&preferred)
+                .and_then(|record| record.history.back());
+            checks.push(match last {
+                Some(entry) if !entry.ok => check.fail(
+                    format!(
+                        "the last {} failed: {}",
+                        entry.operation,
+                        entry.error.as_deref().unwrap_or("unknown error")
+                    ),
+                    None,
+                ),
+                _ => check,
+            });
+
+            let current = vol.get_current_mount_point(Some(&mounts));
+            let check = HealthCheck::new("misplaced", &preferred, Severity::Error);
+            match vol.misplaced_mount_point(Some(&mounts)) {
+                Some(misplaced) => {
+                    checks.push(check.fail(
+                        format!("is mounted at {}", misplaced),
+                        Some("fix-misplaced"),
+                    ));
+                    continue;
+                }
+                None => checks.push(check),
+            }
+
+            let check = HealthCheck::new("cloud-sync", &preferred, Severity::Warning);
+            checks.push(match cloud_sync_service(Path::new(&preferred)) {
+                Some(service) => {
+                    check.fail(format!("is inside a directory synced by {}", service), None)
+                }
+                None => check,
+            });
+
+            let check = HealthCheck::new("mount-point-owner", &preferred, Severity::Error);
+            checks.push(match mount_point_owner_problem(&preferred, &registry) {
+                Some(problem) => check.fail(problem, Some("adopt")),
+                None => check,
+            });
+
+            // The remaining checks can only inspect volumes that are
+            // mounted where they belong
+            if current.as_ref() != Some(&preferred) {
+                continue;
+            }
+
+            let check = HealthCheck::new("backup-exclusion", &preferred, Severity::Warning);
+            checks.push(if is_excluded_from_backups(&preferred)? {
+                check
+            } else {
+                check.fail(
+                    "is not excluded from backups".to_owned(),
+                    Some("doctor-fix"),
+                )
+            });
+
+            for kind in &config.exclusions {
+                let check = HealthCheck::new(
+                    &format!("exclusion:{}", kind.name()),
+                    &preferred,
+                    Severity::Warning,
+                );
+                checks.push(match kind.exclusion().is_applied(&preferred) {
+                    Ok(true) => check,
+                    Ok(false) => check.fail(
+                        format!("the {} exclusion is not applied", kind.name()),
+                        Some("remount"),
+                    ),
+                    Err(err) => check.fail(
+                        format!("checking the {} exclusion: {:#}", kind.name(), err),
+                        None,
+                    ),
+                });
+            }
+        }
+    }
+
+    checks.extend(installation_checks()?);
+    checks.extend(container_usage_checks(&containers, &config.thresholds));
+
+    let fallbacks = plist_fallbacks();
+    if fallbacks > 0 {
+        let check = HealthCheck::new("plist-fallback", "", Severity::Warning);
+        checks.push(check.fail(
+            format!(
+                "the native parser rejected {} plist(s) from diskutil; its output format may have changed",
+                fallbacks
+            ),
+            None,
+        ));
+    }
+
+    if !registry.mounts.is_empty() {
+        for discrepancy in reconcile(&registry, &containers, &mounts) {
+            let check = HealthCheck::new(
+                &format!("reconcile:{}", discrepancy.kind.name()),
+                &discrepancy.mount_point,
+                Severity::Warning,
+            );
+            checks.push(check.fail(discrepancy.detail, Some(discrepancy.kind.remediation())));
+        }
+    }
+    Ok(checks)
+}
+
+fn show_health(config: &Config, format: OutputFormat, check: bool) -> Result<()> {
+    let checks = health_checks(config)?;
+    let status = if check {
+        let failed = checks.iter().any(|check| check.status == CheckStatus::Fail);
+        let (status, violations) = check_thresholds(&collect_stats()?, &config.thresholds);
+        for violation in violations {
+            eprintln!("{}", violation);
+        }
+        status | if failed { CHECK_HEALTH } else { 0 }
+    } else {
+        0
+    };
+    if format == OutputFormat::Table {
+        print_health_checks(&checks);
+    } else {
+        print_report(&HealthReport { checks }, format)?;
+    }
+    exit_with_check_status(status);
+    Ok(())
+}
+
+fn print_health_checks(checks: &[HealthCheck]) {
+    for check in checks {
+        let status = match check.status {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Fail => "FAIL",
+        };
+        if check.message.is_empty() {
+            println!("{}\t{}\t{}", status, check.id, check.mount_point);
+        } else {
+            println!(
+                "{}\t{}\t{}\t{}",
+                status, check.id, check.mount_point, check.message
+            );
+        }
+    }
+}
+
+/// The exit statuses of `stats --check` and `health --check`.  These are
+/// bits that are combined when several categories need attention.
+const CHECK_USAGE: i32 = 2;
+const CHECK_ORPHANS: i32 = 4;
+const CHECK_FAILED_MOUNTS: i32 = 8;
+const CHECK_HEALTH: i32 = 16;
+
+/// The figures that `stats` reports
+#[derive(Debug, Default)]
+struct Stats {
+    /// The percentage of each container that holds our volumes that
+    /// is in use.  Purgeable data counts as being in use, as macOS
+    /// only frees it under pressure.
+    usage: Vec<(String, u64)>,
+    /// The free space in each of those containers, followed by the
+    /// space that would be free after purging any purgeable data
+    free: Vec<(String, u64, u64)>,
+    /// The mount points of our volumes whose parent directory no longer
+    /// exists, typically because the repo was removed
+    orphans: Vec<String>,
+    /// The mount points whose most recent mount failed
+    failed_mounts: Vec<String>,
+}
+
+fn collect_stats() -> Result<Stats> {
+    let mut stats = Stats::default();
+    let mounts = MountTable::parse_system_mount_table()?;
+    for container in apfs_list()? {
+        let ours: Vec<String> = container
+            .volumes
+            .iter()
+            .filter_map(ApfsVolume::preferred_mount_point)
+            .collect();
+        if ours.is_empty() {
+            continue;
+        }
+        if container.capacity_ceiling > 0 {
+            let used = container
+                .capacity_ceiling
+                .saturating_sub(container.capacity_free);
+            stats.usage.push((
+                container.container_reference.clone(),
+                used * 100 / container.capacity_ceiling,
+            ));
+        }
+        let purgeable = container_purgeable_space(&container, &mounts)?;
+        stats.free.push((
+            container.container_reference.clone(),
+            container.capacity_free,
+            container.capacity_free.saturating_add(purgeable),
+        ));
+        for mount_point in ours {
+            let parent_exists = Path::new(&mount_point)
+                .parent()
+                .map(Path::exists)
+                .unwrap_or(false);
+            if !parent_exists {
+                stats.orphans.push(mount_point);
+            }
+        }
+    }
+
+    for (mount_point, record) in Registry::load()?.mounts {
+        let last_mount = record
+            .history
+            .iter()
+            .rev()
+            .find(|entry| entry.operation == "mount");
+        if let Some(entry) = last_mount {
+            if !entry.ok {
+                stats.failed_mounts.push(mount_point);
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Compare `stats` with `thresholds`, returning the `--check` exit status
+/// along with a description of each violation
+fn check_thresholds(stats: &Stats, thresholds: &Thresholds) -> (i32, Vec<String>) {
+    let mut status = 0;
+    let mut violations = vec![];
+    for (container, percent) in &stats.usage {
+        if *percent > thresholds.max_usage_percent {
+            status |= CHECK_USAGE;
+            violations.push(format!(
+                "{} is {}% full, which exceeds the limit of {}%",
+                container, percent, thresholds.max_usage_percent
+            ));
+        }
+    }
+    if stats.orphans.len() > thresholds.max_orphans {
+        status |= CHECK_ORPHANS;
+        violations.push(format!(
+            "there are {} orphaned volume(s), which exceeds the limit of {}",
+            stats.orphans.len(),
+            thresholds.max_orphans
+        ));
+    }
+    if stats.failed_mounts.len() > thresholds.max_failed_mounts {
+        status |= CHECK_FAILED_MOUNTS;
+        violations.push(format!(
+            "the last mount failed for {} mount point(s), which exceeds the limit of {}",
+            stats.failed_mounts.len(),
+            thresholds.max_failed_mounts
+        ));
+    }
+    (status, violations)
+}
+
+/// Exit with `status` if it is non-zero
+fn exit_with_check_status(status: i32) {
+    if status != 0 {
+        transcript::record_outcome(&format!("exit status {}", status));
+        std::io::Write::flush(&mut std::io::stdout()).ok();
+        std::process::exit(status);
+    }
+}
+
+/// The growth of the space in use, projected from the usage samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Forecast {
+    /// The average growth in bytes per day, which is negative if the
+    /// space in use is shrinking
+    bytes_per_day: i64,
+    /// The number of days until the space in use reaches the limit at
+    /// that rate, or None if it isn't growing
+    days_left: Option<u64>,
+}
+
+const SECONDS_PER_DAY: i128 = 24 * 60 * 60;
+
+/// Project when the space in use will reach `limit`, from the average
+/// growth between the oldest and the most recent of `samples`
+fn forecast(samples: &VecDeque<UsageSample>, limit: u64) -> Option<Forecast> {
+    let (first, last) = (samples.front()?, samples.back()?);
+    if last.time <= first.time {
+        return None;
+    }
+    let growth = last.bytes as i128 - first.bytes as i128;
+    let bytes_per_day = growth * SECONDS_PER_DAY / (last.time - first.time) as i128;
+    let days_left = if bytes_per_day > 0 {
+        Some((limit.saturating_sub(last.bytes) as i128 / bytes_per_day) as u64)
+    } else {
+        None
+    };
+    Some(Forecast {
+        bytes_per_day: bytes_per_day as i64,
+        days_left,
+    })
+}
+
+/// Record the space in use in each of our volumes and the containers
+/// that hold them.  This requires root privs.
+fn record_usage(containers: &[ApfsContainer]) -> Result<()> {
+    let time = unix_time();
+    Registry::update(|registry| {
+        for container in containers {
+            let mut ours = false;
+            for vol in &container.volumes {
+                if let Some(mount_point) = vol.preferred_mount_point() {
+                    ours = true;
+                    let record = registry.mounts.entry(mount_point).or_default();
+                    let sample = UsageSample {
+                        time,
+                        bytes: vol.capacity_in_use,
+                    };
+                    push_usage(&mut record.usage, sample);
+                }
+            }
+            if ours {
+                let samples = registry
+                    .containers
+                    .entry(container.container_reference.clone())
+                    .or_default();
+                let sample = UsageSample {
+                    time,
+                    bytes: container
+                        .capacity_ceiling
+                        .saturating_sub(container.capacity_free),
+                };
+                push_usage(samples, sample);
+            }
+        }
+    })
+}
+
+fn show_forecasts() -> Result<()> {
+    let registry = Registry::load()?;
+    let print = |name: &str, limit_kind: &str, forecast: Option<Forecast>| {
+        if let Some(forecast) = forecast {
+            println!(
+                "forecast\t{}\t{}\t{}\t{}",
+                name,
+                limit_kind,
+                forecast.bytes_per_day,
+                forecast
+                    .days_left
+                    .map(|days| days.to_string())
+                    .unwrap_or_default()
+            );
+        }
+    };
+    for container in apfs_list()? {
+        if let Some(samples) = registry.containers.get(&container.container_reference) {
+            print(
+                &container.container_reference,
+                "capacity",
+                forecast(samples, container.capacity_ceiling),
+            );
+        }
+        for vol in &container.volumes {
+            if vol.capacity_quota == 0 {
+                continue;
+            }
+            let mount_point = match vol.preferred_mount_point() {
+                Some(mount_point) => mount_point,
+                None => continue,
+            };
+            if let Some(record) = registry.mounts.get(&mount_point) {
+                print(
+                    &mount_point,
+                    "quota",
+                    forecast(&record.usage, vol.capacity_quota),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Report the size of the files on each of our volumes that is mounted
+/// where it belongs, and the space that they take
+fn show_compression() -> Result<()> {
+    let mounts = MountTable::parse_system_mount_table()?;
+    for container in apfs_list()? {
+        for vol in &container.volumes {
+            let mount_point = match vol.preferred_mount_point() {
+                Some(mount_point) => mount_point,
+                None => continue,
+            };
+            if vol.get_current_mount_point(Some(&mounts)).as_ref() != Some(&mount_point) {
+                continue;
+            }
+            let device = match std::fs::metadata(&mount_point) {
+                Ok(metadata) => metadata.dev(),
+                Err(_) => continue,
+            };
+            let mut usage = FileUsage::default();
+            file_usage(Path::new(&mount_point), device, &mut usage);
+            println!(
+                "compression\t{}\t{}\t{}\t{}",
+                mount_point,
+                usage.size,
+                usage.allocated,
+                compression_ratio(usage.size, usage.allocated)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The size of the files in a tree and the space that they take
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct FileUsage {
+    size: u64,
+    allocated: u64,
+}
+
+/// Add up the size of the regular files under `path`, and the space that
+/// they take, without following symlinks or crossing into other
+/// filesystems.  As with `disk_usage`, anything unreadable is skipped.
+fn file_usage(path: &Path, device: u64, usage: &mut FileUsage) {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) if metadata.dev() == device => metadata,
+        _ => return,
+    };
+    if metadata.is_file() {
+        usage.size += metadata.len();
+        usage.allocated += metadata.blocks() * 512;
+    } else if metadata.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for entry in entries.flatten() {
+                file_usage(&entry.path(), device, usage);
+            }
+        }
+    }
+}
+
+/// How many times larger the files are than the space they take, eg:
+/// `2.50` when compression saves 60%
+fn compression_ratio(size: u64, allocated: u64) -> String {
+    if allocated == 0 {
+        return "-".to_owned();
+    }
+    format!("{:.2}", size as f64 / allocated as f64)
+}
+
+fn show_stats(
+    config: &Config,
+    check: bool,
+    forecast: bool,
+    compression: bool,
+    latency: bool,
+) -> Result<()> {
+    let stats = collect_stats()?;
+    for (container, percent) in &stats.usage {
+        println!("usage\t{}\t{}%", container, percent);
+    }
+    for (container, free, after_purge) in &stats.free {
+        println!("free\t{}\t{}", container, free);
+        println!("free-after-purge\t{}\t{}", container, after_purge);
+    }
+    println!("orphans\t{}", stats.orphans.len());
+    for mount_point in &stats.orphans {
+        println!("orphan\t{}", mount_point);
+    }
+    println!("failed-mounts\t{}", stats.failed_mounts.len());
+    for mount_point in &stats.failed_mounts {
+        println!("failed-mount\t{}", mount_point);
+    }
+    if forecast {
+        show_forecasts()?;
+    }
+    if compression {
+        show_compression()?;
+    }
+    if latency {
+        show_latency(&latency_report(&Registry::load()?));
+    }
+
+    if check {
+        let (status, violations) = check_thresholds(&stats, &config.thresholds);
+        for violation in violations {
+            eprintln!("{}", violation);
+        }
+        exit_with_check_status(status);
+    }
+    Ok(())
+}
+
+/// Summarize the latency histograms in `registry`, which cover the last
+/// `LATENCY_DAYS` days, along with the latencies yet to be folded into them
+fn latency_report(registry: &Registry) -> LatencyReport {
+    let mut merged: BTreeMap<&str, LatencyHistogram> = BTreeMap::new();
+    for (operation, histograms) in &registry.latency {
+        let total = merged.entry(operation).or_default();
+        for histogram in histograms.iter().rev().take(LATENCY_DAYS) {
+            total.merge(histogram);
+        }
+    }
+    for (operation, pending) in &registry.pending_latency {
+        let total = merged.entry(operation).or_default();
+        for millis in pending {
+            total.add(*millis);
+        }
+    }
+    LatencyReport {
+        operations: merged
+            .into_iter()
+            .map(|(operation, histogram)| OperationLatency {
+                operation: operation.to_owned(),
+                samples: histogram.samples(),
+                p50_ms: histogram.percentile(50),
+                p95_ms: histogram.percentile(95),
+                p99_ms: histogram.percentile(99),
+            })
+            .collect(),
+    }
+}
+
+fn show_latency(report: &LatencyReport) {
+    let format_ms = |ms: Option<u64>| match ms {
+        Some(ms) => ms.to_string(),
+        None => format!(">{}", LATENCY_BUCKETS_MS[LATENCY_BUCKETS_MS.len() - 1]),
+    };
+    for operation in &report.operations {
+        println!(
+            "latency\t{}\t{}\t{}\t{}\t{}",
+            operation.operation,
+            operation.samples,
+            format_ms(operation.p50_ms),
+            format_ms(operation.p95_ms),
+            format_ms(operation.p99_ms)
+        );
+    }
+}
+
+/// Print the space in use in each of our volumes, and the free space in
+/// the containers that hold them, every `interval` until interrupted or
+/// until `count` refreshes have been printed.  On a terminal the screen
+/// is cleared before each refresh; otherwise they are separated by a
+/// blank line.
+fn watch_stats(interval: Duration, count: Option<u64>) -> Result<()> {
+    if interval.as_secs() == 0 {
+        bail!("the --watch interval must be at least 1 second");
+    }
+    let terminal = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    let mut previous: BTreeMap<String, u64> = BTreeMap::new();
+    let mut refreshes = 0;
+    loop {
+        let containers = apfs_list()?;
+        if terminal {
+            print!("\x1b[H\x1b[2J");
+        } else if refreshes > 0 {
+            println!();
+        }
+        let mut current = BTreeMap::new();
+        for container in &containers {
+            let ours: Vec<(&ApfsVolume, String)> = container
+                .volumes
+                .iter()
+                .filter_map(|vol| Some((vol, vol.preferred_mount_point()?)))
+                .collect();
+            if ours.is_empty() {
+                continue;
+            }
+            println!(
+                "free\t{}\t{}",
+                container.container_reference, container.capacity_free
+            );
+            for (vol, mount_point) in ours {
+                let change = previous
+                    .get(&vol.device_identifier)
+                    .map(|&before| vol.capacity_in_use as i128 - before as i128)
+                    .unwrap_or(0);
+                println!(
+                    "volume\t{}\t{}\t{:+}\t{}",
+                    vol.device_identifier, vol.capacity_in_use, change, mount_point
+                );
+                current.insert(vol.device_identifier.clone(), vol.capacity_in_use);
+            }
+        }
+        std::io::Write::flush(&mut std::io::stdout())?;
+        refreshes += 1;
+        if count.is_some_and(|count| refreshes >= count) {
+            return Ok(());
+        }
+        previous = current;
+        std::thread::sleep(interval);
+    }
+}
+
+/// Sum the space allocated to `path` and everything under it, without
+/// following symlinks or crossing into other filesystems.  Anything that
+/// we can't read is skipped, as the result only serves to guide a cleanup.
+fn disk_usage(path: &Path, device: u64) -> u64 {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) if metadata.dev() == device => metadata,
+        _ => return 0,
+    };
+    let mut bytes = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for entry in entries.flatten() {
+                bytes += disk_usage(&entry.path(), device);
+            }
+        }
+    }
+    bytes
+}
+
+/// Return the `count` largest entries at the top of the volume mounted
+/// at `mount_point`, largest first
+fn largest_entries(mount_point: &str, count: usize) -> Result<Vec<EntryUsage>> {
+    let device = std::fs::metadata(mount_point)
+        .with_context(|| format!("inspecting {}", mount_point))?
+        .dev();
+    let mut entries = vec![];
+    for entry in
+        std::fs::read_dir(mount_point).with_context(|| format!("listing {}", mount_point))?
+    {
+        let path = entry?.path();
+        entries.push(EntryUsage {
+            bytes: disk_usage(&path, device),
+            path: path.to_string_lossy().into_owned(),
+        });
+    }
+    entries.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+    entries.truncate(count);
+    Ok(entries)
+}
+
+const DITTO: &str = "/usr/bin/ditto";
+
+/// Files smaller than this gain too little from compression to be worth
+/// rewriting
+const MIN_COMPRESS_SIZE: u64 = 16 * 1024;
+
+/// The suffix of the copy that we compress a file into before replacing
+/// the original with it
+const COMPRESS_SUFFIX: &str = ".eden-compress";
+
+/// Whether a file is worth compressing.  It must be big enough to gain
+/// something and must take at least its size, which compressed and sparse
+/// files don't.  It must have no other hard links, which replacing it
+/// would break, and must not have been read or written since `cutoff`.
+fn should_compress(size: u64, allocated: u64, links: u64, last_used: i64, cutoff: i64) -> bool {
+    size >= MIN_COMPRESS_SIZE && allocated >= size && links == 1 && last_used < cutoff
+}
+
+/// Replace the file at `path` with a compressed copy, unless it changes
+/// while we are making the copy.  Returns the space that it now takes.
+fn compress_file(path: &Path, metadata: &std::fs::Metadata) -> Result<u64> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(COMPRESS_SUFFIX);
+    let temp = PathBuf::from(temp);
+    std::fs::remove_file(&temp).ok();
+    let output = new_cmd_unprivileged(DITTO)
+        .arg("--hfsCompression")
+        .arg(path)
+        .arg(&temp)
+        .run()?;
+    if !output.status.success() {
+        std::fs::remove_file(&temp).ok();
+        bail!(
+            "failed to compress {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let current = path
+        .symlink_metadata()
+        .with_context(|| format!("inspecting {}", path.display()))?;
+    if current.len() != metadata.len()
+        || current.mtime() != metadata.mtime()
+        || current.mtime_nsec() != metadata.mtime_nsec()
+    {
+        std::fs::remove_file(&temp).ok();
+        bail!("{} changed while it was being compressed", path.display());
+    }
+    std::fs::rename(&temp, path).with_context(|| format!("replacing {}", path.display()))?;
+    Ok(path.symlink_metadata()?.blocks() * 512)
+}
+
+/// What `compress` did
+#[derive(Debug, Default)]
+struct CompressionTotals {
+    files: u64,
+    /// The space that the compressed files took before and after
+    before: u64,
+    after: u64,
+    failures: u64,
+}
+
+/// Compress the cold files under `path`, which is on `device`
+fn compress_tree(path: &Path, device: u64, cutoff: i64, totals: &mut CompressionTotals) {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) if metadata.dev() == device => metadata,
+        _ => return,
+    };
+    if metadata.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            for entry in entries.flatten() {
+                compress_tree(&entry.path(), device, cutoff, totals);
+            }
+        }
+        return;
+    }
+    let last_used = metadata.atime().max(metadata.mtime());
+    let allocated = metadata.blocks() * 512;
+    if !metadata.is_file()
+        || path.to_string_lossy().ends_with(COMPRESS_SUFFIX)
+        || !should_compress(
+            metadata.len(),
+            allocated,
+            metadata.nlink(),
+            last_used,
+            cutoff,
+        )
+    {
+        return;
+    }
+    match compress_file(path, &metadata) {
+        Ok(after) => {
+            totals.files += 1;
+            totals.before += allocated;
+            totals.after += after;
+        }
+        Err(err) => {
+            eprintln!("{:#}", err);
+            totals.failures += 1;
+        }
+    }
+}
+
+/// Compress the files on the volume mounted at `mount_point` that haven't
+/// been used for `older_than`
+fn compress_scratch(mount_point: &str, older_than: Duration) -> Result<()> {
+    let mounts = MountTable::parse_system_mount_table()?;
+    let (_, mount_point) = find_volume_for_mount_point(mount_point, &mounts)?;
+    if !mounts
+        .entries
+        .iter()
+        .any(|entry| entry.mount_point == mount_point)
+    {
+        bail!("the volume for {} isn't mounted there", mount_point);
+    }
+    let device = std::fs::metadata(&mount_point)
+        .with_context(|| format!("inspecting {}", mount_point))?
+        .dev();
+    let cutoff = unix_time().saturating_sub(older_than.as_secs()) as i64;
+    let mut totals = CompressionTotals::default();
+    compress_tree(Path::new(&mount_point), device, cutoff, &mut totals);
+    println!(
+        "Compressed {} file(s) on {}, which took {} bytes and now take {} bytes",
+        totals.files, mount_point, totals.before, totals.after
+    );
+    if totals.failures > 0 {
+        bail!("failed to compress {} file(s)", totals.failures);
+    }
+    Ok(())
+}
+
+fn resize_preflight(
+    mount_point: &str,
+    quota: u64,
+    largest: usize,
+    format: OutputFormat,
+) -> Result<()> {
+    let (volume, mount_point) = find_listed_volume(mount_point)?;
+    let excess = if quota == 0 {
+        0
+    } else {
+        volume.capacity_in_use.saturating_sub(quota)
+    };
+    let mut report = ResizePreflight {
+        mount_point,
+        capacity_in_use: volume.capacity_in_use,
+        quota,
+        excess,
+        largest: vec![],
+    };
+    if largest > 0 && excess > 0 {
+        let mounts = MountTable::parse_system_mount_table()?;
+        match volume.get_current_mount_point(Some(&mounts)) {
+            Some(current) => report.largest = largest_entries(&current, largest)?,
+            None => eprintln!(
+                "{} is not mounted, so its largest entries can't be listed",
+                report.mount_point
+            ),
+        }
+    }
+
+    if format == OutputFormat::Table {
+        println!("in-use\t{}", report.capacity_in_use);
+        println!("quota\t{}", report.quota);
+        println!("excess\t{}", report.excess);
+        for entry in &report.largest {
+            println!("largest\t{}\t{}", entry.bytes, entry.path);
+        }
+    } else {
+        print_report(&report, format)?;
+    }
+    if excess > 0 {
+        bail!(
+            "the volume for {} holds {} bytes, so at least {} bytes must be \
+             deleted before its quota can be set to {} bytes",
+            report.mount_point,
+            report.capacity_in_use,
+            excess,
+            quota
+        );
+    }
+    Ok(())
+}
+
+fn check_space(mount_point: &str, need: u64, min_free: u64, format: OutputFormat) -> Result<()> {
+    let (mount_point, name) = resolve_volume_name(mount_point);
+    let available = apfs_list()?
+        .iter()
+        .find_map(|container| {
+            container
+                .volumes
+                .iter()
+                .find(|vol| vol.name.as_deref() == Some(name.as_str()))
+                .map(|vol| vol.available_space(container.capacity_free))
+        })
+        .ok_or_else(|| anyhow!("Did not find a volume named {}", name))?;
+    let report = SpaceCheck {
+        mount_point,
+        available,
+        need,
+        min_free,
+        fits: available >= need.saturating_add(min_free),
+    };
+
+    if format == OutputFormat::Table {
+        println!("available\t{}", report.available);
+        println!("need\t{}", report.need);
+        println!("min-free\t{}", report.min_free);
+    } else {
+        print_report(&report, format)?;
+    }
+    if !report.fits {
+        bail!(
+            "the volume for {} has {} bytes available, which isn't enough \
+             for {} bytes while leaving {} bytes free",
+            report.mount_point,
+            report.available,
+            need,
+            min_free
+        );
+    }
+    Ok(())
+}
+
+fn set_quota(mount_point: &str, quota: u64) -> Result<()> {
+    let (volume, mount_point) = find_listed_volume(mount_point)?;
+    let my_uid = get_real_uid()?;
+    if !volume_belongs_to(&mount_point, &Registry::load()?, my_uid) {
+        bail!(
+            "Refusing to change the quota of the volume for {} because it doesn't belong to uid {}",
+            mount_point,
+            my_uid
+        );
+    }
+    if quota != 0 && volume.capacity_in_use > quota {
+        bail!(
+            "the volume for {} holds {} bytes, which doesn't fit within {} bytes; \
+             run `resize-preflight {} {} --largest 10` to see what to delete",
+            mount_point,
+            volume.capacity_in_use,
+            quota,
+            mount_point,
+            quota
+        );
+    }
+
+    let result = set_volume_quota(&volume, quota);
+    record_history(&mount_point, "set-quota", &result);
+    result?;
+    if quota == 0 {
+        println!("Removed the quota of {}", mount_point);
+    } else {
+        println!("Set the quota of {} to {} bytes", mount_point, quota);
+    }
+    Ok(())
+}
+
+/// Create the volume for `mount_point` as `new_volume` says, but leave it
+/// unmounted.  There is nothing to do if the volume already exists, so
+/// that image builds can be re-run.
+fn prepare_volume(
+    config: &Config,
+    test_container: Option<&str>,
+    new_volume: &NewVolume,
+    mount_point: &str,
+    summary: &mut ChangeSummary,
+) -> Result<()> {
+    if get_real_uid()? != 0 {
+        bail!("prepare is for building machine images, and must be run as root");
+    }
+    if new_volume.encrypted {
+        bail!(
+            "prepare can't create encrypted volumes, as their passphrase would only be \
+             in the keychain of root; pass --encrypted to the first mount instead"
+        );
+    }
+    let containers = list_containers(test_container)?;
+    let name = encode_mount_point_as_volume_name(mount_point);
+    if find_existing_volume(&containers, &name).is_some() {
+        new_volume.warn_unused(mount_point);
+        println!("There is already a volume for {}", mount_point);
+        return Ok(());
+    }
+    check_no_image(test_container, &name, mount_point)?;
+    let result = new_volume.options().and_then(|options| {
+        match new_volume.backend(config, test_container)? {
+            Backend::Apfs => {}
+            Backend::DiskImage => {
+                return make_image_volume(&name, &options, config.disk_image.size);
+            }
+            Backend::RamDisk => bail!(
+                "prepare can't create volumes on RAM disks, which don't last until \
+                 the image is booted"
+            ),
+        }
+        let container = new_volume_container(
+            &containers,
+            test_container,
+            new_volume.container.as_deref(),
+            config.placement,
+            mount_point,
+        )?;
+        make_new_volume(&name, &container, &options)
+    });
+    record_history(mount_point, "prepare", &result);
+    result?;
+    // Forget any RAM disk that an earlier volume was on
+    record_ram_disk(mount_point, None);
+    summary.created.push(mount_point.to_owned());
+    println!("Prepared a volume for {}", mount_point);
+    Ok(())
+}
+
+/// Unmount the volume mounted at (or belonging at) `mount_point`,
+/// returning the location that it was unmounted from.  The caller
+/// should pass that to `finish_unmount` once it has dropped privileges.
+fn unmount_scratch(mount_point: &str, force: bool, mount_table: &MountTable) -> Result<String> {
+    let result = find_and_unmount(mount_point, force, mount_table);
+    record_history(&history_key(mount_point), "unmount", &result);
+    result
+}
+
+/// Unmount the volume for `mount_point` as `unmount_scratch` does, and
+/// detach the RAM disk that it is on, if any, for the commands that
+/// are done with the volume rather than moving it
+fn unmount_and_release(mount_point: &str, force: bool, mount_table: &MountTable) -> Result<String> {
+    let unmounted = unmount_scratch(mount_point, force, mount_table)?;
+    let key = history_key(mount_point);
+    let ram_disk = Registry::load()?
+        .mounts
+        .get(&key)
+        .and_then(|record| record.ram_disk.clone());
+    if let Some(disk) = ram_disk {
+        let name = encode_mount_point_as_volume_name(&key);
+        if is_on_ram_disk(&apfs_list()?, &name, &disk) {
+            detach_ram_disk(&disk)?;
+        } else {
+            eprintln!(
+                "forgetting the RAM disk {} recorded for {}, which no longer holds its volume",
+                disk, key
+            );
+        }
+        record_ram_disk(&key, None);
+    }
+    Ok(unmounted)
+}
+
+fn find_and_unmount(mount_point: &str, force: bool, mount_table: &MountTable) -> Result<String> {
+    // The common case is that the volume is mounted where it belongs,
+    // which we can resolve without listing every volume on the system
+    if let Some(volume) = find_mounted_volume(mount_point, mount_table) {
+        if volume.is_edenfs_managed_volume() {
+            unmount_volume(&volume, mount_point, force)?;
+            return Ok(mount_point.to_owned());
+        }
+    }
+
+    let containers = apfs_list()?;
+
+    let mut unmounted_volume = false;
+    for container in containers {
+        for volume in &container.volumes {
+            let preferred = match volume.preferred_mount_point() {
+                Some(path) => path,
+                None => continue,
+            };
+
+            match volume.get_current_mount_point(Some(mount_table)) {
+                Some(current_mount) => {
+                    if current_mount == mount_point || mount_point == preferred {
+                        unmount_volume(volume, &current_mount, force)?;
+                        return Ok(current_mount);
+                    }
+                }
+                None => unmounted_volume |= mount_point == preferred,
+            }
+        }
+    }
+    if unmounted_volume {
+        bail!("The volume for {} is not mounted", mount_point);
+    }
+    Err(NoVolume::new(
+        format!("Did not find a volume mounted on {}", mount_point),
+        mount_point,
+    )?
+    .into())
+}
+
+/// Why there is no volume for a mount point, which is also the exit
+/// status of the commands that need one, so that edenfs can tell
+/// whether to provision the volume or to raise the alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Absence {
+    /// We have never had a volume for the mount point, or deleted it
+    /// when asked to
+    NeverManaged = 3,
+    /// We had a volume for the mount point, but something else has
+    /// deleted it
+    Missing = 4,
+}
+
+impl Absence {
+    /// Work out why the volume for `record` is gone, in the same way as
+    /// `doctor`
+    fn from_record(record: Option<&MountRecord>) -> Self {
+        match record.and_then(registry_expects_volume) {
+            Some(true) => Absence::Missing,
+            _ => Absence::NeverManaged,
+        }
+    }
+}
+
+/// The error for a mount point without a volume; `main` exits with the
+/// status of its `absence`
+#[derive(Debug)]
+struct NoVolume {
+    message: String,
+    mount_point: String,
+    absence: Absence,
+}
+
+impl NoVolume {
+    fn new(message: String, mount_point: &str) -> Result<Self> {
+        let registry = Registry::load()?;
+        let absence = Absence::from_record(registry.mounts.get(&history_key(mount_point)));
+        Ok(NoVolume {
+            message,
+            mount_point: mount_point.to_owned(),
+            absence,
+        })
+    }
+}
+
+impl std::fmt::Display for NoVolume {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.absence {
+            Absence::NeverManaged => write!(
+                fmt,
+                "{}; there has never been a volume for {}",
+                self.message, self.mount_point
+            ),
+            Absence::Missing => write!(
+                fmt,
+                "{}; there was a volume for {}, but something else has deleted it",
+                self.message, self.mount_point
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NoVolume {}
+
+/// The part of unmounting a volume that happens after we have given up
+/// our privileges
+fn finish_unmount(config: &Config, mount_point: &str) {
+    if dry_run() {
+        return;
+    }
+    if let Err(err) = notify_watchman(&config.watchman, mount_point) {
+        eprintln!("failed to notify watchman about {}: {:#}", mount_point, err);
+    }
+}
+
+/// System daemons that briefly hold volumes open, preventing them from
+/// being unmounted.  These let go once they are done with what they
+/// were doing, so we give them a moment.
+const QUIESCABLE_DAEMONS: &[&str] = &[
+    "mds",
+    "mds_stores",
+    "mdworker",
+    "mdworker_shared",
+    "fseventsd",
+];
+
+/// How many times, and how often, we retry an unmount while they let go
+const QUIESCE_ATTEMPTS: usize = 6;
+const QUIESCE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+const LSOF: &str = "/usr/sbin/lsof";
+
+/// Returns the names of the processes that have files open on the
+/// filesystem mounted at `mount_point`.  This needs root privs to
+/// be able to see processes belonging to other users.
+fn volume_holders(mount_point: &str) -> Result<Vec<String>> {
+    let output = new_cmd_with_root_privs(LSOF)
+        .args(&["-F", "c", "+f", "--", mount_point])
+        .run()?;
+    // lsof exits with 1 when nothing is open.  If we can't make sense of
+    // its output then we can't name the holders, but the unmount failure
+    // is still reported.
+    match parse_lsof_commands(&String::from_utf8_lossy(&output.stdout)) {
+        Ok(commands) => Ok(commands),
+        Err(err) => {
+            eprintln!("unable to tell what is using {}: {:#}", mount_point, err);
+            Ok(vec![])
+        }
+    }
+}
+
+fn run_diskutil_unmount(volume: &ApfsVolume, force: bool) -> Result<std::process::Output> {
+    let mut cmd = new_cmd_unprivileged(&tool_paths()?.diskutil);
+    cmd.arg("unmount");
+
+    if force {
+        cmd.arg("force");
+    }
+    cmd.arg(&volume.device_identifier);
+    Ok(cmd.run_timed(Operation::Unmount)?)
+}
+
+/// Unmount `volume`, which is currently mounted at `current_mount`.
+/// If a non-forced unmount fails only because Spotlight or fseventsd
+/// are using the volume, we retry for a little while before reporting
+/// the volume as busy.
+fn unmount_volume(volume: &ApfsVolume, current_mount: &str, force: bool) -> Result<()> {
+    let mut output = run_diskutil_unmount(volume, force)?;
+    if !output.status.success() && !force && (geteuid() == 0 || fixtures_active()) {
+        let holders = volume_holders(current_mount)?;
+        if !holders.is_empty()
+            && holders
+                .iter()
+                .all(|name| QUIESCABLE_DAEMONS.contains(&name.as_str()))
+        {
+            eprintln!(
+                "{} is in use by {}; retrying",
+                current_mount,
+                holders.join(", ")
+            );
+            for _ in 0..QUIESCE_ATTEMPTS {
+                std::thread::sleep(QUIESCE_RETRY_DELAY);
+                output = run_diskutil_unmount(volume, force)?;
+                if output.status.success() {
+                    break;
+                }
+            }
+        } else if !holders.is_empty() {
+            bail!(
+                "{} is busy; it is in use by {}",
+                current_mount,
+                holders.join(", ")
+            );
+        }
+    }
+    if !output.status.success() {
+        anyhow::bail!(
+            "failed to execute diskutil unmount {}: {:?}",
+            volume.device_identifier,
+            output
+        );
+    }
+    Ok(())
+}
+
+/// Delete the volume for `mount_point`, returning the location that it
+/// was mounted at, if any.  The caller should pass that to `finish_unmount`
+/// once it has dropped privileges.
+fn delete_scratch(
+    test_container: Option<&str>,
+    mount_point: &str,
+    summary: &mut ChangeSummary,
+) -> Result<Option<String>> {
+    let result = find_and_delete(test_container, mount_point, summary);
+    record_history(&history_key(mount_point), "delete", &result);
+    if let Err(err) = &result {
+        summary
+            .errors
+            .push(format!("deleting {}: {:#}", mount_point, err));
+    }
+    result
+}
+
+fn find_and_delete(
+    test_container: Option<&str>,
+    mount_point: &str,
+    summary: &mut ChangeSummary,
+) -> Result<Option<String>> {
+    let containers = list_containers(test_container)?;
+    let name = encode_mount_point_as_volume_name(mount_point);
+    // The test container confines us to its volumes, which are never
+    // in images
+    let image = test_container.is_none() && has_image(&name);
+    let ram_disk = Registry::load()?
+        .mounts
+        .get(mount_point)
+        .and_then(|record| record.ram_disk.clone());
+    let ram_disk = match ram_disk {
+        Some(disk) if !is_on_ram_disk(&containers, &name, &disk) => {
+            // The RAM disk went away, as on reboot, and this volume is
+            // one that was created since
+            record_ram_disk(mount_point, None);
+            None
+        }
+        ram_disk => ram_disk,
+    };
+    if let Some(volume) = find_existing_volume(&containers, &name) {
+        let current_mount = volume.get_current_mount_point(None);
+        // This will implicitly unmount, so we don't need to deal
+        // with that here
+        if let Some(disk) = &ram_disk {
+            delete_ram_disk_volume(volume, disk, mount_point)?;
+            record_ram_disk(mount_point, None);
+        } else if image {
+            let container = containers
+                .iter()
+                .find(|container| container.volumes.contains(volume))
+                .ok_or_else(|| anyhow!("Did not find the container of {}", name))?;
+            delete_image_volume(volume, container, mount_point)?;
+        } else {
+            delete_volume(volume, mount_point)?;
+        }
+        remove_exclusions(mount_point);
+        summary.deleted.push(mount_point.to_owned());
+        summary.bytes_reclaimed += volume.capacity_in_use;
+        Ok(current_mount)
+    } else if image {
+        // The image isn't attached, so there is nothing to unmount
+        remove_image(&name)?;
+        remove_exclusions(mount_point);
+        summary.deleted.push(mount_point.to_owned());
+        Ok(None)
+    } else {
+        Err(NoVolume::new(format!("Did not find a volume named {}", name), mount_point)?.into())
+    }
+}
+
+/// Erase the volume for `mount_point` and mount it again, returning the
+/// canonical mount point.  As with `mount_scratch_space_on`, the caller
+/// must drop privileges and then call `finish_mount`.
+fn erase_scratch(
+    config: &Config,
+    test_container: Option<&str>,
+    mount_point: &str,
+    summary: &mut ChangeSummary,
+) -> Result<String> {
+    let result = find_and_erase(test_container, mount_point, summary);
+    record_history(&history_key(mount_point), "erase", &result);
+    if let Err(err) = &result {
+        summary
+            .errors
+            .push(format!("erasing {}: {:#}", mount_point, err));
+    }
+    result?;
+    mount_scratch_space_on(
+        config,
+        test_container,
+        &NewVolume::default(),
+        &MountFlags::default(),
+        mount_point,
+        None,
+        summary,
+    )
+}
+
+fn find_and_erase(
+    test_container: Option<&str>,
+    mount_point: &str,
+    summary: &mut ChangeSummary,
+) -> Result<()> {
+    let containers = list_containers(test_container)?;
+    let name = encode_mount_point_as_volume_name(mount_point);
+    let volume = find_existing_volume(&containers, &name)
+        .ok_or_else(|| anyhow!("Did not find a volume named {}", name))?;
+    if volume.locked {
+        bail!(
+            "Refusing to erase the volume for {} because it is locked, \
+             and erasing it would remove its encryption",
+            mount_point
+        );
+    }
+    let my_uid = get_real_uid()?;
+    if !volume_belongs_to(mount_point, &Registry::load()?, my_uid) {
+        bail!(
+            "Refusing to erase the volume for {} because it doesn't belong to uid {}",
+            mount_point,
+            my_uid
+        );
+    }
+
+    if let Some(current) = volume.get_current_mount_point(None) {
+        unmount_volume(volume, &current, false)?;
+        summary.unmounted.push(current);
+    }
+    erase_volume(volume, mount_point)?;
+    summary.erased.push(mount_point.to_owned());
+    // diskutil mounts the freshly erased volume under /Volumes, from
+    // where it has to be moved back to its mount point
+    if let Some(current) = volume.get_current_mount_point(None) {
+        unmount_volume(volume, &current, false)?;
+    }
+    Ok(())
+}
+
+/// Find the volume for `mount_point`, returning it along with the
+/// canonical form of the mount point.  This only resolves the identity
+/// of the volume; see `find_listed_volume` for its capacity figures.
+fn find_volume_for_mount_point(
+    mount_point: &str,
+    mounts: &MountTable,
+) -> Result<(ApfsVolume, String)> {
+    let (mount_point, name) = resolve_volume_name(mount_point);
+
+    // If the volume is mounted where it belongs then we can skip
+    // listing all of the volumes on the system
+    let volume = match find_mounted_volume(&mount_point, mounts) {
+        Some(volume) if volume.name.as_ref() == Some(&name) => volume,
+        _ => {
+            let containers = apfs_list()?;
+            find_existing_volume(&containers, &name)
+                .cloned()
+                .ok_or_else(|| anyhow!("Did not find a volume named {}", name))?
+        }
+    };
+    Ok((volume, mount_point))
+}
+
+/// Find the volume for `mount_point` in the output of `apfs_list`, which
+/// unlike `find_volume_for_mount_point` includes the space it uses
+fn find_listed_volume(mount_point: &str) -> Result<(ApfsVolume, String)> {
+    let (mount_point, name) = resolve_volume_name(mount_point);
+    let containers = apfs_list()?;
+    let volume = find_existing_volume(&containers, &name)
+        .cloned()
+        .ok_or_else(|| anyhow!("Did not find a volume named {}", name))?;
+    Ok((volume, mount_point))
+}
+
+/// Return the canonical form of `mount_point` along with the name of its
+/// volume.  The mount point may no longer exist, in which case we use it
+/// as given.
+fn resolve_volume_name(mount_point: &str) -> (String, String) {
+    let mount_point = normalize_mount_point(mount_point).unwrap_or_else(|_| mount_point.to_owned());
+    let name = encode_mount_point_as_volume_name(&mount_point);
+    (mount_point, name)
+}
+
+/// Whether a volume that belongs at `preferred` was mounted by macOS
+/// under /Volumes instead, as happens at boot
+fn is_automounted(current: Option<&str>, preferred: Option<&str>) -> bool {
+    match current {
+        Some(current) => current.starts_with("/Volumes/") && Some(current) != preferred,
+        None => false,
+    }
+}
+
+fn show_volume_info(mount_point: Option<&str>, selector: &VolumeSelector) -> Result<()> {
+    let mounts = MountTable::parse_system_mount_table()?;
+    let (volume, preferred) = match mount_point {
+        Some(mount_point) => {
+            let (volume, mount_point) = find_listed_volume(mount_point)?;
+            (volume, Some(mount_point))
+        }
+        None => {
+            let volume = selector.find()?;
+            let preferred = volume.preferred_mount_point();
+            (volume, preferred)
+        }
+    };
+
+    println!("name\t{}", volume.name.as_deref().unwrap_or(""));
+    println!("device\t{}", volume.device_identifier);
+    if let Some(uuid) = &volume.uuid {
+        println!("uuid\t{}", uuid);
+    }
+    let current_mount = volume.get_current_mount_point(Some(&mounts));
+    println!(
+        "mounted\t{}",
+        current_mount.as_ref().map(String::as_str).unwrap_or("no")
+    );
+    println!("in-use\t{}", volume.capacity_in_use);
+    println!(
+        "automounted\t{}",
+        if is_automounted(current_mount.as_deref(), preferred.as_deref()) {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+
+    // We can only tell whether the exclusions are in effect for the
+    // volume while it is mounted in the right place
+    if let Some(mount_point) =
+        preferred.filter(|preferred| current_mount.as_ref() == Some(preferred))
+    {
+        for kind in ExclusionKind::ALL {
+            let applied = match kind.exclusion().is_applied(&mount_point) {
+                Ok(true) => "applied",
+                Ok(false) => "not applied",
+                Err(_) => "unknown",
+            };
+            println!("exclusion\t{}\t{}", kind.name(), applied);
+        }
+    }
+    Ok(())
+}
+
+const FSCK_APFS: &str = "/sbin/fsck_apfs";
+
+/// The exit status of `fsck_apfs` when it doesn't understand its
+/// arguments, as is the case for `-l` on older systems
+const EX_USAGE: i32 = 64;
+
+/// How thoroughly `verify` was able to check a volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VerifyLevel {
+    /// fsck_apfs on the unmounted volume
+    Full,
+    /// fsck_apfs on a snapshot of the mounted volume
+    Live,
+    /// Checks of what the system reports about the mounted volume
+    SpotCheck,
+}
+
+impl VerifyLevel {
+    fn name(self) -> &'static str {
+        match self {
+            VerifyLevel::Full => "full",
+            VerifyLevel::Live => "live",
+            VerifyLevel::SpotCheck => "metadata spot check",
+        }
+    }
+}
+
+/// Run `fsck_apfs` in its read-only mode
+fn run_fsck(volume: &ApfsVolume, live: bool) -> Result<std::process::Output> {
+    let device = format!("/dev/{}", volume.device_identifier);
+    let mut cmd = new_cmd_with_root_privs(FSCK_APFS);
+    cmd.arg("-n");
+    if live {
+        cmd.arg("-l");
+    }
+    Ok(cmd.arg(&device).run_timed(Operation::Fsck)?)
+}
+
+fn fsck_failure(volume: &ApfsVolume, output: &std::process::Output) -> Error {
+    anyhow!(
+        "fsck_apfs found problems with {}: {}{}",
+        volume.device_identifier,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr).trim()
+    )
+}
+
+/// Check that what diskutil and the filesystem report about `volume`,
+/// which is mounted at `mount_point`, is consistent
+fn metadata_spot_check(volume: &ApfsVolume, mount_point: &str) -> Result<()> {
+    let info = disk_info(&volume.device_identifier)?;
+    if info.device_identifier != volume.device_identifier || info.volume_name != volume.name {
+        bail!(
+            "diskutil describes {} as {} named {:?}",
+            volume.device_identifier,
+            info.device_identifier,
+            info.volume_name
+        );
+    }
+    if info.mount_point != mount_point {
+        bail!(
+            "diskutil reports that {} is mounted at {:?} rather than {}",
+            volume.device_identifier,
+            info.mount_point,
+            mount_point
+        );
+    }
+    open_mounted_volume_root(mount_point, volume)?;
+    for entry in std::fs::read_dir(mount_point)
+        .with_context(|| format!("reading the root directory of {}", mount_point))?
+    {
+        entry.with_context(|| format!("reading the root directory of {}", mount_point))?;
+    }
+    Ok(())
+}
+
+fn verify_scratch(mount_point: &str, live: bool) -> Result<()> {
+    let mounts = MountTable::parse_system_mount_table()?;
+    let (volume, mount_point) = find_volume_for_mount_point(mount_point, &mounts)?;
+    let current_mount = volume.get_current_mount_point(Some(&mounts));
+
+    let level = match (live, &current_mount) {
+        (false, Some(current)) => bail!(
+            "the volume for {} is mounted at {}; unmount it first, or use --live",
+            mount_point,
+            current
+        ),
+        (false, None) => {
+            let output = run_fsck(&volume, false)?;
+            if !output.status.success() {
+                return Err(fsck_failure(&volume, &output));
+            }
+            VerifyLevel::Full
+        }
+        (true, None) => bail!(
+            "the volume for {} is not mounted; verify it without --live",
+            mount_point
+        ),
+        (true, Some(current)) => {
+            let output = run_fsck(&volume, true)?;
+            if output.status.success() {
+                VerifyLevel::Live
+            } else if output.status.code() == Some(EX_USAGE) {
+                metadata_spot_check(&volume, current)?;
+                VerifyLevel::SpotCheck
+            } else {
+                return Err(fsck_failure(&volume, &output));
+            }
+        }
+    };
+    println!("verification\t{}", level.name());
+    println!("result\tok");
+    Ok(())
+}
+
+/// Describe what is wrong with where `vol` is mounted, if anything
+fn location_problem(vol: &ApfsVolume, mounts: &MountTable) -> Option<String> {
+    let preferred = match vol.name.as_deref().and_then(decode_volume_name) {
+        Some(preferred) => preferred,
+        None => return Some("the name doesn't decode to a mount point".to_owned()),
+    };
+    match vol.get_current_mount_point(Some(mounts)) {
+        Some(current) if current != preferred => {
+            Some(format!("mounted at {} instead of {}", current, preferred))
+        }
+        Some(_) => None,
+        None => mounts
+            .entries
+            .iter()
+            .find(|entry| entry.mount_point == preferred)
+            .map(|entry| {
+                format!(
+                    "not mounted, and {} is mounted on {}",
+                    entry.device, preferred
+                )
+            }),
+    }
+}
+
+/// Check that each of our volumes is unmounted or mounted where its name
+/// says that it belongs
+fn verify_locations(test_container: Option<&str>) -> Result<()> {
+    let containers = list_containers(test_container)?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let mut checked = 0;
+    let mut mismatches = 0;
+    for container in &containers {
+        for vol in &container.volumes {
+            if !vol.is_edenfs_managed_volume() {
+                continue;
+            }
+            checked += 1;
+            if let Some(problem) = location_problem(vol, &mounts) {
+                println!(
+                    "{}\t{}\t{}",
+                    vol.device_identifier,
+                    vol.name.as_deref().unwrap_or_default(),
+                    problem
+                );
+                mismatches += 1;
+            }
+        }
+    }
+    if mismatches > 0 {
+        bail!(
+            "{} of {} volume(s) aren't mounted where their names say",
+            mismatches,
+            checked
+        );
+    }
+    println!("All {} volume(s) are where their names say", checked);
+    Ok(())
+}
+
+/// Run `fsck_apfs` on the unmounted `volume`, repairing what it can
+fn run_repair_fsck(volume: &ApfsVolume) -> Result<std::process::Output> {
+    let device = format!("/dev/{}", volume.device_identifier);
+    Ok(new_cmd_with_root_privs(FSCK_APFS)
+        .args(&["-y", &device])
+        .run_timed(Operation::Fsck)?)
+}
+
+/// Classify the outcome of `run_repair_fsck`.  fsck_apfs succeeds both
+/// when the volume was fine and when it repaired it, so we tell those
+/// apart by what it says about the volume.
+fn fsck_status(output: &std::process::Output) -> FsckStatus {
+    if !output.status.success() {
+        FsckStatus::Damaged
+    } else if String::from_utf8_lossy(&output.stdout).contains("was repaired successfully") {
+        FsckStatus::Repaired
+    } else {
+        FsckStatus::Clean
+    }
+}
+
+fn fsck_scratch(
+    config: &Config,
+    test_container: Option<&str>,
+    mount_point: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut summary = ChangeSummary::default();
+    let result = check_and_repair(config, test_container, mount_point, &mut summary);
+    drop_privileges()?;
+    let (report, unmounted) = result?;
+    if let Some(unmounted) = unmounted {
+        finish_unmount(config, &unmounted);
+    }
+    if report.remounted {
+        finish_mount(config, &report.mount_point);
+    }
+
+    if format == OutputFormat::Table {
+        println!("device\t{}", report.device_identifier);
+        println!("result\t{}", report.status.name());
+        println!("remounted\t{}", if report.remounted { "yes" } else { "no" });
+    } else {
+        print_report(&report, format)?;
+    }
+    if report.status == FsckStatus::Damaged {
+        bail!(
+            "fsck_apfs could not repair {}: {}",
+            report.device_identifier,
+            report.output.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the outcome of the check, along with the location that the
+/// volume was unmounted from, if it was mounted
+fn check_and_repair(
+    config: &Config,
+    test_container: Option<&str>,
+    mount_point: &str,
+    summary: &mut ChangeSummary,
+) -> Result<(FsckReport, Option<String>)> {
+    let mounts = MountTable::parse_system_mount_table()?;
+    let (volume, mount_point) = find_volume_for_mount_point(mount_point, &mounts)?;
+    let unmounted = match volume.get_current_mount_point(Some(&mounts)) {
+        Some(current) => {
+            let unmounted = unmount_scratch(&current, false, &mounts)?;
+            summary.unmounted.push(unmounted.clone());
+            Some(unmounted)
+        }
+        None => None,
+    };
+
+    let output = run_repair_fsck(&volume);
+    let status = output.as_ref().map(fsck_status);
+    let result = match &status {
+        Ok(FsckStatus::Damaged) => Err(anyhow!("fsck_apfs could not repair the volume")),
+        Ok(_) => Ok(()),
+        Err(err) => Err(anyhow!("{:#}", err)),
+    };
+    record_history(&history_key(&mount_point), "fsck", &result);
+    let output = output?;
+    let status = fsck_status(&output);
+
+    // Only put back what we took away, and only if it is fit to use
+    let remounted = unmounted.is_some() && status != FsckStatus::Damaged;
+    if remounted {
+        mount_scratch_space_on(
+            config,
+            test_container,
+            &NewVolume::default(),
+            &MountFlags::default(),
+            &mount_point,
+            None,
+            summary,
+        )?;
+    }
+    let report = FsckReport {
+        mount_point,
+        device_identifier: volume.device_identifier.clone(),
+        status,
+        exit_code: output.status.code(),
+        output: format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        remounted,
+    };
+    Ok((report, unmounted))
+}
+
+/// Format seconds since the unix epoch as an ISO 8601 UTC timestamp
+fn format_timestamp(time: u64) -> String {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let mut buf = [0u8; 32];
+    let len = unsafe {
+        if libc::gmtime_r(&time, &mut tm).is_null() {
+            0
+        } else {
+            libc::strftime(
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+                b"%Y-%m-%dT%H:%M:%SZ\0".as_ptr() as *const libc::c_char,
+                &tm,
+            )
+        }
+    };
+    if len == 0 {
+        return time.to_string();
+    }
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+fn show_history(mount_point: &str, format: OutputFormat) -> Result<()> {
+    let mount_point = history_key(mount_point);
+    let registry = Registry::load()?;
+    let history: Vec<_> = registry
+        .mounts
+        .get(&mount_point)
+        .map(|record| record.history.iter().cloned().collect())
+        .unwrap_or_default();
+
+    if format != OutputFormat::Table {
+        let report = HistoryReport {
+            mount_point,
+            history,
+        };
+        return print_report(&report, format);
+    }
+
+    for entry in history {
+        let mut outcome = if entry.ok { "ok" } else { "failed" }.to_owned();
+        if let Some(environment) = entry.environment {
+            outcome = format!("{} ({})", outcome, environment.name());
+        }
+        match &entry.error {
+            Some(error) => println!(
+                "{}\t{}\t{}\t{}",
+                format_timestamp(entry.time),
+                entry.operation,
+                outcome,
+                error
+            ),
+            None => println!(
+                "{}\t{}\t{}",
+                format_timestamp(entry.time),
+                entry.operation,
+                outcome
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn list_volumes(
+    all: bool,
+    misplaced: bool,
+    roles: &[String],
+    no_system: bool,
+    long: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let containers = apfs_list()?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let (registry, host_id) = if long {
+        (Registry::load()?, load_host_id()?)
+    } else {
+        (Registry::default(), None)
+    };
+    let mut report = ListReport { containers: vec![] };
+    for container in containers {
+        let mut listed = ListedContainer {
+            container_reference: container.container_reference,
+            volumes: vec![],
+        };
+        for vol in container.volumes {
+            if misplaced && vol.misplaced_mount_point(Some(&mounts)).is_none() {
+                continue;
+            }
+            if no_system && vol.is_system_volume() {
+                continue;
+            }
+            if !roles.is_empty() && !vol.has_any_role(roles) {
+                continue;
+            }
+            if !all && !vol.is_edenfs_managed_volume() {
+                continue;
+            }
+            let record = vol
+                .preferred_mount_point()
+                .and_then(|preferred| registry.mounts.get(&preferred));
+            let label = record.and_then(|record| record.label.clone());
+            let mount_point = vol.get_current_mount_point(Some(&mounts));
+            let origin = if long && vol.is_edenfs_managed_volume() {
+                Some(volume_origin(
+                    mount_point.as_deref().and_then(read_volume_tag).as_deref(),
+                    host_id.as_deref(),
+                    record.is_some(),
+                ))
+            } else {
+                None
+            };
+            listed.volumes.push(ListedVolume {
+                mount_point,
+                device_identifier: vol.device_identifier,
+                name: vol.name,
+                uuid: vol.uuid,
+                roles: vol.roles,
+                capacity_in_use: vol.capacity_in_use,
+                label,
+                origin,
+            });
+        }
+        report.containers.push(listed);
+    }
+
+    if format != OutputFormat::Table {
+        return print_report(&report, format);
+    }
+    for vol in report
+        .containers
+        .iter()
+        .flat_map(|container| container.volumes.iter())
+    {
+        let name = vol.name.as_deref().unwrap_or("");
+        if long {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                vol.device_identifier,
+                name,
+                vol.mount_point.as_deref().unwrap_or(""),
+                vol.label.as_deref().unwrap_or(""),
+                vol.origin.map(VolumeOrigin::name).unwrap_or("")
+            );
+        } else if let Some(mount_point) = &vol.mount_point {
+            println!("{}\t{}\t{}", vol.device_identifier, name, mount_point);
+        } else {
+            println!("{}\t{}", vol.device_identifier, name);
+        }
+    }
+    Ok(())
+}
+
+/// Collect the space used by each of our volumes, largest first
+fn collect_usage(containers: &[ApfsContainer]) -> UsageReport {
+    let mut volumes: Vec<VolumeUsage> = containers
+        .iter()
+        .flat_map(|container| container.volumes.iter())
+        .filter_map(|vol| {
+            Some(VolumeUsage {
+                mount_point: vol.preferred_mount_point()?,
+                device_identifier: vol.device_identifier.clone(),
+                capacity_in_use: vol.capacity_in_use,
+                capacity_quota: vol.capacity_quota,
+            })
+        })
+        .collect();
+    volumes.sort_by(|a, b| {
+        b.capacity_in_use
+            .cmp(&a.capacity_in_use)
+            .then_with(|| a.device_identifier.cmp(&b.device_identifier))
+    });
+    UsageReport { volumes }
+}
+
+fn show_usage(format: OutputFormat) -> Result<()> {
+    let report = collect_usage(&apfs_list()?);
+    if format != OutputFormat::Table {
+        return print_report(&report, format);
+    }
+    for vol in &report.volumes {
+        let quota = if vol.capacity_quota > 0 {
+            vol.capacity_quota.to_string()
+        } else {
+            String::new()
+        };
+        println!(
+            "{}\t{}\t{}\t{}",
+            vol.device_identifier, vol.capacity_in_use, quota, vol.mount_point
+        );
+    }
+    Ok(())
+}
+
+/// Compact the disk images whose volumes aren't mounted, reporting the
+/// bytes reclaimed from each.  An image that can't be compacted is
+/// reported and the rest are compacted regardless.
+fn compact_images() -> Result<()> {
+    if get_real_uid()? != 0 {
+        bail!("compact-images detaches the disk images of every user, and must be run as root");
+    }
+    let containers = apfs_list()?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let mut failures = 0;
+    for name in list_images()? {
+        let mount_point = decode_volume_name(&name).unwrap_or(&name);
+        let current = find_existing_volume(&containers, &name)
+            .and_then(|volume| volume.get_current_mount_point(Some(&mounts)));
+        if let Some(current) = current {
+            eprintln!(
+                "leaving the image for {} alone, as its volume is mounted at {}",
+                mount_point, current
+            );
+            continue;
+        }
+        match compact_image(&name) {
+            Ok(reclaimed) => println!("{}\t{}", mount_point, reclaimed),
+            Err(err) => {
+                eprintln!("failed to compact the image for {}: {:#}", mount_point, err);
+                failures += 1;
+            }
+        }
+    }
+    if failures > 0 {
+        bail!("failed to compact {} image(s)", failures);
+    }
+    Ok(())
+}
+
+/// The mount points whose most recent mount, unmount or delete was a
+/// successful mount, and so which we expect to be mounted
+fn persistent_mounts(registry: &Registry) -> Vec<String> {
+    registry
+        .mounts
+        .iter()
+        .filter(|(_, record)| {
+            record
+                .history
+                .iter()
+                .rev()
+                .filter(|entry| entry.ok)
+                .find(|entry| ["mount", "unmount", "delete"].contains(&entry.operation.as_str()))
+                .map(|entry| entry.operation == "mount")
+                .unwrap_or(false)
+        })
+        .map(|(mount_point, _)| mount_point.clone())
+        .collect()
+}
+
+/// Return each of `persistent` that is not up, along with why not
+fn unready_mounts(
+    persistent: &[String],
+    containers: &[ApfsContainer],
+    mounts: &MountTable,
+) -> Vec<(String, String)> {
+    let mut unready = vec![];
+    for mount_point in persistent {
+        let name = encode_mount_point_as_volume_name(mount_point);
+        let reason = match find_existing_volume(containers, &name) {
+            None => "its volume is missing".to_owned(),
+            Some(vol) => match vol.get_current_mount_point(Some(mounts)) {
+                Some(current) if &current == mount_point => continue,
+                Some(current) => format!("is mounted at {}", current),
+                None => "is not mounted".to_owned(),
+            },
+        };
+        unready.push((mount_point.clone(), reason));
+    }
+    unready
+}
+
+/// Check that every persistent mount is up, checking again each second
+/// until `wait` has passed
+fn wait_until_ready(wait: Duration) -> Result<()> {
+    let deadline = Instant::now() + wait;
+    loop {
+        let persistent = persistent_mounts(&Registry::load()?);
+        let unready = unready_mounts(
+            &persistent,
+            &apfs_list()?,
+            &MountTable::parse_system_mount_table()?,
+        );
+        if unready.is_empty() {
+            println!("ready");
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            for (mount_point, reason) in &unready {
+                println!("not-ready\t{}\t{}", mount_point, reason);
+            }
+            bail!(
+                "{} of {} persistent mount(s) are not up",
+                unready.len(),
+                persistent.len()
+            );
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// How the volume for a mount point changed over a period of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VolumeChange {
+    Created,
+    Deleted,
+    Changed,
+}
+
+impl VolumeChange {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Deleted => "deleted",
+            Self::Changed => "changed",
+        }
+    }
+}
+
+/// Summarize the successful operations recorded at or after `since`,
+/// returning the change to each mount point along with the time of the
+/// most recent of those operations, which serves as the cursor for the
+/// next query.  Operations in the same second as the cursor are
+/// reported again by the next query, so that none are missed.
+fn changes_since(registry: &Registry, since: u64) -> (Vec<(String, VolumeChange)>, u64) {
+    let mut changes = vec![];
+    let mut cursor = since;
+    for (mount_point, record) in &registry.mounts {
+        let recent: Vec<_> = record
+            .history
+            .iter()
+            .filter(|entry| entry.ok && entry.time >= since)
+            .collect();
+        let last = match recent.last() {
+            Some(last) => last,
+            None => continue,
+        };
+        cursor = cursor.max(recent.iter().map(|entry| entry.time).max().unwrap_or(since));
+        let change = if last.operation == "delete" {
+            VolumeChange::Deleted
+        } else if recent.iter().any(|entry| entry.operation == "create") {
+            VolumeChange::Created
+        } else {
+            VolumeChange::Changed
+        };
+        changes.push((mount_point.clone(), change));
+    }
+    (changes, cursor)
+}
+
+fn list_changes_since(since: u64) -> Result<()> {
+    let (changes, cursor) = changes_since(&Registry::load()?, since);
+    for (mount_point, change) in changes {
+        println!("{}\t{}", change.name(), mount_point);
+    }
+    println!("cursor\t{}", cursor);
+    Ok(())
+}
+
+fn show_free_space(container: Option<&str>, format: OutputFormat) -> Result<()> {
+    let mut stats = get_container_stats()?;
+    if let Some(container) = container {
+        stats.retain(|c| c.container == container);
+        if stats.is_empty() {
+            bail!("There is no APFS container named {}", container);
+        }
+    }
+    if format != OutputFormat::Table {
+        return print_report(&stats, format);
+    }
+    for container in &stats {
+        println!(
+            "container\t{}\t{}\t{}\t{}",
+            container.container,
+            container.capacity_ceiling,
+            container.capacity_free,
+            container.purgeable
+        );
+        for vol in &container.volumes {
+            println!(
+                "volume\t{}\t{}\t{}\t{}",
+                vol.device_identifier, vol.mount_point, vol.capacity_in_use, vol.available
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The subset of the eden redirection config that we care about.
+/// This is the format of both the `.eden-redirections` file in the
+/// repo and the `[redirections]` section of the checkout config.
+#[derive(Deserialize, Default)]
+struct RedirectionConfig {
+    #[serde(default)]
+    redirections: BTreeMap<String, String>,
+}
+
+/// Extract the `buck_out` setting from the `[project]` section of
+/// a `.buckconfig` file, if present.
+fn parse_buckconfig_buck_out(text: &str) -> Option<String> {
+    let mut in_project = false;
+    let mut buck_out = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            in_project = line[1..line.len() - 1].trim() == "project";
+            continue;
+        }
+        if !in_project {
+            continue;
+        }
+        let mut iter = line.splitn(2, '=');
+        if let (Some(key), Some(value)) = (iter.next(), iter.next()) {
+            if key.trim() == "buck_out" {
+                buck_out = Some(value.trim().to_owned());
+            }
+        }
+    }
+    buck_out
+}
+
+/// Load a redirection config file, returning an empty config if
+/// the file doesn't exist.
+fn load_redirection_config(path: &Path) -> Result<RedirectionConfig> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => toml::from_str(&text).with_context(|| format!("parsing {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(RedirectionConfig::default()),
+        Err(err) => Err(err).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+/// Compute the set of scratch directories that should be mounted for
+/// the checkout rooted at `checkout`.  The checkout config overrides
+/// the repo config in the same way that the eden CLI resolves them.
+/// Only `bind` redirections map to scratch volumes; buck-out is always
+/// included for repos that have a `.buckconfig`.
+fn configured_scratch_dirs(checkout: &Path) -> Result<Vec<PathBuf>> {
+    let mut redirections =
+        load_redirection_config(&checkout.join(".eden-redirections"))?.redirections;
+    redirections
+        .extend(load_redirection_config(&checkout.join(".eden/client/config.toml"))?.redirections);
+
+    match std::fs::read_to_string(checkout.join(".buckconfig")) {
+        Ok(text) => {
+            let buck_out =
+                parse_buckconfig_buck_out(&text).unwrap_or_else(|| "buck-out".to_owned());
+            redirections
+                .entry(buck_out)
+                .or_insert_with(|| "bind".to_owned());
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err).context("reading .buckconfig"),
+    }
+
+    let mut dirs = vec![];
+    for (repo_path, redir_type) in redirections {
+        if redir_type != "bind" {
+            continue;
+        }
+        let rel = Path::new(&repo_path);
+        if rel.is_absolute()
+            || rel
+                .components()
+                .any(|c| c == std::path::Component::ParentDir)
+        {
+            bail!(
+                "redirection {} must be a relative path inside the checkout",
+                repo_path
+            );
+        }
+        dirs.push(checkout.join(rel));
+    }
+    Ok(dirs)
+}
+
+/// Set or clear the label of the volume for `mount_point`.  Only the
+/// user that the volume belongs to may do this, as for renaming it; see
+/// `volume_belongs_to`.
+fn set_label(mount_point: &str, label: Option<String>) -> Result<()> {
+    let mount_point = with_real_user_privs(|| canonicalize_mount_point_path(mount_point))?;
+    let my_uid = get_real_uid()?;
+    if !volume_belongs_to(&mount_point, &Registry::load()?, my_uid) {
+        bail!(
+            "Refusing to label the volume for {} because it doesn't belong to uid {}",
+            mount_point,
+            my_uid
+        );
+    }
+    let name = encode_mount_point_as_volume_name(&mount_point);
+    if find_existing_volume(&apfs_list()?, &name).is_none() {
+        bail!("Did not find a volume named {}", name);
+    }
+
+    let result = Registry::update(|registry| {
+        registry
+            .mounts
+            .entry(mount_point.clone())
+            .or_default()
+            .label = label
+    });
+    drop_privileges()?;
+    result
+}
+
+/// Check that the volume for `mount_point`, which belongs to `owner`,
+/// was created by `owner`.  If the mount point changed hands since, the
+/// data on the volume belongs to someone else.
+fn check_creator(mount_point: &str, registry: &Registry, owner: u32) -> Result<()> {
+    match registry
+        .mounts
+        .get(mount_point)
+        .and_then(|record| record.creator)
+    {
+        Some(creator) if creator != owner => bail!(
+            "The volume for {} was created by uid {}, \
+             but {} now belongs to uid {}; if the volume should pass to uid {} along \
+             with its data, run `eden_apfs_mount_helper adopt --transfer-ownership {}` as root",
+            mount_point,
+            creator,
+            mount_point,
+            owner,
+            owner,
+            mount_point
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn adopt_volume(mount_point: &str, transfer_ownership: bool) -> Result<()> {
+    let mount_point = with_real_user_privs(|| canonicalize_mount_point_path(mount_point))?;
+    let metadata = with_real_user_privs(|| {
+        std::fs::metadata(&mount_point).with_context(|| format!("checking {}", mount_point))
+    })?;
+    let owner = metadata.uid();
+    let my_uid = get_real_uid()?;
+    if transfer_ownership && my_uid != 0 {
+        // Whoever owns the mount point now, perhaps by recreating it,
+        // would otherwise be able to take the data of the creator
+        bail!(
+            "--transfer-ownership hands the data on a volume to another uid, \
+             and must be run as root"
+        );
+    }
+    if my_uid != 0 && owner != my_uid {
+        bail!(
+            "Refusing to adopt the volume for {} because the owned uid {} doesn't match your uid {}",
+            mount_point,
+            owner,
+            my_uid
+        );
+    }
+    let name = encode_mount_point_as_volume_name(&mount_point);
+    if find_existing_volume(&apfs_list()?, &name).is_none() {
+        bail!("Did not find a volume named {}", name);
+    }
+    if let Err(err) = check_creator(&mount_point, &Registry::load()?, owner) {
+        if !transfer_ownership {
+            return Err(err);
+        }
+    }
+
+    let result = Registry::update(|registry| {
+        registry
+            .mounts
+            .entry(mount_point.clone())
+            .or_default()
+            .creator = Some(owner)
+    });
+    record_history(&mount_point, "adopt", &result);
+    result?;
+    println!("Adopted the volume for {}", mount_point);
+    Ok(())
+}
+
+/// Report what a command changed, as the last thing that it prints
+fn print_change_summary(summary: &ChangeSummary, format: OutputFormat) -> Result<()> {
+    let summary = &ChangeSummary {
+        dry_run: dry_run(),
+        ..summary.clone()
+    };
+    match format {
+        OutputFormat::Table => print!("{}", summary),
+        OutputFormat::Json => println!("{}", serde_json::to_string(summary)?),
+        OutputFormat::Plist => print!("{}", to_plist_xml(summary)?),
+    }
+    Ok(())
+}
+
+/// Report what a command changed in the structured formats only, for
+/// the commands whose table output predates the summaries
+fn print_structured_change_summary(
+    summary: &ChangeSummary,
+    format: Option<OutputFormat>,
+) -> Result<()> {
+    match format {
+        None | Some(OutputFormat::Table) => Ok(()),
+        Some(format) => print_change_summary(summary, format),
+    }
+}
+
+/// Print `report` in `format`, which is one of the structured formats;
+/// each command renders its own table
+fn print_report<T: Serialize>(report: &T, format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Table => bail!("there is no generic table format"),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(report)?),
+        OutputFormat::Plist => print!("{}", to_plist_xml(report)?),
+    }
+    Ok(())
+}
+
+/// Resolve the format for a command that has its own `--json` option
+fn format_with_json_option(format: Option<OutputFormat>, json: bool) -> Result<OutputFormat> {
+    match (format, json) {
+        (Some(format), true) if format != OutputFormat::Json => {
+            bail!("--json conflicts with --format {}", format.name())
+        }
+        (_, true) => Ok(OutputFormat::Json),
+        (format, false) => Ok(format.unwrap_or(OutputFormat::Table)),
+    }
+}
+
+/// Reject the structured formats for a command that only produces text
+fn require_table_format(format: Option<OutputFormat>, command: &str) -> Result<()> {
+    match format {
+        None | Some(OutputFormat::Table) => Ok(()),
+        Some(format) => bail!("{} does not support --format {}", command, format.name()),
+    }
+}
+
+/// Unmount each of our volumes that is mounted on a directory that belongs
+/// to the real user
+fn unmount_all(config: &Config, force: bool, format: OutputFormat) -> Result<()> {
+    let containers = apfs_list()?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let my_uid = get_real_uid()?;
+    let mut mounted = vec![];
+    for container in &containers {
+        for vol in &container.volumes {
+            if !vol.is_edenfs_managed_volume() {
+                continue;
+            }
+            let current = match vol.get_current_mount_point(Some(&mounts)) {
+                Some(current) => current,
+                None => continue,
+            };
+            match std::fs::symlink_metadata(&current) {
+                Ok(metadata) if metadata.uid() == my_uid => {}
+                _ => continue,
+            }
+            mounted.push(current);
+        }
+    }
+
+    // Unmount the volumes that are nested inside others first
+    let mut results = vec![];
+    let mut summary = ChangeSummary::default();
+    for index in mount_order(&mounted)?.into_iter().rev() {
+        let current = mounted[index].clone();
+        let result = unmount_and_release(&current, force, &mounts);
+        match &result {
+            Ok(mount_point) => summary.unmounted.push(mount_point.clone()),
+            Err(err) => summary
+                .errors
+                .push(format!("unmounting {}: {:#}", current, err)),
+        }
+        results.push((current, result));
+    }
+
+    drop_privileges()?;
+
+    let mut failed = false;
+    for (current, result) in results {
+        match result {
+            Ok(mount_point) => {
+                finish_unmount(config, &mount_point);
+                println!("Unmounted {}", mount_point);
+            }
+            Err(err) => {
+                eprintln!("Failed to unmount {}: {:#}", current, err);
+                failed = true;
+            }
+        }
+    }
+    print_change_summary(&summary, format)?;
+    if failed {
+        bail!("some volumes could not be unmounted");
+    }
+    Ok(())
+}
+
+/// Run the `hooks.pre_unmount` programs for `mount_point` as the real
+/// user, stopping at the first that fails
+fn run_pre_unmount_hooks(hooks: &Hooks, mount_point: &str) -> Result<()> {
+    for hook in &hooks.pre_unmount {
+        if !Path::new(hook).is_absolute() {
+            bail!("the pre-unmount hook {} is not an absolute path", hook);
+        }
+        let output = new_cmd_unprivileged(hook)
+            .arg(mount_point)
+            .run()
+            .with_context(|| format!("running the pre-unmount hook {}", hook))?;
+        if !output.status.success() {
+            bail!(
+                "the pre-unmount hook {} failed with {}: {}",
+                hook,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Unmount our volumes on the physical disk `disk` and then eject it.
+/// A volume mounted inside another is unmounted first, so that it is
+/// out of the way by the time we get to the outer one.  The disk is only
+/// ejected if every volume was unmounted.
+fn eject_disk(config: &Config, disk: &str, force: bool, format: OutputFormat) -> Result<()> {
+    if !is_container_identifier(disk) {
+        bail!("`{}` is not a disk identifier such as disk2", disk);
+    }
+    let containers: Vec<ApfsContainer> = apfs_list()?
+        .into_iter()
+        .filter(|container| container.is_on_disk(disk))
+        .collect();
+    if containers.is_empty() {
+        bail!("there are no APFS containers on {}", disk);
+    }
+    if containers
+        .iter()
+        .any(|container| container.volumes.iter().any(ApfsVolume::is_system_volume))
+    {
+        bail!("refusing to eject {}, which holds the system volumes", disk);
+    }
+    let mounts = MountTable::parse_system_mount_table()?;
+    let registry = Registry::load()?;
+    let my_uid = get_real_uid()?;
+
+    let mut mounted = vec![];
+    for container in &containers {
+        for vol in &container.volumes {
+            if !vol.is_edenfs_managed_volume() {
+                continue;
+            }
+            let current = match vol.get_current_mount_point(Some(&mounts)) {
+                Some(current) => current,
+                None => continue,
+            };
+            let owner_key = vol
+                .preferred_mount_point()
+                .unwrap_or_else(|| current.clone());
+            if !volume_belongs_to(&owner_key, &registry, my_uid) {
+                bail!(
+                    "refusing to eject {}, as the volume for {} belongs to another user",
+                    disk,
+                    owner_key
+                );
+            }
+            mounted.push(current);
+        }
+    }
+
+    let mut results = vec![];
+    let mut summary = ChangeSummary::default();
+    for index in mount_order(&mounted)?.into_iter().rev() {
+        let current = mounted[index].clone();
+        let result = run_pre_unmount_hooks(&config.hooks, &current)
+            .and_then(|_| unmount_and_release(&current, force, &mounts));
+        match &result {
+            Ok(mount_point) => summary.unmounted.push(mount_point.clone()),
+            Err(err) => summary
+                .errors
+                .push(format!("unmounting {}: {:#}", current, err)),
+        }
+        results.push((current, result));
+    }
+
+    drop_privileges()?;
+
+    let mut failed = false;
+    for (current, result) in results {
+        match result {
+            Ok(mount_point) => {
+                finish_unmount(config, &mount_point);
+                println!("Unmounted {}", mount_point);
+            }
+            Err(err) => {
+                eprintln!("Failed to unmount {}: {:#}", current, err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        print_change_summary(&summary, format)?;
+        bail!(
+            "{} is not safe to remove, as some volumes could not be unmounted",
+            disk
+        );
+    }
+
+    let output = new_cmd_unprivileged(&tool_paths()?.diskutil)
+        .args(&["eject", disk])
+        .run_timed(Operation::Unmount)?;
+    if !output.status.success() {
+        summary.errors.push(format!("ejecting {}", disk));
+        print_change_summary(&summary, format)?;
+        bail!(
+            "failed to eject {}: {}",
+            disk,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    println!("Ejected {}; it is now safe to remove", disk);
+    print_change_summary(&summary, format)
+}
+
+/// Whether the volume for `mount_point` belongs to `uid`.  Root may act
+/// on every volume.  Otherwise the volume must have been created by `uid`,
+/// or, if we didn't record who created it, its mount point must belong
+/// to `uid`.
+fn volume_belongs_to(mount_point: &str, registry: &Registry, uid: u32) -> bool {
+    if uid == 0 {
+        return true;
+    }
+    match registry
+        .mounts
+        .get(mount_point)
+        .and_then(|record| record.creator)
+    {
+        Some(creator) => creator == uid,
+        None => std::fs::symlink_metadata(mount_point)
+            .map(|metadata| metadata.uid() == uid)
+            .unwrap_or(false),
+    }
+}
+
+/// Ask the user to confirm something, returning true if they answered yes
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::{BufRead, Write};
+
+    eprint!("{} [y/N] ", prompt);
+    std::io::stderr().flush().ok();
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .context("reading the answer")?;
+    let answer = line.trim();
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+/// Unmount and delete all of the volumes created by this utility that
+/// belong to the real user, once the user confirms it
+fn delete_all(
+    config: &Config,
+    test_container: Option<&str>,
+    yes: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let containers = list_containers(test_container)?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let registry = Registry::load()?;
+    let my_uid = get_real_uid()?;
+    let volumes: Vec<ApfsVolume> = containers
+        .into_iter()
+        .flat_map(|container| container.volumes)
+        .filter(|vol| match vol.preferred_mount_point() {
+            Some(preferred) => volume_belongs_to(&preferred, &registry, my_uid),
+            None => false,
+        })
+        .collect();
+
+    if !yes && !volumes.is_empty() {
+        eprintln!("This will delete:");
+        for vol in &volumes {
+            eprintln!("  {}", vol.preferred_mount_point().unwrap());
+        }
+        if !confirm(&format!("Delete {} volume(s)?", volumes.len()))? {
+            bail!("nothing was deleted");
+        }
+    }
+
+    // We report on what we did once we have dropped privileges
+    let mut unmounted = vec![];
+    let mut report = vec![];
+    let mut summary = ChangeSummary::default();
+    for vol in volumes {
+        let mut try_delete = true;
+
+        if let Some(mount_point) = vol.get_current_mount_point(Some(&mounts)) {
+            // In the context of deleting all volumes, we want to
+            // force the unmount--we know it is safe.
+            let force = true;
+            match unmount_scratch(&mount_point, force, &mounts) {
+                Ok(mount_point) => {
+                    summary.unmounted.push(mount_point.clone());
+                    unmounted.push(mount_point);
+                }
+                Err(err) => {
+                    summary
+                        .errors
+                        .push(format!("unmounting {}: {:#}", mount_point, err));
+                    report.push(Err(format!("Failed to unmount: {}", err)));
+                    try_delete = false;
+                }
+            }
+        }
+
+        if try_delete {
+            let mount_point = vol.preferred_mount_point().unwrap();
+            match delete_scratch(test_container, &mount_point, &mut summary) {
+                Ok(_) => report.push(Ok(format!("Deleted {}", mount_point))),
+                Err(err) => report.push(Err(format!("Failed to delete {:#?}: {}", vol, err))),
+            }
+        }
+    }
+
+    drop_privileges()?;
+
+    for mount_point in unmounted {
+        finish_unmount(config, &mount_point);
+    }
+    for line in report {
+        match line {
+            Ok(line) => println!("{}", line),
+            Err(line) => eprintln!("{}", line),
+        }
+    }
+    print_change_summary(&summary, format)
+}
+
+/// Move the volumes that are mounted somewhere other than their preferred
+/// location to that location
+fn fix_misplaced(
+    config: &Config,
+    test_container: Option<&str>,
+    format: OutputFormat,
+) -> Result<()> {
+    let containers = list_containers(test_container)?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let mut results = vec![];
+    let mut summary = ChangeSummary::default();
+    for container in &containers {
+        for vol in &container.volumes {
+            if let Some(current) = vol.misplaced_mount_point(Some(&mounts)) {
+                // This unmounts the volume from its current location first
+                let preferred = vol.preferred_mount_point().unwrap();
+                let result = mount_scratch_space_on(
+                    config,
+                    test_container,
+                    &NewVolume::default(),
+                    &MountFlags::default(),
+                    &preferred,
+                    None,
+                    &mut summary,
+                );
+                results.push((current, preferred, result));
+            }
+        }
+    }
+
+    drop_privileges()?;
+
+    if results.is_empty() {
+        println!("No misplaced volumes found");
+        return print_change_summary(&summary, format);
+    }
+    let mut failed = false;
+    for (current, preferred, result) in results {
+        match result {
+            Ok(mount_point) => {
+                finish_mount(config, &mount_point);
+                println!("Moved {} to {}", current, mount_point);
+            }
+            Err(err) => {
+                eprintln!("Failed to move {} to {}: {:#}", current, preferred, err);
+                failed = true;
+            }
+        }
+    }
+    print_change_summary(&summary, format)?;
+    if failed {
+        bail!("some misplaced volumes could not be moved");
+    }
+    Ok(())
+}
+
+/// Mount scratch space at each of `mount_points` that isn't already
+/// mounted, outer ones first.  When `atomic` is set we stop at the first
+/// failure and undo everything that we did.
+fn ensure_mounted(
+    config: &Config,
+    test_container: Option<&str>,
+    mount_points: &[String],
+    atomic: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let containers = list_containers(test_container)?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let mut summary = ChangeSummary::default();
+    let mut results = vec![];
+    let mut failed: Vec<&str> = vec![];
+    for index in mount_order(mount_points)? {
+        let mount_point = &mount_points[index];
+        if let Some(outer) = failed.iter().find(|outer| is_nested_in(mount_point, outer)) {
+            let err = anyhow!("it is inside {}, which couldn't be mounted", outer);
+            summary
+                .errors
+                .push(format!("mounting {}: {:#}", mount_point, err));
+            failed.push(mount_point);
+            results.push((mount_point.clone(), Err(err)));
+            continue;
+        }
+        let already_mounted =
+            find_existing_volume(&containers, &encode_mount_point_as_volume_name(mount_point))
+                .and_then(|vol| vol.get_current_mount_point(Some(&mounts)))
+                .is_some_and(|current| &current == mount_point);
+        if already_mounted {
+            println!("{} is already mounted", mount_point);
+            continue;
+        }
+        let result = mount_scratch_space_on(
+            config,
+            test_container,
+            &NewVolume::default(),
+            &MountFlags::default(),
+            mount_point,
+            None,
+            &mut summary,
+        );
+        if result.is_err() {
+            failed.push(mount_point);
+        }
+        results.push((mount_point.clone(), result));
+        if atomic && !failed.is_empty() {
+            break;
+        }
+    }
+    let rolled_back = atomic && !failed.is_empty();
+    if rolled_back {
+        roll_back_mounts(test_container, &mut summary);
+    }
+
+    drop_privileges()?;
+
+    let mut failures = 0;
+    for (mount_point, result) in results {
+        match result {
+            Ok(mounted) if !rolled_back => {
+                finish_mount(config, &mounted);
+                println!("Mounted {}", mount_point);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("Failed to mount {}: {:#}", mount_point, err);
+                failures += 1;
+            }
+        }
+    }
+    print_change_summary(&summary, format)?;
+    if rolled_back {
+        // Any error beyond the failure that made us roll back came
+        // from undoing what we had done
+        if summary.errors.len() > failures {
+            bail!("failed to mount every volume, and couldn't undo all of the changes");
+        }
+        bail!("failed to mount every volume, so nothing was changed");
+    }
+    if failures > 0 {
+        bail!("some volumes could not be mounted");
+    }
+    Ok(())
+}
+
+/// Undo the mounts and the creations recorded in `summary`, newest first,
+/// so that inner mount points go before those that they are inside.  A
+/// volume that was created is deleted, which also unmounts it.
+fn roll_back_mounts(test_container: Option<&str>, summary: &mut ChangeSummary) {
+    let attempt = summary.clone();
+    // A volume that was created but not mounted is the one whose mount
+    // failed, so it is the newest
+    let mut newest_first: Vec<&String> = attempt
+        .created
+        .iter()
+        .filter(|mount_point| !attempt.mounted.contains(mount_point))
+        .collect();
+    newest_first.extend(attempt.mounted.iter().rev());
+
+    let containers = match list_containers(test_container) {
+        Ok(containers) => containers,
+        Err(err) => {
+            summary.errors.push(format!("rolling back: {:#}", err));
+            return;
+        }
+    };
+    for mount_point in newest_first {
+        if attempt.created.contains(mount_point) {
+            let name = encode_mount_point_as_volume_name(mount_point);
+            // There is nothing to delete if creating the volume failed
+            if find_existing_volume(&containers, &name).is_some() {
+                eprintln!("Rolling back {}", mount_point);
+                delete_scratch(test_container, mount_point, summary).ok();
+            }
+            continue;
+        }
+        eprintln!("Rolling back {}", mount_point);
+        let result = MountTable::parse_system_mount_table()
+            .and_then(|mounts| unmount_and_release(mount_point, true, &mounts));
+        match result {
+            Ok(unmounted) => summary.unmounted.push(unmounted),
+            Err(err) => summary
+                .errors
+                .push(format!("unmounting {}: {:#}", mount_point, err)),
+        }
+    }
+}
+
+/// Mount each of our volumes that isn't mounted where it belongs and whose
+/// mount point belongs to the real user.  A volume whose mount point is
+/// inside that of another is mounted after it, as its mount point only
+/// exists once the outer volume is mounted.
+fn mount_all(config: &Config, test_container: Option<&str>, format: OutputFormat) -> Result<()> {
+    let containers = list_containers(test_container)?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let my_uid = get_real_uid()?;
+    let mut unmounted = vec![];
+    for container in &containers {
+        for vol in &container.volumes {
+            let preferred = match vol.preferred_mount_point() {
+                Some(path) => path,
+                None => continue,
+            };
+            if vol.get_current_mount_point(Some(&mounts)).as_ref() != Some(&preferred) {
+                unmounted.push(preferred);
+            }
+        }
+    }
+
+    let mut results = vec![];
+    let mut skipped = vec![];
+    let mut summary = ChangeSummary::default();
+    // The mount points that we skipped or failed to mount
+    let mut left_out: Vec<String> = vec![];
+    for index in mount_order(&unmounted)? {
+        let preferred = unmounted[index].clone();
+        if let Some(outer) = left_out
+            .iter()
+            .find(|outer| is_nested_in(&preferred, outer))
+        {
+            skipped.push((
+                preferred.clone(),
+                format!("is inside {}, which wasn't mounted", outer),
+            ));
+            left_out.push(preferred);
+            continue;
+        }
+        let skip_reason = match with_real_user_privs(|| Ok(std::fs::symlink_metadata(&preferred)))?
+        {
+            Ok(metadata) if metadata.uid() == my_uid => None,
+            Ok(_) => Some("belongs to another user".to_owned()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                Some("does not exist; see gc".to_owned())
+            }
+            Err(err) => Some(err.to_string()),
+        };
+        if let Some(reason) = skip_reason {
+            skipped.push((preferred.clone(), reason));
+            left_out.push(preferred);
+            continue;
+        }
+        let result = mount_scratch_space_on(
+            config,
+            test_container,
+            &NewVolume::default(),
+            &MountFlags::default(),
+            &preferred,
+            None,
+            &mut summary,
+        );
+        if result.is_err() {
+            left_out.push(preferred.clone());
+        }
+        results.push((preferred, result));
+    }
+
+    drop_privileges()?;
+
+    for (mount_point, reason) in skipped {
+        println!("Skipped {}: {}", mount_point, reason);
+    }
+    let mut failed = false;
+    for (preferred, result) in results {
+        match result {
+            Ok(mount_point) => {
+                finish_mount(config, &mount_point);
+                println!("Mounted {}", mount_point);
+            }
+            Err(err) => {
+                eprintln!("Failed to mount {}: {:#}", preferred, err);
+                failed = true;
+            }
+        }
+    }
+    print_change_summary(&summary, format)?;
+    if failed {
+        bail!("some volumes could not be mounted");
+    }
+    Ok(())
+}
+
+/// Mount scratch space for all of the configured scratch dirs of a checkout
+fn apply_checkout_config(
+    config: &Config,
+    test_container: Option<&str>,
+    checkout: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    let (checkout, dirs) = with_real_user_privs(|| {
+        let checkout = canonicalize_mount_point_path(checkout)?;
+        let dirs = configured_scratch_dirs(Path::new(&checkout))?;
+        Ok((checkout, dirs))
+    })?;
+    let mut summary = ChangeSummary::default();
+    if dirs.is_empty() {
+        println!("No scratch directories are configured for {}", checkout);
+        return print_change_summary(&summary, format);
+    }
+
+    let containers = apfs_list()?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let mut results = vec![];
+    // Scratch dirs may be nested, so mount the outer ones first, and skip
+    // those inside one that couldn't be mounted
+    let names: Vec<String> = dirs
+        .iter()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .collect();
+    let mut failed_dirs: Vec<&str> = vec![];
+    for index in mount_order(&names)? {
+        let dir = dirs[index].clone();
+        if let Some(outer) = failed_dirs
+            .iter()
+            .find(|outer| is_nested_in(&names[index], outer))
+        {
+            let err = anyhow!("it is inside {}, which couldn't be mounted", outer);
+            summary
+                .errors
+                .push(format!("mounting {}: {:#}", dir.display(), err));
+            failed_dirs.push(&names[index]);
+            results.push((dir, Err(err)));
+            continue;
+        }
+        let already_mounted =
+            find_existing_volume(&containers, &encode_mount_point_as_volume_name(&dir))
+                .and_then(|vol| vol.get_current_mount_point(Some(&mounts)))
+                .map(|current| Path::new(&current) == dir)
+                .unwrap_or(false);
+        if already_mounted {
+            println!("{} is already mounted", dir.display());
+            continue;
+        }
+
+        let result = with_real_user_privs(|| {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("creating directory {}", dir.display()))
+        })
+        .and_then(|_| {
+            let dir = dir
+                .to_str()
+                .ok_or_else(|| anyhow!("path {} somehow isn't unicode on macOS", dir.display()))?;
+            Ok(dir)
+        });
+        let result = match result {
+            Ok(dir) => mount_scratch_space_on(
+                config,
+                test_container,
+                &NewVolume::default(),
+                &MountFlags::default(),
+                dir,
+                None,
+                &mut summary,
+            ),
+            Err(err) => {
+                summary.errors.push(format!("{:#}", err));
+                Err(err)
+            }
+        };
+        if result.is_err() {
+            failed_dirs.push(&names[index]);
+        }
+        results.push((dir, result));
+    }
+
+    drop_privileges()?;
+
+    let mut failed = false;
+    for (dir, result) in results {
+        match result {
+            Ok(mount_point) => {
+                finish_mount(config, &mount_point);
+                println!("Mounted {}", dir.display());
+            }
+            Err(err) => {
+                eprintln!("Failed to mount {}: {:#}", dir.display(), err);
+                failed = true;
+            }
+        }
+    }
+    print_change_summary(&summary, format)?;
+
+    if failed {
+        bail!("some scratch directories could not be mounted");
+    }
+    Ok(())
+}
+
+/// Create the transcript file at `path` on behalf of the user and start
+/// recording into it
+fn start_transcript(path: &Path) -> Result<()> {
+    let file = with_real_user_privs(|| {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .with_context(|| format!("creating the transcript {}", path.display()))
+    })?;
+    transcript::start(file)
+}
+
+pub fn main() -> Result<()> {
+    harden_process(&[TEST_CONTAINER_ENV])?;
+
+    let opts = Opt::from_args();
+    if let Some(path) = &opts.transcript {
+        start_transcript(path)?;
+    }
+    let result = run(opts);
+    // The commands that never gave up their privs haven't recorded their
+    // latencies yet
+    record_latencies();
+    transcript::finish(&result);
+    if let Err(err) = &result {
+        // So that triage can tell these apart from failures on real
+        // hardware
+        if let Some(environment) = environment_tag() {
+            eprintln!("environment: {}", environment.name());
+        }
+        if let Some(no_volume) = err.downcast_ref::<NoVolume>() {
+            eprintln!("Error: {:?}", err);
+            std::io::Write::flush(&mut std::io::stdout()).ok();
+            std::process::exit(no_volume.absence as i32);
+        }
+    }
+    result
+}
+
+impl SubCommand {
+    /// Replace the mount points that we were given with their normalized
+    /// form.  The destination of `rename` is left alone, as it has to
+    /// exist and is resolved by the filesystem.
+    fn normalize_mount_points(&mut self) -> Result<()> {
+        if let SubCommand::Ensure { mount_points, .. } = self {
+            for mount_point in mount_points.iter_mut() {
+                *mount_point = normalize_mount_point(mount_point)?;
+            }
+            return Ok(());
+        }
+        let mount_point = match self {
+            SubCommand::Mount { mount_point, .. }
+            | SubCommand::Unlock { mount_point, .. }
+            | SubCommand::Rename {
+                from: mount_point, ..
+            }
+            | SubCommand::Label(LabelCommand::Set { mount_point, .. })
+            | SubCommand::Label(LabelCommand::Get { mount_point })
+            | SubCommand::Label(LabelCommand::Clear { mount_point })
+            | SubCommand::UnMount { mount_point, .. }
+            | SubCommand::Delete { mount_point }
+            | SubCommand::Erase { mount_point }
+            | SubCommand::Info {
+                mount_point: Some(mount_point),
+                ..
+            }
+            | SubCommand::ResizePreflight { mount_point, .. }
+            | SubCommand::CheckSpace { mount_point, .. }
+            | SubCommand::Compress { mount_point, .. }
+            | SubCommand::SetQuota { mount_point, .. }
+            | SubCommand::Prepare { mount_point, .. }
+            | SubCommand::Adopt { mount_point, .. }
+            | SubCommand::Verify {
+                mount_point: Some(mount_point),
+                ..
+            }
+            | SubCommand::Fsck { mount_point }
+            | SubCommand::History { mount_point, .. } => mount_point,
+            _ => return Ok(()),
+        };
+        *mount_point = normalize_mount_point(mount_point)?;
+        Ok(())
+    }
+}
+
+fn run(mut opts: Opt) -> Result<()> {
+    let test_container = opts.test_container.as_deref();
+    let format = opts.format;
+
+    // The policy is only loaded once something needs it, and the tools
+    // that it relocates are only verified once one of them is run, so
+    // that the commands that need neither stay cheap
+    TIMEOUT_OVERRIDES.set(opts.timeouts.clone()).ok();
+    set_policy_loader(|| config().map(|_| ()));
+    opts.cmd.normalize_mount_points()?;
+    if opts.dry_run {
+        match opts.cmd {
+            SubCommand::Mount { .. }
+            | SubCommand::UnMount { .. }
+            | SubCommand::Delete { .. }
+            | SubCommand::Gc { .. }
+            | SubCommand::Batch => set_dry_run(),
+            _ => bail!("--dry-run is only supported by mount, unmount, delete, gc and batch"),
+        }
+    }
+
+    // Mounting and unmounting require root privs, as does recording what
+    // we did in the registry.  Those commands give them up as soon as they
+    // are done with them; everything else can give them up right away.
+    match opts.cmd {
+        SubCommand::Mount { .. }
+        | SubCommand::Ensure { .. }
+        | SubCommand::Unlock { .. }
+        | SubCommand::UnMount { .. }
+        | SubCommand::UnMountAll { .. }
+        | SubCommand::Eject { .. }
+        | SubCommand::Verify { .. }
+        | SubCommand::Fsck { .. }
+        | SubCommand::Audit
+        | SubCommand::Delete { .. }
+        | SubCommand::DeleteAll { .. }
+        | SubCommand::Erase { .. }
+        | SubCommand::Gc { .. }
+        | SubCommand::Apply { .. }
+        | SubCommand::Batch
+        | SubCommand::FixMisplaced { .. }
+        | SubCommand::MountAll
+        | SubCommand::Rename { .. }
+        | SubCommand::MigrateFromSymlink { .. }
+        | SubCommand::SetQuota { .. }
+        | SubCommand::Prepare { .. }
+        | SubCommand::Adopt { .. }
+        | SubCommand::Label(LabelCommand::Set { .. })
+        | SubCommand::Label(LabelCommand::Clear { .. })
+        | SubCommand::CompactImages
+        | SubCommand::Stats { record: true, .. } => {}
+        _ => drop_privileges()?,
+    }
+
+    match opts.cmd {
+        SubCommand::List {
+            all,
+            misplaced,
+            roles,
+            no_system,
+            long,
+            since,
+            json,
+        } => {
+            if let Some(since) = since {
+                require_table_format(format, "list --since")?;
+                return list_changes_since(since);
+            }
+            let format = format_with_json_option(format, json)?;
+            list_volumes(all, misplaced, &roles, no_system, long, format)
+        }
+
+        SubCommand::Mount {
+            mount_point,
+            new_volume,
+            mount_flags,
+        } => {
+            let mut summary = ChangeSummary::default();
+            let result = mount_scratch_space_on(
+                config()?,
+                test_container,
+                &new_volume,
+                &mount_flags,
+                &mount_point,
+                None,
+                &mut summary,
+            );
+            drop_privileges()?;
+            finish_mount(config()?, &result?);
+            print_structured_change_summary(&summary, format)
+        }
+
+        SubCommand::Unlock {
+            mount_point,
+            save_to_keychain,
+        } => {
+            let unlock = Unlock {
+                passphrase: read_passphrase(&format!("Passphrase for {}: ", mount_point))?,
+                save_to_keychain,
+            };
+            let mut summary = ChangeSummary::default();
+            let result = mount_scratch_space_on(
+                config()?,
+                test_container,
+                &NewVolume::default(),
+                &MountFlags::default(),
+                &mount_point,
+                Some(&unlock),
+                &mut summary,
+            );
+            drop_privileges()?;
+            finish_mount(config()?, &result?);
+            print_structured_change_summary(&summary, format)
+        }
+
+        SubCommand::Rename { from, to } => {
+            rename_scratch(config()?, test_container, &from, &to, format)
+        }
+
+        SubCommand::MigrateFromSymlink { mount_point } => {
+            migrate_from_symlink(config()?, test_container, &mount_point, format)
+        }
+
+        SubCommand::Adopt {
+            mount_point,
+            transfer_ownership,
+        } => {
+            require_table_format(format, "adopt")?;
+            let result = adopt_volume(&mount_point, transfer_ownership);
+            drop_privileges()?;
+            result
+        }
+
+        SubCommand::Label(LabelCommand::Set { mount_point, label }) => {
+            require_table_format(format, "label set")?;
+            set_label(&mount_point, Some(label))
+        }
+
+        SubCommand::Label(LabelCommand::Get { mount_point }) => {
+            require_table_format(format, "label get")?;
+            let registry = Registry::load()?;
+            if let Some(label) = registry
+                .mounts
+                .get(&history_key(&mount_point))
+                .and_then(|record| record.label.as_ref())
+            {
+                println!("{}", label);
+            }
+            Ok(())
+        }
+
+        SubCommand::Label(LabelCommand::Clear { mount_point }) => {
+            require_table_format(format, "label clear")?;
+            set_label(&mount_point, None)
+        }
+
+        SubCommand::Policy(PolicyCommand::Verify { reference }) => {
+            require_table_format(format, "policy verify")?;
+            verify_policy(&reference)
+        }
+
+        SubCommand::Capabilities => {
+            let report = Capabilities {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                policy_version: config()?.version.clone(),
+                environment: host_environment(),
+            };
+            match format.unwrap_or(OutputFormat::Table) {
+                OutputFormat::Table => {
+                    println!("version\t{}", report.version);
+                    println!(
+                        "policy-version\t{}",
+                        report.policy_version.as_deref().unwrap_or("")
+                    );
+                    println!("environment\t{}", report.environment.name());
+                    Ok(())
+                }
+                format => print_report(&report, format),
+            }
+        }
+
+        SubCommand::UnMount { mount_point, force } => {
+            // We keep our privs while unmounting so that we can tell which
+            // processes are holding the volume busy
+            let result = unmount_and_release(
+                &mount_point,
+                force,
+                &MountTable::parse_system_mount_table()?,
+            );
+            drop_privileges()?;
+            let unmounted = result?;
+            finish_unmount(config()?, &unmounted);
+            let summary = ChangeSummary {
+                unmounted: vec![unmounted],
+                ..Default::default()
+            };
+            print_structured_change_summary(&summary, format)
+        }
+
+        SubCommand::UnMountAll { force } => {
+            unmount_all(config()?, force, format.unwrap_or(OutputFormat::Table))
+        }
+
+        SubCommand::Eject { disk, force } => eject_disk(
+            config()?,
+            &disk,
+            force,
+            format.unwrap_or(OutputFormat::Table),
+        ),
+
+        SubCommand::Delete { mount_point } => {
+            let mut summary = ChangeSummary::default();
+            let result = delete_scratch(test_container, &mount_point, &mut summary);
+            drop_privileges()?;
+            if let Some(unmounted) = result? {
+                finish_unmount(config()?, &unmounted);
+            }
+            print_structured_change_summary(&summary, format)
+        }
+
+        SubCommand::Erase { mount_point } => {
+            let mut summary = ChangeSummary::default();
+            let result = erase_scratch(config()?, test_container, &mount_point, &mut summary);
+            drop_privileges()?;
+            finish_mount(config()?, &result?);
+            println!("Erased the volume for {}", mount_point);
+            print_structured_change_summary(&summary, format)
+        }
+
+        SubCommand::DeleteAll { json, yes } => delete_all(
+            config()?,
+            test_container,
+            yes,
+            format_with_json_option(format, json)?,
+        ),
+
+        SubCommand::Gc {
+            include_foreign,
+            keep,
+            ignore_policy_keep,
+        } => {
+            let mut rules = if ignore_policy_keep {
+                vec![]
+            } else {
+                config()?.gc.keep.clone()
+            };
+            rules.extend(keep);
+            collect_garbage(
+                test_container,
+                include_foreign,
+                &rules,
+                format.unwrap_or(OutputFormat::Table),
+            )
+        }
+
+        SubCommand::FixMisplaced { json } => fix_misplaced(
+            config()?,
+            test_container,
+            format_with_json_option(format, json)?,
+        ),
+
+        SubCommand::MountAll => mount_all(
+            config()?,
+            test_container,
+            format.unwrap_or(OutputFormat::Table),
+        ),
+
+        SubCommand::Ensure {
+            mount_points,
+            atomic,
+        } => ensure_mounted(
+            config()?,
+            test_container,
+            &mount_points,
+            atomic,
+            format.unwrap_or(OutputFormat::Table),
+        ),
+
+        SubCommand::Audit => {
+            // We inspect the mount points with our root privs, so that
+            // a directory that the real user can't see isn't reported
+            // as missing
+            let result = audit_volumes();
+            drop_privileges()?;
+            show_audit(&result?, format.unwrap_or(OutputFormat::Table))
+        }
+
+        SubCommand::Doctor { fix, json } => {
+            if !fix {
+                require_table_format(format, "doctor without --fix")?;
+            }
+            run_doctor(config()?, fix, format_with_json_option(format, json)?)
+        }
+
+        SubCommand::FreeSpace { container } => show_free_space(
+            container.as_deref(),
+            // This reported json long before there was a choice
+            format.unwrap_or(OutputFormat::Json),
+        ),
+
+        SubCommand::Info {
+            mount_point,
+            selector,
+        } => {
+            require_table_format(format, "info")?;
+            show_volume_info(mount_point.as_deref(), &selector)
+        }
+
+        SubCommand::ResizePreflight {
+            mount_point,
+            quota,
+            largest,
+        } => resize_preflight(
+            &mount_point,
+            quota,
+            largest,
+            format.unwrap_or(OutputFormat::Table),
+        ),
+
+        SubCommand::CheckSpace {
+            mount_point,
+            need,
+            min_free,
+        } => check_space(
+            &mount_point,
+            need,
+            min_free.unwrap_or(config()?.thresholds.min_free_bytes),
+            format.unwrap_or(OutputFormat::Table),
+        ),
+
+        SubCommand::SetQuota { mount_point, quota } => {
+            require_table_format(format, "set-quota")?;
+            let result = set_quota(&mount_point, quota);
+            drop_privileges()?;
+            result
+        }
+
+        SubCommand::Prepare {
+            mount_point,
+            new_volume,
+        } => {
+            let mut summary = ChangeSummary::default();
+            let result = prepare_volume(
+                config()?,
+                test_container,
+                &new_volume,
+                &mount_point,
+                &mut summary,
+            );
+            drop_privileges()?;
+            result?;
+            print_structured_change_summary(&summary, format)
+        }
+
+        SubCommand::Verify {
+            mount_point,
+            live,
+            locations,
+        } => {
+            require_table_format(format, "verify")?;
+            let mount_point = match mount_point {
+                Some(mount_point) if !locations => mount_point,
+                _ => {
+                    drop_privileges()?;
+                    return verify_locations(test_container);
+                }
+            };
+            // fsck_apfs needs root privs to read the device
+            let result = verify_scratch(&mount_point, live);
+            drop_privileges()?;
+            result
+        }
+
+        SubCommand::Fsck { mount_point } => fsck_scratch(
+            config()?,
+            test_container,
+            &mount_point,
+            format.unwrap_or(OutputFormat::Table),
+        ),
+
+        SubCommand::Health { json, check } => {
+            show_health(config()?, format_with_json_option(format, json)?, check)
+        }
+
+        SubCommand::Stats {
+            check,
+            record,
+            forecast,
+            compression,
+            latency,
+            watch,
+            count,
+        } => {
+            match format {
+                Some(format) if latency && format != OutputFormat::Table => {
+                    // Dashboards only want the latencies
+                    return print_report(&latency_report(&Registry::load()?), format);
+                }
+                _ => require_table_format(format, "stats")?,
+            }
+            if let Some(seconds) = watch {
+                return watch_stats(Duration::from_secs(seconds), count);
+            }
+            if record {
+                record_usage(&apfs_list()?)?;
+                Registry::update(|registry| fold_latency(registry, unix_time()))?;
+                drop_privileges()?;
+            }
+            show_stats(config()?, check, forecast, compression, latency)
+        }
+
+        SubCommand::Usage => show_usage(format.unwrap_or(OutputFormat::Table)),
+
+        SubCommand::CompactImages => {
+            require_table_format(format, "compact-images")?;
+            compact_images()
+        }
+
+        SubCommand::Compress {
+            mount_point,
+            older_than,
+        } => {
+            require_table_format(format, "compress")?;
+            compress_scratch(
+                &mount_point,
+                Duration::from_secs(older_than * SECONDS_PER_DAY as u64),
+            )
+        }
+
+        SubCommand::Ready { wait } => {
+            require_table_format(format, "ready")?;
+            wait_until_ready(Duration::from_secs(wait))
+        }
+
+        SubCommand::History { mount_point, json } => {
+            show_history(&mount_point, format_with_json_option(format, json)?)
+        }
+
+        SubCommand::Apply { checkout, json } => apply_checkout_config(
+            config()?,
+            test_container,
+            &checkout,
+            format_with_json_option(format, json)?,
+        ),
+
+        SubCommand::Batch => {
+            if let Some(format) = format.filter(|&format| format != OutputFormat::Json) {
+                bail!("batch does not support --format {}", format.name());
+            }
+            let stdin = std::io::stdin();
+            let input = stdin.lock();
+            run_batch(config()?, test_container, input)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_reconcile() {
+        use eden_apfs_mount_helper::report::HistoryEntry;
+
+        let entry = |operation: &str, ok: bool| HistoryEntry {
+            time: 0,
+            operation: operation.to_owned(),
+            ok,
+            error: None,
+            environment: None,
+        };
+        let record = |entries: Vec<HistoryEntry>| MountRecord {
+            history: entries.into_iter().collect(),
+            ..Default::default()
+        };
+        let volume = |device: &str, mount_point: &str| ApfsVolume {
+            device_identifier: device.to_owned(),
+            name: Some(encode_mount_point_as_volume_name(mount_point)),
+            ..Default::default()
+        };
+
+        let mut registry = Registry::default();
+        // Consistent in all three
+        registry
+            .mounts
+            .insert("/a".to_owned(), record(vec![entry("mount", true)]));
+        // The volume went away
+        registry.mounts.insert(
+            "/b".to_owned(),
+            record(vec![entry("mount", true), entry("unmount", false)]),
+        );
+        // Deleted, but the volume remains
+        registry
+            .mounts
+            .insert("/c".to_owned(), record(vec![entry("delete", true)]));
+        // Nothing ever succeeded, so we don't know what to expect
+        registry
+            .mounts
+            .insert("/e".to_owned(), record(vec![entry("mount", false)]));
+
+        let containers = vec![ApfsContainer {
+            container_reference: "disk1".to_owned(),
+            capacity_ceiling: 0,
+            capacity_free: 0,
+            physical_stores: vec![],
+            volumes: vec![
+                volume("disk1s5", "/a"),
+                volume("disk1s6", "/c"),
+                volume("disk1s7", "/d"),
+            ],
+        }];
+        let mounts = MountTable::parse_mount_table_text(
+            "/dev/disk1s5 on /a (apfs, local)\n/dev/disk2s1 on /e (apfs, local)\n",
+        );
+
+        let found: Vec<(String, DiscrepancyKind)> = reconcile(&registry, &containers, &mounts)
+            .into_iter()
+            .map(|discrepancy| (discrepancy.mount_point, discrepancy.kind))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("/b".to_owned(), DiscrepancyKind::MissingVolume),
+                ("/c".to_owned(), DiscrepancyKind::UnrecordedVolume),
+                ("/d".to_owned(), DiscrepancyKind::UnrecordedVolume),
+                ("/e".to_owned(), DiscrepancyKind::ForeignMount),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_absence() {
+        use eden_apfs_mount_helper::report::HistoryEntry;
+
+        let record = |operations: &[(&str, bool)]| MountRecord {
+            history: operations
+                .iter()
+                .map(|(operation, ok)| HistoryEntry {
+                    time: 0,
+                    operation: operation.to_string(),
+                    ok: *ok,
+                    error: None,
+                    environment: None,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(Absence::from_record(None), Absence::NeverManaged);
+        for (operations, expected) in &[
+            (&[][..], Absence::NeverManaged),
+            (&[("create", false)][..], Absence::NeverManaged),
+            (&[("create", true), ("mount", true)][..], Absence::Missing),
+            (&[("create", true), ("unmount", true)][..], Absence::Missing),
+            (
+                &[("create", true), ("delete", true)][..],
+                Absence::NeverManaged,
+            ),
+            (
+                &[("create", true), ("delete", true), ("mount", false)][..],
+                Absence::NeverManaged,
+            ),
+        ] {
+            assert_eq!(
+                Absence::from_record(Some(&record(operations))),
+                *expected,
+                "{:?}",
+                operations
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_thresholds() {
+        let thresholds = Thresholds::default();
+        let mut stats = Stats {
+            usage: vec![("disk1".to_owned(), 90), ("disk3".to_owned(), 50)],
+            ..Default::default()
+        };
+        assert_eq!(check_thresholds(&stats, &thresholds), (0, vec![]));
+
+        stats.usage[1].1 = 95;
+        stats.orphans.push("/a".to_owned());
+        let (status, violations) = check_thresholds(&stats, &thresholds);
+        assert_eq!(status, CHECK_USAGE | CHECK_ORPHANS);
+        assert_eq!(violations.len(), 2);
+
+        stats.failed_mounts.push("/b".to_owned());
+        let thresholds = Thresholds {
+            max_usage_percent: 95,
+            max_orphans: 1,
+            max_failed_mounts: 0,
+            ..Default::default()
+        };
+        assert_eq!(check_thresholds(&stats, &thresholds).0, CHECK_FAILED_MOUNTS);
+    }
+
+    #[test]
+    fn test_expand_user_path() {
+        let home = || Ok("/Users/test/".to_owned());
+        assert_eq!(expand_user_path("/a/b", home).unwrap(), "/a/b");
+        assert_eq!(expand_user_path("a/~b", home).unwrap(), "a/~b");
+        assert_eq!(expand_user_path("~", home).unwrap(), "/Users/test/");
+        assert_eq!(
+            expand_user_path("~/repo/buck-out", home).unwrap(),
+            "/Users/test/repo/buck-out"
+        );
+        assert!(expand_user_path("~other/repo", home).is_err());
+        assert!(expand_user_path("$HOME/repo", home).is_err());
+        assert!(expand_user_path("/a/${REPO}/b", home).is_err());
+        assert_eq!(expand_user_path("/a/$/b", home).unwrap(), "/a/$/b");
+    }
+
+    #[test]
+    fn test_template_path() {
+        let mount_point = "/Users/test/repo/buck-out";
+        assert_eq!(
+            template_path(mount_point, "gen/tmp").unwrap(),
+            PathBuf::from("/Users/test/repo/buck-out/gen/tmp")
+        );
+        assert_eq!(
+            template_path(mount_point, ".").unwrap(),
+            PathBuf::from("/Users/test/repo/buck-out/.")
+        );
+        assert!(template_path(mount_point, "").is_err());
+        assert!(template_path(mount_point, "/etc").is_err());
+        assert!(template_path(mount_point, "gen/../../.git").is_err());
+    }
+
+    #[test]
+    fn test_changes_since() {
+        use eden_apfs_mount_helper::report::HistoryEntry;
+
+        let entry = |time: u64, operation: &str, ok: bool| HistoryEntry {
+            time,
+            operation: operation.to_owned(),
+            ok,
+            error: None,
+            environment: None,
+        };
+        let mut registry = Registry::default();
+        for (mount_point, entries) in [
+            (
+                "/new",
+                vec![entry(10, "create", true), entry(10, "mount", true)],
+            ),
+            (
+                "/gone",
+                vec![entry(5, "mount", true), entry(20, "delete", true)],
+            ),
+            (
+                "/remounted",
+                vec![entry(1, "create", true), entry(15, "mount", true)],
+            ),
+            (
+                "/failed",
+                vec![entry(1, "mount", true), entry(30, "delete", false)],
+            ),
+            (
+                "/old",
+                vec![entry(1, "create", true), entry(2, "mount", true)],
+            ),
+        ] {
+            registry.mounts.insert(
+                mount_point.to_owned(),
+                MountRecord {
+                    history: entries.into_iter().collect(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        assert_eq!(
+            changes_since(&registry, 10),
+            (
+                vec![
+                    ("/gone".to_owned(), VolumeChange::Deleted),
+                    ("/new".to_owned(), VolumeChange::Created),
+                    ("/remounted".to_owned(), VolumeChange::Changed),
+                ],
+                20
+            )
+        );
+        assert_eq!(changes_since(&registry, 21), (vec![], 21));
+    }
+
+    #[test]
+    fn test_volume_belongs_to() {
+        let dir = std::env::temp_dir().join(format!("eden-apfs-owner-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.to_str().unwrap().to_owned();
+        let owner = dir.metadata().unwrap().uid();
+        let other = owner + 1;
+
+        let mut registry = Registry::default();
+        let untracked = (
+            volume_belongs_to(&path, &registry, owner),
+            volume_belongs_to(&path, &registry, other),
+        );
+        registry.mounts.insert(
+            path.clone(),
+            MountRecord {
+                creator: Some(other),
+                ..Default::default()
+            },
+        );
+        let tracked = (
+            volume_belongs_to(&path, &registry, owner),
+            volume_belongs_to(&path, &registry, other),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(untracked, (true, false));
+        assert_eq!(tracked, (owner == 0, true));
+        assert!(volume_belongs_to("/does/not/exist", &registry, 0));
+        assert!(!volume_belongs_to("/does/not/exist", &registry, 501));
+    }
+
+    #[test]
+    fn test_setuid_problem() {
+        let setuid = SETUID_BIT;
+        assert_eq!(setuid_problem(0, setuid | 0o755), None);
+        assert!(setuid_problem(0, 0o755).is_some());
+        assert!(setuid_problem(501, setuid | 0o755).is_some());
+        assert!(setuid_problem(0, setuid | 0o775).is_some());
+    }
+
+    #[test]
+    fn test_mount_options() {
+        let options = |policy: &MountPolicy, requested: &[&str]| {
+            let flags = MountFlags {
+                mount_options: requested.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            };
+            mount_options(policy, &flags)
+        };
+        let mut policy = MountPolicy::default();
+        assert_eq!(options(&policy, &[]).unwrap(), "nobrowse,nodev,nosuid");
+        assert_eq!(
+            options(&policy, &["noatime", "nfc"]).unwrap(),
+            "nobrowse,nodev,nosuid,noatime,nfc"
+        );
+        assert!(options(&policy, &["suid"]).is_err());
+        assert!(options(&policy, &["dev"]).is_err());
+        assert!(options(&policy, &["noatime,suid"]).is_err());
+        assert!(options(&policy, &[""]).is_err());
+
+        policy.options = vec!["noatime".to_owned()];
+        policy.allow_overrides = vec!["suid".to_owned()];
+        assert_eq!(
+            options(&policy, &["suid"]).unwrap(),
+            "nobrowse,nodev,nosuid,noatime,suid"
+        );
+        assert!(options(&policy, &["dev"]).is_err());
+
+        policy.noexec = true;
+        assert_eq!(
+            options(&policy, &["exec"]).unwrap(),
+            "nobrowse,nodev,nosuid,noatime,exec,noexec"
+        );
+        let flags = MountFlags {
+            noexec: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            mount_options(&MountPolicy::default(), &flags).unwrap(),
+            "nobrowse,nodev,nosuid,noexec"
+        );
+        let flags = MountFlags {
+            read_only: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            mount_options(&MountPolicy::default(), &flags).unwrap(),
+            "nobrowse,nodev,nosuid,rdonly"
+        );
+    }
+
+    #[test]
+    fn test_new_volume_container() {
+        let container = |reference: &str, capacity_free, roles: &[&str]| ApfsContainer {
+            container_reference: reference.to_owned(),
+            capacity_ceiling: 500_000_000_000,
+            capacity_free,
+            physical_stores: vec![],
+            volumes: vec![ApfsVolume {
+                device_identifier: format!("{}s1", reference),
+                roles: roles.iter().map(|role| role.to_string()).collect(),
+                ..Default::default()
+            }],
+        };
+        let containers = vec![
+            container("disk1", 10_000_000_000, &["System"]),
+            container("disk3", 300_000_000_000, &[]),
+            container("disk4", 300_000_000_000, &[]),
+        ];
+        let choose = |test_container, requested, placement| {
+            new_volume_container(&containers, test_container, requested, placement, "/tmp")
+        };
+        assert_eq!(choose(None, None, Placement::System).unwrap(), "disk1");
+        // The last of the containers with the most space wins a tie
+        assert_eq!(choose(None, None, Placement::MostFree).unwrap(), "disk4");
+        assert_eq!(
+            choose(Some("disk1"), None, Placement::MostFree).unwrap(),
+            "disk1"
+        );
+        assert_eq!(
+            choose(None, Some("disk3"), Placement::System).unwrap(),
+            "disk3"
+        );
+        assert!(choose(Some("disk1"), Some("disk3"), Placement::System).is_err());
+        assert!(choose(None, Some("disk9"), Placement::MostFree).is_err());
+        assert!(new_volume_container(&[], None, None, Placement::MostFree, "/tmp").is_err());
+    }
+
+    #[test]
+    fn test_check_creator() {
+        let mut registry = Registry::default();
+        registry.mounts.insert(
+            "/a".to_owned(),
+            MountRecord {
+                creator: Some(501),
+                ..Default::default()
+            },
+        );
+        registry
+            .mounts
+            .insert("/b".to_owned(), MountRecord::default());
+        assert!(check_creator("/a", &registry, 501).is_ok());
+        assert!(check_creator("/a", &registry, 502).is_err());
+        assert!(check_creator("/b", &registry, 502).is_ok());
+        assert!(check_creator("/c", &registry, 502).is_ok());
+    }
+
+    #[test]
+    fn test_keep_rule() {
+        let rule: KeepRule = "/Users/*/release/buck-out".parse().unwrap();
+        assert!(rule.matches("/Users/wez/release/buck-out", None));
+        assert!(!rule.matches("/Users/wez/repo/buck-out", Some("release")));
+
+        let rule: KeepRule = "label:release*".parse().unwrap();
+        assert_eq!(rule.to_string(), "label:release*");
+        assert!(rule.matches("/Users/wez/repo/buck-out", Some("release builds")));
+        assert!(!rule.matches("/Users/wez/repo/buck-out", Some("scratch")));
+        assert!(!rule.matches("/Users/wez/repo/buck-out", None));
+
+        assert!("buck-out".parse::<KeepRule>().is_err());
+        assert!("label:".parse::<KeepRule>().is_err());
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let big = MIN_COMPRESS_SIZE * 4;
+        assert!(should_compress(big, big, 1, 100, 200));
+        assert!(!should_compress(big, big, 1, 200, 200));
+        assert!(!should_compress(MIN_COMPRESS_SIZE - 1, big, 1, 100, 200));
+        // Already compressed, or sparse
+        assert!(!should_compress(big, big / 3, 1, 100, 200));
+        assert!(!should_compress(big, big, 2, 100, 200));
+    }
+
+    #[test]
+    fn test_compression_ratio() {
+        assert_eq!(compression_ratio(1000, 400), "2.50");
+        assert_eq!(compression_ratio(1000, 1000), "1.00");
+        assert_eq!(compression_ratio(0, 0), "-");
+    }
+
+    #[test]
+    fn test_forecast() {
+        let samples = |points: &[(u64, u64)]| -> VecDeque<UsageSample> {
+            points
+                .iter()
+                .map(|&(time, bytes)| UsageSample { time, bytes })
+                .collect()
+        };
+        let day = 24 * 60 * 60;
+
+        assert_eq!(forecast(&samples(&[]), 100), None);
+        assert_eq!(forecast(&samples(&[(day, 10)]), 100), None);
+        assert_eq!(
+            forecast(&samples(&[(0, 10), (day, 15), (2 * day, 30)]), 100),
+            Some(Forecast {
+                bytes_per_day: 10,
+                days_left: Some(7)
+            })
+        );
+        assert_eq!(
+            forecast(&samples(&[(0, 10), (day, 120)]), 100),
+            Some(Forecast {
+                bytes_per_day: 110,
+                days_left: Some(0)
+            })
+        );
+        assert_eq!(
+            forecast(&samples(&[(0, 30), (3 * day, 30)]), 100),
+            Some(Forecast {
+                bytes_per_day: 0,
+                days_left: None
+            })
+        );
+        assert_eq!(
+            forecast(&samples(&[(0, 30), (day, 20)]), 100),
+            Some(Forecast {
+                bytes_per_day: -10,
+                days_left: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_persistent_mounts() {
+        use eden_apfs_mount_helper::report::HistoryEntry;
+
+        let entry = |operation: &str, ok: bool| HistoryEntry {
+            time: 0,
+            operation: operation.to_owned(),
+            ok,
+            error: None,
+            environment: None,
+        };
+        let mut registry = Registry::default();
+        for (mount_point, entries) in [
+            (
+                "/mounted",
+                vec![entry("create", true), entry("mount", true)],
+            ),
+            (
+                "/verified",
+                vec![entry("mount", true), entry("verify", true)],
+            ),
+            (
+                "/unmount-failed",
+                vec![entry("mount", true), entry("unmount", false)],
+            ),
+            (
+                "/unmounted",
+                vec![entry("mount", true), entry("unmount", true)],
+            ),
+            (
+                "/deleted",
+                vec![entry("mount", true), entry("delete", true)],
+            ),
+            ("/never", vec![entry("mount", false)]),
+        ] {
+            registry.mounts.insert(
+                mount_point.to_owned(),
+                MountRecord {
+                    history: entries.into_iter().collect(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        assert_eq!(
+            persistent_mounts(&registry),
+            vec![
+                "/mounted".to_owned(),
+                "/unmount-failed".to_owned(),
+                "/verified".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1600000000), "2020-09-13T12:26:40Z");
+    }
+
+    #[test]
+    fn test_open_mount_point_dir() {
+        let dir = std::env::temp_dir().join(format!("eden-apfs-mp-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("real")).unwrap();
+        std::fs::write(dir.join("file"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        let real = open_mount_point_dir(&path("real"));
+        let link = open_mount_point_dir(&path("link"));
+        let file = open_mount_point_dir(&path("file"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(real.unwrap().metadata().unwrap().is_dir());
+        assert!(link.is_err());
+        assert!(file.is_err());
+    }
+
+    #[test]
+    fn test_largest_entries() {
+        let dir = std::env::temp_dir().join(format!("eden-apfs-largest-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("big/nested")).unwrap();
+        std::fs::write(dir.join("big/nested/data"), vec![1u8; 1 << 20]).unwrap();
+        std::fs::write(dir.join("small"), "x").unwrap();
+        std::fs::write(dir.join("empty"), "").unwrap();
+
+        let entries = largest_entries(dir.to_str().unwrap(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let entries = entries.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, dir.join("big").to_str().unwrap());
+        assert!(entries[0].bytes >= 1 << 20);
+        assert_eq!(entries[1].path, dir.join("small").to_str().unwrap());
+    }
+
+    #[test]
+    fn test_buckconfig_buck_out() {
+        let data = r#"
+[cxx]
+  buck_out = not-this-one
+
+[project]
+  # buck_out = commented-out
+  ignore = .hg
+  buck_out = buck-out/v2
+"#;
+        assert_eq!(
+            parse_buckconfig_buck_out(data),
+            Some("buck-out/v2".to_owned())
+        );
+        assert_eq!(parse_buckconfig_buck_out("[project]\nignore = .hg\n"), None);
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! `batch`, which runs the mount, unmount and delete commands read from
+//! stdin with a single exec of this utility.
+
+use super::{
+    delete_scratch, finish_mount, finish_unmount, mount_scratch_space_on, normalize_mount_point,
+    unmount_and_release, Config, MountFlags, NewVolume,
+};
+use anyhow::*;
+use eden_apfs_mount_helper::dry_run::dry_run;
+use eden_apfs_mount_helper::mount_table::MountTable;
+use eden_apfs_mount_helper::privs::drop_privileges;
+use eden_apfs_mount_helper::report::{BatchCommand, BatchResult, ChangeSummary};
+
+/// The part of a batch command that is left to do once we have given up
+/// our privileges
+enum Unfinished {
+    Mount(String),
+    Unmount(String),
+}
+
+/// Run one of the commands read by `batch`, with our privileges
+fn run_batch_command(
+    config: &Config,
+    test_container: Option<&str>,
+    command: &BatchCommand,
+    summary: &mut ChangeSummary,
+) -> Result<Option<Unfinished>> {
+    match command {
+        BatchCommand::Mount { mount_point } => {
+            let mount_point = normalize_mount_point(mount_point)?;
+            let mounted = mount_scratch_space_on(
+                config,
+                test_container,
+                &NewVolume::default(),
+                &MountFlags::default(),
+                &mount_point,
+                None,
+                summary,
+            )?;
+            Ok(Some(Unfinished::Mount(mounted)))
+        }
+        BatchCommand::Unmount { mount_point, force } => {
+            let mount_point = normalize_mount_point(mount_point)?;
+            let unmounted = unmount_and_release(
+                &mount_point,
+                *force,
+                &MountTable::parse_system_mount_table()?,
+            )?;
+            summary.unmounted.push(unmounted.clone());
+            Ok(Some(Unfinished::Unmount(unmounted)))
+        }
+        BatchCommand::Delete { mount_point } => {
+            let mount_point = normalize_mount_point(mount_point)?;
+            Ok(delete_scratch(test_container, &mount_point, summary)?.map(Unfinished::Unmount))
+        }
+    }
+}
+
+/// Run the commands in `input`, one JSON object per line, printing the
+/// result of each as a line of JSON.  A command that fails doesn't stop
+/// the rest.  We hold on to our privileges until every command is done,
+/// and only then tell watchman about the mounts that changed.
+pub(super) fn run_batch(
+    config: &Config,
+    test_container: Option<&str>,
+    input: impl std::io::BufRead,
+) -> Result<()> {
+    let mut unfinished = vec![];
+    let mut count = 0;
+    let mut failed = 0;
+    for (index, line) in input.lines().enumerate() {
+        let line = line.context("reading the batch commands from stdin")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        count += 1;
+        let mut changes = ChangeSummary::default();
+        let request = serde_json::from_str::<BatchCommand>(&line)
+            .with_context(|| format!("parsing the command on line {}", index + 1));
+        let result = request
+            .as_ref()
+            .map_err(|err| anyhow!("{:#}", err))
+            .and_then(|command| run_batch_command(config, test_container, command, &mut changes));
+        let error = match result {
+            Ok(done) => {
+                unfinished.extend(done);
+                None
+            }
+            Err(err) => {
+                failed += 1;
+                Some(format!("{:#}", err))
+            }
+        };
+        let result = BatchResult {
+            line: index + 1,
+            request: request.ok(),
+            ok: error.is_none(),
+            error,
+            changes: ChangeSummary {
+                dry_run: dry_run(),
+                ..changes
+            },
+        };
+        println!("{}", serde_json::to_string(&result)?);
+    }
+
+    drop_privileges()?;
+
+    for done in unfinished {
+        match done {
+            Unfinished::Mount(mount_point) => finish_mount(config, &mount_point),
+            Unfinished::Unmount(mount_point) => finish_unmount(config, &mount_point),
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} batch command(s) failed", failed, count);
+    }
+    Ok(())
+}
@@ -0,0 +1,335 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The exclusions that keep Spotlight, fseventsd, the Trash and Time
+//! Machine away from our volumes.  Which of them apply is up to the
+//! `exclusions` in the policy; see `ExclusionKind`.
+
+use super::{config, XATTR};
+use anyhow::*;
+use eden_apfs_mount_helper::dry_run::dry_run;
+use eden_apfs_mount_helper::privs::{
+    new_cmd_unprivileged, new_cmd_with_root_privs, with_real_user_privs, RunCommand,
+};
+use eden_apfs_mount_helper::tool_output::{parse_plist_buddy_array, parse_xattr_hex};
+use serde::Deserialize;
+use std::path::Path;
+
+/// Remove the exclusions that the policy applies from the volume that was
+/// at `mount_point`, which was deleted or moved elsewhere, so that they
+/// don't build up.  This is called while we still have root privs, and
+/// failing to do so is reported but otherwise ignored.
+pub(super) fn remove_exclusions(mount_point: &str) {
+    if dry_run() {
+        // We can't tell what there is to remove without reading the
+        // exclusions, which the dry-run mode skips
+        return;
+    }
+    let kinds = match config() {
+        Ok(config) => &config.exclusions,
+        Err(err) => {
+            eprintln!(
+                "failed to remove the exclusions for {}: {:#}",
+                mount_point, err
+            );
+            return;
+        }
+    };
+    for kind in kinds {
+        if let Err(err) = kind.exclusion().remove(mount_point) {
+            eprintln!(
+                "failed to remove the {} exclusion for {}: {:#}",
+                kind.name(),
+                mount_point,
+                err
+            );
+        }
+    }
+}
+
+const MDUTIL: &str = "/usr/bin/mdutil";
+
+/// Don't bother indexing an artifact dir.  It's just a waste of resources
+/// to build an index for something managed entirely by the machine.
+pub(super) fn disable_spotlight(mount_point: &str) -> Result<()> {
+    let output = new_cmd_with_root_privs(MDUTIL)
+        .args(&["-Ed", "-i", "off", mount_point])
+        .run()?;
+    if !output.status.success() {
+        eprintln!(
+            "failed to disable spotlight on {}: {:#?}",
+            mount_point, output
+        );
+    }
+
+    let spotlight = Path::new(mount_point).join(".Spotlight-V100");
+    std::fs::remove_dir_all(&spotlight).ok();
+
+    Ok(())
+}
+
+/// Disable fsevents logging for the artifact dirs: this is for performance
+/// reasons; we don't need/want fseventsd to run here.
+pub(super) fn disable_fsevents(mount_point: &str) -> Result<()> {
+    // See https://developer.apple.com/library/archive/documentation/Darwin/Conceptual/FSEvents_ProgGuide/FileSystemEventSecurity/FileSystemEventSecurity.html#//apple_ref/doc/uid/TP40005289-CH6-SW5
+    // Those docs say that we should recreate the directory and touch a control
+    // file to disable logging data, but the presence of the directory can
+    // confuse some tools, so we simply delete it at mount time; that should
+    // be good enough in most cases.
+
+    let fseventsd = Path::new(mount_point).join(".fseventsd");
+    std::fs::remove_dir_all(&fseventsd).ok();
+
+    Ok(())
+}
+
+/// The .Trashes directory has root permissions by default, which makes it
+/// awkward for users to clean up the contents of the mount point when it
+/// is used in place of an artifact directory.
+pub(super) fn disable_trashcan(mount_point: &str) -> Result<()> {
+    let trashes = Path::new(mount_point).join(".Trashes");
+    std::fs::remove_dir_all(trashes)?;
+
+    // There's some thought that touching a regular file named
+    // `.Trashes` is a good idea to prevent the trash dir from
+    // coming back (which is what happens when using Finder to
+    // send something to the trash).
+    // For now we're just removing it at mount time.
+
+    Ok(())
+}
+
+/// The kinds of exclusion that can be enabled via the policy file.
+/// Some system daemons watch paths rather than volumes, so disabling
+/// indexing on the volume itself isn't always sufficient to keep
+/// them away from the artifacts that we store there.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(super) enum ExclusionKind {
+    /// Add the mount point to the Spotlight privacy list
+    SpotlightPrivacy,
+    /// Place a `.metadata_never_index` marker in the root of the volume
+    MetadataNeverIndex,
+    /// Ask fseventsd not to log events for the volume
+    FseventsdNoLog,
+}
+
+impl ExclusionKind {
+    pub(super) const ALL: &'static [ExclusionKind] = &[
+        ExclusionKind::SpotlightPrivacy,
+        ExclusionKind::MetadataNeverIndex,
+        ExclusionKind::FseventsdNoLog,
+    ];
+
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            ExclusionKind::SpotlightPrivacy => "spotlight-privacy",
+            ExclusionKind::MetadataNeverIndex => "metadata-never-index",
+            ExclusionKind::FseventsdNoLog => "fseventsd-no-log",
+        }
+    }
+
+    pub(super) fn exclusion(self) -> Box<dyn Exclusion> {
+        match self {
+            ExclusionKind::SpotlightPrivacy => Box::new(SpotlightPrivacy),
+            ExclusionKind::MetadataNeverIndex => Box::new(MetadataNeverIndex),
+            ExclusionKind::FseventsdNoLog => Box::new(FseventsdNoLog),
+        }
+    }
+}
+
+/// A mechanism for telling some system service to leave a mount point alone
+pub(super) trait Exclusion {
+    /// Register the exclusion for a freshly mounted volume.
+    /// This is called while we still have root privs.
+    fn apply(&self, mount_point: &str) -> Result<()>;
+
+    /// Returns true if the exclusion is currently in effect for
+    /// the volume mounted at `mount_point`.
+    fn is_applied(&self, mount_point: &str) -> Result<bool>;
+
+    /// Remove the exclusion for the volume that was at `mount_point`,
+    /// once it has been deleted or moved elsewhere.  This is called while
+    /// we still have root privs.  The exclusions that are kept on the
+    /// volume itself go with it, so there is nothing to do for those.
+    fn remove(&self, _mount_point: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct SpotlightPrivacy;
+
+impl SpotlightPrivacy {
+    const PLIST_BUDDY: &'static str = "/usr/libexec/PlistBuddy";
+
+    /// The privacy list lives in the Spotlight configuration for the
+    /// data volume.  That moved with the read-only system volume split
+    /// in Catalina.
+    fn volume_config() -> &'static str {
+        if Path::new("/System/Volumes/Data/.Spotlight-V100").exists() {
+            "/System/Volumes/Data/.Spotlight-V100/VolumeConfiguration.plist"
+        } else {
+            "/.Spotlight-V100/VolumeConfiguration.plist"
+        }
+    }
+
+    /// The paths in the privacy list, which is empty if there isn't one
+    fn exclusions() -> Result<Vec<String>> {
+        let output = new_cmd_unprivileged(Self::PLIST_BUDDY)
+            .args(&["-c", "Print :Exclusions", Self::volume_config()])
+            .run()?;
+        if !output.status.success() {
+            return Ok(vec![]);
+        }
+        parse_plist_buddy_array(&String::from_utf8_lossy(&output.stdout))
+            .with_context(|| format!("reading the exclusions from {}", Self::volume_config()))
+    }
+}
+
+impl Exclusion for SpotlightPrivacy {
+    fn apply(&self, mount_point: &str) -> Result<()> {
+        if self.is_applied(mount_point)? {
+            return Ok(());
+        }
+        let config = Self::volume_config();
+        // This fails if the array already exists, which is fine
+        new_cmd_with_root_privs(Self::PLIST_BUDDY)
+            .args(&["-c", "Add :Exclusions array", config])
+            .run()?;
+        // System Integrity Protection prevents this from working on
+        // some systems; we report that as a failure to the caller.
+        let output = new_cmd_with_root_privs(Self::PLIST_BUDDY)
+            .args(&[
+                "-c",
+                &format!("Add :Exclusions: string {}", mount_point),
+                config,
+            ])
+            .run()?;
+        if !output.status.success() {
+            bail!("failed to execute PlistBuddy on {}: {:#?}", config, output);
+        }
+        Ok(())
+    }
+
+    fn is_applied(&self, mount_point: &str) -> Result<bool> {
+        Ok(Self::exclusions()?.iter().any(|path| path == mount_point))
+    }
+
+    fn remove(&self, mount_point: &str) -> Result<()> {
+        let config = Self::volume_config();
+        // We delete from the end, so that the indices of the entries
+        // that we have yet to delete stay put
+        for (index, _) in Self::exclusions()?
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, path)| *path == mount_point)
+        {
+            let output = new_cmd_with_root_privs(Self::PLIST_BUDDY)
+                .args(&["-c", &format!("Delete :Exclusions:{}", index), config])
+                .run()?;
+            if !output.status.success() {
+                bail!("failed to execute PlistBuddy on {}: {:#?}", config, output);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct MetadataNeverIndex;
+
+impl Exclusion for MetadataNeverIndex {
+    fn apply(&self, mount_point: &str) -> Result<()> {
+        let marker = Path::new(mount_point).join(".metadata_never_index");
+        with_real_user_privs(|| {
+            std::fs::write(&marker, b"").with_context(|| format!("creating {}", marker.display()))
+        })
+    }
+
+    fn is_applied(&self, mount_point: &str) -> Result<bool> {
+        Ok(Path::new(mount_point)
+            .join(".metadata_never_index")
+            .exists())
+    }
+}
+
+/// See https://developer.apple.com/library/archive/documentation/Darwin/Conceptual/FSEvents_ProgGuide/FileSystemEventSecurity/FileSystemEventSecurity.html#//apple_ref/doc/uid/TP40005289-CH6-SW5
+/// This is the documented approach; it is opt-in because the presence of
+/// the `.fseventsd` directory confuses some tools (see `disable_fsevents`).
+struct FseventsdNoLog;
+
+impl Exclusion for FseventsdNoLog {
+    fn apply(&self, mount_point: &str) -> Result<()> {
+        // The root of the volume belongs to the user, who could replace
+        // `.fseventsd` with a symlink to anywhere, so we don't follow it
+        // with our root privs
+        let fseventsd = Path::new(mount_point).join(".fseventsd");
+        let no_log = fseventsd.join("no_log");
+        with_real_user_privs(|| {
+            std::fs::create_dir_all(&fseventsd)
+                .with_context(|| format!("creating {}", fseventsd.display()))?;
+            std::fs::write(&no_log, b"").with_context(|| format!("creating {}", no_log.display()))
+        })
+    }
+
+    fn is_applied(&self, mount_point: &str) -> Result<bool> {
+        Ok(Path::new(mount_point).join(".fseventsd/no_log").exists())
+    }
+}
+
+/// The extended attribute that Time Machine checks to decide whether
+/// to skip an item.  This is what `tmutil addexclusion` sets on a path.
+const BACKUP_EXCLUSION_XATTR: &str = "com.apple.metadata:com_apple_backup_excludeItem";
+
+/// The value of `BACKUP_EXCLUSION_XATTR`: the string `com.apple.backupd`
+/// encoded as a binary plist, in hex form as consumed by `xattr -wx`.
+const BACKUP_EXCLUSION_VALUE: &str = "62706c6973743030\
+                                      5f1011636f6d2e6170706c652e6261636b757064\
+                                      08\
+                                      0000000000000101\
+                                      0000000000000001\
+                                      0000000000000000\
+                                      000000000000001c";
+
+/// Set the backup exclusion attribute on the root of the volume.
+/// Unlike a volume level `tmutil` exclusion, this is stored with the
+/// data and so it remains in effect wherever the volume is mounted.
+/// The root is owned by the user by the time we get here, so we don't
+/// need any privs to do this.
+pub(super) fn exclude_from_backups(mount_point: &str) -> Result<()> {
+    let output = new_cmd_unprivileged(XATTR)
+        .args(&[
+            "-wx",
+            BACKUP_EXCLUSION_XATTR,
+            BACKUP_EXCLUSION_VALUE,
+            mount_point,
+        ])
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "failed to execute xattr -wx on {}: {:#?}",
+            mount_point,
+            output
+        );
+    }
+    Ok(())
+}
+
+/// Returns true if the backup exclusion attribute is set on the root of
+/// the volume mounted at `mount_point`.
+pub(super) fn is_excluded_from_backups(mount_point: &str) -> Result<bool> {
+    let output = new_cmd_unprivileged(XATTR)
+        .args(&["-px", BACKUP_EXCLUSION_XATTR, mount_point])
+        .run()?;
+    if !output.status.success() {
+        // The attribute isn't present
+        return Ok(false);
+    }
+    let actual = parse_xattr_hex(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("reading {} from {}", BACKUP_EXCLUSION_XATTR, mount_point))?;
+    Ok(actual == BACKUP_EXCLUSION_VALUE)
+}
@@ -0,0 +1,247 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Finding the volumes that have gone stale because their mount point
+//! went away or changed hands: `audit` reports them and `gc` deletes
+//! them.
+
+use super::{delete_scratch, list_containers, print_change_summary, print_report, KeepRule};
+use anyhow::*;
+use eden_apfs_mount_helper::apfs::{apfs_list, ApfsVolume};
+use eden_apfs_mount_helper::dry_run::dry_run;
+use eden_apfs_mount_helper::host::{load_host_id, read_volume_tag, volume_origin};
+use eden_apfs_mount_helper::mount_table::MountTable;
+use eden_apfs_mount_helper::privs::drop_privileges;
+use eden_apfs_mount_helper::registry::{MountRecord, Registry, KEEP_LABEL};
+use eden_apfs_mount_helper::report::{
+    AuditFinding, AuditKind, AuditReport, ChangeSummary, OutputFormat, VolumeOrigin,
+};
+use eden_apfs_mount_helper::volume_name::{decode_volume_name, is_edenfs_volume_name};
+use std::os::unix::fs::MetadataExt;
+
+/// Explain why the volume for `mount_point` is an orphan, or return None
+/// if it isn't one.  We look at the mount point with our root privs so
+/// that a directory that the real user can't see isn't mistaken for one
+/// that is gone.
+fn orphan_reason(mount_point: &str, creator: Option<u32>) -> Result<Option<String>> {
+    match std::fs::symlink_metadata(mount_point) {
+        Ok(metadata) => Ok(match creator {
+            Some(uid) if metadata.uid() != uid => Some(format!(
+                "is owned by uid {} rather than its creator, uid {}",
+                metadata.uid(),
+                uid
+            )),
+            _ => None,
+        }),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Some("no longer exists".to_owned()))
+        }
+        Err(err) => Err(err).context(format!("inspecting {}", mount_point)),
+    }
+}
+
+/// Find the problems with `vol` that `audit` reports, if any
+fn audit_volume(vol: &ApfsVolume, registry: &Registry) -> Result<Option<(AuditKind, String)>> {
+    let name = match vol.name.as_deref() {
+        Some(name) if is_edenfs_volume_name(name) => name,
+        _ => return Ok(None),
+    };
+    let mount_point = match decode_volume_name(name) {
+        Some(mount_point) => mount_point,
+        None => {
+            return Ok(Some((
+                AuditKind::InvalidName,
+                "the name doesn't decode to a mount point".to_owned(),
+            )));
+        }
+    };
+    let metadata = match std::fs::symlink_metadata(mount_point) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Some((
+                AuditKind::MissingMountPoint,
+                format!("{} no longer exists", mount_point),
+            )));
+        }
+        Err(err) => return Err(err).context(format!("inspecting {}", mount_point)),
+    };
+    let creator = registry
+        .mounts
+        .get(mount_point)
+        .and_then(|record| record.creator);
+    Ok(match creator {
+        Some(uid) if metadata.uid() != uid => Some((
+            AuditKind::WrongOwner,
+            format!(
+                "{} no longer belongs to its creator, uid {}",
+                mount_point, uid
+            ),
+        )),
+        _ => None,
+    })
+}
+
+pub(super) fn audit_volumes() -> Result<AuditReport> {
+    let registry = Registry::load()?;
+    let mut findings = vec![];
+    for container in apfs_list()? {
+        for vol in &container.volumes {
+            if let Some((kind, detail)) = audit_volume(vol, &registry)? {
+                findings.push(AuditFinding {
+                    device_identifier: vol.device_identifier.clone(),
+                    name: vol.name.clone().unwrap_or_default(),
+                    kind,
+                    detail,
+                });
+            }
+        }
+    }
+    Ok(AuditReport { findings })
+}
+
+pub(super) fn show_audit(report: &AuditReport, format: OutputFormat) -> Result<()> {
+    if format == OutputFormat::Table {
+        for finding in &report.findings {
+            println!(
+                "{}\t{}\t{}\t{}",
+                finding.device_identifier,
+                finding.kind.name(),
+                finding.name,
+                finding.detail
+            );
+        }
+    } else {
+        print_report(report, format)?;
+    }
+    if !report.findings.is_empty() {
+        bail!("found {} stale volume(s)", report.findings.len());
+    }
+    Ok(())
+}
+
+/// Delete the volumes whose mount points are gone, along with the volumes
+/// whose mount points now belong to someone other than their creator.
+/// Unless `include_foreign` is set, only the volumes created on this host
+/// are deleted.  Volumes that match any of `keep_rules` are kept.
+pub(super) fn collect_garbage(
+    test_container: Option<&str>,
+    include_foreign: bool,
+    keep_rules: &[KeepRule],
+    format: OutputFormat,
+) -> Result<()> {
+    let containers = list_containers(test_container)?;
+    let mounts = MountTable::parse_system_mount_table()?;
+    let registry = Registry::load()?;
+    let host_id = load_host_id()?;
+    let mut report = vec![];
+    let mut summary = ChangeSummary::default();
+    for container in &containers {
+        for vol in &container.volumes {
+            let preferred = match vol.preferred_mount_point() {
+                Some(path) => path,
+                None => continue,
+            };
+            // A volume that is mounted where it belongs is in use
+            let current = vol.get_current_mount_point(Some(&mounts));
+            if current.as_ref() == Some(&preferred) {
+                continue;
+            }
+            let record = registry.mounts.get(&preferred);
+            let reason = match orphan_reason(&preferred, record.and_then(|record| record.creator)) {
+                Ok(Some(reason)) => reason,
+                Ok(None) => continue,
+                Err(err) => {
+                    report.push(Err(format!("Skipped {}: {:#}", preferred, err)));
+                    continue;
+                }
+            };
+            if record.map(MountRecord::is_kept).unwrap_or(false) {
+                report.push(Ok(format!(
+                    "Kept {}, which {}, because it is labeled {}",
+                    preferred, reason, KEEP_LABEL
+                )));
+                continue;
+            }
+            let label = record.and_then(|record| record.label.as_deref());
+            if let Some(rule) = keep_rules
+                .iter()
+                .find(|rule| rule.matches(&preferred, label))
+            {
+                report.push(Ok(format!(
+                    "Kept {}, which {}, because it matches the keep rule {}",
+                    preferred, reason, rule
+                )));
+                continue;
+            }
+            let origin = volume_origin(
+                current.as_deref().and_then(read_volume_tag).as_deref(),
+                host_id.as_deref(),
+                record.is_some(),
+            );
+            if origin != VolumeOrigin::Local && !include_foreign {
+                report.push(Ok(format!(
+                    "Kept {}, which {}, because its origin is {}; \
+                     use --include-foreign to delete it",
+                    preferred,
+                    reason,
+                    origin.name()
+                )));
+                continue;
+            }
+            // Deleting the volume unmounts it from wherever macOS put it.
+            // Nothing watches that location, so there's no need to finish
+            // the unmount.
+            let deleted = if dry_run() { "Would delete" } else { "Deleted" };
+            match delete_scratch(test_container, &preferred, &mut summary) {
+                Ok(_) => report.push(Ok(format!("{} {}, which {}", deleted, preferred, reason))),
+                Err(err) => report.push(Err(format!("Failed to delete {}: {:#}", preferred, err))),
+            }
+        }
+    }
+
+    drop_privileges()?;
+
+    for line in report {
+        match line {
+            Ok(line) => println!("{}", line),
+            Err(line) => eprintln!("{}", line),
+        }
+    }
+    print_change_summary(&summary, format)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_orphan_reason() {
+        let dir = std::env::temp_dir().join(format!("eden-apfs-orphan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.to_str().unwrap();
+        let uid = dir.metadata().unwrap().uid();
+
+        let owned = orphan_reason(path, Some(uid)).unwrap();
+        let unknown_creator = orphan_reason(path, None).unwrap();
+        let other = orphan_reason(path, Some(uid + 1)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let gone = orphan_reason(path, Some(uid)).unwrap();
+
+        assert_eq!(owned, None);
+        assert_eq!(unknown_creator, None);
+        assert_eq!(
+            other,
+            Some(format!(
+                "is owned by uid {} rather than its creator, uid {}",
+                uid,
+                uid + 1
+            ))
+        );
+        assert_eq!(gone, Some("no longer exists".to_owned()));
+    }
+}
@@ -0,0 +1,202 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! `policy verify`, which checks that the installed policy has the same
+//! settings as a reference copy, such as the one pushed out by MDM.
+
+use super::{read_policy, CONFIG_PATH};
+use anyhow::*;
+use eden_apfs_mount_helper::privs::{new_cmd_unprivileged, RunCommand};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Used to check the digest of a reference policy.  Like `CODESIGN`,
+/// this is deliberately not overridable by the policy.
+const SHASUM: &str = "/usr/bin/shasum";
+
+/// Extract the digest from the output of `shasum -a 256`, which looks
+/// like `<64 hex digits>  <path>`
+fn parse_sha256(text: &str) -> Option<String> {
+    let digest = text.split_whitespace().next()?;
+    if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(digest.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+fn sha256_digest(path: &Path) -> Result<String> {
+    let output = new_cmd_unprivileged(SHASUM)
+        .args(&["-a", "256"])
+        .arg(path)
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "failed to compute the digest of {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_sha256(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+        anyhow!(
+            "unexpected output from shasum for {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stdout).trim()
+        )
+    })
+}
+
+/// Flatten the tables in `value` into `settings`, keyed by the dotted
+/// name of each setting, eg: `thresholds.max_orphans`
+fn flatten_policy(prefix: &str, value: &toml::Value, settings: &mut BTreeMap<String, String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_policy(&name, value, settings);
+            }
+        }
+        value => {
+            settings.insert(prefix.to_owned(), value.to_string());
+        }
+    }
+}
+
+/// The settings that differ between two policies, by name, with their
+/// values in the installed policy and in the reference
+fn policy_differences(
+    installed: &toml::Value,
+    reference: &toml::Value,
+) -> Vec<(String, Option<String>, Option<String>)> {
+    let mut installed_settings = BTreeMap::new();
+    flatten_policy("", installed, &mut installed_settings);
+    let mut reference_settings = BTreeMap::new();
+    flatten_policy("", reference, &mut reference_settings);
+    let names: BTreeSet<&String> = installed_settings
+        .keys()
+        .chain(reference_settings.keys())
+        .collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let installed = installed_settings.get(name);
+            let reference = reference_settings.get(name);
+            if installed == reference {
+                None
+            } else {
+                Some((name.clone(), installed.cloned(), reference.cloned()))
+            }
+        })
+        .collect()
+}
+
+pub(super) fn verify_policy(reference: &Path) -> Result<()> {
+    let mut digest_path = reference.as_os_str().to_owned();
+    digest_path.push(".sha256");
+    let digest_path = PathBuf::from(digest_path);
+    let expected = std::fs::read_to_string(&digest_path)
+        .with_context(|| format!("reading {}", digest_path.display()))?;
+    let expected = parse_sha256(&expected)
+        .ok_or_else(|| anyhow!("{} doesn't hold a SHA-256 digest", digest_path.display()))?;
+    if sha256_digest(reference)? != expected {
+        bail!(
+            "{} doesn't match the digest in {}, so it can't be trusted",
+            reference.display(),
+            digest_path.display()
+        );
+    }
+    let reference_text = std::fs::read_to_string(reference)
+        .with_context(|| format!("reading {}", reference.display()))?;
+    let reference_policy: toml::Value = toml::from_str(&reference_text)
+        .with_context(|| format!("parsing {}", reference.display()))?;
+    let (origin, installed_policy) = match read_policy()? {
+        Some((origin, text)) => {
+            let policy = toml::from_str(&text).with_context(|| format!("parsing {}", origin))?;
+            (origin, policy)
+        }
+        None => (
+            CONFIG_PATH.to_owned(),
+            toml::Value::Table(Default::default()),
+        ),
+    };
+
+    let differences = policy_differences(&installed_policy, &reference_policy);
+    for (name, installed, reference) in &differences {
+        println!(
+            "{}\t{}\t{}",
+            name,
+            installed.as_deref().unwrap_or("-"),
+            reference.as_deref().unwrap_or("-")
+        );
+    }
+    if !differences.is_empty() {
+        bail!(
+            "{} differs from {} in {} setting(s)",
+            origin,
+            reference.display(),
+            differences.len()
+        );
+    }
+    println!("{} matches {}", origin, reference.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_sha256() {
+        let digest = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        assert_eq!(
+            parse_sha256(&format!("{}  /etc/policy.toml\n", digest)),
+            Some(digest.to_owned())
+        );
+        assert_eq!(
+            parse_sha256(&digest.to_ascii_uppercase()),
+            Some(digest.to_owned())
+        );
+        assert_eq!(parse_sha256(""), None);
+        assert_eq!(parse_sha256("9f86d081  /etc/policy.toml"), None);
+        assert_eq!(parse_sha256(&format!("{}z", &digest[1..])), None);
+    }
+
+    #[test]
+    fn test_policy_differences() {
+        let installed: toml::Value = toml::from_str(
+            "version = \"1\"\n\
+             [thresholds]\n\
+             max_orphans = 2\n\
+             [watchman]\n\
+             mode = \"off\"\n",
+        )
+        .unwrap();
+        let reference: toml::Value = toml::from_str(
+            "version = \"2\"\n\
+             [thresholds]\n\
+             max_orphans = 2\n\
+             [gc]\n\
+             keep = [\"label:release*\"]\n",
+        )
+        .unwrap();
+        let some = |value: &str| Some(value.to_owned());
+        assert_eq!(
+            policy_differences(&installed, &reference),
+            vec![
+                ("gc.keep".to_owned(), None, some("[\"label:release*\"]")),
+                ("version".to_owned(), some("\"1\""), some("\"2\"")),
+                ("watchman.mode".to_owned(), some("\"off\""), None),
+            ]
+        );
+        assert_eq!(policy_differences(&installed, &installed), vec![]);
+    }
+}
@@ -0,0 +1,362 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Moving a volume: `rename` gives a volume a new mount point, and
+//! `migrate-from-symlink` puts a volume where a symlink or a directory
+//! used to redirect a scratch directory.
+
+use super::exclusions::remove_exclusions;
+use super::{
+    canonicalize_mount_point_path, expand_user_path, finish_mount, finish_unmount, list_containers,
+    mount_scratch_space_on, normalize_mount_point, print_structured_change_summary,
+    resolve_volume_name, roll_back_mounts, unmount_scratch, volume_belongs_to, Config, MountFlags,
+    NewVolume, DITTO,
+};
+use anyhow::*;
+use eden_apfs_mount_helper::apfs::{find_existing_volume, rename_volume};
+use eden_apfs_mount_helper::mount_table::MountTable;
+use eden_apfs_mount_helper::paths::normalize_path;
+use eden_apfs_mount_helper::privs::{
+    drop_privileges, get_real_uid, get_real_user_home, new_cmd_unprivileged, with_real_user_privs,
+    RunCommand,
+};
+use eden_apfs_mount_helper::registry::{record_history, record_rename, Registry};
+use eden_apfs_mount_helper::report::{ChangeSummary, OutputFormat};
+use eden_apfs_mount_helper::volume_name::encode_mount_point_as_volume_name;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Move the volume for `from` to `to` by renaming it, and mount it there
+pub(super) fn rename_scratch(
+    config: &Config,
+    test_container: Option<&str>,
+    from: &str,
+    to: &str,
+    format: Option<OutputFormat>,
+) -> Result<()> {
+    let mut summary = ChangeSummary::default();
+    let result = move_volume(config, test_container, from, to, &mut summary);
+    drop_privileges()?;
+    let (unmounted, mounted) = result?;
+    if let Some(unmounted) = unmounted {
+        finish_unmount(config, &unmounted);
+    }
+    finish_mount(config, &mounted);
+    println!("Moved {} to {}", from, mounted);
+    print_structured_change_summary(&summary, format)
+}
+
+/// Returns the location that the volume was unmounted from, if it was
+/// mounted, along with its new mount point
+fn move_volume(
+    config: &Config,
+    test_container: Option<&str>,
+    from: &str,
+    to: &str,
+    summary: &mut ChangeSummary,
+) -> Result<(Option<String>, String)> {
+    let (from, from_name) = resolve_volume_name(from);
+    let to = with_real_user_privs(|| canonicalize_mount_point_path(to))?;
+    let to_name = encode_mount_point_as_volume_name(&to);
+    let containers = list_containers(test_container)?;
+    let volume = find_existing_volume(&containers, &from_name)
+        .cloned()
+        .ok_or_else(|| anyhow!("Did not find a volume named {}", from_name))?;
+    if find_existing_volume(&containers, &to_name).is_some() {
+        bail!("There is already a volume named {}", to_name);
+    }
+    let my_uid = get_real_uid()?;
+    if !volume_belongs_to(&from, &Registry::load()?, my_uid) {
+        bail!(
+            "Refusing to move the volume for {} because it doesn't belong to uid {}",
+            from,
+            my_uid
+        );
+    }
+    // Mounting checks this again, but by then the volume and its record
+    // would already have moved
+    let metadata = with_real_user_privs(|| {
+        std::fs::metadata(&to).with_context(|| format!("checking {}", to))
+    })?;
+    if metadata.uid() != my_uid {
+        bail!(
+            "Refusing to move the volume for {} to {} because the owned uid {} doesn't match your uid {}",
+            from,
+            to,
+            metadata.uid(),
+            my_uid
+        );
+    }
+
+    let mounts = MountTable::parse_system_mount_table()?;
+    let unmounted = match volume.get_current_mount_point(Some(&mounts)) {
+        Some(current) => {
+            let unmounted = unmount_scratch(&current, false, &mounts)?;
+            summary.unmounted.push(unmounted.clone());
+            Some(unmounted)
+        }
+        None => None,
+    };
+
+    let result = rename_volume(&volume, &to_name);
+    if result.is_err() {
+        record_history(&from, "rename", &result);
+    }
+    result?;
+    record_rename(&from, &to);
+    // Mounting the volume applies the exclusions for `to`
+    remove_exclusions(&from);
+
+    // This finds the renamed volume
+    let mounted = mount_scratch_space_on(
+        config,
+        test_container,
+        &NewVolume::default(),
+        &MountFlags::default(),
+        &to,
+        None,
+        summary,
+    )?;
+    Ok((unmounted, mounted))
+}
+
+/// The suffix of the name that `migrate-from-symlink` moves a plain
+/// directory to while the volume is mounted in its place
+const MIGRATE_SUFFIX: &str = ".eden-migrate";
+
+/// How the contents of a mount point are kept before it has a volume
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Redirection {
+    /// The mount point is a symlink, which reads as `link`, to the
+    /// directory `target`
+    Symlink { link: PathBuf, target: PathBuf },
+    /// The mount point is a plain directory
+    Directory,
+}
+
+impl Redirection {
+    fn describe(&self) -> &'static str {
+        match self {
+            Redirection::Symlink { .. } => "a symlink",
+            Redirection::Directory => "a directory",
+        }
+    }
+}
+
+/// Normalize `path` without following its last component, which
+/// `migrate-from-symlink` replaces rather than resolves
+fn normalize_link_path(path: &str) -> Result<String> {
+    let expanded = expand_user_path(path, get_real_user_home)?;
+    let cwd = std::env::current_dir().context("finding the current directory")?;
+    let path = normalize_path(&expanded, &cwd)?;
+    let (parent, name) = match path.rsplit_once('/') {
+        Some((parent, name)) if !name.is_empty() => (parent, name),
+        _ => bail!("{} can't be a mount point", path),
+    };
+    let parent = normalize_mount_point(if parent.is_empty() { "/" } else { parent })?;
+    Ok(format!("{}/{}", parent.trim_end_matches('/'), name))
+}
+
+/// Work out how the contents of `mount_point` are kept.  This must be
+/// called with the privs of the real user.
+fn detect_redirection(mount_point: &str) -> Result<Redirection> {
+    let path = Path::new(mount_point);
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => bail!(
+            "there is nothing at {} to migrate; `mount` sets up a new volume",
+            mount_point
+        ),
+        Err(err) => return Err(err).context(format!("inspecting {}", mount_point)),
+    };
+    let parent = path.parent().unwrap_or(path);
+    if metadata.file_type().is_symlink() {
+        let link = std::fs::read_link(path).with_context(|| format!("reading {}", mount_point))?;
+        let target = std::fs::canonicalize(parent.join(&link))
+            .with_context(|| format!("resolving the symlink {}", mount_point))?;
+        if !target.is_dir() {
+            bail!(
+                "{} links to {}, which is not a directory",
+                mount_point,
+                target.display()
+            );
+        }
+        return Ok(Redirection::Symlink { link, target });
+    }
+    if !metadata.is_dir() {
+        bail!("{} is neither a symlink nor a directory", mount_point);
+    }
+    let parent_metadata = parent
+        .metadata()
+        .with_context(|| format!("inspecting {}", parent.display()))?;
+    if metadata.dev() != parent_metadata.dev() {
+        bail!(
+            "{} is already a mount point, so there is nothing to migrate",
+            mount_point
+        );
+    }
+    Ok(Redirection::Directory)
+}
+
+/// Replace the redirection at `mount_point` with a volume and move its
+/// contents into the volume
+pub(super) fn migrate_from_symlink(
+    config: &Config,
+    test_container: Option<&str>,
+    mount_point: &str,
+    format: Option<OutputFormat>,
+) -> Result<()> {
+    let mount_point = normalize_link_path(mount_point)?;
+    let mut summary = ChangeSummary::default();
+    let result = mount_in_place_of_redirection(config, test_container, &mount_point, &mut summary);
+    drop_privileges()?;
+    let (redirection, contents) = result?;
+    finish_mount(config, &mount_point);
+
+    let output = new_cmd_unprivileged(DITTO)
+        .arg(&contents)
+        .arg(&mount_point)
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "failed to copy {} into the volume at {}, so it has been left in place: {}",
+            contents.display(),
+            mount_point,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    if let Err(err) = std::fs::remove_dir_all(&contents) {
+        eprintln!(
+            "failed to remove {} after copying it into the volume: {}",
+            contents.display(),
+            err
+        );
+    }
+    println!(
+        "Migrated {} from {} to a volume",
+        mount_point,
+        redirection.describe()
+    );
+    print_structured_change_summary(&summary, format)
+}
+
+/// Move the redirection at `mount_point` out of the way and mount a new
+/// volume there.  If that fails, the new volume is deleted and the
+/// redirection is put back.  Returns the
+/// redirection along with the directory whose contents belong in the
+/// volume.
+fn mount_in_place_of_redirection(
+    config: &Config,
+    test_container: Option<&str>,
+    mount_point: &str,
+    summary: &mut ChangeSummary,
+) -> Result<(Redirection, PathBuf)> {
+    let redirection = with_real_user_privs(|| detect_redirection(mount_point))?;
+    let name = encode_mount_point_as_volume_name(mount_point);
+    if find_existing_volume(&list_containers(test_container)?, &name).is_some() {
+        bail!(
+            "There is already a volume named {}; `mount` will mount it",
+            name
+        );
+    }
+
+    let staging = PathBuf::from(format!("{}{}", mount_point, MIGRATE_SUFFIX));
+    let contents = with_real_user_privs(|| {
+        let contents = match &redirection {
+            Redirection::Symlink { target, .. } => {
+                std::fs::remove_file(mount_point)
+                    .with_context(|| format!("removing the symlink {}", mount_point))?;
+                target.clone()
+            }
+            Redirection::Directory => {
+                if staging.symlink_metadata().is_ok() {
+                    bail!(
+                        "{} is in the way of moving {} aside",
+                        staging.display(),
+                        mount_point
+                    );
+                }
+                std::fs::rename(mount_point, &staging)
+                    .with_context(|| format!("moving {} aside", mount_point))?;
+                staging.clone()
+            }
+        };
+        std::fs::create_dir(mount_point)
+            .with_context(|| format!("creating the mount point {}", mount_point))?;
+        Ok(contents)
+    })?;
+
+    let result = mount_scratch_space_on(
+        config,
+        test_container,
+        &NewVolume::default(),
+        &MountFlags::default(),
+        mount_point,
+        None,
+        summary,
+    );
+    if let Err(err) = result {
+        roll_back_mounts(test_container, summary);
+        let restored = with_real_user_privs(|| {
+            std::fs::remove_dir(mount_point)
+                .with_context(|| format!("removing the mount point {}", mount_point))?;
+            match &redirection {
+                Redirection::Symlink { link, .. } => std::os::unix::fs::symlink(link, mount_point)
+                    .with_context(|| format!("restoring the symlink {}", mount_point)),
+                Redirection::Directory => std::fs::rename(&staging, mount_point)
+                    .with_context(|| format!("moving {} back", mount_point)),
+            }
+        });
+        if let Err(restore_err) = restored {
+            eprintln!(
+                "failed to put {} back the way it was; its contents are in {}: {:#}",
+                mount_point,
+                contents.display(),
+                restore_err
+            );
+        }
+        return Err(err);
+    }
+    Ok((redirection, contents))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_detect_redirection() {
+        let dir = std::env::temp_dir().join(format!("eden-apfs-redirect-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("plain")).unwrap();
+        std::fs::create_dir_all(dir.join("storage/buck-out")).unwrap();
+        std::fs::write(dir.join("file"), "").unwrap();
+        std::os::unix::fs::symlink("storage/buck-out", dir.join("link")).unwrap();
+        std::os::unix::fs::symlink("file", dir.join("file-link")).unwrap();
+        let dir = std::fs::canonicalize(&dir).unwrap();
+
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        let plain = detect_redirection(&path("plain"));
+        let link = detect_redirection(&path("link"));
+        let file = detect_redirection(&path("file"));
+        let file_link = detect_redirection(&path("file-link"));
+        let missing = detect_redirection(&path("missing"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(plain.unwrap(), Redirection::Directory);
+        assert_eq!(
+            link.unwrap(),
+            Redirection::Symlink {
+                link: PathBuf::from("storage/buck-out"),
+                target: dir.join("storage/buck-out"),
+            }
+        );
+        assert!(file.is_err());
+        assert!(file_link.is_err());
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,94 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Detecting whether a path lies inside a directory that a cloud storage
+//! service keeps in sync, such as iCloud Drive or Dropbox.  Their sync
+//! daemons don't expect a volume to appear inside the directories that
+//! they manage, and fight with it: uploading build output, or trying to
+//! evict it to make space.
+
+use std::path::Path;
+
+/// The directory, relative to a home directory, in which the File
+/// Provider framework keeps the files of services such as Dropbox,
+/// OneDrive and Google Drive.  Each service has a subdirectory named
+/// `<Service>-<Account>`.
+const CLOUD_STORAGE_DIR: &str = "Library/CloudStorage";
+
+/// The directory, relative to a home directory, that holds iCloud Drive
+const ICLOUD_DRIVE_DIR: &str = "Library/Mobile Documents";
+
+/// Files that mark the root of a directory synced by a service that
+/// predates the File Provider framework
+const SYNC_ROOT_MARKERS: &[(&str, &str)] = &[(".dropbox", "Dropbox")];
+
+/// Return the name of the service that keeps `path` in sync, if any.
+/// `path` should be canonical, as the services are often reached through
+/// symlinks such as `~/Dropbox`.
+pub fn cloud_sync_service(path: &Path) -> Option<String> {
+    path.ancestors().find_map(|dir| {
+        service_by_location(dir).or_else(|| {
+            SYNC_ROOT_MARKERS
+                .iter()
+                .find(|(marker, _)| dir.join(marker).is_file())
+                .map(|(_, service)| (*service).to_owned())
+        })
+    })
+}
+
+/// Return the name of the service that keeps `dir` in sync, judging
+/// only by where it is
+fn service_by_location(dir: &Path) -> Option<String> {
+    if dir.ends_with(ICLOUD_DRIVE_DIR) {
+        return Some("iCloud Drive".to_owned());
+    }
+    if dir.parent()?.ends_with(CLOUD_STORAGE_DIR) {
+        let name = dir.file_name()?.to_str()?;
+        let service = name.split('-').next().unwrap_or(name);
+        return Some(service.to_owned());
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_service_by_location() {
+        let service = |path: &str| cloud_sync_service(Path::new(path));
+        assert_eq!(service("/Users/wez/fbsource/buck-out"), None);
+        assert_eq!(
+            service("/Users/wez/Library/Mobile Documents/com~apple~CloudDocs/repo/buck-out"),
+            Some("iCloud Drive".to_owned())
+        );
+        assert_eq!(
+            service("/Users/wez/Library/CloudStorage/Dropbox-Personal/repo/buck-out"),
+            Some("Dropbox".to_owned())
+        );
+        assert_eq!(
+            service("/Users/wez/Library/CloudStorage/OneDrive-Contoso"),
+            Some("OneDrive".to_owned())
+        );
+        assert_eq!(service("/Users/wez/Library/CloudStorage"), None);
+    }
+
+    #[test]
+    fn test_sync_root_marker() {
+        let dir = std::env::temp_dir().join(format!("eden-apfs-sync-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("Dropbox/repo")).unwrap();
+        std::fs::write(dir.join("Dropbox/.dropbox"), "{}").unwrap();
+
+        let inside = cloud_sync_service(&dir.join("Dropbox/repo/buck-out"));
+        let outside = cloud_sync_service(&dir.join("repo/buck-out"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(inside, Some("Dropbox".to_owned()));
+        assert_eq!(outside, None);
+    }
+}
@@ -0,0 +1,464 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Sparse bundle disk images that stand in for APFS volumes on hosts
+//! whose MDM policy forbids creating volumes.
+//! Each image holds a single APFS volume with the same name that the
+//! volume would have had, so once the image is attached its volume shows
+//! up in `diskutil apfs list` in a container of its own, and is mounted,
+//! listed and unmounted like any other volume.  The images live in a
+//! root-owned directory, at a path derived from the name of the volume,
+//! which is how we tell that a volume is backed by an image.
+//! Images are attached by the first mount after a reboot and stay
+//! attached until their volume is deleted or the image is compacted, so
+//! `list` only shows the volumes of the images that have been mounted
+//! since then.
+//! The images grow as their volumes fill up, but don't shrink when files
+//! are deleted until they are compacted, which `compact-images` does for
+//! the images whose volumes aren't mounted.
+
+use crate::apfs::{
+    apfs_list, check_interlock, disk_info, find_existing_volume, run_plist_command, whole_disk,
+};
+use crate::apfs::{ApfsContainer, ApfsVolume, VolumeOptions};
+use crate::dry_run::dry_run;
+use crate::privs::{new_cmd_unprivileged, new_cmd_with_root_privs, RunCommand};
+use crate::timeouts::Operation;
+use anyhow::*;
+use serde::Deserialize;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+const HDIUTIL: &str = "/usr/bin/hdiutil";
+
+const IMAGE_SUFFIX: &str = ".sparsebundle";
+
+/// The directory that holds the images
+pub fn images_dir() -> PathBuf {
+    crate::registry::registry_dir().join("images")
+}
+
+/// The image for the volume `name`, eg:
+/// `images/edenfs/Users/me/repo/buck-out.sparsebundle` for
+/// `edenfs:/Users/me/repo/buck-out`
+pub fn image_path(name: &str) -> Result<PathBuf> {
+    let (prefix, mount_point) = name
+        .split_once(':')
+        .ok_or_else(|| anyhow!("{} is not the name of one of our volumes", name))?;
+    let relative = Path::new(prefix).join(mount_point.trim_start_matches('/'));
+    if prefix.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("refusing to derive an image path from {:?}", name);
+    }
+    let mut path = images_dir().join(relative).into_os_string();
+    path.push(IMAGE_SUFFIX);
+    Ok(path.into())
+}
+
+/// The names of the volumes with the prefix in effect that have images,
+/// eg: `edenfs:/Users/me/repo/buck-out`
+pub fn list_images() -> Result<Vec<String>> {
+    let prefix = crate::volume_name::volume_prefix().trim_end_matches(':');
+    let root = images_dir().join(prefix);
+    let mut names = vec![];
+    let mut dirs = vec![root.clone()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).context(format!("reading {}", dir.display())),
+        };
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("reading {}", dir.display()))?
+                .path();
+            if !path.is_dir() {
+                continue;
+            }
+            match image_name(prefix, &root, &path) {
+                Some(name) => names.push(name),
+                None => dirs.push(path),
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// The name of the volume in the image at `path`, which is somewhere
+/// under `root`, the directory of the images for `prefix`, or `None` if
+/// `path` isn't an image; the reverse of `image_path`
+fn image_name(prefix: &str, root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?.to_str()?;
+    let mount_point = relative.strip_suffix(IMAGE_SUFFIX)?;
+    Some(format!("{}:/{}", prefix, mount_point))
+}
+
+/// Whether there is an image for the volume `name`
+pub fn has_image(name: &str) -> bool {
+    image_path(name).map(|path| path.exists()).unwrap_or(false)
+}
+
+/// The argument to `hdiutil create -size` for an image that can hold
+/// `bytes`, rounded up to whole megabytes
+fn size_arg(bytes: u64) -> String {
+    format!("{}m", bytes.div_ceil(1024 * 1024).max(1))
+}
+
+/// The arguments to `hdiutil create` for an image at `path` holding the
+/// volume `name`
+fn create_args(name: &str, options: &VolumeOptions, size: u64, path: &Path) -> Vec<String> {
+    let fs = if options.case_sensitive {
+        "Case-sensitive APFS"
+    } else {
+        "APFS"
+    };
+    vec![
+        "create".to_owned(),
+        "-type".to_owned(),
+        "SPARSEBUNDLE".to_owned(),
+        "-fs".to_owned(),
+        fs.to_owned(),
+        "-volname".to_owned(),
+        name.to_owned(),
+        "-size".to_owned(),
+        size_arg(options.quota.unwrap_or(size)),
+        path.to_string_lossy().into_owned(),
+    ]
+}
+
+/// The subset of the output from `hdiutil info -plist` that we use
+#[derive(Deserialize, Debug)]
+struct HdiutilInfo {
+    #[serde(default)]
+    images: Vec<AttachedImage>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct AttachedImage {
+    image_path: PathBuf,
+    #[serde(default)]
+    system_entities: Vec<SystemEntity>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct SystemEntity {
+    /// eg: `/dev/disk5s1`
+    dev_entry: String,
+}
+
+impl HdiutilInfo {
+    /// The disk that the image at `path` is attached as, if it is, eg:
+    /// `disk5`
+    fn attached_disk(&self, path: &Path) -> Option<&str> {
+        self.images
+            .iter()
+            .find(|image| image.image_path == path)?
+            .system_entities
+            .iter()
+            .find_map(|entity| entity.dev_entry.strip_prefix("/dev/"))
+            .map(whole_disk)
+    }
+
+    /// Whether the image at `path` is attached as the disk `disk`, eg:
+    /// `disk5`
+    fn is_attached_as(&self, path: &Path, disk: &str) -> bool {
+        self.images.iter().any(|image| {
+            image.image_path == path
+                && image.system_entities.iter().any(|entity| {
+                    entity
+                        .dev_entry
+                        .strip_prefix("/dev/")
+                        .is_some_and(|dev| whole_disk(dev) == disk)
+                })
+        })
+    }
+}
+
+/// Whether the image at `path` is attached as the disk `disk`, according
+/// to hdiutil
+fn is_image_attached_as(path: &Path, disk: &str) -> Result<bool> {
+    let info: HdiutilInfo =
+        run_plist_command(new_cmd_unprivileged(HDIUTIL).args(&["info", "-plist"]))?;
+    Ok(info.is_attached_as(path, disk))
+}
+
+/// Attach the image at `path` without mounting its volume
+pub fn attach_image(path: &Path) -> Result<()> {
+    let output = new_cmd_with_root_privs(HDIUTIL)
+        .args(&["attach", "-nomount", "-noverify", "-noautofsck"])
+        .arg(path)
+        .run_timed(Operation::Mount)?;
+    if !output.status.success() {
+        bail!(
+            "failed to attach {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Create an image holding the volume `name`, which is `size` bytes
+/// unless the options give it a quota, and attach it, returning its
+/// volume
+pub fn make_image_volume(name: &str, options: &VolumeOptions, size: u64) -> Result<ApfsVolume> {
+    if options.passphrase.is_some() {
+        bail!("disk images can't be encrypted; use the apfs backend for --encrypted");
+    }
+    if options.reserve.is_some() {
+        bail!("disk images can't reserve space; use the apfs backend for --reserve");
+    }
+    let path = image_path(name)?;
+    if path.exists() {
+        bail!("there is already an image at {}", path.display());
+    }
+    if !dry_run() {
+        if let Some(parent) = path.parent() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)
+                .with_context(|| format!("creating {}", parent.display()))?;
+        }
+    }
+    let output = new_cmd_with_root_privs(HDIUTIL)
+        .args(create_args(name, options, size, &path))
+        .run_timed(Operation::Create)?;
+    if !output.status.success() {
+        bail!(
+            "failed to create the disk image {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    attach_image(&path)?;
+    if dry_run() {
+        return Ok(ApfsVolume {
+            device_identifier: "disk<image>s1".to_owned(),
+            name: Some(name.to_owned()),
+            ..Default::default()
+        });
+    }
+    find_existing_volume(&apfs_list()?, name)
+        .cloned()
+        .ok_or_else(|| {
+            anyhow!(
+                "attached {} but found no volume named {}",
+                path.display(),
+                name
+            )
+        })
+}
+
+/// Detach the image that holds `volume`, which lives in `container`, and
+/// remove it.  As with `delete_volume`, `volume` must be the volume that
+/// we created for `mount_point`, which is checked again first, and the
+/// disk that holds `container` must be the image, rather than a real
+/// disk that happens to hold a volume with the same name.
+pub fn delete_image_volume(
+    volume: &ApfsVolume,
+    container: &ApfsContainer,
+    mount_point: &str,
+) -> Result<()> {
+    let info = disk_info(&volume.device_identifier)?;
+    check_interlock(volume, &info, mount_point, "delete")?;
+    let name = volume.name.as_deref().unwrap_or("");
+    let disk = container
+        .physical_stores
+        .first()
+        .map(|store| whole_disk(&store.device_identifier))
+        .ok_or_else(|| {
+            anyhow!(
+                "can't tell which disk holds {}",
+                container.container_reference
+            )
+        })?;
+    let path = image_path(name)?;
+    if !is_image_attached_as(&path, disk)? {
+        bail!(
+            "refusing to detach {}, which holds {} but isn't attached from {}",
+            disk,
+            volume.device_identifier,
+            path.display()
+        );
+    }
+    let output = new_cmd_with_root_privs(HDIUTIL)
+        .args(&["detach", disk])
+        .run_timed(Operation::Unmount)?;
+    if !output.status.success() {
+        bail!(
+            "failed to detach the disk image of {}: {}",
+            volume.device_identifier,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    remove_image(name)
+}
+
+/// The space that the files under `path` take on disk
+fn disk_usage(path: &Path) -> Result<u64> {
+    let metadata =
+        std::fs::symlink_metadata(path).with_context(|| format!("reading {}", path.display()))?;
+    let mut bytes = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        for entry in
+            std::fs::read_dir(path).with_context(|| format!("reading {}", path.display()))?
+        {
+            let entry = entry.with_context(|| format!("reading {}", path.display()))?;
+            bytes += disk_usage(&entry.path())?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// Detach the image for the volume `name`, if it is attached, and compact
+/// it, returning the bytes reclaimed.  The volume must not be mounted,
+/// which hdiutil checks for us, as the image isn't detached by force.
+/// The next mount attaches the image again.
+pub fn compact_image(name: &str) -> Result<u64> {
+    let path = image_path(name)?;
+    let info: HdiutilInfo =
+        run_plist_command(new_cmd_unprivileged(HDIUTIL).args(&["info", "-plist"]))?;
+    if let Some(disk) = info.attached_disk(&path) {
+        let output = new_cmd_with_root_privs(HDIUTIL)
+            .args(&["detach", disk])
+            .run_timed(Operation::Unmount)?;
+        if !output.status.success() {
+            bail!(
+                "failed to detach {} from {}: {}",
+                disk,
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    let before = disk_usage(&path)?;
+    // Compacting takes as long as it takes, as it has to move the data
+    // in the image around
+    let output = new_cmd_with_root_privs(HDIUTIL)
+        .arg("compact")
+        .arg(&path)
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "failed to compact {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(before.saturating_sub(disk_usage(&path)?))
+}
+
+/// Remove the image for the volume `name`, which must not be attached
+pub fn remove_image(name: &str) -> Result<()> {
+    let path = image_path(name)?;
+    if dry_run() {
+        eprintln!("dry-run: would remove {}", path.display());
+        return Ok(());
+    }
+    std::fs::remove_dir_all(&path).with_context(|| format!("removing {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_image_path() {
+        assert_eq!(
+            image_path("edenfs:/Users/me/repo/buck-out").unwrap(),
+            images_dir().join("edenfs/Users/me/repo/buck-out.sparsebundle")
+        );
+        assert!(image_path("edenfs:/Users/me/../other").is_err());
+        assert!(image_path("Macintosh HD").is_err());
+        assert!(image_path(":/tmp").is_err());
+    }
+
+    #[test]
+    fn test_image_name() {
+        let root = Path::new("/images/edenfs");
+        assert_eq!(
+            image_name(
+                "edenfs",
+                root,
+                Path::new("/images/edenfs/Users/me/repo/buck-out.sparsebundle")
+            ),
+            Some("edenfs:/Users/me/repo/buck-out".to_owned())
+        );
+        assert_eq!(
+            image_name("edenfs", root, Path::new("/images/edenfs/Users/me")),
+            None
+        );
+        assert_eq!(
+            image_name("edenfs", root, Path::new("/elsewhere/a.sparsebundle")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_create_args() {
+        let path = Path::new("/images/edenfs/tmp/a.sparsebundle");
+        assert_eq!(
+            create_args("edenfs:/tmp/a", &VolumeOptions::default(), 1 << 40, path),
+            vec![
+                "create",
+                "-type",
+                "SPARSEBUNDLE",
+                "-fs",
+                "APFS",
+                "-volname",
+                "edenfs:/tmp/a",
+                "-size",
+                "1048576m",
+                "/images/edenfs/tmp/a.sparsebundle",
+            ]
+        );
+        let options = VolumeOptions {
+            quota: Some(1_500_000),
+            case_sensitive: true,
+            ..Default::default()
+        };
+        let args = create_args("edenfs:/tmp/a", &options, 1 << 40, path);
+        assert_eq!(args[4], "Case-sensitive APFS");
+        assert_eq!(args[8], "2m");
+    }
+
+    #[test]
+    fn test_is_attached_as() {
+        let info: HdiutilInfo = serde_json::from_str(
+            r#"{
+                "framework": "671.140.2",
+                "images": [
+                    {
+                        "image-path": "/images/edenfs/tmp/a.sparsebundle",
+                        "system-entities": [
+                            {"dev-entry": "/dev/disk5", "content-hint": "GUID_partition_scheme"},
+                            {"dev-entry": "/dev/disk5s1", "content-hint": "Apple_APFS"}
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let path = Path::new("/images/edenfs/tmp/a.sparsebundle");
+        assert!(info.is_attached_as(path, "disk5"));
+        assert!(!info.is_attached_as(path, "disk1"));
+        assert!(!info.is_attached_as(Path::new("/images/edenfs/tmp/b.sparsebundle"), "disk5"));
+        assert_eq!(info.attached_disk(path), Some("disk5"));
+        assert_eq!(
+            info.attached_disk(Path::new("/images/edenfs/tmp/b.sparsebundle")),
+            None
+        );
+    }
+}
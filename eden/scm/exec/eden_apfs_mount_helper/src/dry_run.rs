@@ -0,0 +1,118 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The `--dry-run` mode, in which we report the commands that would
+//! change something instead of running them.  The commands that only
+//! inspect the system still run, so that the rest of the helper sees
+//! the volumes and mounts that are really there and makes the same
+//! decisions that it would otherwise make.
+
+use crate::tools::{tool_paths, CODESIGN};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Enable the dry-run mode for the rest of this process
+pub fn set_dry_run() {
+    DRY_RUN.store(true, Ordering::SeqCst);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+/// Whether `argv` only inspects the system
+fn is_read_only(argv: &[String]) -> bool {
+    let args: Vec<&str> = argv.iter().skip(1).map(String::as_str).collect();
+    // We verify the tools that the policy names when the first tool is
+    // looked up, which may be after the dry-run mode was enabled.  This
+    // must come first, as the tool paths aren't available until then.
+    if argv.first().map(String::as_str) == Some(CODESIGN) {
+        return true;
+    }
+    let tools = match tool_paths() {
+        Ok(tools) => tools,
+        Err(_) => return false,
+    };
+    match argv.first() {
+        Some(program) if *program == tools.diskutil => matches!(
+            args.as_slice(),
+            ["info", ..] | ["list", ..] | ["apfs", "list", ..]
+        ),
+        Some(program) if *program == tools.plutil => true,
+        Some(program) if program == "/sbin/mount" => args.is_empty(),
+        _ => false,
+    }
+}
+
+fn command_argv(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// In the dry-run mode, report `cmd` instead of running it, unless it
+/// only inspects the system.  The command is treated as though it
+/// succeeded without output.
+pub fn skip_command(cmd: &Command) -> Option<Output> {
+    if !dry_run() {
+        return None;
+    }
+    let argv = command_argv(cmd);
+    if is_read_only(&argv) {
+        return None;
+    }
+    eprintln!("dry-run: would run {}", argv.join(" "));
+    Some(Output {
+        status: ExitStatus::from_raw(0),
+        stdout: vec![],
+        stderr: vec![],
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_read_only() {
+        let argv = |args: &[&str]| -> Vec<String> { args.iter().map(|s| s.to_string()).collect() };
+        assert!(is_read_only(&argv(&[
+            "/usr/sbin/diskutil",
+            "apfs",
+            "list",
+            "-plist"
+        ])));
+        assert!(is_read_only(&argv(&[
+            "/usr/sbin/diskutil",
+            "info",
+            "-plist",
+            "disk1s5"
+        ])));
+        assert!(is_read_only(&argv(&["/sbin/mount"])));
+        assert!(!is_read_only(&argv(&["/sbin/mount", "-u", "/"])));
+        assert!(!is_read_only(&argv(&[
+            "/usr/sbin/diskutil",
+            "apfs",
+            "deleteVolume",
+            "disk1s5"
+        ])));
+        assert!(!is_read_only(&argv(&[
+            "/usr/sbin/diskutil",
+            "unmount",
+            "disk1s5"
+        ])));
+        assert!(!is_read_only(&argv(&[
+            "/sbin/mount_apfs",
+            "/dev/disk1s5",
+            "."
+        ])));
+    }
+}
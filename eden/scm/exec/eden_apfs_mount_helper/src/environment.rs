@@ -0,0 +1,186 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Telling a physical Mac apart from a virtual machine or a sandboxed CI
+//! runner.  diskutil behaves differently in those: the disks of a
+//! virtual machine are synthesized by the hypervisor, and there may be
+//! no container that outlives the job, so failures there often aren't
+//! bugs in this tool.  We warn before creating a volume in one, and tag
+//! errors and the history in the registry with the environment so that
+//! triage can separate them from the failures on real hardware.
+//! The policy may set `environment` for images that we misdetect.
+
+use crate::privs::fixtures_active;
+pub use crate::report::HostEnvironment;
+use once_cell::sync::OnceCell;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+
+/// Substrings of `hw.model` that identify a virtual machine, for the
+/// hypervisors that don't set `kern.hv_vmm_present`
+const VIRTUAL_MODELS: &[&str] = &["VirtualMac", "VMware", "Parallels"];
+
+extern "C" {
+    fn sysctlbyname(
+        name: *const c_char,
+        oldp: *mut c_void,
+        oldlenp: *mut usize,
+        newp: *mut c_void,
+        newlen: usize,
+    ) -> c_int;
+    fn sandbox_check(pid: libc::pid_t, operation: *const c_char, filter_type: c_int, ...) -> c_int;
+}
+
+static ENVIRONMENT: OnceCell<HostEnvironment> = OnceCell::new();
+
+/// Use `environment` in place of the one that we would detect, as set by
+/// the policy.  This must be called before anything asks for it.
+pub fn set_environment(environment: HostEnvironment) {
+    ENVIRONMENT.set(environment).ok();
+}
+
+/// The environment that we are running in, which is detected the first
+/// time that it is needed
+pub fn host_environment() -> HostEnvironment {
+    *ENVIRONMENT.get_or_init(detect_environment)
+}
+
+/// The environment to tag errors and history with, which is None on a
+/// physical Mac so that the common case stays uncluttered
+pub fn environment_tag() -> Option<HostEnvironment> {
+    match host_environment() {
+        HostEnvironment::Physical => None,
+        environment => Some(environment),
+    }
+}
+
+fn detect_environment() -> HostEnvironment {
+    // The fixtures describe a physical Mac unless their policy says
+    // otherwise, whatever the host running the tests is
+    if fixtures_active() {
+        return HostEnvironment::Physical;
+    }
+    classify(
+        sysctl_bytes("kern.hv_vmm_present").map(|value| value.iter().any(|&b| b != 0)),
+        sysctl_bytes("hw.model")
+            .map(|model| {
+                String::from_utf8_lossy(&model)
+                    .trim_end_matches('\0')
+                    .to_owned()
+            })
+            .as_deref(),
+        unsafe { sandbox_check(libc::getpid(), std::ptr::null(), 0) } == 1,
+    )
+}
+
+/// Decide which environment we are in from `kern.hv_vmm_present` and
+/// `hw.model`, which are missing on some versions of macOS, and whether
+/// this process is sandboxed.  A sandboxed process in a virtual machine
+/// is counted as sandboxed, as that is the more restrictive of the two.
+fn classify(hv_vmm_present: Option<bool>, model: Option<&str>, sandboxed: bool) -> HostEnvironment {
+    if sandboxed {
+        return HostEnvironment::Sandbox;
+    }
+    let virtual_model =
+        model.is_some_and(|model| VIRTUAL_MODELS.iter().any(|name| model.contains(name)));
+    if hv_vmm_present == Some(true) || virtual_model {
+        HostEnvironment::VirtualMachine
+    } else {
+        HostEnvironment::Physical
+    }
+}
+
+/// Read the value of the sysctl `name`, if there is one
+fn sysctl_bytes(name: &str) -> Option<Vec<u8>> {
+    let name = CString::new(name).ok()?;
+    let mut len = 0;
+    let status = unsafe {
+        sysctlbyname(
+            name.as_ptr(),
+            std::ptr::null_mut(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if status != 0 {
+        return None;
+    }
+    let mut value = vec![0u8; len];
+    let status = unsafe {
+        sysctlbyname(
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if status != 0 {
+        return None;
+    }
+    value.truncate(len);
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_classify() {
+        for (hv_vmm_present, model, sandboxed, expected) in &[
+            (
+                Some(false),
+                Some("MacBookPro18,3"),
+                false,
+                HostEnvironment::Physical,
+            ),
+            (None, None, false, HostEnvironment::Physical),
+            (
+                Some(true),
+                Some("MacBookPro18,3"),
+                false,
+                HostEnvironment::VirtualMachine,
+            ),
+            (
+                None,
+                Some("VMware7,1"),
+                false,
+                HostEnvironment::VirtualMachine,
+            ),
+            (
+                Some(false),
+                Some("VirtualMac2,1"),
+                false,
+                HostEnvironment::VirtualMachine,
+            ),
+            (
+                Some(false),
+                Some("Macmini9,1"),
+                true,
+                HostEnvironment::Sandbox,
+            ),
+            (
+                Some(true),
+                Some("VirtualMac2,1"),
+                true,
+                HostEnvironment::Sandbox,
+            ),
+        ] {
+            assert_eq!(
+                classify(*hv_vmm_present, *model, *sandboxed),
+                *expected,
+                "{:?} {:?} {}",
+                hv_vmm_present,
+                model,
+                sandboxed
+            );
+        }
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A deterministic test mode in which the external tools that we run
+//! are replaced with canned responses read from fixture files.
+//! This is only compiled in when the `fixtures` feature is enabled,
+//! and is only active when `EDEN_APFS_FIXTURES` names a fixture
+//! directory, so that the CLI can be tested end to end without touching
+//! any real disks.
+//!
+//! The fixture directory contains a `commands.toml` file that lists
+//! the commands that are expected to run:
+//!
+//! ```toml
+//! [[command]]
+//! argv = ["/usr/sbin/diskutil", "apfs", "list", "-plist"]
+//! stdout = "apfs_list.json"
+//! status = 0
+//! ```
+//!
+//! `stdout` and `stderr` name files relative to the fixture directory.
+//! If several fixtures have the same `argv`, they are used in turn for
+//! successive invocations of that command, with the last one repeating.
+//! Commands that produce plists are parsed from the fixture as json,
+//! which is the form that `plutil` would have converted them to.
+//! The directory may also contain a `policy.toml` that is used in place
+//! of the system policy file, and a `purgeable.toml` that maps mount
+//! points to the purgeable space in their container, which is otherwise
+//! reported as zero.
+//! Each command is logged to stderr as it is run so that tests can
+//! verify what the helper would have done.
+
+use anyhow::*;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::*;
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+use std::sync::Mutex;
+
+/// The environment variable that activates the fixture mode
+pub const FIXTURES_ENV: &str = "EDEN_APFS_FIXTURES";
+
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    #[serde(default)]
+    command: Vec<FixtureCommand>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FixtureCommand {
+    argv: Vec<String>,
+    stdout: Option<String>,
+    stderr: Option<String>,
+    #[serde(default)]
+    status: i32,
+}
+
+/// The number of times that each command has been run so far
+static INVOCATIONS: Lazy<Mutex<HashMap<Vec<String>, usize>>> = Lazy::new(Default::default);
+
+/// Returns the fixture directory if the fixture mode is active
+pub fn fixture_dir() -> Option<&'static Path> {
+    static DIR: OnceCell<Option<PathBuf>> = OnceCell::new();
+    DIR.get_or_init(|| std::env::var_os(FIXTURES_ENV).map(PathBuf::from))
+        .as_deref()
+}
+
+pub fn is_active() -> bool {
+    fixture_dir().is_some()
+}
+
+fn load_manifest(dir: &Path) -> Result<Manifest> {
+    let path = dir.join("commands.toml");
+    let text =
+        std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+}
+
+fn read_fixture_file(dir: &Path, name: &Option<String>) -> Result<Vec<u8>> {
+    match name {
+        Some(name) => {
+            let path = dir.join(name);
+            std::fs::read(&path).with_context(|| format!("reading {}", path.display()))
+        }
+        None => Ok(vec![]),
+    }
+}
+
+fn command_argv(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// If the fixture mode is active, return the canned output for `cmd`.
+/// It is an error for a command to have no fixture.
+pub fn fake_output(cmd: &Command) -> Option<std::io::Result<Output>> {
+    let dir = fixture_dir()?;
+    let argv = command_argv(cmd);
+    eprintln!("[fixture] {}", argv.join(" "));
+
+    let result = load_manifest(dir).and_then(|manifest| {
+        let mut candidates: Vec<FixtureCommand> = manifest
+            .command
+            .into_iter()
+            .filter(|fixture| fixture.argv == argv)
+            .collect();
+        if candidates.is_empty() {
+            bail!("there is no fixture for {:?}", argv);
+        }
+        let invocation = {
+            let mut counts = INVOCATIONS.lock().unwrap();
+            let count = counts.entry(argv.clone()).or_insert(0);
+            *count += 1;
+            *count - 1
+        };
+        let fixture = candidates.remove(invocation.min(candidates.len() - 1));
+        Ok(Output {
+            status: ExitStatus::from_raw(fixture.status << 8),
+            stdout: read_fixture_file(dir, &fixture.stdout)?,
+            stderr: read_fixture_file(dir, &fixture.stderr)?,
+        })
+    });
+    Some(result.map_err(|err| std::io::Error::other(format!("{:#}", err))))
+}
+
+/// If the fixture mode is active, return the purgeable space for the
+/// container of the volume mounted at `path`
+pub fn fake_purgeable_space(path: &Path) -> Option<Result<u64>> {
+    let dir = fixture_dir()?;
+    let path_str = path.to_string_lossy();
+    let fixture = dir.join("purgeable.toml");
+    let result = match std::fs::read_to_string(&fixture) {
+        Ok(text) => toml::from_str::<HashMap<String, u64>>(&text)
+            .with_context(|| format!("parsing {}", fixture.display()))
+            .map(|table| table.get(path_str.as_ref()).copied().unwrap_or(0)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err).context(format!("reading {}", fixture.display())),
+    };
+    Some(result)
+}
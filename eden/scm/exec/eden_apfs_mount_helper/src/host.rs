@@ -0,0 +1,188 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Telling the volumes that were created on this host apart from those
+//! on an external disk that was moved here from another Mac.  Their names
+//! encode paths on the other host, which are likely to be missing here,
+//! and so they look like orphans.
+//! Each host has a random identifier that we generate the first time that
+//! we create a volume, which lives alongside the registry.  We place it in
+//! a tag file in the root of each volume that we create, and so the tag
+//! can only be read while the volume is mounted.  macOS mounts the volumes
+//! on an external disk under /Volumes as soon as it is attached, so the
+//! tag is usually available when it matters.
+
+use crate::privs::fixtures_active;
+use crate::registry::registry_dir;
+pub use crate::report::VolumeOrigin;
+use anyhow::*;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+/// The name of the file that holds the host identifier, in the registry
+/// directory
+const HOST_ID_FILE: &str = "host-id";
+
+/// The name of the tag file in the root of each volume
+pub const VOLUME_TAG_FILE: &str = ".eden-apfs-host";
+
+/// The longest tag that we will read; anything longer isn't ours
+const MAX_TAG_LEN: u64 = 128;
+
+/// Load the identifier of this host, if one has been generated
+pub fn load_host_id() -> Result<Option<String>> {
+    let path = registry_dir().join(HOST_ID_FILE);
+    let metadata = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context(format!("reading {}", path.display())),
+    };
+    if !fixtures_active() && (metadata.uid() != 0 || metadata.mode() & 0o022 != 0) {
+        bail!(
+            "refusing to use {} because it is not owned by root \
+             or is writable by other users",
+            path.display()
+        );
+    }
+    let id =
+        std::fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+    Ok(Some(id.trim().to_owned()))
+}
+
+/// Load the identifier of this host, generating it if there isn't one
+/// yet.  This requires root privs.
+pub fn ensure_host_id() -> Result<String> {
+    if let Some(id) = load_host_id()? {
+        return Ok(id);
+    }
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("generating a host identifier")?;
+    let id: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let dir = registry_dir();
+    std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let path = dir.join(HOST_ID_FILE);
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o644)
+        .open(&path)
+    {
+        Ok(mut file) => {
+            file.write_all(format!("{}\n", id).as_bytes())
+                .with_context(|| format!("writing {}", path.display()))?;
+            Ok(id)
+        }
+        // Another invocation got there first
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => load_host_id()?
+            .ok_or_else(|| anyhow!("{} disappeared while reading it", path.display())),
+        Err(err) => Err(err).context(format!("creating {}", path.display())),
+    }
+}
+
+/// Place the tag for `host_id` in the root of a volume, given the open
+/// directory of its root
+pub fn tag_volume(root: &File, host_id: &str) -> Result<()> {
+    let name = std::ffi::CString::new(VOLUME_TAG_FILE)?;
+    let fd = unsafe {
+        libc::openat(
+            root.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0o644,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+            .context(format!("creating {}", VOLUME_TAG_FILE));
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(format!("{}\n", host_id).as_bytes())
+        .with_context(|| format!("writing {}", VOLUME_TAG_FILE))
+}
+
+/// Read the tag in the root of the volume mounted at `mount_point`, if
+/// there is one
+pub fn read_volume_tag(mount_point: &str) -> Option<String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(Path::new(mount_point).join(VOLUME_TAG_FILE))
+        .ok()?;
+    let mut tag = String::new();
+    file.take(MAX_TAG_LEN).read_to_string(&mut tag).ok()?;
+    let tag = tag.trim();
+    if tag.is_empty() {
+        None
+    } else {
+        Some(tag.to_owned())
+    }
+}
+
+/// Decide where a volume came from, given its tag, the identifier of this
+/// host, and whether the registry has a record of the volume's mount
+/// point.  Volumes that predate the tags are only known to be ours
+/// through the registry.
+pub fn volume_origin(
+    tag: Option<&str>,
+    host_id: Option<&str>,
+    known_locally: bool,
+) -> VolumeOrigin {
+    match tag {
+        Some(tag) if Some(tag) == host_id => VolumeOrigin::Local,
+        Some(_) => VolumeOrigin::Foreign,
+        None if known_locally => VolumeOrigin::Local,
+        None => VolumeOrigin::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_volume_origin() {
+        assert_eq!(
+            volume_origin(Some("abc"), Some("abc"), false),
+            VolumeOrigin::Local
+        );
+        assert_eq!(
+            volume_origin(Some("abc"), Some("def"), true),
+            VolumeOrigin::Foreign
+        );
+        assert_eq!(
+            volume_origin(Some("abc"), None, true),
+            VolumeOrigin::Foreign
+        );
+        assert_eq!(volume_origin(None, Some("abc"), true), VolumeOrigin::Local);
+        assert_eq!(
+            volume_origin(None, Some("abc"), false),
+            VolumeOrigin::Unknown
+        );
+    }
+
+    #[test]
+    fn test_volume_tag() {
+        let dir = std::env::temp_dir().join(format!("eden-apfs-tag-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.to_str().unwrap().to_owned();
+
+        let untagged = read_volume_tag(&path);
+        tag_volume(&File::open(&dir).unwrap(), "0123abcd").unwrap();
+        let tagged = read_volume_tag(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(untagged, None);
+        assert_eq!(tagged, Some("0123abcd".to_owned()));
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Storage for the passphrases of encrypted scratch volumes in the
+//! keychain of the user that invoked us, so that their volumes can be
+//! unlocked and mounted again without prompting.
+//! We always access the keychain with the privs of the real user.
+
+use crate::privs::{new_cmd_unprivileged, RunCommand};
+use anyhow::*;
+use std::fs::File;
+use std::io::Read;
+
+const SECURITY: &str = "/usr/bin/security";
+
+/// The keychain service under which we store passphrases.  The account
+/// is the UUID of the volume.
+const SERVICE: &str = "eden_apfs_mount_helper";
+
+/// The number of random bytes in the passphrases that we generate
+const GENERATED_PASSPHRASE_BYTES: usize = 32;
+
+/// Generate a random passphrase for a new encrypted volume.  Nobody
+/// needs to type it, as it lives in the keychain.
+pub fn generate_passphrase() -> Result<String> {
+    let mut bytes = [0u8; GENERATED_PASSPHRASE_BYTES];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("generating a passphrase")?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Look up the passphrase stored for `account`
+pub fn find_passphrase(account: &str) -> Result<Option<String>> {
+    let output = new_cmd_unprivileged(SECURITY)
+        .args(&["find-generic-password", "-s", SERVICE, "-a", account, "-w"])
+        .run_secret()?;
+    if !output.status.success() {
+        // There is no such item, or the user declined to share it
+        return Ok(None);
+    }
+    let passphrase =
+        String::from_utf8(output.stdout).context("the stored passphrase is not valid utf8")?;
+    Ok(Some(passphrase.trim_end_matches('\n').to_owned()))
+}
+
+/// Quote `text` as a single argument for the `security -i` command
+/// interpreter
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Store `passphrase` for `account`, replacing any existing item.
+/// The command is fed to `security` on stdin rather than passed as
+/// arguments, so that the passphrase doesn't show up in `ps`.
+pub fn store_passphrase(account: &str, passphrase: &str) -> Result<()> {
+    if passphrase.contains('\n') {
+        bail!("passphrases that contain newlines can't be stored in the keychain");
+    }
+    let command = format!(
+        "add-generic-password -U -s {} -a {} -w {}\n",
+        quote(SERVICE),
+        quote(account),
+        quote(passphrase)
+    );
+    let output = new_cmd_unprivileged(SECURITY)
+        .arg("-i")
+        .run_with_input(command.as_bytes())?;
+    if !output.status.success() {
+        bail!(
+            "failed to store the passphrase in the keychain: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("simple"), "\"simple\"");
+        assert_eq!(quote("a \"b\" c"), "\"a \\\"b\\\" c\"");
+        assert_eq!(quote("back\\slash"), "\"back\\\\slash\"");
+    }
+}
@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The guts of the `eden_apfs_mount_helper` utility.
+//! In addition to the plumbing used by the utility itself, this exposes
+//! capacity and placement queries so that the eden CLI and daemon can
+//! reason about scratch space programmatically rather than by parsing
+//! the output of the utility.
+//! The implementation is macOS specific; on other platforms only the
+//! capacity queries are available, and they report an error.
+
+#[cfg(target_os = "macos")]
+pub mod apfs;
+pub mod capacity;
+pub mod cloud_sync;
+#[cfg(target_os = "macos")]
+pub mod disk_image;
+#[cfg(target_os = "macos")]
+pub mod dry_run;
+#[cfg(target_os = "macos")]
+pub mod environment;
+#[cfg(all(target_os = "macos", feature = "fixtures"))]
+pub mod fixtures;
+
+// The fixture mode lets the caller decide what the tools we run report,
+// which must never be possible in a setuid binary that we ship.
+#[cfg(all(feature = "fixtures", not(debug_assertions)))]
+compile_error!("the `fixtures` feature is only available in debug builds");
+// Likewise for running a diskutil of the caller's choosing
+#[cfg(all(feature = "fake-diskutil", not(debug_assertions)))]
+compile_error!("the `fake-diskutil` feature is only available in debug builds");
+#[cfg(target_os = "macos")]
+pub mod host;
+#[cfg(target_os = "macos")]
+pub mod keychain;
+#[cfg(target_os = "macos")]
+pub mod mount_table;
+pub mod paths;
+pub mod policy;
+#[cfg(target_os = "macos")]
+pub mod privs;
+#[cfg(target_os = "macos")]
+pub mod purgeable;
+#[cfg(target_os = "macos")]
+pub mod ram_disk;
+#[cfg(target_os = "macos")]
+pub mod registry;
+pub mod report;
+#[cfg(target_os = "macos")]
+pub mod timeouts;
+#[cfg(target_os = "macos")]
+pub mod tool_output;
+#[cfg(target_os = "macos")]
+pub mod tools;
+#[cfg(target_os = "macos")]
+pub mod transcript;
+pub mod volume_name;
+
+pub use capacity::{
+    estimate_available_scratch_space, get_container_stats, resolve_container_for_path,
+    ContainerStats, ScratchVolumeStats,
+};
+
+/// The error reported by the stubs that we build on platforms other than macOS
+#[cfg(not(target_os = "macos"))]
+pub fn unsupported_platform() -> anyhow::Error {
+    anyhow::anyhow!("eden_apfs_mount_helper is only supported on macOS")
+}
@@ -0,0 +1,335 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! A mock backend for the integration tests of edenfs, which need a
+//! helper that they can drive through the usual command line interface
+//! without it touching any real disks.  It is only compiled in when the
+//! `mock` feature is enabled, in which case it replaces the real
+//! implementation, and it works on any unix system.
+//!
+//! The volumes are directories in the state directory named by
+//! `EDEN_APFS_MOCK_DIR`, and "mounting" a volume replaces the (empty)
+//! mount point with a symlink to its directory, which gives bind-like
+//! semantics for the purposes of the tests.  The state of the volumes is
+//! kept in `volumes.json` in the same directory.
+//! The output of each command has the same form as that of the real one.
+
+use anyhow::*;
+use eden_apfs_mount_helper::volume_name::{decode_volume_name, encode_mount_point_as_volume_name};
+use serde::*;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// The environment variable that names the state directory
+const MOCK_DIR_ENV: &str = "EDEN_APFS_MOCK_DIR";
+
+#[derive(StructOpt, Debug)]
+enum SubCommand {
+    /// List the (mock) volumes
+    #[structopt(name = "list")]
+    List {
+        /// Accepted for compatibility; every mock volume is ours
+        #[structopt(long = "all")]
+        _all: bool,
+    },
+
+    /// Create and mount a volume on the specified path
+    #[structopt(name = "mount")]
+    Mount { mount_point: String },
+
+    /// Unmount the volume mounted on the specified path
+    #[structopt(name = "unmount")]
+    UnMount {
+        mount_point: String,
+        /// Accepted for compatibility; unmounting a mock volume
+        /// can't fail because it is busy
+        #[structopt(long = "force")]
+        _force: bool,
+    },
+
+    /// Unmount and delete the volume associated with a specific path
+    #[structopt(name = "delete")]
+    Delete { mount_point: String },
+
+    /// Unmount and delete all of the volumes
+    #[structopt(name = "delete-all")]
+    DeleteAll {
+        /// Accepted for compatibility; the mock never asks for
+        /// confirmation
+        #[structopt(long = "yes", short = "y")]
+        _yes: bool,
+    },
+
+    /// Show what is known about the volume for a mount point
+    #[structopt(name = "info")]
+    Info { mount_point: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct MockVolume {
+    name: String,
+    mount_point: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct MockState {
+    #[serde(default)]
+    next_id: u64,
+    /// The volumes, keyed by their device identifier
+    #[serde(default)]
+    volumes: BTreeMap<String, MockVolume>,
+}
+
+impl MockState {
+    fn find(&self, name: &str) -> Option<String> {
+        self.volumes
+            .iter()
+            .find(|(_, vol)| vol.name == name)
+            .map(|(device, _)| device.clone())
+    }
+}
+
+struct Mock {
+    dir: PathBuf,
+    /// Held for the lifetime of the process to serialize concurrent
+    /// invocations
+    _lock: std::fs::File,
+    state: MockState,
+}
+
+impl Mock {
+    fn open() -> Result<Self> {
+        let dir = PathBuf::from(
+            std::env::var_os(MOCK_DIR_ENV)
+                .ok_or_else(|| anyhow!("{} must name the mock state directory", MOCK_DIR_ENV))?,
+        );
+        std::fs::create_dir_all(dir.join("volumes"))
+            .with_context(|| format!("creating {}", dir.display()))?;
+
+        let lock_path = dir.join("volumes.lock");
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("opening {}", lock_path.display()))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            let err = std::io::Error::last_os_error();
+            return Err(err).context(format!("locking {}", lock_path.display()));
+        }
+
+        let path = dir.join("volumes.json");
+        let state = match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .with_context(|| format!("parsing {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => MockState::default(),
+            Err(err) => return Err(err).context(format!("reading {}", path.display())),
+        };
+        Ok(Self {
+            dir,
+            _lock: lock,
+            state,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = self.dir.join("volumes.json");
+        std::fs::write(&path, serde_json::to_string_pretty(&self.state)?)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+
+    fn volume_dir(&self, device: &str) -> PathBuf {
+        self.dir.join("volumes").join(device)
+    }
+
+    fn mount(&mut self, mount_point: &str) -> Result<()> {
+        let name = volume_name(mount_point);
+        let device = match self.state.find(&name) {
+            Some(device) => device,
+            None => {
+                self.state.next_id += 1;
+                let device = format!("mock{}", self.state.next_id);
+                std::fs::create_dir_all(self.volume_dir(&device))?;
+                self.state.volumes.insert(
+                    device.clone(),
+                    MockVolume {
+                        name,
+                        mount_point: None,
+                    },
+                );
+                device
+            }
+        };
+
+        let vol = &self.state.volumes[&device];
+        if vol.mount_point.as_deref() == Some(mount_point) {
+            // Already mounted where it belongs
+            return Ok(());
+        }
+        if let Some(current) = &vol.mount_point {
+            bail!("volume {} is already mounted at {}", device, current);
+        }
+
+        // Like a real mount, we need an existing directory to mount on,
+        // but unlike one we can't hide its contents
+        let path = Path::new(mount_point);
+        match std::fs::read_dir(path) {
+            Ok(mut entries) => {
+                if entries.next().is_some() {
+                    bail!(
+                        "the mock can only mount on an empty directory: {}",
+                        mount_point
+                    );
+                }
+                std::fs::remove_dir(path)?;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+            }
+            Err(err) => return Err(err).context(format!("checking {}", mount_point)),
+        }
+        std::os::unix::fs::symlink(self.volume_dir(&device), path)
+            .with_context(|| format!("mounting {} on {}", device, mount_point))?;
+
+        self.state.volumes.get_mut(&device).unwrap().mount_point = Some(mount_point.to_owned());
+        self.save()
+    }
+
+    fn unmount_device(&mut self, device: &str) -> Result<()> {
+        let vol = self.state.volumes.get_mut(device).unwrap();
+        if let Some(current) = vol.mount_point.take() {
+            let path = Path::new(&current);
+            if path
+                .symlink_metadata()
+                .map(|m| m.file_type().is_symlink())
+                .unwrap_or(false)
+            {
+                std::fs::remove_file(path)?;
+                std::fs::create_dir(path)?;
+            }
+        }
+        self.save()
+    }
+
+    fn find_volume(&self, mount_point: &str) -> Result<String> {
+        let name = volume_name(mount_point);
+        self.state
+            .find(&name)
+            .ok_or_else(|| anyhow!("Did not find a volume named {}", name))
+    }
+
+    fn unmount(&mut self, mount_point: &str) -> Result<()> {
+        let device = self.find_volume(mount_point)?;
+        if self.state.volumes[&device].mount_point.is_none() {
+            bail!("{} is not mounted", mount_point);
+        }
+        self.unmount_device(&device)
+    }
+
+    fn delete_device(&mut self, device: &str) -> Result<()> {
+        self.unmount_device(device)?;
+        std::fs::remove_dir_all(self.volume_dir(device))?;
+        self.state.volumes.remove(device);
+        self.save()
+    }
+
+    fn delete(&mut self, mount_point: &str) -> Result<()> {
+        let device = self.find_volume(mount_point)?;
+        self.delete_device(&device)
+    }
+
+    fn delete_all(&mut self) -> Result<()> {
+        let volumes: Vec<(String, MockVolume)> = self.state.volumes.clone().into_iter().collect();
+        for (device, vol) in volumes {
+            self.delete_device(&device)?;
+            println!("Deleted {}", decode_name(&vol.name));
+        }
+        Ok(())
+    }
+
+    fn list(&self) {
+        for (device, vol) in &self.state.volumes {
+            match &vol.mount_point {
+                Some(mount_point) => println!("{}\t{}\t{}", device, vol.name, mount_point),
+                None => println!("{}\t{}", device, vol.name),
+            }
+        }
+    }
+
+    fn info(&self, mount_point: &str) -> Result<()> {
+        let device = self.find_volume(mount_point)?;
+        let vol = &self.state.volumes[&device];
+        println!("name\t{}", vol.name);
+        println!("device\t{}", device);
+        println!("mounted\t{}", vol.mount_point.as_deref().unwrap_or("no"));
+        Ok(())
+    }
+}
+
+/// Resolve the mount point in the same way as the real helper.  Only
+/// the parent is canonicalized, as the mount point itself is a symlink
+/// while it is mounted.
+fn canonicalize_mount_point(mount_point: &str) -> Result<String> {
+    let path = Path::new(mount_point);
+    let resolved = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => std::fs::canonicalize(parent)
+            .with_context(|| format!("canonicalizing path {}", mount_point))?
+            .join(file_name),
+        _ => bail!("{} is not a valid mount point", mount_point),
+    };
+    resolved
+        .to_str()
+        .ok_or_else(|| anyhow!("path {} isn't unicode", resolved.display()))
+        .map(str::to_owned)
+}
+
+fn volume_name(mount_point: &str) -> String {
+    encode_mount_point_as_volume_name(mount_point)
+}
+
+fn decode_name(name: &str) -> &str {
+    decode_volume_name(name).unwrap_or(name)
+}
+
+pub fn main() -> Result<()> {
+    // The mock never needs privileges, and a mock installed setuid
+    // would let anyone create symlinks anywhere
+    if unsafe { libc::geteuid() != libc::getuid() } {
+        bail!("the mock helper must not be installed setuid");
+    }
+
+    let cmd = SubCommand::from_args();
+    let mut mock = Mock::open()?;
+    match cmd {
+        SubCommand::List { .. } => {
+            mock.list();
+            Ok(())
+        }
+        SubCommand::Mount { mount_point } => {
+            let mount_point = canonicalize_mount_point(&mount_point)?;
+            mock.mount(&mount_point)
+        }
+        SubCommand::UnMount { mount_point, .. } => {
+            let mount_point = canonicalize_mount_point(&mount_point)?;
+            mock.unmount(&mount_point)
+        }
+        SubCommand::Delete { mount_point } => {
+            let mount_point = canonicalize_mount_point(&mount_point)?;
+            mock.delete(&mount_point)
+        }
+        SubCommand::DeleteAll { .. } => mock.delete_all(),
+        SubCommand::Info { mount_point } => {
+            let mount_point = canonicalize_mount_point(&mount_point)?;
+            mock.info(&mount_point)
+        }
+    }
+}
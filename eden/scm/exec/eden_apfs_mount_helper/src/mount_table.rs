@@ -0,0 +1,102 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Parsing the system mount table
+
+use crate::privs::{new_cmd_unprivileged, RunCommand};
+use crate::tool_output::{parse_mount_line, parse_mount_table};
+use anyhow::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountEntry {
+    pub device: String,
+    pub mount_point: String,
+}
+
+impl MountEntry {
+    pub fn new(device: &str, mount_point: &str) -> Self {
+        Self {
+            device: device.to_owned(),
+            mount_point: mount_point.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountTable {
+    pub entries: Vec<MountEntry>,
+}
+
+impl MountTable {
+    /// Parse the output from `mount`, skipping any lines that we don't
+    /// recognize
+    pub fn parse_mount_table_text(text: &str) -> Self {
+        Self {
+            entries: text.lines().filter_map(parse_mount_line).collect(),
+        }
+    }
+
+    pub fn parse_system_mount_table() -> Result<Self> {
+        let output = new_cmd_unprivileged("/sbin/mount").run()?;
+        if !output.status.success() {
+            bail!("failed to execute mount: {:#?}", output);
+        }
+        Ok(Self {
+            entries: parse_mount_table(&String::from_utf8(output.stdout)?)?,
+        })
+    }
+
+    pub fn parse_if_needed(existing: Option<&Self>) -> Result<Self> {
+        if let Some(table) = existing {
+            Ok(table.clone())
+        } else {
+            Self::parse_system_mount_table()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_mount_parse() {
+        let data = r#"
+/dev/disk1s1 on / (apfs, local, journaled)
+devfs on /dev (devfs, local, nobrowse)
+/dev/disk1s4 on /private/var/vm (apfs, local, noexec, journaled, noatime, nobrowse)
+map -hosts on /net (autofs, nosuid, automounted, nobrowse)
+map auto_home on /home (autofs, automounted, nobrowse)
+eden@osxfuse0 on /Users/wez/fbsource (osxfuse_eden, nosuid, synchronous)
+/dev/disk1s5 on /Users/wez/fbsource/buck-out (apfs, local, nodev, nosuid, journaled, nobrowse)
+/dev/disk1s6 on /Users/wez/fbsource/fbcode/buck-out (apfs, local, nodev, nosuid, journaled, nobrowse)
+/dev/disk1s7 on /Users/wez/fbsource/fbobjc/buck-out (apfs, local, nodev, nosuid, journaled, nobrowse)
+/dev/disk1s8 on /private/tmp/wat the/woot (apfs, local, nodev, nosuid, journaled, nobrowse)
+map -fstab on /Network/Servers (autofs, automounted, nobrowse)
+/dev/disk1s9 on /private/tmp/parens (1) (apfs, local, nodev, nosuid, journaled, nobrowse)
+"#;
+        assert_eq!(
+            MountTable::parse_mount_table_text(data).entries,
+            vec![
+                MountEntry::new("/dev/disk1s1", "/"),
+                MountEntry::new("devfs", "/dev"),
+                MountEntry::new("/dev/disk1s4", "/private/var/vm"),
+                MountEntry::new("map -hosts", "/net"),
+                MountEntry::new("map auto_home", "/home"),
+                MountEntry::new("eden@osxfuse0", "/Users/wez/fbsource"),
+                MountEntry::new("/dev/disk1s5", "/Users/wez/fbsource/buck-out"),
+                MountEntry::new("/dev/disk1s6", "/Users/wez/fbsource/fbcode/buck-out"),
+                MountEntry::new("/dev/disk1s7", "/Users/wez/fbsource/fbobjc/buck-out"),
+                // This one has a space in the mount point path!
+                MountEntry::new("/dev/disk1s8", "/private/tmp/wat the/woot"),
+                MountEntry::new("map -fstab", "/Network/Servers"),
+                MountEntry::new("/dev/disk1s9", "/private/tmp/parens (1)"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Normalizing the mount points that we are given, so that every command
+//! agrees on which mount point a path names.  Mount points are recorded
+//! in volume names and in the registry in their normalized form, so
+//! `buck-out/`, `./buck-out` and `/Users/wez/repo/buck-out` must all
+//! come out the same.
+//!
+//! Paths that exist are resolved by the filesystem, which also takes
+//! care of symlinks; this handles the paths that don't, such as the
+//! mount point of a volume whose repo has since been deleted.
+//!
+//! Mount points may be nested inside one another, eg: `buck-out` and
+//! `buck-out/cache`, so this also works out the order in which to
+//! mount them.
+
+use anyhow::*;
+use std::path::{Component, Path};
+
+/// Make `path` absolute relative to `cwd`, and remove empty and `.`
+/// components along with any trailing separator.  `..` is rejected,
+/// as without the filesystem to consult we can't tell which directory
+/// it refers to when the path passes through a symlink.
+pub fn normalize_path(path: &str, cwd: &Path) -> Result<String> {
+    if path.is_empty() {
+        bail!("the path is empty");
+    }
+    let absolute = cwd.join(path);
+    if !absolute.is_absolute() {
+        bail!("{} is not an absolute path", absolute.display());
+    }
+    let mut normalized = String::new();
+    for component in absolute.components() {
+        match component {
+            Component::Normal(name) => {
+                let name = name
+                    .to_str()
+                    .ok_or_else(|| anyhow!("path {} isn't unicode", absolute.display()))?;
+                normalized.push('/');
+                normalized.push_str(name);
+            }
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => bail!(
+                "{} contains `..` and doesn't exist, so it can't be resolved",
+                path
+            ),
+            Component::Prefix(_) => bail!("{} has a prefix", path),
+        }
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Whether `path` lies strictly inside the directory `dir`.  Both must
+/// be normalized.
+pub fn is_nested_in(path: &str, dir: &str) -> bool {
+    match path.strip_prefix(dir) {
+        Some(rest) => rest.starts_with('/') || (dir.ends_with('/') && !rest.is_empty()),
+        None => false,
+    }
+}
+
+/// Order the `count` items numbered from 0 so that each comes after
+/// all of those that it depends on, and otherwise in numerical order.
+/// If some items depend on each other, they are returned as the error.
+pub fn dependency_order(
+    count: usize,
+    depends_on: impl Fn(usize, usize) -> bool,
+) -> std::result::Result<Vec<usize>, Vec<usize>> {
+    let mut waiting_for: Vec<usize> = (0..count)
+        .map(|item| {
+            (0..count)
+                .filter(|&other| other != item && depends_on(item, other))
+                .count()
+        })
+        .collect();
+    let mut done = vec![false; count];
+    let mut order = Vec::with_capacity(count);
+    while order.len() < count {
+        let next = match (0..count).find(|&item| !done[item] && waiting_for[item] == 0) {
+            Some(next) => next,
+            None => return Err((0..count).filter(|&item| !done[item]).collect()),
+        };
+        done[next] = true;
+        order.push(next);
+        for item in 0..count {
+            if !done[item] && item != next && depends_on(item, next) {
+                waiting_for[item] -= 1;
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// Order `mount_points` so that each comes after those that it is
+/// nested inside, which is the order to mount them in; unmount them in
+/// the reverse order.  Returns the indices into `mount_points`.
+pub fn mount_order<S: AsRef<str>>(mount_points: &[S]) -> Result<Vec<usize>> {
+    dependency_order(mount_points.len(), |item, other| {
+        is_nested_in(mount_points[item].as_ref(), mount_points[other].as_ref())
+    })
+    .map_err(|cycle| {
+        let names: Vec<&str> = cycle.iter().map(|&i| mount_points[i].as_ref()).collect();
+        anyhow!(
+            "can't order the mount points {}, as they are nested in each other",
+            names.join(", ")
+        )
+    })
+}
+
+/// Match `text` against the glob `pattern`, in which `?` matches any
+/// character other than `/`, `*` matches any run of those, and `**`
+/// matches any run of characters including `/`
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern {
+            [] => text.is_empty(),
+            ['*', '*', rest @ ..] => (0..=text.len()).any(|skip| matches(rest, &text[skip..])),
+            ['*', rest @ ..] => {
+                let limit = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+                (0..=limit).any(|skip| matches(rest, &text[skip..]))
+            }
+            ['?', rest @ ..] => match text {
+                [c, text @ ..] if *c != '/' => matches(rest, text),
+                _ => false,
+            },
+            [p, rest @ ..] => match text {
+                [c, text @ ..] if c == p => matches(rest, text),
+                _ => false,
+            },
+        }
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_normalize_path() {
+        let cwd = Path::new("/Users/wez/repo");
+        for (input, expected) in &[
+            ("/Users/wez/repo/buck-out", "/Users/wez/repo/buck-out"),
+            ("/Users/wez/repo/buck-out/", "/Users/wez/repo/buck-out"),
+            ("/Users/wez/repo/buck-out//", "/Users/wez/repo/buck-out"),
+            ("/Users//wez/./repo/buck-out", "/Users/wez/repo/buck-out"),
+            ("buck-out", "/Users/wez/repo/buck-out"),
+            ("buck-out/", "/Users/wez/repo/buck-out"),
+            ("./buck-out", "/Users/wez/repo/buck-out"),
+            ("./buck-out/.", "/Users/wez/repo/buck-out"),
+            ("my repo/buck-out", "/Users/wez/repo/my repo/buck-out"),
+            (".", "/Users/wez/repo"),
+            ("/", "/"),
+            ("//", "/"),
+        ] {
+            assert_eq!(&normalize_path(input, cwd).unwrap(), expected, "{}", input);
+        }
+        for input in &[
+            "",
+            "..",
+            "../buck-out",
+            "buck-out/..",
+            "/Users/wez/repo/../other/buck-out",
+        ] {
+            assert!(normalize_path(input, cwd).is_err(), "{}", input);
+        }
+        assert!(normalize_path("buck-out", Path::new("relative")).is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        for (pattern, text, expected) in &[
+            ("/Users/wez/repo/buck-out", "/Users/wez/repo/buck-out", true),
+            (
+                "/Users/wez/repo/buck-out",
+                "/Users/wez/repo/buck-out2",
+                false,
+            ),
+            (
+                "/Users/*/release/buck-out",
+                "/Users/wez/release/buck-out",
+                true,
+            ),
+            (
+                "/Users/*/release/buck-out",
+                "/Users/wez/a/release/buck-out",
+                false,
+            ),
+            ("/Users/**/buck-out", "/Users/wez/a/release/buck-out", true),
+            ("/Users/**/buck-out", "/Users/wez/buck-out", true),
+            (
+                "/Users/wez/release-?/buck-out",
+                "/Users/wez/release-1/buck-out",
+                true,
+            ),
+            (
+                "/Users/wez/release-?/buck-out",
+                "/Users/wez/release-10/buck-out",
+                false,
+            ),
+            ("/Users/wez/*", "/Users/wez/", true),
+            ("*", "", true),
+            ("", "x", false),
+        ] {
+            assert_eq!(
+                glob_match(pattern, text),
+                *expected,
+                "{} against {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_nested_in() {
+        assert!(is_nested_in("/repo/buck-out/cache", "/repo/buck-out"));
+        assert!(is_nested_in("/repo/buck-out/a/b", "/repo/buck-out"));
+        assert!(is_nested_in("/repo", "/"));
+        assert!(!is_nested_in("/repo/buck-out", "/repo/buck-out"));
+        assert!(!is_nested_in("/repo/buck-out2", "/repo/buck-out"));
+        assert!(!is_nested_in("/repo/buck-out", "/repo/buck-out/cache"));
+        assert!(!is_nested_in("/", "/"));
+    }
+
+    #[test]
+    fn test_mount_order() {
+        assert_eq!(mount_order::<&str>(&[]).unwrap(), Vec::<usize>::new());
+        // Unrelated mount points keep their order
+        assert_eq!(
+            mount_order(&["/b/buck-out", "/a/buck-out"]).unwrap(),
+            vec![0, 1]
+        );
+        assert_eq!(
+            mount_order(&[
+                "/repo/buck-out/cache/deep",
+                "/other/buck-out",
+                "/repo/buck-out/cache",
+                "/repo/buck-out",
+                "/repo/buck-out/tmp",
+            ])
+            .unwrap(),
+            vec![1, 3, 2, 0, 4]
+        );
+    }
+
+    #[test]
+    fn test_dependency_order() {
+        assert_eq!(dependency_order(3, |_, _| false), Ok(vec![0, 1, 2]));
+        assert_eq!(
+            dependency_order(3, |item, other| item + 1 == other),
+            Ok(vec![2, 1, 0])
+        );
+        // 1 and 2 depend on each other, and 3 depends on 1
+        let depends_on = |item, other| matches!((item, other), (1, 2) | (2, 1) | (3, 1));
+        assert_eq!(dependency_order(4, depends_on), Err(vec![1, 2, 3]));
+    }
+}
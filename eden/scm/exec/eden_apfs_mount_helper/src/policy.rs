@@ -0,0 +1,36 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The policy file belongs to the binary, which knows its format, but
+//! the tool paths, volume prefix and timeouts that it configures are
+//! consulted throughout this crate.  The binary registers a loader here,
+//! and the policy is loaded the first time that one of those is needed,
+//! so that the commands that never need them don't pay for reading the
+//! policy and verifying the tools that it names.
+
+use anyhow::*;
+use once_cell::sync::OnceCell;
+
+static LOADER: OnceCell<fn() -> Result<()>> = OnceCell::new();
+static LOADED: OnceCell<()> = OnceCell::new();
+
+/// Register the function that loads the policy and installs the settings
+/// that it configures.  Only the first loader to be registered is used.
+pub fn set_policy_loader(loader: fn() -> Result<()>) {
+    LOADER.set(loader).ok();
+}
+
+/// Load the policy, unless that was already done.  Without a loader the
+/// defaults stay in effect.  A policy that fails to load is retried, and
+/// so reported, by the next caller.
+pub fn load_policy() -> Result<()> {
+    LOADED.get_or_try_init(|| match LOADER.get() {
+        Some(loader) => loader(),
+        None => Ok(()),
+    })?;
+    Ok(())
+}
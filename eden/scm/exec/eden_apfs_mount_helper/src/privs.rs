@@ -0,0 +1,471 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Helpers for managing the privileges that we run with.
+//! We are usually installed setuid root, so the effective uid is root
+//! and the real uid is that of the user that invoked us.
+
+use crate::timeouts::{Operation, Watchdog};
+use anyhow::*;
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+
+/// Prepare a command to be run with root privs.
+/// The path must be absolute to avoid being fooled into running something
+/// unexpected.
+/// Like all of the commands that we run, it runs in the C locale so
+/// that we can parse its output.
+/// The caller must already have root privs, otherwise this will fail.
+pub fn new_cmd_with_root_privs(path: &str) -> Command {
+    let path: PathBuf = path.into();
+    assert!(path.is_absolute());
+    assert!(
+        geteuid() == 0 || fixtures_active(),
+        "root privs are required to run {}",
+        path.display()
+    );
+    let mut cmd = Command::new(path);
+    cmd.env("LC_ALL", "C");
+    cmd
+}
+
+/// Prepare a command to be run with no special privs.
+/// We're usually installed setuid root so we already have privs; the
+/// command invocation will restore the real uid/gid of the caller
+/// as part of running the command so that we avoid running too much
+/// stuff with privs.
+pub fn new_cmd_unprivileged(path: &str) -> Command {
+    let path: PathBuf = path.into();
+    assert!(path.is_absolute());
+    let mut cmd = Command::new(path);
+    cmd.env("LC_ALL", "C");
+
+    if geteuid() == 0 {
+        // We're running with effective root privs; run this command
+        // with the privs of the real user, just in case.
+        cmd.uid(getuid()).gid(getgid());
+    }
+
+    cmd
+}
+
+/// Run a command prepared by one of the functions above and collect
+/// its output.  This is the point at which the fixture test mode
+/// substitutes canned output for the real thing, and at which commands
+/// are recorded in the transcript.
+pub trait RunCommand {
+    fn run(&mut self) -> std::io::Result<Output>;
+
+    /// `run`, for a command whose output is a secret, such as a
+    /// passphrase, which is kept out of the transcript
+    fn run_secret(&mut self) -> std::io::Result<Output>;
+
+    /// Run the command with `input` as its stdin.  This is how we pass
+    /// secrets to the commands that we run, so that they don't show
+    /// up in the process table.
+    fn run_with_input(&mut self, input: &[u8]) -> std::io::Result<Output>;
+
+    /// Run the command on behalf of `operation`, killing it if it runs
+    /// for longer than the timeout for that operation allows
+    fn run_timed(&mut self, operation: Operation) -> std::io::Result<Output>;
+
+    /// `run_timed`, with `input` as the stdin of the command
+    fn run_timed_with_input(
+        &mut self,
+        operation: Operation,
+        input: &[u8],
+    ) -> std::io::Result<Output>;
+}
+
+impl RunCommand for Command {
+    fn run(&mut self) -> std::io::Result<Output> {
+        if let Some(output) = crate::dry_run::skip_command(self) {
+            return Ok(output);
+        }
+        crate::transcript::recording(self, output)
+    }
+
+    fn run_secret(&mut self) -> std::io::Result<Output> {
+        if let Some(output) = crate::dry_run::skip_command(self) {
+            return Ok(output);
+        }
+        crate::transcript::recording_secret(self, output)
+    }
+
+    fn run_with_input(&mut self, input: &[u8]) -> std::io::Result<Output> {
+        if let Some(output) = crate::dry_run::skip_command(self) {
+            return Ok(output);
+        }
+        crate::transcript::recording(self, |cmd| {
+            #[cfg(feature = "fixtures")]
+            {
+                if let Some(output) = crate::fixtures::fake_output(cmd) {
+                    return output;
+                }
+            }
+            let mut child = cmd
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()?;
+            // The command may exit without reading all of its input, which
+            // we'll find out about from its exit status
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(input).ok();
+            }
+            child.wait_with_output()
+        })
+    }
+
+    fn run_timed(&mut self, operation: Operation) -> std::io::Result<Output> {
+        run_timed(self, operation, None)
+    }
+
+    fn run_timed_with_input(
+        &mut self,
+        operation: Operation,
+        input: &[u8],
+    ) -> std::io::Result<Output> {
+        run_timed(self, operation, Some(input))
+    }
+}
+
+/// Run `cmd` to completion and collect its output
+fn output(cmd: &mut Command) -> std::io::Result<Output> {
+    #[cfg(feature = "fixtures")]
+    {
+        if let Some(output) = crate::fixtures::fake_output(cmd) {
+            return output;
+        }
+    }
+    cmd.output()
+}
+
+fn run_timed(
+    cmd: &mut Command,
+    operation: Operation,
+    input: Option<&[u8]>,
+) -> std::io::Result<Output> {
+    if let Some(output) = crate::dry_run::skip_command(cmd) {
+        return Ok(output);
+    }
+    crate::transcript::recording(cmd, |cmd| {
+        #[cfg(feature = "fixtures")]
+        {
+            if let Some(output) = crate::fixtures::fake_output(cmd) {
+                return output;
+            }
+        }
+        let mut child = cmd
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+        let watchdog = Watchdog::start(&child, operation);
+        // The input is small enough to fit in the pipe, so this can't
+        // block; as with `run_with_input`, a command that exits without
+        // reading it tells us so with its exit status
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input).ok();
+        }
+        let status = watchdog.finish(&mut child)?;
+        Ok(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    })
+}
+
+/// Read all of `pipe` on another thread, so that the child can't block
+/// on a full pipe while we wait for it
+pub(crate) fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut data = vec![];
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut data).ok();
+        }
+        data
+    })
+}
+
+/// Returns true if the fixture test mode is active, in which case
+/// nothing that we run really requires privileges.
+#[cfg(feature = "fixtures")]
+pub fn fixtures_active() -> bool {
+    crate::fixtures::is_active()
+}
+
+#[cfg(not(feature = "fixtures"))]
+pub fn fixtures_active() -> bool {
+    false
+}
+
+pub fn getgid() -> u32 {
+    unsafe { libc::getgid() }
+}
+
+pub fn getuid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+pub fn geteuid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+pub fn get_real_uid() -> Result<u32> {
+    let uid = getuid();
+
+    if uid != 0 {
+        return Ok(uid);
+    }
+
+    // We're really root (not just setuid root).  We may actually be
+    // running under sudo so let's see what sudo says about the UID
+    match std::env::var("SUDO_UID") {
+        Ok(uid) => Ok(uid.parse().context(format!(
+            "parsing the SUDO_UID={} env var as an integer",
+            uid
+        ))?),
+        Err(std::env::VarError::NotPresent) => Ok(uid),
+        Err(std::env::VarError::NotUnicode(_)) => bail!("the SUDO_UID env var is not unicode"),
+    }
+}
+
+/// Returns the home directory of the real user, as recorded in the
+/// password database.  `$HOME` is deliberately not consulted, as it is
+/// under the control of the caller.
+pub fn get_real_user_home() -> Result<String> {
+    let uid = get_real_uid()?;
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut pwd,
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+                &mut result,
+            )
+        };
+        if rc == libc::ERANGE {
+            let len = buf.len() * 2;
+            buf.resize(len, 0);
+            continue;
+        }
+        if rc != 0 {
+            return Err(std::io::Error::from_raw_os_error(rc))
+                .context(format!("looking up the home directory of uid {}", uid));
+        }
+        if result.is_null() || pwd.pw_dir.is_null() {
+            bail!("uid {} has no entry in the password database", uid);
+        }
+        let dir = unsafe { std::ffi::CStr::from_ptr(pwd.pw_dir) };
+        return dir
+            .to_str()
+            .map(str::to_owned)
+            .map_err(|_| anyhow!("the home directory of uid {} isn't unicode", uid));
+    }
+}
+
+/// Run `func` with the effective uid temporarily set to the real uid.
+/// We use this when touching files that belong to the caller so that
+/// access is checked against their permissions rather than ours,
+/// and so that anything we create is owned by them.
+pub fn with_real_user_privs<T>(func: impl FnOnce() -> Result<T>) -> Result<T> {
+    let euid = geteuid();
+    let uid = getuid();
+    if euid == uid {
+        return func();
+    }
+
+    if unsafe { libc::seteuid(uid) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).context(format!("failed to switch effective uid to {}", uid));
+    }
+    let result = func();
+    if unsafe { libc::seteuid(euid) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).context(format!("failed to restore effective uid {}", euid));
+    }
+    result
+}
+
+/// Irrevocably give up our root privs by setting all of our user and
+/// group ids to the real ids.  Call this as soon as the last privileged
+/// operation is complete.  This is a no-op if we are not running with
+/// elevated privs.  The latencies noted so far are recorded in the
+/// registry first, while we still can.
+pub fn drop_privileges() -> Result<()> {
+    crate::registry::record_latencies();
+    let uid = getuid();
+    let gid = getgid();
+    if geteuid() == uid && unsafe { libc::getegid() } == gid {
+        return Ok(());
+    }
+    // The group must be changed first, as we can't change it once we've
+    // given up root
+    if unsafe { libc::setgid(gid) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).context(format!("failed to set the group id to {}", gid));
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).context(format!("failed to set the user id to {}", uid));
+    }
+    if uid != 0 && unsafe { libc::seteuid(0) } == 0 {
+        bail!("root privs could be regained after dropping them");
+    }
+    Ok(())
+}
+
+/// The environment variables that survive `harden_process`, in addition
+/// to those nominated by the caller.
+const PRESERVED_ENV_VARS: &[&str] = &[
+    "HOME",
+    "LOGNAME",
+    "USER",
+    "SUDO_UID",
+    "SUDO_GID",
+    "SUDO_USER",
+    "TERM",
+    #[cfg(feature = "fixtures")]
+    crate::fixtures::FIXTURES_ENV,
+    #[cfg(feature = "fake-diskutil")]
+    crate::tools::FAKE_DISKUTIL_ENV,
+];
+
+/// The search path that we run with; we use absolute paths for the tools
+/// that we run, but they may run others in turn.
+const SAFE_PATH: &str = "/usr/bin:/bin:/usr/sbin:/sbin";
+
+fn is_preserved_env_var(name: &str, preserve: &[&str]) -> bool {
+    PRESERVED_ENV_VARS.contains(&name) || preserve.contains(&name)
+}
+
+/// Protect the rest of the process from a hostile calling environment.
+/// This must be called at the very start of `main`, before we look at
+/// our arguments or environment.  It:
+///
+/// * refuses to run unless stdin, stdout and stderr are open, so that
+///   nothing that we open can be mistaken for one of them,
+/// * closes any other descriptors that we inherited,
+/// * restores the default signal dispositions and unblocks all signals,
+/// * sets a umask that doesn't permit group or world writes,
+/// * removes all environment variables other than a small set of
+///   benign ones and those named in `preserve`, and sets a safe `PATH`.
+pub fn harden_process(preserve: &[&str]) -> Result<()> {
+    if STDIO_WAS_CLOSED.load(Ordering::SeqCst) {
+        bail!("refusing to run with stdin, stdout or stderr closed");
+    }
+
+    close_inherited_fds();
+
+    for signal in 1..32 {
+        // SIGPIPE is ignored by the Rust runtime so that failed writes
+        // are reported as errors, so we leave that one alone.
+        if signal == libc::SIGKILL || signal == libc::SIGSTOP || signal == libc::SIGPIPE {
+            continue;
+        }
+        unsafe { libc::signal(signal, libc::SIG_DFL) };
+    }
+    unsafe {
+        let mut mask: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut mask);
+        if libc::sigprocmask(libc::SIG_SETMASK, &mask, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error()).context("unblocking signals");
+        }
+        libc::umask(0o022);
+    }
+
+    for (name, _) in std::env::vars_os() {
+        let keep = name
+            .to_str()
+            .map(|name| is_preserved_env_var(name, preserve))
+            .unwrap_or(false);
+        if !keep {
+            std::env::remove_var(&name);
+        }
+    }
+    std::env::set_var("PATH", SAFE_PATH);
+
+    Ok(())
+}
+
+/// Set before `main` runs if any of stdin, stdout or stderr was closed
+/// when we were started.  The Rust runtime quietly reopens them on
+/// /dev/null before calling `main`, so we have to look before it does.
+static STDIO_WAS_CLOSED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn check_stdio_at_startup() {
+    for fd in 0..=2 {
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            STDIO_WAS_CLOSED.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Registers `check_stdio_at_startup` as a static initializer, which
+/// dyld runs before the Rust runtime is initialized.
+#[used]
+#[cfg_attr(target_vendor = "apple", link_section = "__DATA,__mod_init_func")]
+static CHECK_STDIO_AT_STARTUP: extern "C" fn() = check_stdio_at_startup;
+
+/// Close every descriptor other than stdin, stdout and stderr
+fn close_inherited_fds() {
+    // /dev/fd lists the descriptors that are open.  Reading it opens
+    // one more, which will already be closed by the time we try to
+    // close it.
+    let fds: Vec<libc::c_int> = match std::fs::read_dir("/dev/fd") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => {
+            let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+            let max = if max <= 0 { 1024 } else { max.min(65536) };
+            (0..max as libc::c_int).collect()
+        }
+    };
+    for fd in fds {
+        if fd > 2 {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_preserved_env_vars() {
+        assert!(is_preserved_env_var("HOME", &[]));
+        assert!(is_preserved_env_var("SUDO_UID", &[]));
+        assert!(is_preserved_env_var("EXTRA", &["EXTRA"]));
+        for name in &[
+            "DYLD_INSERT_LIBRARIES",
+            "LD_PRELOAD",
+            "IFS",
+            "PATH",
+            "TMPDIR",
+        ] {
+            assert!(!is_preserved_env_var(name, &[]), "{}", name);
+        }
+    }
+}
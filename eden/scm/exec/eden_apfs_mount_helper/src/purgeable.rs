@@ -0,0 +1,112 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The space held by purgeable data, such as local snapshots and caches,
+//! which macOS frees on demand when space runs low.  diskutil doesn't
+//! report this, so we ask CoreFoundation for the capacity that is
+//! available for "important" usage, which counts purgeable data as
+//! available, and subtract the space that statfs reports as available.
+
+use anyhow::*;
+use std::ffi::CString;
+use std::os::raw::{c_long, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+type CFTypeRef = *const c_void;
+type CFIndex = c_long;
+type Boolean = u8;
+
+const K_CF_NUMBER_SINT64_TYPE: CFIndex = 4;
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    static kCFURLVolumeAvailableCapacityForImportantUsageKey: CFTypeRef;
+    fn CFURLCreateFromFileSystemRepresentation(
+        allocator: CFTypeRef,
+        buffer: *const u8,
+        len: CFIndex,
+        is_directory: Boolean,
+    ) -> CFTypeRef;
+    fn CFURLCopyResourcePropertyForKey(
+        url: CFTypeRef,
+        key: CFTypeRef,
+        value: *mut CFTypeRef,
+        error: *mut CFTypeRef,
+    ) -> Boolean;
+    fn CFNumberGetValue(number: CFTypeRef, the_type: CFIndex, value: *mut c_void) -> Boolean;
+    fn CFRelease(cf: CFTypeRef);
+}
+
+/// The space available to the volume that holds `path` for important
+/// usage, which counts purgeable data as available
+fn important_usage_capacity(path: &Path) -> Result<u64> {
+    let bytes = path.as_os_str().as_bytes();
+    let mut capacity: i64 = 0;
+    unsafe {
+        let url = CFURLCreateFromFileSystemRepresentation(
+            std::ptr::null(),
+            bytes.as_ptr(),
+            bytes.len() as CFIndex,
+            1,
+        );
+        if url.is_null() {
+            bail!("failed to create a URL for {}", path.display());
+        }
+        let mut value: CFTypeRef = std::ptr::null();
+        let ok = CFURLCopyResourcePropertyForKey(
+            url,
+            kCFURLVolumeAvailableCapacityForImportantUsageKey,
+            &mut value,
+            std::ptr::null_mut(),
+        );
+        CFRelease(url);
+        if ok == 0 || value.is_null() {
+            bail!(
+                "failed to query the available capacity of {}",
+                path.display()
+            );
+        }
+        let ok = CFNumberGetValue(
+            value,
+            K_CF_NUMBER_SINT64_TYPE,
+            &mut capacity as *mut i64 as *mut c_void,
+        );
+        CFRelease(value);
+        if ok == 0 {
+            bail!(
+                "the available capacity of {} is not a 64-bit number",
+                path.display()
+            );
+        }
+    }
+    Ok(capacity.max(0) as u64)
+}
+
+/// The space that is available to the volume that holds `path` right
+/// now, without purging anything
+fn statfs_available(path: &Path) -> Result<u64> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(cpath.as_ptr(), &mut buf) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).context(format!("statfs {}", path.display()));
+    }
+    Ok(buf.f_bavail.saturating_mul(buf.f_bsize as u64))
+}
+
+/// Returns the space held by purgeable data in the container of the
+/// volume mounted at `path`
+pub fn purgeable_space(path: &Path) -> Result<u64> {
+    #[cfg(feature = "fixtures")]
+    {
+        if let Some(fake) = crate::fixtures::fake_purgeable_space(path) {
+            return fake;
+        }
+    }
+    Ok(important_usage_capacity(path)?.saturating_sub(statfs_available(path)?))
+}
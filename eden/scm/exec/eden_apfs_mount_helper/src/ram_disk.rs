@@ -0,0 +1,229 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! RAM disks holding a single APFS volume, for CI jobs with memory to
+//! spare and no need to keep their scratch space.
+//! The RAM disk is given an APFS container of its own, in which the
+//! volume is created with the usual name, so it is mounted and listed
+//! like any other volume.  The registry records which RAM disk holds the
+//! volume for each mount point, so that unmounting or deleting the
+//! volume detaches the RAM disk and gives its memory back; the contents
+//! of the volume go with it.
+
+use crate::apfs::{
+    apfs_list, check_interlock, disk_info, is_container_identifier, make_new_volume,
+};
+use crate::apfs::{ApfsContainer, ApfsVolume, VolumeOptions};
+use crate::dry_run::dry_run;
+use crate::privs::{new_cmd_unprivileged, new_cmd_with_root_privs, RunCommand};
+use crate::timeouts::Operation;
+use crate::tools::tool_paths;
+use anyhow::*;
+
+const HDIUTIL: &str = "/usr/bin/hdiutil";
+
+/// The size of the sectors that RAM disks are measured in
+const SECTOR_SIZE: u64 = 512;
+
+/// The image to attach for a RAM disk that can hold `bytes`
+fn ram_disk_url(bytes: u64) -> String {
+    format!("ram://{}", bytes.div_ceil(SECTOR_SIZE).max(1))
+}
+
+/// Extract the disk from the output of `hdiutil attach -nomount ram://N`,
+/// which is the device node followed by some padding, eg: `/dev/disk6`
+fn parse_attach_output(text: &str) -> Option<String> {
+    let disk = text.split_whitespace().next()?.strip_prefix("/dev/")?;
+    // A whole disk looks like a container, eg: disk6
+    if is_container_identifier(disk) {
+        Some(disk.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Create a RAM disk of `size` bytes, or of the quota in `options`, and
+/// the volume `name` in it, returning the volume and the RAM disk.
+/// `size` comes from the policy and is a ceiling, so a larger quota is
+/// refused rather than having us set that much memory aside as root.
+pub fn make_ram_disk_volume(
+    name: &str,
+    options: &VolumeOptions,
+    size: u64,
+) -> Result<(ApfsVolume, String)> {
+    if options.passphrase.is_some() {
+        bail!("RAM disks can't be encrypted; use the apfs backend for --encrypted");
+    }
+    if options.reserve.is_some() {
+        bail!("RAM disks can't reserve space; use the apfs backend for --reserve");
+    }
+    if let Some(quota) = options.quota.filter(|&quota| quota > size) {
+        bail!(
+            "--quota {} is more than the {} bytes that the policy allows a RAM disk",
+            quota,
+            size
+        );
+    }
+    let output = new_cmd_with_root_privs(HDIUTIL)
+        .args(&[
+            "attach",
+            "-nomount",
+            &ram_disk_url(options.quota.unwrap_or(size)),
+        ])
+        .run_timed(Operation::Create)?;
+    if !output.status.success() {
+        bail!(
+            "failed to create a RAM disk: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    if dry_run() {
+        return Ok((
+            ApfsVolume {
+                device_identifier: "disk<ram>s1".to_owned(),
+                name: Some(name.to_owned()),
+                ..Default::default()
+            },
+            "disk<ram>".to_owned(),
+        ));
+    }
+    let disk = parse_attach_output(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+        anyhow!(
+            "didn't recognize the output of hdiutil attach: {:?}",
+            output
+        )
+    })?;
+    match make_ram_disk_container(&disk).and_then(|container| {
+        // The size of the RAM disk already limits the volume
+        let options = VolumeOptions {
+            quota: None,
+            ..options.clone()
+        };
+        make_new_volume(name, &container, &options)
+    }) {
+        Ok(volume) => Ok((volume, disk)),
+        Err(err) => {
+            if let Err(detach_err) = detach_ram_disk(&disk) {
+                eprintln!("failed to detach the RAM disk {}: {:#}", disk, detach_err);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Create an APFS container on the RAM disk `disk`, returning it
+fn make_ram_disk_container(disk: &str) -> Result<String> {
+    let output = new_cmd_unprivileged(&tool_paths()?.diskutil)
+        .args(&["apfs", "createContainer", disk])
+        .run_timed(Operation::Create)?;
+    if !output.status.success() {
+        bail!(
+            "failed to create an APFS container on the RAM disk {}: {:?}",
+            disk,
+            output
+        );
+    }
+    apfs_list()?
+        .into_iter()
+        .find(|container| container.is_on_disk(disk))
+        .map(|container| container.container_reference)
+        .ok_or_else(|| anyhow!("did not find the APFS container on the RAM disk {}", disk))
+}
+
+/// Detach the RAM disk `disk`, discarding its contents
+pub fn detach_ram_disk(disk: &str) -> Result<()> {
+    let output = new_cmd_with_root_privs(HDIUTIL)
+        .args(&["detach", disk])
+        .run_timed(Operation::Unmount)?;
+    if !output.status.success() {
+        bail!(
+            "failed to detach the RAM disk {}: {}",
+            disk,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Whether the volume `name` lives on the RAM disk `disk`.  The registry
+/// records the RAM disk that holds each volume, but RAM disks go away on
+/// reboot and their numbers are handed out again, so the record alone
+/// mustn't lead us to detach whatever disk has that number now.
+pub fn is_on_ram_disk(containers: &[ApfsContainer], name: &str, disk: &str) -> bool {
+    containers.iter().any(|container| {
+        container.is_on_disk(disk)
+            && container
+                .volumes
+                .iter()
+                .any(|volume| volume.name.as_deref() == Some(name))
+    })
+}
+
+/// Delete `volume`, which lives on the RAM disk `disk`, by detaching the
+/// RAM disk.  As with `delete_volume`, `volume` must be the volume that
+/// we created for `mount_point`, which is checked again first, along with
+/// the RAM disk that it is on.
+pub fn delete_ram_disk_volume(volume: &ApfsVolume, disk: &str, mount_point: &str) -> Result<()> {
+    let info = disk_info(&volume.device_identifier)?;
+    check_interlock(volume, &info, mount_point, "delete")?;
+    let name = volume.name.as_deref().unwrap_or("");
+    if !is_on_ram_disk(&apfs_list()?, name, disk) {
+        bail!(
+            "refusing to detach {}, which doesn't hold {}",
+            disk,
+            volume.device_identifier
+        );
+    }
+    detach_ram_disk(disk)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_ram_disk_url() {
+        assert_eq!(ram_disk_url(4 << 30), "ram://8388608");
+        assert_eq!(ram_disk_url(1000), "ram://2");
+        assert_eq!(ram_disk_url(0), "ram://1");
+    }
+
+    #[test]
+    fn test_parse_attach_output() {
+        assert_eq!(
+            parse_attach_output("/dev/disk6          \t             \t\n"),
+            Some("disk6".to_owned())
+        );
+        assert_eq!(parse_attach_output("/dev/disk6s1\n"), None);
+        assert_eq!(parse_attach_output("hdiutil: attach failed\n"), None);
+        assert_eq!(parse_attach_output(""), None);
+    }
+
+    #[test]
+    fn test_is_on_ram_disk() {
+        let containers: Vec<ApfsContainer> = serde_json::from_str(
+            r#"[
+                {
+                    "ContainerReference": "disk7",
+                    "PhysicalStores": [{"DeviceIdentifier": "disk6s1"}],
+                    "Volumes": [{"DeviceIdentifier": "disk7s1", "Name": "edenfs:/tmp/a"}]
+                },
+                {
+                    "ContainerReference": "disk1",
+                    "PhysicalStores": [{"DeviceIdentifier": "disk0s2"}],
+                    "Volumes": [{"DeviceIdentifier": "disk1s5", "Name": "edenfs:/tmp/b"}]
+                }
+            ]"#,
+        )
+        .unwrap();
+        assert!(is_on_ram_disk(&containers, "edenfs:/tmp/a", "disk6"));
+        assert!(!is_on_ram_disk(&containers, "edenfs:/tmp/b", "disk6"));
+        assert!(!is_on_ram_disk(&containers, "edenfs:/tmp/b", "disk0s2"));
+        assert!(!is_on_ram_disk(&containers, "edenfs:/tmp/c", "disk6"));
+    }
+}
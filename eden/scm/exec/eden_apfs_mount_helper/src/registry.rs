@@ -0,0 +1,619 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The registry is where we keep track of what we have done to each
+//! mount point, so that tools such as `eden doctor` can report on the
+//! recent history of a mount point.
+//! It is a json file owned by root, and so it can only be updated
+//! while we have root privs.
+
+pub use crate::report::HistoryEntry;
+use crate::volume_name::{volume_prefix, DEFAULT_VOLUME_PREFIX};
+use anyhow::*;
+use serde::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The directory that holds the registry
+const REGISTRY_DIR: &str = "/var/db/eden_apfs_mount_helper";
+
+/// The number of history entries that we keep for each mount point
+pub const HISTORY_LIMIT: usize = 50;
+
+/// The number of usage samples that we keep for each mount point and
+/// container
+pub const USAGE_LIMIT: usize = 60;
+
+/// Volumes whose label starts with this word are never garbage collected
+pub const KEEP_LABEL: &str = "keep";
+
+/// The upper bounds of the buckets of the latency histograms, in
+/// milliseconds.  There is one more bucket for anything longer.
+pub const LATENCY_BUCKETS_MS: &[u64] = &[
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000, 120_000, 300_000,
+];
+
+/// The number of daily latency histograms that we keep for each operation
+pub const LATENCY_DAYS: usize = 7;
+
+/// The number of latencies that we keep for each operation until
+/// `stats --record` folds them into the histograms
+pub const PENDING_LATENCY_LIMIT: usize = 1000;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct Registry {
+    #[serde(default)]
+    pub mounts: BTreeMap<String, MountRecord>,
+    /// The space in use in each container that holds our volumes, as
+    /// recorded by `stats --record`, oldest first
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub containers: BTreeMap<String, VecDeque<UsageSample>>,
+    /// The latencies of each operation, in milliseconds, that have yet
+    /// to be folded into `latency` by `stats --record`, oldest first
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pending_latency: BTreeMap<String, VecDeque<u64>>,
+    /// A histogram of the latency of each operation for each of the last
+    /// `LATENCY_DAYS` days that it ran on, oldest first
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub latency: BTreeMap<String, VecDeque<LatencyHistogram>>,
+}
+
+/// How long an operation took over the course of a day
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// The start of the day, in seconds since the unix epoch
+    pub day: u64,
+    /// The number of latencies in each of the buckets bounded by
+    /// `LATENCY_BUCKETS_MS`, followed by the number that were longer
+    pub counts: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    pub fn add(&mut self, millis: u64) {
+        self.counts.resize(LATENCY_BUCKETS_MS.len() + 1, 0);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.counts[bucket] += 1;
+    }
+
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        self.counts.resize(LATENCY_BUCKETS_MS.len() + 1, 0);
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The latency in milliseconds that `percent`% of the samples didn't
+    /// exceed, to the resolution of the buckets.  This is None if there
+    /// are no samples, or if it is beyond the last bucket.
+    pub fn percentile(&self, percent: u64) -> Option<u64> {
+        let rank = (self.samples() * percent).div_ceil(100);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return LATENCY_BUCKETS_MS.get(bucket).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Fold the pending latencies into the histograms for the day that
+/// includes `now`, discarding the histograms that are too old
+pub fn fold_latency(registry: &mut Registry, now: u64) {
+    let day = now - now % SECONDS_PER_DAY;
+    for (operation, pending) in std::mem::take(&mut registry.pending_latency) {
+        let histograms = registry.latency.entry(operation).or_default();
+        if histograms.back().map(|histogram| histogram.day) != Some(day) {
+            histograms.push_back(LatencyHistogram {
+                day,
+                ..Default::default()
+            });
+        }
+        let histogram = histograms.back_mut().unwrap();
+        for millis in pending {
+            histogram.add(millis);
+        }
+    }
+    for histograms in registry.latency.values_mut() {
+        while histograms.len() > LATENCY_DAYS {
+            histograms.pop_front();
+        }
+    }
+}
+
+/// The space in use at a point in time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsageSample {
+    /// In seconds since the unix epoch
+    pub time: u64,
+    pub bytes: u64,
+}
+
+/// Record a sample, discarding the oldest samples to stay within
+/// `USAGE_LIMIT`
+pub fn push_usage(samples: &mut VecDeque<UsageSample>, sample: UsageSample) {
+    samples.push_back(sample);
+    while samples.len() > USAGE_LIMIT {
+        samples.pop_front();
+    }
+}
+
+/// What we know about a mount point
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct MountRecord {
+    /// The most recent operations on this mount point, oldest first
+    #[serde(default)]
+    pub history: VecDeque<HistoryEntry>,
+    /// A free-form note about the volume, as set by `label set`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// The real uid of the user that created the volume, which `gc`
+    /// expects to still own the mount point
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<u32>,
+    /// The space in use in the volume, as recorded by `stats --record`,
+    /// oldest first
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    pub usage: VecDeque<UsageSample>,
+    /// The RAM disk that holds the volume, eg: `disk6`, which is detached
+    /// when the volume is unmounted; see `ram_disk`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ram_disk: Option<String>,
+}
+
+impl MountRecord {
+    /// Record an entry, discarding the oldest entries to stay
+    /// within `HISTORY_LIMIT`
+    pub fn push_history(&mut self, entry: HistoryEntry) {
+        self.history.push_back(entry);
+        while self.history.len() > HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+    }
+
+    /// Take what describes the volume itself rather than the mount point,
+    /// leaving the history behind
+    pub fn take_volume(&mut self) -> MountRecord {
+        MountRecord {
+            history: VecDeque::new(),
+            label: self.label.take(),
+            creator: self.creator.take(),
+            usage: std::mem::take(&mut self.usage),
+            ram_disk: self.ram_disk.take(),
+        }
+    }
+
+    /// Whether the label asks for the volume to be kept, eg:
+    /// `keep: CI artifacts for D12345`
+    pub fn is_kept(&self) -> bool {
+        self.label
+            .as_deref()
+            .and_then(|label| {
+                label
+                    .split(|c: char| c.is_whitespace() || c == ':')
+                    .find(|word| !word.is_empty())
+            })
+            .map(|word| word.eq_ignore_ascii_case(KEEP_LABEL))
+            .unwrap_or(false)
+    }
+
+    /// Whether the volume was created by `prepare` and hasn't been
+    /// mounted by the user that it was prepared for yet
+    pub fn is_prepared(&self) -> bool {
+        self.creator.is_none()
+            && self
+                .history
+                .iter()
+                .any(|entry| entry.ok && entry.operation == "prepare")
+    }
+}
+
+pub(crate) fn registry_dir() -> PathBuf {
+    #[cfg(feature = "fixtures")]
+    {
+        if let Some(dir) = crate::fixtures::fixture_dir() {
+            return dir.to_owned();
+        }
+    }
+    PathBuf::from(REGISTRY_DIR)
+}
+
+/// The registry of the volumes with the prefix in effect.  Each prefix
+/// has a registry of its own, so that the tools that share this one
+/// don't see each other's mount points as missing volumes.  The policy
+/// sets the prefix, so this loads it.
+fn registry_path() -> Result<PathBuf> {
+    crate::policy::load_policy()?;
+    Ok(match volume_prefix() {
+        DEFAULT_VOLUME_PREFIX => registry_dir().join("registry.json"),
+        prefix => registry_dir().join(format!("registry-{}.json", prefix.trim_end_matches(':'))),
+    })
+}
+
+impl Registry {
+    /// Load the registry.  It is empty if it doesn't exist yet.
+    /// A registry that isn't root-owned or that is writable by other
+    /// users is rejected.
+    pub fn load() -> Result<Self> {
+        let path = registry_path()?;
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).context(format!("reading {}", path.display())),
+        };
+        if !crate::privs::fixtures_active() && (metadata.uid() != 0 || metadata.mode() & 0o022 != 0)
+        {
+            bail!(
+                "refusing to use {} because it is not owned by root \
+                 or is writable by other users",
+                path.display()
+            );
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Apply `func` to the registry and save the result.  Updates are
+    /// serialized by holding an exclusive lock for the duration.
+    /// This requires root privs.  Nothing is recorded in the dry-run mode.
+    pub fn update<F: FnOnce(&mut Registry)>(func: F) -> Result<()> {
+        if crate::dry_run::dry_run() {
+            return Ok(());
+        }
+        let dir = registry_dir();
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+
+        let lock_path = dir.join("registry.lock");
+        let lock = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(0o644)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&lock_path)
+            .with_context(|| format!("opening {}", lock_path.display()))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            let err = std::io::Error::last_os_error();
+            return Err(err).context(format!("locking {}", lock_path.display()));
+        }
+
+        let mut registry = Self::load()?;
+        func(&mut registry);
+
+        // Write the new contents alongside and then atomically replace
+        // the registry so that readers never see a partial update
+        let path = registry_path()?;
+        let temp_path = dir.join("registry.json.tmp");
+        std::fs::remove_file(&temp_path).ok();
+        let mut temp = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o644)
+            .open(&temp_path)
+            .with_context(|| format!("creating {}", temp_path.display()))?;
+        temp.write_all(serde_json::to_string_pretty(&registry)?.as_bytes())
+            .and_then(|_| temp.sync_all())
+            .with_context(|| format!("writing {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| format!("replacing {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Record `entry` for `mount_point`; see `record_history`
+    pub fn push_history(&mut self, mount_point: &str, entry: HistoryEntry) {
+        if entry.ok {
+            self.mounts
+                .entry(mount_point.to_owned())
+                .or_default()
+                .push_history(entry);
+        } else if let Some(record) = self.mounts.get_mut(mount_point) {
+            record.push_history(entry);
+        }
+    }
+}
+
+/// The current time, in seconds since the unix epoch, as recorded in
+/// the registry
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Record the outcome of `operation` on `mount_point` in the registry.
+/// Failing to update the registry is reported but otherwise ignored, as
+/// it shouldn't prevent the operation itself from succeeding.
+/// A failure is only recorded for a mount point that the registry already
+/// knows, as any path can be passed to `unmount` or `delete`, and a record
+/// for each of them would grow the registry without bound.
+pub fn record_history<T>(mount_point: &str, operation: &str, result: &Result<T>) {
+    let entry = history_entry(operation, result);
+    let update = Registry::update(|registry| registry.push_history(mount_point, entry));
+    if let Err(err) = update {
+        eprintln!(
+            "failed to record the {} of {} in the registry: {:#}",
+            operation, mount_point, err
+        );
+    }
+}
+
+fn history_entry<T>(operation: &str, result: &Result<T>) -> HistoryEntry {
+    HistoryEntry {
+        time: unix_time(),
+        operation: operation.to_owned(),
+        ok: result.is_ok(),
+        error: result.as_ref().err().map(|err| format!("{:#}", err)),
+        environment: crate::environment::environment_tag(),
+    }
+}
+
+/// Record that the volume for `from` was renamed to be the one for `to`.
+/// As far as their histories, and so `list --since`, are concerned, the
+/// volume was deleted from `from` and created at `to`, but what describes
+/// the volume itself, such as its label and creator, goes with it.  As
+/// with `record_history`, failing to do so is reported but otherwise
+/// ignored.
+pub fn record_rename(from: &str, to: &str) {
+    let update = Registry::update(|registry| {
+        let record = registry.mounts.entry(from.to_owned()).or_default();
+        record.push_history(history_entry("delete", &Ok(())));
+        let mut moved = record.take_volume();
+        let record = registry.mounts.entry(to.to_owned()).or_default();
+        moved.history = std::mem::take(&mut record.history);
+        moved.push_history(history_entry("create", &Ok(())));
+        *record = moved;
+    });
+    if let Err(err) = update {
+        eprintln!(
+            "failed to record the rename of {} to {} in the registry: {:#}",
+            from, to, err
+        );
+    }
+}
+
+/// Record the latencies noted by the watchdogs so far in the registry,
+/// if we can; see `timeouts`.  This is called before we give up root
+/// privs, which writing the registry needs, and failing to do so is
+/// reported but otherwise ignored.
+pub fn record_latencies() {
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let latencies = crate::timeouts::take_latencies();
+    if latencies.is_empty() {
+        return;
+    }
+    let update = Registry::update(|registry| {
+        for (operation, elapsed) in latencies {
+            let pending = registry
+                .pending_latency
+                .entry(operation.name().to_owned())
+                .or_default();
+            pending.push_back(elapsed.as_millis() as u64);
+            while pending.len() > PENDING_LATENCY_LIMIT {
+                pending.pop_front();
+            }
+        }
+    });
+    if let Err(err) = update {
+        eprintln!("failed to record latencies in the registry: {:#}", err);
+    }
+}
+
+/// Record that `uid` created the volume for `mount_point`.  As with
+/// `record_history`, failing to do so is reported but otherwise ignored.
+pub fn record_creator(mount_point: &str, uid: u32) {
+    let update = Registry::update(|registry| {
+        registry
+            .mounts
+            .entry(mount_point.to_owned())
+            .or_default()
+            .creator = Some(uid)
+    });
+    if let Err(err) = update {
+        eprintln!(
+            "failed to record the creator of {} in the registry: {:#}",
+            mount_point, err
+        );
+    }
+}
+
+/// Record which RAM disk holds the volume for `mount_point`, if any.
+/// As with `record_history`, failing to do so is reported but otherwise
+/// ignored.
+pub fn record_ram_disk(mount_point: &str, disk: Option<&str>) {
+    let update = Registry::update(|registry| {
+        let record = registry.mounts.entry(mount_point.to_owned()).or_default();
+        record.ram_disk = disk.map(str::to_owned);
+    });
+    if let Err(err) = update {
+        eprintln!(
+            "failed to record the RAM disk of {} in the registry: {:#}",
+            mount_point, err
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_history_limit() {
+        let mut record = MountRecord::default();
+        for time in 0..(HISTORY_LIMIT as u64 + 5) {
+            record.push_history(HistoryEntry {
+                time,
+                operation: "mount".to_owned(),
+                ok: true,
+                error: None,
+                environment: None,
+            });
+        }
+        assert_eq!(record.history.len(), HISTORY_LIMIT);
+        assert_eq!(record.history.front().unwrap().time, 5);
+        assert_eq!(
+            record.history.back().unwrap().time,
+            HISTORY_LIMIT as u64 + 4
+        );
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50), None);
+        for millis in &[5, 10, 11, 40, 90, 200, 400, 800, 2_000, 400_000] {
+            histogram.add(*millis);
+        }
+        assert_eq!(histogram.samples(), 10);
+        assert_eq!(histogram.percentile(10), Some(10));
+        assert_eq!(histogram.percentile(20), Some(10));
+        assert_eq!(histogram.percentile(50), Some(100));
+        assert_eq!(histogram.percentile(90), Some(2_500));
+        assert_eq!(histogram.percentile(99), None);
+
+        let mut merged = LatencyHistogram::default();
+        merged.merge(&histogram);
+        merged.merge(&histogram);
+        assert_eq!(merged.samples(), 20);
+        assert_eq!(merged.percentile(50), Some(100));
+    }
+
+    #[test]
+    fn test_fold_latency() {
+        let mut registry = Registry::default();
+        let day = 1_600_000_000 - 1_600_000_000 % SECONDS_PER_DAY;
+        for n in 0..(LATENCY_DAYS as u64 + 2) {
+            registry
+                .pending_latency
+                .insert("mount".to_owned(), vec![100, 2_000].into());
+            fold_latency(&mut registry, day + n * SECONDS_PER_DAY + 60);
+        }
+        registry
+            .pending_latency
+            .insert("mount".to_owned(), vec![20].into());
+        let last_day = day + (LATENCY_DAYS as u64 + 1) * SECONDS_PER_DAY;
+        fold_latency(&mut registry, last_day + 3600);
+
+        assert!(registry.pending_latency.is_empty());
+        let histograms = &registry.latency["mount"];
+        assert_eq!(histograms.len(), LATENCY_DAYS);
+        assert_eq!(histograms.front().unwrap().day, day + 2 * SECONDS_PER_DAY);
+        assert_eq!(histograms.back().unwrap().day, last_day);
+        assert_eq!(histograms.back().unwrap().samples(), 3);
+    }
+
+    #[test]
+    fn test_is_kept() {
+        let record = |label: Option<&str>| MountRecord {
+            label: label.map(str::to_owned),
+            ..Default::default()
+        };
+        assert!(!record(None).is_kept());
+        assert!(!record(Some("")).is_kept());
+        assert!(!record(Some("safe to delete after March")).is_kept());
+        assert!(!record(Some("keeper")).is_kept());
+        assert!(record(Some("keep")).is_kept());
+        assert!(record(Some("Keep: CI artifacts for D12345")).is_kept());
+        assert!(record(Some("  keep until March")).is_kept());
+    }
+
+    #[test]
+    fn test_is_prepared() {
+        let entry = |operation: &str, ok: bool| HistoryEntry {
+            time: 0,
+            operation: operation.to_owned(),
+            ok,
+            error: None,
+            environment: None,
+        };
+        let record = |history: Vec<HistoryEntry>, creator: Option<u32>| MountRecord {
+            history: history.into(),
+            creator,
+            ..Default::default()
+        };
+        assert!(!record(vec![], None).is_prepared());
+        assert!(!record(vec![entry("create", true)], None).is_prepared());
+        assert!(!record(vec![entry("prepare", false)], None).is_prepared());
+        assert!(record(vec![entry("prepare", true)], None).is_prepared());
+        assert!(!record(vec![entry("prepare", true)], Some(501)).is_prepared());
+    }
+
+    #[test]
+    fn test_failures_of_unknown_mount_points() {
+        let entry = |ok: bool| HistoryEntry {
+            time: 0,
+            operation: "unmount".to_owned(),
+            ok,
+            error: None,
+            environment: None,
+        };
+        let mut registry = Registry::default();
+        registry.push_history("/nowhere", entry(false));
+        assert!(registry.mounts.is_empty());
+        registry.push_history("/somewhere", entry(true));
+        registry.push_history("/somewhere", entry(false));
+        assert_eq!(
+            registry.mounts["/somewhere"].history,
+            VecDeque::from(vec![entry(true), entry(false)])
+        );
+    }
+
+    #[test]
+    fn test_take_volume() {
+        let history: VecDeque<HistoryEntry> = vec![HistoryEntry {
+            time: 1,
+            operation: "create".to_owned(),
+            ok: true,
+            error: None,
+            environment: None,
+        }]
+        .into();
+        let mut record = MountRecord {
+            history: history.clone(),
+            label: Some("keep".to_owned()),
+            creator: Some(501),
+            usage: vec![UsageSample { time: 1, bytes: 2 }].into(),
+            ram_disk: Some("disk6".to_owned()),
+        };
+        let moved = record.take_volume();
+        assert_eq!(
+            record,
+            MountRecord {
+                history,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            moved,
+            MountRecord {
+                history: VecDeque::new(),
+                label: Some("keep".to_owned()),
+                creator: Some(501),
+                usage: vec![UsageSample { time: 1, bytes: 2 }].into(),
+                ram_disk: Some("disk6".to_owned()),
+            }
+        );
+    }
+}
@@ -0,0 +1,739 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The data model of the JSON that the utility reports, so that the eden
+//! CLI and daemon can consume it with the same types that produce it.
+//! The JSON Schemas in `schemas/` are generated from these types, and
+//! `tests/schemas.rs` fails if they drift apart.
+
+use schemars::JsonSchema;
+use serde::*;
+use serde_json::Value;
+
+/// The forms in which the commands can report their output, as chosen
+/// with `--format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Text for humans, usually in tab-separated columns
+    Table,
+    Json,
+    /// An XML property list, for the benefit of macOS tooling
+    Plist,
+}
+
+impl OutputFormat {
+    pub const NAMES: &'static [&'static str] = &["table", "json", "plist"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Table => "table",
+            Self::Json => "json",
+            Self::Plist => "plist",
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            "plist" => Ok(Self::Plist),
+            _ => Err(format!(
+                "unknown format {}; expected one of {}",
+                s,
+                Self::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+/// Render `value` as an XML property list.  Property lists have no
+/// equivalent of null, so null values are left out.
+pub fn to_plist_xml<T: Serialize>(value: &T) -> serde_json::Result<String> {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+         \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n",
+    );
+    write_plist_value(&mut out, &serde_json::to_value(value)?, 0);
+    out.push_str("</plist>\n");
+    Ok(out)
+}
+
+fn write_plist_value(out: &mut String, value: &Value, depth: usize) {
+    let indent = "\t".repeat(depth);
+    match value {
+        Value::Null => {}
+        Value::Bool(b) => out.push_str(&format!("{}<{}/>\n", indent, b)),
+        Value::Number(n) if n.is_f64() => out.push_str(&format!("{}<real>{}</real>\n", indent, n)),
+        Value::Number(n) => out.push_str(&format!("{}<integer>{}</integer>\n", indent, n)),
+        Value::String(s) => {
+            out.push_str(&format!("{}<string>{}</string>\n", indent, escape_xml(s)))
+        }
+        Value::Array(items) => {
+            let items: Vec<&Value> = items.iter().filter(|item| !item.is_null()).collect();
+            if items.is_empty() {
+                out.push_str(&format!("{}<array/>\n", indent));
+                return;
+            }
+            out.push_str(&format!("{}<array>\n", indent));
+            for item in items {
+                write_plist_value(out, item, depth + 1);
+            }
+            out.push_str(&format!("{}</array>\n", indent));
+        }
+        Value::Object(map) => {
+            let entries: Vec<(&String, &Value)> =
+                map.iter().filter(|(_, item)| !item.is_null()).collect();
+            if entries.is_empty() {
+                out.push_str(&format!("{}<dict/>\n", indent));
+                return;
+            }
+            out.push_str(&format!("{}<dict>\n", indent));
+            for (key, item) in entries {
+                out.push_str(&format!("{}\t<key>{}</key>\n", indent, escape_xml(key)));
+                write_plist_value(out, item, depth + 1);
+            }
+            out.push_str(&format!("{}</dict>\n", indent));
+        }
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// The outcome of one health check on one volume
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Identifies the kind of check, eg: `backup-exclusion`
+    pub id: String,
+    pub mount_point: String,
+    pub status: CheckStatus,
+    /// How serious a failure of this check is
+    pub severity: Severity,
+    /// Describes the failure; this is empty for checks that passed
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+    /// Identifies the action that repairs a failure, so that the caller
+    /// can suggest the right command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remediation: Option<String>,
+}
+
+impl HealthCheck {
+    /// A check that passed
+    pub fn new(id: &str, mount_point: &str, severity: Severity) -> Self {
+        Self {
+            id: id.to_owned(),
+            mount_point: mount_point.to_owned(),
+            status: CheckStatus::Pass,
+            severity,
+            message: String::new(),
+            remediation: None,
+        }
+    }
+
+    /// Turn this into a check that failed
+    pub fn fail(mut self, message: String, remediation: Option<&str>) -> Self {
+        self.status = CheckStatus::Fail;
+        self.message = message;
+        self.remediation = remediation.map(str::to_owned);
+        self
+    }
+}
+
+/// The output of `health --json`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub checks: Vec<HealthCheck>,
+}
+
+/// An operation on a mount point, as recorded in the registry
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// When the operation completed, in seconds since the unix epoch
+    pub time: u64,
+    /// The operation, eg: `mount`
+    pub operation: String,
+    pub ok: bool,
+    /// The error, if the operation failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The kind of host that the operation ran on, unless it was a
+    /// physical Mac
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<HostEnvironment>,
+}
+
+/// The output of `history --json`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryReport {
+    pub mount_point: String,
+    /// The most recent operations, oldest first
+    pub history: Vec<HistoryEntry>,
+}
+
+/// A volume in the output of `list --json`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ListedVolume {
+    /// eg: `disk1s5`
+    pub device_identifier: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Where the volume is currently mounted, if it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount_point: Option<String>,
+    /// The space used by the volume, in bytes
+    pub capacity_in_use: u64,
+    /// The note set by `label set`, which is only reported by `list --long`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Whether the volume was created on this host, which is only
+    /// reported for our volumes by `list --long`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<VolumeOrigin>,
+}
+
+/// Where a volume created by this utility came from
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeOrigin {
+    /// Created on this host
+    Local,
+    /// Created on another host, and moved here on an external disk
+    Foreign,
+    /// Neither tagged by this host nor recorded in its registry.  This
+    /// is typically a volume on an external disk that isn't mounted.
+    Unknown,
+}
+
+impl VolumeOrigin {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::Foreign => "foreign",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+/// The kind of host that we are running on, which changes what diskutil
+/// can do
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostEnvironment {
+    Physical,
+    /// A virtual machine, whose disks are synthesized by the hypervisor
+    VirtualMachine,
+    /// A sandboxed process, such as a CI job, which may not be allowed
+    /// to create or mount volumes at all
+    Sandbox,
+}
+
+impl HostEnvironment {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Physical => "physical",
+            Self::VirtualMachine => "virtual-machine",
+            Self::Sandbox => "sandbox",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Physical => "this host is a physical Mac",
+            Self::VirtualMachine => "this host is a virtual machine",
+            Self::Sandbox => "this process is sandboxed",
+        }
+    }
+}
+
+/// A container in the output of `list --json`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ListedContainer {
+    /// eg: `disk1`
+    pub container_reference: String,
+    /// The volumes that were selected by the options to `list`
+    pub volumes: Vec<ListedVolume>,
+}
+
+/// The output of `list --json`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ListReport {
+    pub containers: Vec<ListedContainer>,
+}
+
+/// A volume in the output of `usage`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct VolumeUsage {
+    /// eg: `disk1s5`
+    pub device_identifier: String,
+    /// The mount point encoded in the name of the volume
+    pub mount_point: String,
+    /// The space used by the volume, in bytes
+    pub capacity_in_use: u64,
+    /// The maximum size of the volume in bytes, or 0 if it has no quota
+    pub capacity_quota: u64,
+}
+
+/// The output of `usage`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct UsageReport {
+    /// The volumes created by this utility, largest first
+    pub volumes: Vec<VolumeUsage>,
+}
+
+/// The space used by an entry at the top of a volume
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct EntryUsage {
+    pub path: String,
+    /// The space allocated to the entry and everything under it, in bytes
+    pub bytes: u64,
+}
+
+/// The output of `resize-preflight`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ResizePreflight {
+    pub mount_point: String,
+    pub capacity_in_use: u64,
+    /// The proposed quota, in bytes; 0 means no quota
+    pub quota: u64,
+    /// How much data must be deleted before the quota can be applied,
+    /// in bytes
+    pub excess: u64,
+    /// The largest entries at the top of the volume, largest first,
+    /// when asked for with `--largest`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub largest: Vec<EntryUsage>,
+}
+
+/// The output of `check-space`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct SpaceCheck {
+    pub mount_point: String,
+    /// The space available to the volume, in bytes, taking its quota,
+    /// its reserve and the free space in its container into account
+    pub available: u64,
+    /// The size of the upcoming write, in bytes
+    pub need: u64,
+    /// The space that must remain available after the write, in bytes
+    pub min_free: u64,
+    pub fits: bool,
+}
+
+/// The output of `stats --latency`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct LatencyReport {
+    pub operations: Vec<OperationLatency>,
+}
+
+/// How long one class of operation has taken recently, in milliseconds,
+/// to the resolution of the histogram buckets.  A percentile is null if
+/// it is beyond the last bucket.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct OperationLatency {
+    /// The operation, eg: `mount`; these are the operations that have
+    /// timeouts
+    pub operation: String,
+    pub samples: u64,
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+    pub p99_ms: Option<u64>,
+}
+
+/// The output of `capabilities`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The version of this utility
+    pub version: String,
+    /// The `version` set by the installed policy, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy_version: Option<String>,
+    /// The kind of host that we are running on
+    pub environment: HostEnvironment,
+}
+
+/// The kinds of problem that `audit` looks for
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditKind {
+    /// The mount point encoded in the name of the volume doesn't exist
+    MissingMountPoint,
+    /// The mount point belongs to a uid other than the one that created
+    /// the volume
+    WrongOwner,
+    /// The volume has our prefix, but the rest of its name isn't a
+    /// valid mount point
+    InvalidName,
+}
+
+impl AuditKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditKind::MissingMountPoint => "missing-mount-point",
+            AuditKind::WrongOwner => "wrong-owner",
+            AuditKind::InvalidName => "invalid-name",
+        }
+    }
+}
+
+/// A problem with one volume, as found by `audit`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct AuditFinding {
+    /// eg: `disk1s5`
+    pub device_identifier: String,
+    pub name: String,
+    pub kind: AuditKind,
+    pub detail: String,
+}
+
+/// The output of `audit --format json`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct AuditReport {
+    pub findings: Vec<AuditFinding>,
+}
+
+/// What `fsck` found
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsckStatus {
+    /// The volume had no problems
+    Clean,
+    /// The volume had problems, and fsck_apfs repaired them
+    Repaired,
+    /// The volume has problems that fsck_apfs couldn't repair
+    Damaged,
+}
+
+impl FsckStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            FsckStatus::Clean => "clean",
+            FsckStatus::Repaired => "repaired",
+            FsckStatus::Damaged => "damaged",
+        }
+    }
+}
+
+/// The output of `fsck --format json`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct FsckReport {
+    pub mount_point: String,
+    /// eg: `disk1s5`
+    pub device_identifier: String,
+    pub status: FsckStatus,
+    /// The exit status of fsck_apfs
+    pub exit_code: Option<i32>,
+    /// What fsck_apfs reported
+    pub output: String,
+    /// Whether the volume was mounted again after the check
+    pub remounted: bool,
+}
+
+/// A recap of what a command that changes volumes did, which is
+/// reported at the end of the run
+#[derive(
+    Serialize,
+    Deserialize,
+    JsonSchema,
+    Debug,
+    Default,
+    Clone,
+    PartialEq,
+    Eq
+)]
+pub struct ChangeSummary {
+    /// The mount points for which new volumes were created
+    #[serde(default)]
+    pub created: Vec<String>,
+    #[serde(default)]
+    pub mounted: Vec<String>,
+    #[serde(default)]
+    pub unmounted: Vec<String>,
+    /// The mount points whose volumes were deleted
+    #[serde(default)]
+    pub deleted: Vec<String>,
+    /// The mount points whose volumes were erased and mounted again
+    #[serde(default)]
+    pub erased: Vec<String>,
+    /// Describes each problem that was repaired
+    #[serde(default)]
+    pub repaired: Vec<String>,
+    /// The space that was in use by the deleted volumes, in bytes
+    #[serde(default)]
+    pub bytes_reclaimed: u64,
+    #[serde(default)]
+    pub errors: Vec<String>,
+    /// Set by `--dry-run`, in which case nothing was changed and this
+    /// describes what would have been
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+}
+
+impl std::fmt::Display for ChangeSummary {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.dry_run {
+            writeln!(fmt, "Summary of what would be changed:")?;
+        } else {
+            writeln!(fmt, "Summary:")?;
+        }
+        let mut empty = true;
+        for (label, items) in &[
+            ("created", &self.created),
+            ("mounted", &self.mounted),
+            ("unmounted", &self.unmounted),
+            ("deleted", &self.deleted),
+            ("erased", &self.erased),
+            ("repaired", &self.repaired),
+            ("errors", &self.errors),
+        ] {
+            if items.is_empty() {
+                continue;
+            }
+            empty = false;
+            writeln!(fmt, "  {} {}:", items.len(), label)?;
+            for item in items.iter() {
+                writeln!(fmt, "    {}", item)?;
+            }
+        }
+        if self.bytes_reclaimed > 0 {
+            writeln!(fmt, "  {} bytes reclaimed", self.bytes_reclaimed)?;
+        }
+        if empty {
+            writeln!(fmt, "  nothing was changed")?;
+        }
+        Ok(())
+    }
+}
+
+/// One of the commands that `batch` reads from stdin, one per line, eg:
+/// `{"command": "mount", "mount_point": "/Users/wez/repo/buck-out"}`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "lowercase", deny_unknown_fields)]
+pub enum BatchCommand {
+    Mount {
+        mount_point: String,
+    },
+    Unmount {
+        mount_point: String,
+        /// Unmount the volume even if it is in use
+        #[serde(default)]
+        force: bool,
+    },
+    Delete {
+        mount_point: String,
+    },
+}
+
+/// The outcome of one of the commands run by `batch`, which is printed
+/// as a line of JSON as soon as the command is done
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct BatchResult {
+    /// The line of stdin that held the command, counting from 1
+    pub line: usize,
+    /// The command, which is missing when the line couldn't be parsed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<BatchCommand>,
+    pub ok: bool,
+    /// Why the command failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub changes: ChangeSummary,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn round_trip<T>(value: &T) -> T
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_health_round_trip() {
+        let report = HealthReport {
+            checks: vec![
+                HealthCheck::new("misplaced", "/a", Severity::Error),
+                HealthCheck::new("backup-exclusion", "/a", Severity::Warning)
+                    .fail("is not excluded".to_owned(), Some("doctor-fix")),
+            ],
+        };
+        assert_eq!(round_trip(&report), report);
+        assert_eq!(
+            serde_json::to_value(&report.checks[0]).unwrap(),
+            serde_json::json!({
+                "id": "misplaced",
+                "mount_point": "/a",
+                "status": "pass",
+                "severity": "error",
+            })
+        );
+    }
+
+    #[test]
+    fn test_change_summary() {
+        let summary = ChangeSummary::default();
+        assert_eq!(summary.to_string(), "Summary:\n  nothing was changed\n");
+        let summary = ChangeSummary {
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&summary), summary);
+        assert_eq!(
+            summary.to_string(),
+            "Summary of what would be changed:\n  nothing was changed\n"
+        );
+
+        let summary = ChangeSummary {
+            created: vec!["/a".to_owned()],
+            mounted: vec!["/a".to_owned()],
+            deleted: vec!["/b".to_owned(), "/c".to_owned()],
+            bytes_reclaimed: 1024,
+            errors: vec!["mounting /d: busy".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&summary), summary);
+        assert_eq!(
+            summary.to_string(),
+            "Summary:
+  1 created:
+    /a
+  1 mounted:
+    /a
+  2 deleted:
+    /b
+    /c
+  1 errors:
+    mounting /d: busy
+  1024 bytes reclaimed
+"
+        );
+    }
+
+    #[test]
+    fn test_plist_xml() {
+        let value = serde_json::json!({
+            "name": "edenfs:/a&b",
+            "ok": true,
+            "size": 1024,
+            "ratio": 0.5,
+            "missing": null,
+            "roles": [],
+            "mounts": ["/a", null],
+        });
+        assert_eq!(
+            to_plist_xml(&value).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>mounts</key>
+	<array>
+		<string>/a</string>
+	</array>
+	<key>name</key>
+	<string>edenfs:/a&amp;b</string>
+	<key>ok</key>
+	<true/>
+	<key>ratio</key>
+	<real>0.5</real>
+	<key>roles</key>
+	<array/>
+	<key>size</key>
+	<integer>1024</integer>
+</dict>
+</plist>
+"#
+        );
+        assert_eq!(
+            "plist".parse::<OutputFormat>().unwrap(),
+            OutputFormat::Plist
+        );
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_history_round_trip() {
+        let report = HistoryReport {
+            mount_point: "/a".to_owned(),
+            history: vec![
+                HistoryEntry {
+                    time: 1,
+                    operation: "mount".to_owned(),
+                    ok: true,
+                    error: None,
+                    environment: None,
+                },
+                HistoryEntry {
+                    time: 2,
+                    operation: "unmount".to_owned(),
+                    ok: false,
+                    error: Some("busy".to_owned()),
+                    environment: Some(HostEnvironment::VirtualMachine),
+                },
+            ],
+        };
+        assert_eq!(round_trip(&report), report);
+    }
+
+    #[test]
+    fn test_batch_command() {
+        let parse = |line| serde_json::from_str::<BatchCommand>(line);
+        assert_eq!(
+            parse(r#"{"command": "mount", "mount_point": "/a"}"#).unwrap(),
+            BatchCommand::Mount {
+                mount_point: "/a".to_owned()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command": "unmount", "mount_point": "/a"}"#).unwrap(),
+            BatchCommand::Unmount {
+                mount_point: "/a".to_owned(),
+                force: false
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command": "unmount", "mount_point": "/a", "force": true}"#).unwrap(),
+            BatchCommand::Unmount {
+                mount_point: "/a".to_owned(),
+                force: true
+            }
+        );
+        assert!(parse(r#"{"command": "gc"}"#).is_err());
+        assert!(parse(r#"{"command": "delete"}"#).is_err());
+        assert!(parse(r#"{"command": "delete", "mount_point": "/a", "yes": true}"#).is_err());
+    }
+}
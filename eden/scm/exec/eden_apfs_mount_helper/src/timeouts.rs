@@ -0,0 +1,328 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! How long we let each kind of operation run before giving up on it.
+//! Listing volumes should take a moment, while creating or checking one
+//! can legitimately take minutes, so each operation has its own limit.
+//! The limits come from the policy file and may be overridden on the
+//! command line with `--timeout <operation>=<seconds>`.
+//!
+//! The limits are enforced by a `Watchdog` that kills the command that
+//! we are running for the operation once its limit passes.  It also
+//! notes how long each command took, which the registry keeps for
+//! `stats --latency`.
+
+use anyhow::*;
+use once_cell::sync::OnceCell;
+use serde::*;
+use std::process::{Child, ExitStatus};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The operations that are subject to a timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Listing the containers and volumes
+    List,
+    /// Creating a volume
+    Create,
+    Mount,
+    Unmount,
+    /// Checking a volume with `fsck_apfs`
+    Fsck,
+}
+
+impl Operation {
+    pub const ALL: &'static [Operation] = &[
+        Operation::List,
+        Operation::Create,
+        Operation::Mount,
+        Operation::Unmount,
+        Operation::Fsck,
+    ];
+
+    /// The name of the operation, as used in the policy file and on
+    /// the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::List => "list",
+            Operation::Create => "create",
+            Operation::Mount => "mount",
+            Operation::Unmount => "unmount",
+            Operation::Fsck => "fsck",
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|operation| operation.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(|op| op.name()).collect();
+                anyhow!(
+                    "unknown operation {:?}; expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// The limit for each operation, in seconds.  A limit of 0 means that
+/// the operation may take as long as it takes.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    pub list: u64,
+    pub create: u64,
+    pub mount: u64,
+    pub unmount: u64,
+    pub fsck: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            list: 60,
+            create: 300,
+            mount: 120,
+            unmount: 120,
+            fsck: 3600,
+        }
+    }
+}
+
+impl Timeouts {
+    fn seconds_mut(&mut self, operation: Operation) -> &mut u64 {
+        match operation {
+            Operation::List => &mut self.list,
+            Operation::Create => &mut self.create,
+            Operation::Mount => &mut self.mount,
+            Operation::Unmount => &mut self.unmount,
+            Operation::Fsck => &mut self.fsck,
+        }
+    }
+
+    /// The limit for `operation`, if it has one
+    pub fn limit(&self, operation: Operation) -> Option<Duration> {
+        let seconds = match operation {
+            Operation::List => self.list,
+            Operation::Create => self.create,
+            Operation::Mount => self.mount,
+            Operation::Unmount => self.unmount,
+            Operation::Fsck => self.fsck,
+        };
+        if seconds == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(seconds))
+        }
+    }
+
+    /// Apply overrides given on the command line
+    pub fn apply_overrides(&mut self, overrides: &[TimeoutOverride]) {
+        for o in overrides {
+            *self.seconds_mut(o.operation) = o.seconds;
+        }
+    }
+}
+
+/// A `--timeout <operation>=<seconds>` argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutOverride {
+    pub operation: Operation,
+    pub seconds: u64,
+}
+
+impl FromStr for TimeoutOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (operation, seconds) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <operation>=<seconds>, not {:?}", s))?;
+        Ok(Self {
+            operation: operation.parse()?,
+            seconds: seconds
+                .parse()
+                .with_context(|| format!("parsing the number of seconds in {:?}", s))?,
+        })
+    }
+}
+
+static TIMEOUTS: OnceCell<Timeouts> = OnceCell::new();
+
+/// Returns the timeouts in effect for this process, loading the policy
+/// the first time; these are the defaults unless the policy called
+/// `set_timeouts`.  A policy that fails to load leaves the defaults in
+/// effect here, and is reported by the next lookup that can fail.
+pub fn timeouts() -> &'static Timeouts {
+    crate::policy::load_policy().ok();
+    TIMEOUTS.get_or_init(Timeouts::default)
+}
+
+/// Install the timeouts from the policy file and the command line.
+/// This can only be done once, by the policy loader.
+pub fn set_timeouts(timeouts: Timeouts) -> Result<()> {
+    TIMEOUTS
+        .set(timeouts)
+        .map_err(|_| anyhow!("the timeouts have already been configured"))
+}
+
+/// How long each command that a `Watchdog` watched took, in the order
+/// that they finished, until they are taken by `take_latencies`
+static LATENCIES: Mutex<Vec<(Operation, Duration)>> = Mutex::new(Vec::new());
+
+/// Take the latencies that have been noted so far
+pub fn take_latencies() -> Vec<(Operation, Duration)> {
+    std::mem::take(&mut *LATENCIES.lock().unwrap())
+}
+
+/// Kills a child process that is still running when the limit for its
+/// operation passes
+pub struct Watchdog {
+    operation: Operation,
+    started: Instant,
+    limit: Option<Duration>,
+    stop: Sender<()>,
+    thread: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// Start watching `child`, which is running on behalf of `operation`
+    pub fn start(child: &Child, operation: Operation) -> Self {
+        Self::with_limit(child, operation, timeouts().limit(operation))
+    }
+
+    fn with_limit(child: &Child, operation: Operation, limit: Option<Duration>) -> Self {
+        let pid = child.id() as libc::pid_t;
+        let (stop, stopped) = channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let limit = match limit {
+                Some(limit) => limit,
+                None => return false,
+            };
+            match stopped.recv_timeout(limit) {
+                Err(RecvTimeoutError::Timeout) => {
+                    // The child can't have been reaped yet, as `finish`
+                    // waits for us before doing that, so the pid is
+                    // still that of our child
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                    true
+                }
+                _ => false,
+            }
+        });
+        Self {
+            operation,
+            started: Instant::now(),
+            limit,
+            stop,
+            thread,
+        }
+    }
+
+    /// Wait for the child to exit and stop watching it.  If the child
+    /// had to be killed, the error says which limit it ran into.
+    pub fn finish(self, child: &mut Child) -> std::io::Result<ExitStatus> {
+        wait_without_reaping(child)?;
+        self.stop.send(()).ok();
+        let fired = self.thread.join().unwrap_or(false);
+        LATENCIES
+            .lock()
+            .unwrap()
+            .push((self.operation, self.started.elapsed()));
+        let status = child.wait()?;
+        if fired {
+            let name = self.operation.name();
+            let seconds = self.limit.map(|limit| limit.as_secs()).unwrap_or(0);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "the {} operation timed out after {}s; the limit may be raised \
+                     with `timeouts.{}` in the policy file or `--timeout {}=<seconds>`",
+                    name, seconds, name, name,
+                ),
+            ));
+        }
+        Ok(status)
+    }
+}
+
+/// Wait for `child` to exit, but leave it to be reaped by `Child::wait`
+fn wait_without_reaping(child: &Child) -> std::io::Result<()> {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let rc = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if rc == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_overrides() {
+        let mut timeouts = Timeouts::default();
+        timeouts.apply_overrides(&["list=5".parse().unwrap(), "fsck=0".parse().unwrap()]);
+        assert_eq!(
+            timeouts.limit(Operation::List),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(timeouts.limit(Operation::Fsck), None);
+        assert_eq!(
+            timeouts.limit(Operation::Create),
+            Some(Duration::from_secs(300))
+        );
+        assert!("list".parse::<TimeoutOverride>().is_err());
+        assert!("resize=5".parse::<TimeoutOverride>().is_err());
+        assert!("list=soon".parse::<TimeoutOverride>().is_err());
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let mut child = std::process::Command::new("/bin/sleep")
+            .arg("0")
+            .spawn()
+            .unwrap();
+        let watchdog = Watchdog::start(&child, Operation::List);
+        assert!(watchdog.finish(&mut child).unwrap().success());
+
+        let mut child = std::process::Command::new("/bin/sleep")
+            .arg("60")
+            .spawn()
+            .unwrap();
+        let watchdog =
+            Watchdog::with_limit(&child, Operation::Mount, Some(Duration::from_millis(50)));
+        let err = watchdog.finish(&mut child).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("--timeout mount=<seconds>"));
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Parsers for the text output of the tools that we run, as opposed to
+//! those that produce plists.  Text output is prone to change between
+//! releases of macOS, so each parser accepts only the forms that we have
+//! seen, and reports anything else as unrecognized rather than guessing.
+//! What the caller does about unrecognized output depends on how much
+//! it needs the answer; each parser documents what it tolerates.
+//!
+//! The commands that we run are given the C locale (see `privs`), so
+//! none of this depends on the language of the caller.
+//! Samples of the output from several releases live in
+//! `tests/fixtures/tool_output`, named for the tool and the release.
+
+use crate::mount_table::MountEntry;
+use anyhow::*;
+
+/// The error for output that doesn't have any form that we know about
+fn unrecognized(tool: &str, text: &str) -> Error {
+    anyhow!("unrecognized output from {}: {:?}", tool, text)
+}
+
+/// Parse a line of the output from `mount`, which has the form
+/// `<DEVICE> on <PATH> (<OPTIONS>)`.  The device and path may both
+/// contain spaces, and neither is escaped.
+pub fn parse_mount_line(line: &str) -> Option<MountEntry> {
+    // We trim off the options and split around ` on ` so that we just
+    // have two simple fields to work with, and won't need to consider
+    // spaces.
+    let (lhs, _options) = line.strip_suffix(')')?.rsplit_once(" (")?;
+    let (device, mount_point) = lhs.split_once(" on ")?;
+    if device.is_empty() || !mount_point.starts_with('/') {
+        return None;
+    }
+    Some(MountEntry::new(device, mount_point))
+}
+
+/// Parse the output from `mount`.  Lines that we don't recognize are
+/// skipped, but the output as a whole is rejected if none of its lines
+/// are recognized, as every system has something mounted on `/`.
+pub fn parse_mount_table(text: &str) -> Result<Vec<MountEntry>> {
+    let entries: Vec<MountEntry> = text.lines().filter_map(parse_mount_line).collect();
+    if entries.is_empty() && !text.trim().is_empty() {
+        return Err(unrecognized("mount", text));
+    }
+    Ok(entries)
+}
+
+/// Parse the output from `lsof -F c`, returning the names of the
+/// processes that hold files open.  Each process is described by a
+/// `p<pid>` line followed by a `c<command>` line, along with the `f`
+/// lines that lsof always adds to describe each file.  Output with any
+/// other lines is rejected.
+pub fn parse_lsof_commands(text: &str) -> Result<Vec<String>> {
+    let mut commands = vec![];
+    let mut in_process = false;
+    for line in text.lines() {
+        match line.chars().next() {
+            Some('p') if line[1..].chars().all(|c| c.is_ascii_digit()) && line.len() > 1 => {
+                in_process = true;
+            }
+            Some('c') if in_process => commands.push(line[1..].to_owned()),
+            Some('f') if in_process => {}
+            _ => return Err(unrecognized("lsof", text)),
+        }
+    }
+    commands.sort();
+    commands.dedup();
+    Ok(commands)
+}
+
+/// Parse the output from PlistBuddy printing an array of strings:
+///
+/// ```text
+/// Array {
+///     /some/path
+/// }
+/// ```
+pub fn parse_plist_buddy_array(text: &str) -> Result<Vec<String>> {
+    let mut lines = text.lines();
+    if lines.next().map(str::trim) != Some("Array {") {
+        return Err(unrecognized("PlistBuddy", text));
+    }
+    let mut items = vec![];
+    for line in lines {
+        if line.trim() == "}" {
+            return Ok(items);
+        }
+        // The items are indented; anything else means that this isn't
+        // an array of strings
+        match line.strip_prefix("    ") {
+            Some(item) if !item.starts_with(char::is_whitespace) => items.push(item.to_owned()),
+            _ => return Err(unrecognized("PlistBuddy", text)),
+        }
+    }
+    Err(unrecognized("PlistBuddy", text))
+}
+
+/// Parse the output from `xattr -px`, which prints the value as hex
+/// bytes in groups that are wrapped across lines, returning it as one
+/// lowercase hex string
+pub fn parse_xattr_hex(text: &str) -> Result<String> {
+    let hex: String = text.split_whitespace().collect();
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(unrecognized("xattr", text));
+    }
+    Ok(hex.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Read the sample of the output from `tool` on `release`
+    fn sample(tool: &str, release: &str) -> String {
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tool_output")
+            .join(format!("{}-{}.txt", tool, release));
+        std::fs::read_to_string(&path).unwrap()
+    }
+
+    #[test]
+    fn test_mount_releases() {
+        for (release, root, ours) in &[
+            (
+                "10.15",
+                "/dev/disk1s5",
+                MountEntry::new("/dev/disk1s6", "/Users/wez/fbsource/buck-out"),
+            ),
+            (
+                "11",
+                "/dev/disk1s5s1",
+                MountEntry::new("/dev/disk1s7", "/Users/wez/fbsource/buck-out"),
+            ),
+            (
+                "13",
+                "/dev/disk3s1s1",
+                MountEntry::new("/dev/disk3s7", "/Users/wez/my repo/buck-out"),
+            ),
+        ] {
+            let text = sample("mount", release);
+            let entries = parse_mount_table(&text).unwrap();
+            assert_eq!(entries.len(), text.lines().count(), "macOS {}", release);
+            assert_eq!(entries[0], MountEntry::new(root, "/"), "macOS {}", release);
+            assert_eq!(entries.last(), Some(ours), "macOS {}", release);
+        }
+    }
+
+    #[test]
+    fn test_mount_unrecognized() {
+        assert_eq!(parse_mount_table("").unwrap(), vec![]);
+        assert_eq!(
+            parse_mount_table("garbage\n/dev/disk1s1 on / (apfs, local)\n").unwrap(),
+            vec![MountEntry::new("/dev/disk1s1", "/")]
+        );
+        assert!(parse_mount_table("/dev/disk1s1 sur / (apfs, local)\n").is_err());
+        assert!(parse_mount_table("/dev/disk1s1 on /\n").is_err());
+    }
+
+    #[test]
+    fn test_lsof_releases() {
+        let data = "p123\ncmds_stores\np45\ncfseventsd\np124\ncmds_stores\n";
+        assert_eq!(
+            parse_lsof_commands(data).unwrap(),
+            vec!["fseventsd".to_owned(), "mds_stores".to_owned()]
+        );
+        assert_eq!(
+            parse_lsof_commands(&sample("lsof", "10.15")).unwrap(),
+            vec!["mds".to_owned(), "mdworker_shared".to_owned()]
+        );
+        assert_eq!(
+            parse_lsof_commands(&sample("lsof", "13")).unwrap(),
+            vec!["buck".to_owned(), "mds_stores".to_owned()]
+        );
+        assert_eq!(parse_lsof_commands("").unwrap(), Vec::<String>::new());
+        assert!(parse_lsof_commands("COMMAND PID USER\nmds 412 root\n").is_err());
+        assert!(parse_lsof_commands("cmds\n").is_err());
+    }
+
+    #[test]
+    fn test_plist_buddy_releases() {
+        assert_eq!(
+            parse_plist_buddy_array(&sample("plistbuddy", "10.15")).unwrap(),
+            vec![
+                "/Users/wez/fbsource/buck-out".to_owned(),
+                "/Users/wez/www/buck-out".to_owned()
+            ]
+        );
+        assert_eq!(
+            parse_plist_buddy_array(&sample("plistbuddy", "13")).unwrap(),
+            vec!["/Users/wez/my repo/buck-out".to_owned()]
+        );
+        assert_eq!(
+            parse_plist_buddy_array("Array {\n}\n").unwrap(),
+            Vec::<String>::new()
+        );
+        assert!(parse_plist_buddy_array("Dict {\n    a = b\n}\n").is_err());
+        assert!(parse_plist_buddy_array("Array {\n    Dict {\n").is_err());
+    }
+
+    #[test]
+    fn test_xattr_releases() {
+        for release in &["10.15", "13"] {
+            let hex = parse_xattr_hex(&sample("xattr", release)).unwrap();
+            assert!(hex.starts_with("62706c6973743030"), "macOS {}", release);
+            assert!(hex.ends_with("1c"), "macOS {}", release);
+        }
+        assert!(parse_xattr_hex("").is_err());
+        assert!(parse_xattr_hex("62 7").is_err());
+        assert!(parse_xattr_hex("no such xattr").is_err());
+    }
+}
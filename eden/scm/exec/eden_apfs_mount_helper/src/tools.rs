@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The locations of the system tools that we run.
+//! These default to the standard locations on macOS, but may be overridden
+//! by the root-owned policy file for images that keep their admin tooling
+//! elsewhere.  The environment of the caller is never consulted, because
+//! we run these tools on behalf of an untrusted user while setuid root.
+
+#[cfg(feature = "fake-diskutil")]
+use crate::privs::{geteuid, getuid};
+use crate::privs::{new_cmd_unprivileged, RunCommand};
+use anyhow::*;
+use once_cell::sync::OnceCell;
+use serde::*;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Used to verify the signature of overridden tools.  This is deliberately
+/// not overridable, as it is what we use to decide whether to trust the
+/// overrides.
+pub(crate) const CODESIGN: &str = "/usr/bin/codesign";
+
+/// The environment variable that names a fake diskutil to run in place
+/// of the real one, so that test rigs can exercise the real subprocess
+/// plumbing.  This is only honored when the `fake-diskutil` feature is
+/// enabled, which is never the case in release builds, and it is refused
+/// outright when we are running setuid.
+#[cfg(feature = "fake-diskutil")]
+pub const FAKE_DISKUTIL_ENV: &str = "EDEN_APFS_FAKE_DISKUTIL";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolPaths {
+    pub diskutil: String,
+    pub mount_apfs: String,
+    pub plutil: String,
+}
+
+impl Default for ToolPaths {
+    fn default() -> Self {
+        Self {
+            diskutil: "/usr/sbin/diskutil".to_owned(),
+            mount_apfs: "/sbin/mount_apfs".to_owned(),
+            plutil: "/usr/bin/plutil".to_owned(),
+        }
+    }
+}
+
+/// The tool paths that the policy asked for, which are only validated
+/// when the first tool is looked up
+static POLICY_TOOL_PATHS: OnceCell<ToolPaths> = OnceCell::new();
+static TOOL_PATHS: OnceCell<ToolPaths> = OnceCell::new();
+
+/// Returns the tool paths in effect for this process, loading the policy
+/// the first time; these are the defaults unless the policy called
+/// `set_tool_paths`.  Any path that differs from the default is validated
+/// with `validate_tool_path` first.
+pub fn tool_paths() -> Result<&'static ToolPaths> {
+    crate::policy::load_policy()?;
+    TOOL_PATHS.get_or_try_init(|| {
+        let mut paths = POLICY_TOOL_PATHS.get().cloned().unwrap_or_default();
+        let defaults = ToolPaths::default();
+        for (path, default) in &[
+            (&paths.diskutil, &defaults.diskutil),
+            (&paths.mount_apfs, &defaults.mount_apfs),
+            (&paths.plutil, &defaults.plutil),
+        ] {
+            if path != default {
+                validate_tool_path(path)?;
+            }
+        }
+        // The fake is deliberately not validated, as it belongs to the test
+        apply_fake_diskutil(&mut paths)?;
+        Ok(paths)
+    })
+}
+
+/// Substitute the fake diskutil named by `FAKE_DISKUTIL_ENV`, if any
+#[cfg(feature = "fake-diskutil")]
+fn apply_fake_diskutil(paths: &mut ToolPaths) -> Result<()> {
+    if let Some(fake) = std::env::var_os(FAKE_DISKUTIL_ENV) {
+        let fake = fake
+            .into_string()
+            .map_err(|_| anyhow!("{} is not valid unicode", FAKE_DISKUTIL_ENV))?;
+        // A debug build may still have been installed setuid, and the
+        // caller mustn't get to pick what we run as root
+        if getuid() != geteuid() {
+            bail!(
+                "refusing to honor {} while running setuid",
+                FAKE_DISKUTIL_ENV
+            );
+        }
+        if !Path::new(&fake).is_absolute() {
+            bail!(
+                "{} must be an absolute path, not {}",
+                FAKE_DISKUTIL_ENV,
+                fake
+            );
+        }
+        paths.diskutil = fake;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "fake-diskutil"))]
+fn apply_fake_diskutil(_paths: &mut ToolPaths) -> Result<()> {
+    Ok(())
+}
+
+/// Install the tool paths from the policy file, which `tool_paths`
+/// validates.  This can only be done once, by the policy loader.
+pub fn set_tool_paths(paths: ToolPaths) -> Result<()> {
+    POLICY_TOOL_PATHS
+        .set(paths)
+        .map_err(|_| anyhow!("the tool paths have already been configured"))
+}
+
+/// Check that `path` is an absolute path to a regular file that is
+/// owned by root, is not writable by anyone else, and that has a valid
+/// code signature.
+pub fn validate_tool_path(path: &str) -> Result<()> {
+    check_tool_ownership(path)?;
+    let output = new_cmd_unprivileged(CODESIGN)
+        .args(&["--verify", "--strict", path])
+        .run()?;
+    if !output.status.success() {
+        bail!(
+            "refusing to use {} because its code signature is not valid: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn check_tool_ownership(path: &str) -> Result<()> {
+    if !Path::new(path).is_absolute() {
+        bail!("tool path {} must be absolute", path);
+    }
+    let metadata = std::fs::metadata(path).with_context(|| format!("checking {}", path))?;
+    if !metadata.is_file() {
+        bail!("tool path {} is not a regular file", path);
+    }
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+        bail!(
+            "refusing to use {} because it is not owned by root \
+             or is writable by other users",
+            path
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_tool_ownership() {
+        assert!(check_tool_ownership("usr/sbin/diskutil").is_err());
+        assert!(check_tool_ownership("/").is_err());
+        assert!(check_tool_ownership("/does/not/exist").is_err());
+
+        let path = std::env::temp_dir().join(format!("eden-apfs-tool-{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let result = check_tool_ownership(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,281 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The transcript of a run, as requested with `--transcript`.  This
+//! gathers everything that the helper did into a single file that users
+//! can attach to bug reports: its arguments, the policy that was in
+//! effect, each command that it ran along with the output and how long
+//! it took, and the outcome of the run.
+//! The input that we feed to commands is never recorded, as that is how
+//! we pass passphrases to them, and neither is the output of the commands
+//! that return passphrases to us.
+//! Each section is written as soon as it is known, so that a run that
+//! crashes still leaves a transcript of what led up to the crash.
+
+use anyhow::*;
+use once_cell::sync::Lazy;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+struct Transcript {
+    file: File,
+    started: Instant,
+    /// The number of commands recorded so far
+    commands: usize,
+}
+
+static TRANSCRIPT: Lazy<Mutex<Option<Transcript>>> = Lazy::new(Default::default);
+
+/// Start recording the transcript of this run into `file`, beginning
+/// with what we were asked to do
+pub fn start(mut file: File) -> Result<()> {
+    let header = format!(
+        "eden_apfs_mount_helper {} transcript\n\
+         started: {}\n\
+         argv: {:?}\n\
+         uid: {}, euid: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        std::env::args().collect::<Vec<_>>(),
+        unsafe { libc::getuid() },
+        unsafe { libc::geteuid() },
+    );
+    file.write_all(header.as_bytes())
+        .context("writing the transcript")?;
+    *TRANSCRIPT.lock().unwrap() = Some(Transcript {
+        file,
+        started: Instant::now(),
+        commands: 0,
+    });
+    Ok(())
+}
+
+pub fn is_active() -> bool {
+    TRANSCRIPT.lock().unwrap().is_some()
+}
+
+/// Append a section to the transcript, if there is one.  Failing to
+/// write the transcript is reported but doesn't fail the run.
+pub fn note(title: &str, body: &str) {
+    if let Some(transcript) = TRANSCRIPT.lock().unwrap().as_mut() {
+        write_section(&mut transcript.file, &format!("== {}\n{}", title, body));
+    }
+}
+
+fn write_section(file: &mut File, section: &str) {
+    let mut text = section.to_owned();
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    if let Err(err) = file.write_all(text.as_bytes()) {
+        eprintln!("failed to write the transcript: {}", err);
+    }
+}
+
+/// Run `func`, which runs `cmd`, and record the command along with its
+/// output and how long it took
+pub fn recording(
+    cmd: &mut Command,
+    func: impl FnOnce(&mut Command) -> std::io::Result<Output>,
+) -> std::io::Result<Output> {
+    let started = Instant::now();
+    let output = func(cmd);
+    record_command(cmd, started.elapsed(), &output);
+    output
+}
+
+/// `recording`, for a command whose stdout is a secret, such as a
+/// passphrase.  Everything but its stdout is recorded.
+pub fn recording_secret(
+    cmd: &mut Command,
+    func: impl FnOnce(&mut Command) -> std::io::Result<Output>,
+) -> std::io::Result<Output> {
+    let started = Instant::now();
+    let output = func(cmd);
+    record(cmd, started.elapsed(), &output, true);
+    output
+}
+
+/// Record that `cmd` ran for `elapsed` and produced `output`
+pub fn record_command(cmd: &Command, elapsed: Duration, output: &std::io::Result<Output>) {
+    record(cmd, elapsed, output, false)
+}
+
+fn record(cmd: &Command, elapsed: Duration, output: &std::io::Result<Output>, secret: bool) {
+    if let Some(transcript) = TRANSCRIPT.lock().unwrap().as_mut() {
+        transcript.commands += 1;
+        let section = format_command(transcript.commands, cmd, elapsed, output, secret);
+        write_section(&mut transcript.file, &section);
+    }
+}
+
+/// Describe a command for the transcript.  The stdout of a `secret`
+/// command is left out.
+fn format_command(
+    number: usize,
+    cmd: &Command,
+    elapsed: Duration,
+    output: &std::io::Result<Output>,
+    secret: bool,
+) -> String {
+    let argv: Vec<String> = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let mut text = format!(
+        "== command {}: {}\nelapsed: {}ms\n",
+        number,
+        argv.join(" "),
+        elapsed.as_millis()
+    );
+    match output {
+        Ok(output) => {
+            text.push_str(&format!("status: {}\n", output.status));
+            let streams: &[(&str, &Vec<u8>)] = if secret {
+                text.push_str("--- stdout <redacted>\n");
+                &[("stderr", &output.stderr)]
+            } else {
+                &[("stdout", &output.stdout), ("stderr", &output.stderr)]
+            };
+            for (name, data) in streams {
+                text.push_str(&format!("--- {}\n", name));
+                text.push_str(&String::from_utf8_lossy(data));
+                if !data.is_empty() && !data.ends_with(b"\n") {
+                    text.push('\n');
+                }
+            }
+        }
+        Err(err) => text.push_str(&format!("failed to run: {}\n", err)),
+    }
+    text
+}
+
+/// Record the outcome of the run and how long it took
+pub fn finish<T>(result: &Result<T>) {
+    let outcome = match result {
+        Ok(_) => "ok".to_owned(),
+        Err(err) => format!("error: {:#}", err),
+    };
+    record_outcome(&outcome);
+}
+
+/// Record the outcome of a run that ends by exiting with a particular
+/// status, rather than by returning from `main`
+pub fn record_outcome(outcome: &str) {
+    if let Some(transcript) = TRANSCRIPT.lock().unwrap().as_mut() {
+        let section = format!(
+            "== outcome\n{}\nelapsed: {}ms\n",
+            outcome,
+            transcript.started.elapsed().as_millis()
+        );
+        write_section(&mut transcript.file, &section);
+    }
+}
+
+/// A reader that keeps a copy of what is read through it while there is
+/// a transcript, so that the output of the commands whose output we
+/// stream can be recorded too
+pub struct Tee<R> {
+    inner: R,
+    copy: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl<R> Tee<R> {
+    /// Returns the reader, along with the buffer that it copies into if
+    /// there is a transcript
+    pub fn new(inner: R) -> (Self, Option<Arc<Mutex<Vec<u8>>>>) {
+        let copy = if is_active() {
+            Some(Arc::new(Mutex::new(vec![])))
+        } else {
+            None
+        };
+        (
+            Self {
+                inner,
+                copy: copy.clone(),
+            },
+            copy,
+        )
+    }
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(copy) = &self.copy {
+            copy.lock().unwrap().extend_from_slice(&buf[..len]);
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    #[test]
+    fn test_format_command() {
+        let mut cmd = Command::new("/usr/sbin/diskutil");
+        cmd.args(&["info", "-plist", "disk1s5"]);
+        let output = Ok(Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: b"{}".to_vec(),
+            stderr: b"busy\n".to_vec(),
+        });
+        assert_eq!(
+            format_command(3, &cmd, Duration::from_millis(42), &output, false),
+            "== command 3: /usr/sbin/diskutil info -plist disk1s5
+elapsed: 42ms
+status: exit status: 1
+--- stdout
+{}
+--- stderr
+busy
+"
+        );
+
+        let output = Err(std::io::Error::from_raw_os_error(libc::ENOENT));
+        assert_eq!(
+            format_command(4, &cmd, Duration::from_millis(0), &output, false),
+            "== command 4: /usr/sbin/diskutil info -plist disk1s5
+elapsed: 0ms
+failed to run: No such file or directory (os error 2)
+"
+        );
+    }
+
+    #[test]
+    fn test_format_secret_command() {
+        let mut cmd = Command::new("/usr/bin/security");
+        cmd.args(&["find-generic-password", "-s", "svc", "-a", "uuid", "-w"]);
+        let output = Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: b"hunter2\n".to_vec(),
+            stderr: vec![],
+        });
+        let text = format_command(1, &cmd, Duration::from_millis(5), &output, true);
+        assert_eq!(
+            text,
+            "== command 1: /usr/bin/security find-generic-password -s svc -a uuid -w
+elapsed: 5ms
+status: exit status: 0
+--- stdout <redacted>
+--- stderr
+"
+        );
+        assert!(!text.contains("hunter2"));
+    }
+}
@@ -0,0 +1,244 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! The names that we give to the volumes that we create.
+//! The story here is that diskutil allows any user to create an APFS
+//! volume, but requires root privs to mount it into the VFS.
+//! We're setuid root to facilitate this, but to make things safe(r)
+//! we create volumes with an encoded name so that we can tell that
+//! they were created by this tool for a specific mount point.
+//! We will only mount volumes that have that encoded name, at the
+//! location encoded by their name and refuse to mount anything else.
+//!
+//! The encoding is versioned so that it can evolve: we decode every
+//! format that we have ever written, but only ever write the newest
+//! one, and `doctor --fix` renames the volumes that have an older name.
+//!
+//! Names begin with a prefix that the policy may change from `edenfs:`,
+//! so that other tools can use this one to manage volumes of their own.
+//! Only the volumes with the prefix in effect are ours: the others are
+//! left alone, as though they were created by hand.
+
+use anyhow::*;
+use once_cell::sync::OnceCell;
+use std::path::{Component, Path, PathBuf};
+
+/// The prefix of the volume names, unless the policy says otherwise
+pub const DEFAULT_VOLUME_PREFIX: &str = "edenfs:";
+
+static VOLUME_PREFIX: OnceCell<String> = OnceCell::new();
+
+/// Check that `prefix` can be used for the volume names.  It must be a
+/// run of letters, digits, `.`, `_` and `-` ending with a `:`, so that
+/// no name can have more than one of the prefixes, and so that the
+/// prefixes don't look like paths.
+pub fn check_volume_prefix(prefix: &str) -> Result<()> {
+    let name = match prefix.strip_suffix(':') {
+        Some(name) if !name.is_empty() => name,
+        _ => bail!(
+            "the volume prefix {:?} must be a name followed by `:`, eg: `{}`",
+            prefix,
+            DEFAULT_VOLUME_PREFIX
+        ),
+    };
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')))
+    {
+        bail!(
+            "the volume prefix {:?} contains {:?}; only letters, digits, `.`, `_` and `-` \
+             may precede the `:`",
+            prefix,
+            c
+        );
+    }
+    Ok(())
+}
+
+/// Use `prefix` for the volume names for the rest of this process, in
+/// place of `DEFAULT_VOLUME_PREFIX`.  This is done by the policy loader,
+/// before any names are encoded or decoded.
+pub fn set_volume_prefix(prefix: &str) -> Result<()> {
+    check_volume_prefix(prefix)?;
+    VOLUME_PREFIX
+        .set(prefix.to_owned())
+        .map_err(|_| anyhow!("the volume prefix has already been configured"))
+}
+
+/// The prefix of the volume names in effect, loading the policy the
+/// first time.  A policy that fails to load leaves the default in effect
+/// here, and is reported by the next lookup that can fail.
+pub fn volume_prefix() -> &'static str {
+    crate::policy::load_policy().ok();
+    VOLUME_PREFIX
+        .get()
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_VOLUME_PREFIX)
+}
+
+/// The versions of the volume name encoding, oldest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NameVersion {
+    /// `<prefix><mount point>`, eg: `edenfs:/Users/wez/repo/buck-out`
+    V1,
+}
+
+/// The version that we use for new volumes
+pub const CURRENT_NAME_VERSION: NameVersion = NameVersion::V1;
+
+impl NameVersion {
+    /// The prefix that identifies names with this encoding
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::V1 => volume_prefix(),
+        }
+    }
+
+    fn all() -> &'static [NameVersion] {
+        &[Self::V1]
+    }
+}
+
+impl std::fmt::Display for NameVersion {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::V1 => write!(fmt, "v1"),
+        }
+    }
+}
+
+/// Returns true if `name` looks like one of ours, in any version of the
+/// encoding.  This only checks the prefix; use `decode_volume_name` to
+/// check that the name is valid.
+pub fn is_edenfs_volume_name(name: &str) -> bool {
+    NameVersion::all()
+        .iter()
+        .any(|version| name.starts_with(version.prefix()))
+}
+
+/// Encode a mount point as a volume name, using the current version of
+/// the encoding
+pub fn encode_mount_point_as_volume_name<P: AsRef<Path>>(mount_point: P) -> String {
+    match CURRENT_NAME_VERSION {
+        NameVersion::V1 => format!(
+            "{}{}",
+            NameVersion::V1.prefix(),
+            mount_point.as_ref().display()
+        ),
+    }
+}
+
+/// Decode a volume name produced by any version of
+/// `encode_mount_point_as_volume_name`, returning the version along with
+/// the mount point.  The mount point must be a normalized absolute path
+/// other than the root.
+pub fn decode_versioned_volume_name(name: &str) -> Option<(NameVersion, &str)> {
+    let (version, mount_point) = NameVersion::all().iter().find_map(|version| {
+        name.strip_prefix(version.prefix())
+            .map(|rest| (*version, rest))
+    })?;
+    let path = Path::new(mount_point);
+    if !path.is_absolute() || path.parent().is_none() {
+        return None;
+    }
+    if path
+        .components()
+        .any(|c| matches!(c, Component::CurDir | Component::ParentDir))
+    {
+        return None;
+    }
+    // Reject redundant separators and the like
+    if path.components().collect::<PathBuf>().as_os_str() != path.as_os_str() {
+        return None;
+    }
+    Some((version, mount_point))
+}
+
+/// Decode a volume name produced by `encode_mount_point_as_volume_name`
+/// and return the mount point.  This is stricter than checking the
+/// prefix as `is_edenfs_volume_name` does.
+pub fn decode_volume_name(name: &str) -> Option<&str> {
+    decode_versioned_volume_name(name).map(|(_, mount_point)| mount_point)
+}
+
+/// If `name` uses an older version of the encoding, return the name
+/// that it should be renamed to
+pub fn migrated_volume_name(name: &str) -> Option<String> {
+    match decode_versioned_volume_name(name)? {
+        (version, mount_point) if version < CURRENT_NAME_VERSION => {
+            Some(encode_mount_point_as_volume_name(mount_point))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_decode_volume_name() {
+        assert_eq!(decode_volume_name("edenfs:/a/b"), Some("/a/b"));
+        assert_eq!(decode_volume_name("edenfs:/a b/c"), Some("/a b/c"));
+        assert_eq!(
+            decode_volume_name(&encode_mount_point_as_volume_name("/a/b")),
+            Some("/a/b")
+        );
+        assert_eq!(
+            decode_versioned_volume_name("edenfs:/a/b"),
+            Some((NameVersion::V1, "/a/b"))
+        );
+        for name in &[
+            "",
+            "Macintosh HD",
+            "edenfs:",
+            "edenfs:/",
+            "edenfs://",
+            "edenfs:a/b",
+            "edenfs:../a",
+            "edenfs:/a/../b",
+            "edenfs:/a/./b",
+            "edenfs:/a//b",
+            "edenfs:/a/b/",
+            "Edenfs:/a/b",
+            "xedenfs:/a/b",
+            " edenfs:/a/b",
+        ] {
+            assert_eq!(decode_volume_name(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_current_version() {
+        // Every version must decode, and the newest is what we write
+        for version in NameVersion::all() {
+            assert!(*version <= CURRENT_NAME_VERSION);
+        }
+        assert_eq!(NameVersion::all().last(), Some(&CURRENT_NAME_VERSION));
+        let name = encode_mount_point_as_volume_name("/a/b");
+        assert!(is_edenfs_volume_name(&name));
+        assert_eq!(
+            decode_versioned_volume_name(&name),
+            Some((CURRENT_NAME_VERSION, "/a/b"))
+        );
+        assert_eq!(migrated_volume_name(&name), None);
+        assert_eq!(migrated_volume_name("Macintosh HD"), None);
+    }
+
+    #[test]
+    fn test_check_volume_prefix() {
+        for prefix in &[DEFAULT_VOLUME_PREFIX, "buildcache:", "ci-scratch_2.0:"] {
+            assert!(check_volume_prefix(prefix).is_ok(), "{:?}", prefix);
+        }
+        for prefix in &[
+            "", ":", "edenfs", "edenfs::", "eden:fs:", "/edenfs:", "eden fs:", "édenfs:",
+        ] {
+            assert!(check_volume_prefix(prefix).is_err(), "{:?}", prefix);
+        }
+    }
+}
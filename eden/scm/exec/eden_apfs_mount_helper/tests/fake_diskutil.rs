@@ -0,0 +1,95 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Checks that the helper runs the fake diskutil named by
+//! `EDEN_APFS_FAKE_DISKUTIL`, and that it refuses to while running
+//! setuid, which requires the `fake-diskutil` feature:
+//!
+//! ```text
+//! cargo test --features fake-diskutil --test fake_diskutil
+//! ```
+
+#![cfg(all(target_os = "macos", feature = "fake-diskutil"))]
+
+use pretty_assertions::assert_eq;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The uid and gid of `nobody`, which owns the setuid copy of the helper
+const NOBODY: u32 = 65534;
+
+/// Write a fake diskutil to `dir` that logs its arguments and fails,
+/// returning the paths of the fake and of its log
+fn write_fake_diskutil(dir: &Path) -> (PathBuf, PathBuf) {
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("argv");
+    let fake = dir.join("diskutil");
+    std::fs::write(
+        &fake,
+        format!(
+            "#!/bin/sh\necho \"$@\" > {}\necho 'fake failure' >&2\nexit 1\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+    (fake, log)
+}
+
+#[test]
+fn test_fake_diskutil() {
+    let dir = std::env::temp_dir().join(format!("eden-apfs-fake-{}", std::process::id()));
+    let (fake, log) = write_fake_diskutil(&dir);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_eden_apfs_mount_helper"))
+        .arg("list")
+        .env("EDEN_APFS_FAKE_DISKUTIL", &fake)
+        .env_remove("EDEN_APFS_FIXTURES")
+        .output()
+        .unwrap();
+    println!("{:#?}", output);
+
+    assert!(!output.status.success());
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "apfs list -plist\n");
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// A copy of the helper that is setuid to another user refuses the fake.
+/// Only root can make such a copy, so this is skipped otherwise.
+#[test]
+fn test_fake_diskutil_refused_setuid() {
+    if unsafe { libc::getuid() } != 0 {
+        println!("skipping, as only root can make a setuid copy of the helper");
+        return;
+    }
+    let dir = std::env::temp_dir().join(format!("eden-apfs-fake-setuid-{}", std::process::id()));
+    let (fake, log) = write_fake_diskutil(&dir);
+    let helper = dir.join("eden_apfs_mount_helper");
+    std::fs::copy(env!("CARGO_BIN_EXE_eden_apfs_mount_helper"), &helper).unwrap();
+    let path = CString::new(helper.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::chown(path.as_ptr(), NOBODY, NOBODY) }, 0);
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o4755)).unwrap();
+
+    // `list` gives up its privs before it runs diskutil, so we use a
+    // command that keeps them
+    let output = Command::new(&helper)
+        .args(&["stats", "--record"])
+        .env("EDEN_APFS_FAKE_DISKUTIL", &fake)
+        .env_remove("EDEN_APFS_FIXTURES")
+        .output()
+        .unwrap();
+    println!("{:#?}", output);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("refusing to honor EDEN_APFS_FAKE_DISKUTIL while running setuid"));
+    assert!(!log.exists());
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,161 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Golden tests of the CLI.  These run the helper in the fixture test
+//! mode (see src/fixtures.rs) so that they don't touch any real disks,
+//! and so they require the `fixtures` feature:
+//!
+//! ```text
+//! cargo test --features fixtures --test golden
+//! ```
+//!
+//! Each directory in `tests/fixtures/cases` is a test case.  It holds
+//! an `args` file with one argument per line, the fixtures for the
+//! commands that the helper runs, and an `expected` file with the exit
+//! status and output of the helper.  An optional `root` directory is
+//! copied into a scratch directory that the case can refer to as
+//! `@ROOT@`, keeping any symlinks in it; `@UID@` and `@GID@` are
+//! replaced by the ids of the user running the tests, and `@FIXTURES@`
+//! by the directory that the case is run from, which stands in for the
+//! directory of the registry.  An optional `stdin` file is fed to the
+//! helper, and an optional `env` file holds `NAME=value` lines to add to
+//! its environment, eg: `SUDO_UID` to run it on behalf of another user
+//! when the tests are run as root.
+//! Set `UPDATE_GOLDEN=1` to regenerate the `expected` files.
+
+#![cfg(all(target_os = "macos", feature = "fixtures"))]
+
+use pretty_assertions::assert_eq;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+struct Substitutions {
+    root: String,
+    fixtures: String,
+    uid: u32,
+    gid: u32,
+}
+
+impl Substitutions {
+    fn apply(&self, text: &str) -> String {
+        text.replace("@ROOT@", &self.root)
+            .replace("@FIXTURES@", &self.fixtures)
+            .replace("@UID@", &self.uid.to_string())
+            .replace("@GID@", &self.gid.to_string())
+    }
+
+    /// Undo `apply` on the output of the helper, so that the expected
+    /// output doesn't depend on who or where the tests are run
+    fn normalize(&self, text: &str) -> String {
+        text.replace(&self.root, "@ROOT@")
+            .replace(&self.fixtures, "@FIXTURES@")
+            .replace(
+                &format!("my real uid is {}, effective is {}", self.uid, self.uid),
+                "my real uid is @UID@, effective is @UID@",
+            )
+            .replace(
+                &format!("-u {} -g {}", self.uid, self.gid),
+                "-u @UID@ -g @GID@",
+            )
+    }
+}
+
+/// Recursively copy `src` to `dest`, applying `subst` to the contents
+/// of each file and the target of each symlink
+fn copy_tree(src: &Path, dest: &Path, subst: &Substitutions) {
+    std::fs::create_dir_all(dest).unwrap();
+    for entry in std::fs::read_dir(src).unwrap() {
+        let entry = entry.unwrap();
+        let target = dest.join(entry.file_name());
+        let file_type = entry.file_type().unwrap();
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &target, subst);
+        } else if file_type.is_symlink() {
+            let link = std::fs::read_link(entry.path()).unwrap();
+            std::os::unix::fs::symlink(subst.apply(link.to_str().unwrap()), &target).unwrap();
+        } else {
+            let text = std::fs::read_to_string(entry.path()).unwrap();
+            std::fs::write(&target, subst.apply(&text)).unwrap();
+        }
+    }
+}
+
+fn run_case(fixtures: &Path, case: &str, subst: &Substitutions) -> String {
+    let case_dir = fixtures.join("cases").join(case);
+    let args = std::fs::read_to_string(case_dir.join("args")).unwrap();
+    let stdin = std::fs::read(case_dir.join("stdin")).unwrap_or_default();
+    let env = std::fs::read_to_string(case_dir.join("env")).unwrap_or_default();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_eden_apfs_mount_helper"))
+        .args(args.lines().filter(|line| !line.is_empty()))
+        .env("EDEN_APFS_FIXTURES", &case_dir)
+        .env_remove("EDEN_APFS_TEST_CONTAINER")
+        .env_remove("SUDO_UID")
+        .envs(env.lines().filter_map(|line| line.split_once('=')))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+    subst.normalize(&format!(
+        "status: {}\n--- stdout\n{}--- stderr\n{}",
+        output.status.code().unwrap_or(-1),
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+#[test]
+fn test_golden() {
+    let source = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+    let scratch = std::env::temp_dir().join(format!("eden-apfs-golden-{}", std::process::id()));
+    std::fs::create_dir_all(&scratch).unwrap();
+    let scratch = std::fs::canonicalize(&scratch).unwrap();
+
+    let mut cases: Vec<String> = std::fs::read_dir(source.join("cases"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    cases.sort();
+
+    for case in cases {
+        let root = scratch.join(&case).join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        let fixtures = scratch.join(&case).join("fixtures");
+        let subst = Substitutions {
+            root: root.to_str().unwrap().to_owned(),
+            fixtures: fixtures
+                .join("cases")
+                .join(&case)
+                .to_str()
+                .unwrap()
+                .to_owned(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        };
+        copy_tree(&source, &fixtures, &subst);
+        let case_root = source.join("cases").join(&case).join("root");
+        if case_root.exists() {
+            copy_tree(&case_root, &root, &subst);
+        }
+
+        let actual = run_case(&fixtures, &case, &subst);
+        let expected_path = source.join("cases").join(&case).join("expected");
+        if update {
+            std::fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&expected_path).unwrap_or_default();
+        assert_eq!(expected, actual, "golden output for case {}", case);
+    }
+
+    std::fs::remove_dir_all(&scratch).ok();
+}
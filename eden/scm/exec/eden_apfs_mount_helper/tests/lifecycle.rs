@@ -0,0 +1,76 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Exercises the create/mount/unmount/delete lifecycle against real
+//! APFS volumes.  This is destructive, so it only runs when a test
+//! container has been nominated via `EDEN_APFS_TEST_CONTAINER`; the
+//! helper then refuses to touch volumes in any other container.
+//! The helper binary must be installed setuid root for the mount to
+//! succeed.
+
+#![cfg(target_os = "macos")]
+
+use eden_apfs_mount_helper::apfs::{apfs_list, system_container};
+use std::path::Path;
+use std::process::{Command, Output};
+
+const TEST_CONTAINER_ENV: &str = "EDEN_APFS_TEST_CONTAINER";
+
+fn helper(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_eden_apfs_mount_helper"))
+        .args(args)
+        .output()
+        .expect("failed to run the helper");
+    println!("{:?}: {:#?}", args, output);
+    output
+}
+
+fn is_mounted(path: &Path) -> bool {
+    let output = helper(&["info", path.to_str().unwrap()]);
+    output.status.success()
+        && String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line == format!("mounted\t{}", path.display()))
+}
+
+#[test]
+fn test_lifecycle() {
+    let container = match std::env::var(TEST_CONTAINER_ENV) {
+        Ok(container) if !container.is_empty() => container,
+        _ => {
+            eprintln!(
+                "skipping the volume lifecycle test because {} is not set",
+                TEST_CONTAINER_ENV
+            );
+            return;
+        }
+    };
+    let containers = apfs_list().unwrap();
+    assert_ne!(
+        container,
+        system_container(&containers, None)
+            .unwrap()
+            .container_reference,
+        "refusing to test against the boot container"
+    );
+
+    let dir = std::env::temp_dir().join(format!("eden-apfs-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let dir = std::fs::canonicalize(&dir).unwrap();
+    let path = dir.to_str().unwrap();
+
+    assert!(helper(&["mount", path]).status.success());
+    assert!(is_mounted(&dir));
+
+    assert!(helper(&["unmount", path]).status.success());
+    assert!(!is_mounted(&dir));
+
+    assert!(helper(&["delete", path]).status.success());
+    assert!(!helper(&["info", path]).status.success());
+
+    std::fs::remove_dir(&dir).ok();
+}
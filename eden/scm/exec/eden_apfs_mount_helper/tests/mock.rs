@@ -0,0 +1,74 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Exercises the mock backend (see src/mock.rs), which requires the
+//! `mock` feature:
+//!
+//! ```text
+//! cargo test --features mock --test mock
+//! ```
+
+#![cfg(feature = "mock")]
+
+use pretty_assertions::assert_eq;
+use std::path::Path;
+use std::process::Command;
+
+fn helper(state: &Path, args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_eden_apfs_mount_helper"))
+        .args(args)
+        .env("EDEN_APFS_MOCK_DIR", state)
+        .output()
+        .expect("failed to run the helper");
+    println!("{:?}: {:#?}", args, output);
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    )
+}
+
+#[test]
+fn test_mock_lifecycle() {
+    let dir = std::env::temp_dir().join(format!("eden-apfs-mock-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("repo/buck-out")).unwrap();
+    let dir = std::fs::canonicalize(&dir).unwrap();
+    let state = dir.join("state");
+    let mount_point = dir.join("repo/buck-out");
+    let path = mount_point.to_str().unwrap();
+
+    assert!(helper(&state, &["mount", path]).0);
+    std::fs::write(mount_point.join("file"), "hello").unwrap();
+    // Mounting again is a no-op
+    assert!(helper(&state, &["mount", path]).0);
+    assert_eq!(
+        helper(&state, &["list"]).1,
+        format!("mock1\tedenfs:{}\t{}\n", path, path)
+    );
+
+    assert!(helper(&state, &["unmount", path]).0);
+    assert!(!mount_point.join("file").exists());
+    assert_eq!(
+        helper(&state, &["info", path]).1,
+        format!("name\tedenfs:{}\ndevice\tmock1\nmounted\tno\n", path)
+    );
+
+    // The contents of the volume persist across mounts
+    assert!(helper(&state, &["mount", path]).0);
+    assert_eq!(
+        std::fs::read_to_string(mount_point.join("file")).unwrap(),
+        "hello"
+    );
+
+    assert_eq!(
+        helper(&state, &["delete-all"]),
+        (true, format!("Deleted {}\n", path))
+    );
+    assert!(mount_point.is_dir());
+    assert!(!helper(&state, &["info", path]).0);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
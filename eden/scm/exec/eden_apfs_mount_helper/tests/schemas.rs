@@ -0,0 +1,57 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Checks that the JSON Schemas in `schemas/` match the types that
+//! produce our JSON output, which are their source of truth.
+//! Set `UPDATE_GOLDEN=1` to regenerate the schemas after changing
+//! those types.
+
+use eden_apfs_mount_helper::report::{
+    AuditReport, BatchCommand, BatchResult, Capabilities, ChangeSummary, FsckReport, HealthReport,
+    HistoryReport, LatencyReport, ListReport, ResizePreflight, SpaceCheck, UsageReport,
+};
+use eden_apfs_mount_helper::ContainerStats;
+use pretty_assertions::assert_eq;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use std::path::PathBuf;
+
+fn check_schema(name: &str, schema: RootSchema) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("schemas")
+        .join(format!("{}.json", name));
+    let actual = serde_json::to_string_pretty(&schema).unwrap() + "\n";
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path).unwrap_or_default();
+    assert_eq!(
+        expected,
+        actual,
+        "{} is out of date; run with UPDATE_GOLDEN=1 to regenerate it",
+        path.display()
+    );
+}
+
+#[test]
+fn test_schemas() {
+    check_schema("audit", schema_for!(AuditReport));
+    check_schema("batch-command", schema_for!(BatchCommand));
+    check_schema("batch-result", schema_for!(BatchResult));
+    check_schema("check-space", schema_for!(SpaceCheck));
+    check_schema("capabilities", schema_for!(Capabilities));
+    check_schema("changes", schema_for!(ChangeSummary));
+    check_schema("free-space", schema_for!(Vec<ContainerStats>));
+    check_schema("fsck", schema_for!(FsckReport));
+    check_schema("health", schema_for!(HealthReport));
+    check_schema("history", schema_for!(HistoryReport));
+    check_schema("latency", schema_for!(LatencyReport));
+    check_schema("list", schema_for!(ListReport));
+    check_schema("resize-preflight", schema_for!(ResizePreflight));
+    check_schema("usage", schema_for!(UsageReport));
+}